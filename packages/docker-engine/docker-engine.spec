@@ -21,7 +21,7 @@ Source0: https://%{repo}/archive/v%{gover}/%{project}-%{gover}.tar.gz
 Source1: docker.service
 Source2: docker.socket
 Source3: docker-sysusers.conf
-Source4: daemon-json
+Source4: docker-daemon-json
 Source1000: clarify.toml
 
 # CVE-2021-41091
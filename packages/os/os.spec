@@ -24,6 +24,7 @@ Source3: eni-max-pods
 Source5: updog-toml
 Source6: metricdog-toml
 Source7: host-ctr-toml
+Source8: updates-toml.partial
 
 # 1xx sources: systemd units
 Source100: apiserver.service
@@ -38,6 +39,7 @@ Source111: metricdog.service
 Source112: metricdog.timer
 Source113: send-boot-success.service
 Source114: bootstrap-containers@.service
+Source115: thar-be-settings.service
 
 # 2xx sources: tmpfilesd configs
 Source200: migration-tmpfiles.conf
@@ -370,6 +372,15 @@ for version_path in %{_builddir}/sources/api/migration/migrations/v[0-9]*; do
     [ -e "${migration_path}" ] || continue
 
     version="${version_path##*/}"
+
+    # Declarative migrations are installed as-is for the migrator to interpret.
+    if [[ "${migration_path}" == *.toml ]]; then
+      migration_file_name="${migration_path##*/}"
+      target_path="%{buildroot}%{_cross_datadir}/migrations/migrate_${version}_${migration_file_name}"
+      install -m 0444 "${migration_path}" "${target_path}"
+      continue
+    fi
+
     crate_name="${migration_path##*/}"
     migration_binary_name="migrate_${version}_${crate_name#migrate-}"
     built_path="${HOME}/.cache/.static/%{__cargo_target_static}/release/${crate_name}"
@@ -396,12 +407,14 @@ install -p -m 0644 %{_cross_repo_root_json} %{buildroot}%{_cross_datadir}/updog
 
 install -d %{buildroot}%{_cross_templatedir}
 install -p -m 0644 %{S:5} %{S:6} %{S:7} %{buildroot}%{_cross_templatedir}
+install -d %{buildroot}%{_cross_templatedir}/partials
+install -p -m 0644 %{S:8} %{buildroot}%{_cross_templatedir}/partials
 
 install -d %{buildroot}%{_cross_unitdir}
 install -p -m 0644 \
   %{S:100} %{S:101} %{S:102} %{S:103} %{S:105} \
   %{S:106} %{S:107} %{S:110} %{S:111} %{S:112} \
-  %{S:113} %{S:114} \
+  %{S:113} %{S:114} %{S:115} \
   %{buildroot}%{_cross_unitdir}
 
 install -d %{buildroot}%{_cross_tmpfilesdir}
@@ -446,6 +459,8 @@ install -p -m 0644 %{S:300} %{buildroot}%{_cross_udevrulesdir}/80-ephemeral-stor
 
 %files -n %{_cross_os}schnauzer
 %{_cross_bindir}/schnauzer
+%dir %{_cross_templatedir}/partials
+%{_cross_templatedir}/partials/updates-toml.partial
 
 %files -n %{_cross_os}bork
 %{_cross_bindir}/bork
@@ -453,6 +468,7 @@ install -p -m 0644 %{S:300} %{buildroot}%{_cross_udevrulesdir}/80-ephemeral-stor
 %files -n %{_cross_os}thar-be-settings
 %{_cross_bindir}/thar-be-settings
 %{_cross_unitdir}/settings-applier.service
+%{_cross_unitdir}/thar-be-settings.service
 
 %files -n %{_cross_os}thar-be-updates
 %{_cross_bindir}/thar-be-updates
@@ -15,6 +15,7 @@ Source200: motd.template
 Source201: proxy-env
 Source202: hostname-env
 Source203: hosts.template
+Source204: no-proxy.partial
 
 Source1000: eth0.xml
 Source1001: multi-user.target
@@ -136,6 +137,8 @@ install -p -m 0644 %{S:200} %{buildroot}%{_cross_templatedir}/motd
 install -p -m 0644 %{S:201} %{buildroot}%{_cross_templatedir}/proxy-env
 install -p -m 0644 %{S:202} %{buildroot}%{_cross_templatedir}/hostname-env
 install -p -m 0644 %{S:203} %{buildroot}%{_cross_templatedir}/hosts
+install -d %{buildroot}%{_cross_templatedir}/partials
+install -p -m 0644 %{S:204} %{buildroot}%{_cross_templatedir}/partials/no-proxy.partial
 
 install -d %{buildroot}%{_cross_udevrulesdir}
 install -p -m 0644 %{S:1016} %{buildroot}%{_cross_udevrulesdir}/61-mount-cdrom.rules
@@ -176,6 +179,8 @@ ln -s %{_cross_unitdir}/preconfigured.target %{buildroot}%{_cross_unitdir}/defau
 %{_cross_templatedir}/proxy-env
 %{_cross_templatedir}/hostname-env
 %{_cross_templatedir}/hosts
+%dir %{_cross_templatedir}/partials
+%{_cross_templatedir}/partials/no-proxy.partial
 %{_cross_udevrulesdir}/61-mount-cdrom.rules
 
 %changelog
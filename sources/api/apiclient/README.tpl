@@ -102,6 +102,30 @@ apiclient update apply --check --reboot
 
 > Note that available updates are controlled by your settings under `settings.updates`; see [README](../../../README.md#updates-settings) for details.
 
+### Check mode
+
+This evaluates a built-in set of hardening checks against the host and reports whether each passed or failed.
+
+```
+apiclient check
+```
+
+The checks currently confirm that:
+* kernel lockdown is enabled, and matches `settings.kernel.lockdown`
+* the live values of sysctls match `settings.kernel.sysctl`
+* SELinux is enforcing
+* no superpowered host containers are enabled
+* updates come from the default Bottlerocket update repository, meaning `settings.updates.metadata-base-url` and `settings.updates.targets-base-url` weren't set by a user
+
+If a check can't determine the state of the host, it's reported as unknown rather than failed.
+The exit status is nonzero if any check failed.
+
+For machine-readable output, for example to collect reports from many hosts, request JSON:
+
+```
+apiclient check --output json
+```
+
 ### Reboot mode
 
 This will reboot the system.
@@ -0,0 +1,513 @@
+/*!
+This module evaluates a built-in set of hardening checks against the running host, using both the
+settings from the API and the live state of the kernel, so that users can confirm at a glance that
+a host is configured the way they expect.
+
+Each check results in a pass or a failure, along with a short description of what was found.  If a
+check can't determine the state of the host, for example because a kernel interface is missing,
+its status is "unknown" rather than a failure, so that it's clear the host wasn't evaluated.
+*/
+
+use serde::Serialize;
+use serde_json::Value;
+use snafu::ResultExt;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The settings that choose the update repository.
+const UPDATE_REPOSITORY_SETTINGS: &[&str] = &[
+    "settings.updates.metadata-base-url",
+    "settings.updates.targets-base-url",
+];
+
+/// The outcome of a single check.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Pass,
+    Fail,
+    Unknown,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Status::Pass => write!(f, "PASS"),
+            Status::Fail => write!(f, "FAIL"),
+            Status::Unknown => write!(f, "UNKNOWN"),
+        }
+    }
+}
+
+/// The result of a single check, including a human-readable description of what was found.
+#[derive(Debug, Clone, Serialize)]
+pub struct CheckResult {
+    pub name: &'static str,
+    pub status: Status,
+    pub detail: String,
+}
+
+impl CheckResult {
+    fn new<S>(name: &'static str, status: Status, detail: S) -> Self
+    where
+        S: Into<String>,
+    {
+        Self {
+            name,
+            status,
+            detail: detail.into(),
+        }
+    }
+}
+
+/// The results of all checks run against the host.
+#[derive(Debug, Clone, Serialize)]
+pub struct Report {
+    pub results: Vec<CheckResult>,
+}
+
+impl Report {
+    /// Returns the number of checks that passed.
+    pub fn passed(&self) -> usize {
+        self.count(Status::Pass)
+    }
+
+    /// Returns the number of checks that failed.
+    pub fn failed(&self) -> usize {
+        self.count(Status::Fail)
+    }
+
+    fn count(&self, status: Status) -> usize {
+        self.results.iter().filter(|r| r.status == status).count()
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let width = self.results.iter().map(|r| r.name.len()).max().unwrap_or(0);
+        for result in &self.results {
+            writeln!(
+                f,
+                "{:<7} {:<width$}  {}",
+                result.status.to_string(),
+                result.name,
+                result.detail,
+                width = width
+            )?;
+        }
+        write!(
+            f,
+            "\n{} checks, {} passed, {} failed",
+            self.results.len(),
+            self.passed(),
+            self.failed()
+        )
+    }
+}
+
+/// Locations of the kernel interfaces we inspect, so they can be replaced in testing.
+#[derive(Debug)]
+struct Host {
+    lockdown: PathBuf,
+    selinux_enforce: PathBuf,
+    sysctl_root: PathBuf,
+}
+
+impl Default for Host {
+    fn default() -> Self {
+        Self {
+            lockdown: PathBuf::from("/sys/kernel/security/lockdown"),
+            selinux_enforce: PathBuf::from("/sys/fs/selinux/enforce"),
+            sysctl_root: PathBuf::from("/proc/sys"),
+        }
+    }
+}
+
+/// Fetches settings and their origins from the API and runs all checks against the host.
+pub async fn check<P>(socket_path: P) -> Result<Report>
+where
+    P: AsRef<Path>,
+{
+    let settings = get_json(&socket_path, "/settings").await?;
+    let origins = get_json(&socket_path, constants::API_SETTING_ORIGINS_URI).await?;
+    Ok(run_checks(&Host::default(), &settings, &origins))
+}
+
+async fn get_json<P>(socket_path: P, uri: &'static str) -> Result<Value>
+where
+    P: AsRef<Path>,
+{
+    let method = "GET";
+    let (_status, body) = crate::raw_request(&socket_path, uri, method, None)
+        .await
+        .context(error::Request { uri, method })?;
+    serde_json::from_str(&body).context(error::ResponseJson { uri })
+}
+
+fn run_checks(host: &Host, settings: &Value, origins: &Value) -> Report {
+    Report {
+        results: vec![
+            check_lockdown(host, settings),
+            check_sysctls(host, settings),
+            check_selinux(host),
+            check_superpowered_host_containers(settings),
+            check_update_repository(settings, origins),
+        ],
+    }
+}
+
+/// Confirms the kernel is in a lockdown mode other than "none", and that it matches the setting.
+fn check_lockdown(host: &Host, settings: &Value) -> CheckResult {
+    let name = "kernel-lockdown";
+    let current = match fs::read_to_string(&host.lockdown) {
+        Ok(raw) => parse_kernel_setting(&raw).to_string(),
+        Err(e) => {
+            return CheckResult::new(
+                name,
+                Status::Unknown,
+                format!("Unable to read {}: {}", host.lockdown.display(), e),
+            )
+        }
+    };
+
+    let configured = settings
+        .pointer("/kernel/lockdown")
+        .and_then(Value::as_str)
+        .unwrap_or("none");
+
+    if current == "none" {
+        CheckResult::new(name, Status::Fail, "Kernel lockdown is 'none'")
+    } else if current != configured {
+        CheckResult::new(
+            name,
+            Status::Fail,
+            format!(
+                "Kernel lockdown is '{}' but settings.kernel.lockdown is '{}'",
+                current, configured
+            ),
+        )
+    } else {
+        CheckResult::new(
+            name,
+            Status::Pass,
+            format!("Kernel lockdown is '{}'", current),
+        )
+    }
+}
+
+/// Confirms that the live value of each sysctl in settings.kernel.sysctl matches the setting.
+fn check_sysctls(host: &Host, settings: &Value) -> CheckResult {
+    let name = "kernel-sysctl";
+    let sysctls = match settings
+        .pointer("/kernel/sysctl")
+        .and_then(Value::as_object)
+    {
+        Some(sysctls) if !sysctls.is_empty() => sysctls,
+        _ => return CheckResult::new(name, Status::Pass, "No sysctls are configured"),
+    };
+
+    let mut mismatched = Vec::new();
+    let mut unreadable = Vec::new();
+    for (key, expected) in sysctls {
+        let expected = expected.as_str().unwrap_or_default();
+        match fs::read_to_string(sysctl_path(&host.sysctl_root, key)) {
+            // The kernel separates multiple values with tabs, but users usually give spaces.
+            Ok(actual) if normalize(&actual) == normalize(expected) => {}
+            Ok(actual) => mismatched.push(format!(
+                "{} is '{}', expected '{}'",
+                key,
+                normalize(&actual),
+                expected
+            )),
+            Err(_) => unreadable.push(key.as_str()),
+        }
+    }
+
+    if !mismatched.is_empty() {
+        CheckResult::new(name, Status::Fail, mismatched.join("; "))
+    } else if !unreadable.is_empty() {
+        CheckResult::new(
+            name,
+            Status::Unknown,
+            format!("Unable to read sysctls: {}", unreadable.join(", ")),
+        )
+    } else {
+        CheckResult::new(
+            name,
+            Status::Pass,
+            format!("All {} configured sysctls match", sysctls.len()),
+        )
+    }
+}
+
+/// Confirms SELinux is in enforcing mode.
+fn check_selinux(host: &Host) -> CheckResult {
+    let name = "selinux-enforcing";
+    match fs::read_to_string(&host.selinux_enforce) {
+        Ok(raw) if raw.trim() == "1" => {
+            CheckResult::new(name, Status::Pass, "SELinux is enforcing")
+        }
+        Ok(_) => CheckResult::new(name, Status::Fail, "SELinux is permissive"),
+        Err(e) => CheckResult::new(
+            name,
+            Status::Unknown,
+            format!("Unable to read {}: {}", host.selinux_enforce.display(), e),
+        ),
+    }
+}
+
+/// Confirms no superpowered host containers are enabled.
+fn check_superpowered_host_containers(settings: &Value) -> CheckResult {
+    let name = "superpowered-host-containers";
+    let mut enabled = Vec::new();
+    if let Some(containers) = settings.get("host-containers").and_then(Value::as_object) {
+        for (container, config) in containers {
+            let is_set = |field: &str| config.get(field).and_then(Value::as_bool) == Some(true);
+            if is_set("enabled") && is_set("superpowered") {
+                enabled.push(container.as_str());
+            }
+        }
+    }
+    enabled.sort_unstable();
+
+    if enabled.is_empty() {
+        CheckResult::new(
+            name,
+            Status::Pass,
+            "No superpowered host containers are enabled",
+        )
+    } else {
+        CheckResult::new(
+            name,
+            Status::Fail,
+            format!(
+                "Superpowered host containers are enabled: {}",
+                enabled.join(", ")
+            ),
+        )
+    }
+}
+
+/// Confirms updates come from the default Bottlerocket update repository, meaning the settings
+/// that choose the repository have the values from their defaults rather than ones a user set.
+fn check_update_repository(settings: &Value, origins: &Value) -> CheckResult {
+    let name = "default-update-repository";
+    let mut custom = Vec::new();
+    let mut unknown = Vec::new();
+    for setting in UPDATE_REPOSITORY_SETTINGS {
+        // Defaults are committed by storewolf, or generated by sundog from a default template.
+        match origins.get(setting).and_then(Value::as_str) {
+            Some("default") | Some("generated") => {}
+            Some(_) => {
+                let configured = setting
+                    .strip_prefix("settings.")
+                    .map(|path| format!("/{}", path.replace('.', "/")))
+                    .and_then(|pointer| settings.pointer(&pointer))
+                    .and_then(Value::as_str)
+                    .unwrap_or_default();
+                custom.push(format!("{} is set to '{}'", setting, configured));
+            }
+            None => unknown.push(*setting),
+        }
+    }
+
+    if !custom.is_empty() {
+        CheckResult::new(name, Status::Fail, custom.join("; "))
+    } else if !unknown.is_empty() {
+        CheckResult::new(
+            name,
+            Status::Unknown,
+            format!("Unable to determine origin of {}", unknown.join(", ")),
+        )
+    } else {
+        CheckResult::new(name, Status::Pass, "Updates use the default repository")
+    }
+}
+
+/// Finds the file under `root` for a sysctl, the way sysctl(8) does.  Components of the key are
+/// separated by dots, and a slash stands for a dot within a component, so interface names like
+/// `eth0.100` can be given as `net.ipv4.conf.eth0/100.rp_filter`.  If the first separator in the
+/// key is a slash, components are separated by slashes instead.
+fn sysctl_path(root: &Path, key: &str) -> PathBuf {
+    let mut path = root.to_path_buf();
+    let components: Vec<String> = match key.find(['.', '/']) {
+        Some(idx) if key[idx..].starts_with('/') => key.split('/').map(String::from).collect(),
+        _ => key.split('.').map(|c| c.replace('/', ".")).collect(),
+    };
+    // Skip components that would leave the sysctl directory.
+    path.extend(
+        components
+            .iter()
+            .filter(|c| !matches!(c.as_str(), "" | "." | "..")),
+    );
+    path
+}
+
+/// The Linux kernel provides human-readable output like `[none] integrity confidentiality` when
+/// you read settings from virtual files like /sys/kernel/security/lockdown.  This parses out the
+/// current value of the setting from that human-readable output.
+fn parse_kernel_setting(setting: &str) -> &str {
+    let mut setting = setting.trim();
+    if let Some(idx) = setting.find('[') {
+        setting = &setting[idx + 1..];
+    }
+    if let Some(idx) = setting.find(']') {
+        setting = &setting[..idx];
+    }
+    setting
+}
+
+/// Collapses whitespace so multi-value sysctls compare equal regardless of separators.
+fn normalize(value: &str) -> String {
+    value.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+mod error {
+    use snafu::Snafu;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility = "pub(super)")]
+    pub enum Error {
+        #[snafu(display("Failed {} request to '{}': {}", method, uri, source))]
+        Request {
+            method: String,
+            uri: String,
+            source: crate::Error,
+        },
+
+        #[snafu(display("Unable to deserialize response from '{}': {}", uri, source))]
+        ResponseJson {
+            uri: String,
+            source: serde_json::Error,
+        },
+    }
+}
+pub use error::Error;
+pub type Result<T> = std::result::Result<T, error::Error>;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+    use tempfile::TempDir;
+
+    /// Creates fake kernel interfaces in a temporary directory.
+    fn fake_host(lockdown: &str, enforce: &str, sysctls: &[(&str, &str)]) -> (TempDir, Host) {
+        let dir = TempDir::new().unwrap();
+        let host = Host {
+            lockdown: dir.path().join("lockdown"),
+            selinux_enforce: dir.path().join("enforce"),
+            sysctl_root: dir.path().join("sys"),
+        };
+        fs::write(&host.lockdown, lockdown).unwrap();
+        fs::write(&host.selinux_enforce, enforce).unwrap();
+        for (key, value) in sysctls {
+            let path = sysctl_path(&host.sysctl_root, key);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, value).unwrap();
+        }
+        (dir, host)
+    }
+
+    fn origins() -> Value {
+        json!({
+            "settings.updates.metadata-base-url": "generated",
+            "settings.updates.targets-base-url": "default"
+        })
+    }
+
+    fn settings() -> Value {
+        json!({
+            "kernel": {
+                "lockdown": "integrity",
+                "sysctl": {"net.ipv4.ip_local_port_range": "32768 60999"}
+            },
+            "host-containers": {
+                "admin": {"enabled": false, "superpowered": true},
+                "control": {"enabled": true, "superpowered": false}
+            },
+            "updates": {
+                "metadata-base-url": "https://updates.bottlerocket.aws/2020-07-07/aws-k8s-1.21/x86_64/",
+                "targets-base-url": "https://updates.bottlerocket.aws/targets/"
+            }
+        })
+    }
+
+    #[test]
+    fn all_pass() {
+        let (_dir, host) = fake_host(
+            "none [integrity] confidentiality\n",
+            "1",
+            &[("net.ipv4.ip_local_port_range", "32768\t60999\n")],
+        );
+        let report = run_checks(&host, &settings(), &origins());
+        for result in &report.results {
+            assert_eq!(result.status, Status::Pass, "{:?}", result);
+        }
+        assert_eq!(report.failed(), 0);
+    }
+
+    #[test]
+    fn failures() {
+        let (_dir, host) = fake_host(
+            "[none] integrity confidentiality\n",
+            "0",
+            &[("net.ipv4.ip_local_port_range", "1024\t65535\n")],
+        );
+        let mut settings = settings();
+        settings["host-containers"]["admin"]["enabled"] = json!(true);
+        settings["updates"]["targets-base-url"] = json!("https://example.com/targets/");
+        let mut origins = origins();
+        origins["settings.updates.targets-base-url"] = json!("user");
+
+        let report = run_checks(&host, &settings, &origins);
+        for result in &report.results {
+            assert_eq!(result.status, Status::Fail, "{:?}", result);
+        }
+        assert_eq!(report.failed(), 5);
+    }
+
+    #[test]
+    fn lockdown_mismatch() {
+        let (_dir, host) = fake_host("none integrity [confidentiality]", "1", &[]);
+        let result = check_lockdown(&host, &settings());
+        assert_eq!(result.status, Status::Fail);
+    }
+
+    #[test]
+    fn update_repository_origin_unknown() {
+        let result = check_update_repository(&settings(), &json!({}));
+        assert_eq!(result.status, Status::Unknown);
+    }
+
+    #[test]
+    fn sysctl_paths() {
+        let root = Path::new("/proc/sys");
+        for key in &[
+            "net.ipv4.conf.eth0/100.rp_filter",
+            "net/ipv4/conf/eth0.100/rp_filter",
+        ] {
+            assert_eq!(
+                sysctl_path(root, key),
+                Path::new("/proc/sys/net/ipv4/conf/eth0.100/rp_filter")
+            );
+        }
+        assert_eq!(
+            sysctl_path(root, "net/../../root"),
+            Path::new("/proc/sys/net/root")
+        );
+    }
+
+    #[test]
+    fn unreadable_interfaces() {
+        let host = Host {
+            lockdown: PathBuf::from("/nonexistent/lockdown"),
+            selinux_enforce: PathBuf::from("/nonexistent/enforce"),
+            sysctl_root: PathBuf::from("/nonexistent/sys"),
+        };
+        assert_eq!(check_lockdown(&host, &settings()).status, Status::Unknown);
+        assert_eq!(check_selinux(&host).status, Status::Unknown);
+        assert_eq!(check_sysctls(&host, &settings()).status, Status::Unknown);
+    }
+}
@@ -1,7 +1,8 @@
 #![deny(rust_2018_idioms)]
 
 //! The apiclient library provides high-level methods to interact with the Bottlerocket API.  See
-//! the documentation for submodules [`reboot`], [`set`], and [`update`] for high-level helpers.
+//! the documentation for submodules [`check`], [`reboot`], [`set`], and [`update`] for high-level
+//! helpers.
 //!
 //! For more control, and to handle APIs without high-level wrappers, there are also 'raw' methods
 //! to query an HTTP API over a Unix-domain socket.
@@ -21,6 +22,7 @@ use snafu::{ensure, ResultExt};
 use std::path::Path;
 
 pub mod apply;
+pub mod check;
 pub mod reboot;
 pub mod set;
 pub mod update;
@@ -6,14 +6,14 @@
 // library calls based on the given flags, etc.)  The library modules contain the code for talking
 // to the API, which is intended to be reusable by other crates.
 
-use apiclient::{apply, reboot, set, update};
+use apiclient::{apply, check, reboot, set, update};
 use constants;
 use datastore::{serialize_scalar, Key, KeyType};
 use log::{info, log_enabled, trace, warn};
 use simplelog::{
     ColorChoice, ConfigBuilder as LogConfigBuilder, LevelFilter, TermLogger, TerminalMode,
 };
-use snafu::ResultExt;
+use snafu::{ensure, ResultExt};
 use std::collections::HashMap;
 use std::env;
 use std::process;
@@ -42,6 +42,7 @@ impl Default for Args {
 #[derive(Debug)]
 enum Subcommand {
     Apply(ApplyArgs),
+    Check(CheckArgs),
     Raw(RawArgs),
     Reboot(RebootArgs),
     Set(SetArgs),
@@ -54,6 +55,19 @@ struct ApplyArgs {
     input_sources: Vec<String>,
 }
 
+/// Stores user-supplied arguments for the 'check' subcommand.
+#[derive(Debug)]
+struct CheckArgs {
+    format: CheckFormat,
+}
+
+/// The output formats supported by the 'check' subcommand.
+#[derive(Debug)]
+enum CheckFormat {
+    Text,
+    Json,
+}
+
 /// Stores user-supplied arguments for the 'raw' subcommand.
 #[derive(Debug)]
 struct RawArgs {
@@ -112,6 +126,8 @@ fn usage() -> ! {
                                        'raw' is the default subcommand and may be omitted.
             apply                      Applies settings from TOML/JSON files at given URIs,
                                        or from stdin.
+            check                      Checks the host against a set of hardening rules and
+                                       reports the result of each.
             set                        Changes settings and applies them to the system.
             update check               Prints information about available updates.
             update apply               Applies available updates.
@@ -128,6 +144,9 @@ fn usage() -> ! {
                                        want to apply to the system.  If no URI is specified, or
                                        if "-" is given, reads from stdin.
 
+        check options:
+            -o, --output FORMAT        Output format; text|json.  Default: text
+
         reboot options:
             None.
 
@@ -198,7 +217,7 @@ fn parse_args(args: env::Args) -> (Args, Subcommand) {
             }
 
             // Subcommands
-            "raw" | "apply" | "reboot" | "set" | "update"
+            "raw" | "apply" | "check" | "reboot" | "set" | "update"
                 if subcommand.is_none() && !arg.starts_with('-') =>
             {
                 subcommand = Some(arg)
@@ -213,6 +232,7 @@ fn parse_args(args: env::Args) -> (Args, Subcommand) {
         // Default subcommand is 'raw'
         None | Some("raw") => return (global_args, parse_raw_args(subcommand_args)),
         Some("apply") => return (global_args, parse_apply_args(subcommand_args)),
+        Some("check") => return (global_args, parse_check_args(subcommand_args)),
         Some("reboot") => return (global_args, parse_reboot_args(subcommand_args)),
         Some("set") => return (global_args, parse_set_args(subcommand_args)),
         Some("update") => return (global_args, parse_update_args(subcommand_args)),
@@ -286,6 +306,31 @@ fn parse_apply_args(args: Vec<String>) -> Subcommand {
     Subcommand::Apply(ApplyArgs { input_sources })
 }
 
+/// Parses arguments for the 'check' subcommand.
+fn parse_check_args(args: Vec<String>) -> Subcommand {
+    let mut format = CheckFormat::Text;
+
+    let mut iter = args.into_iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_ref() {
+            "-o" | "--output" => {
+                let format_str = iter
+                    .next()
+                    .unwrap_or_else(|| usage_msg("Did not give argument to -o | --output"));
+                format = match format_str.as_ref() {
+                    "text" => CheckFormat::Text,
+                    "json" => CheckFormat::Json,
+                    x => usage_msg(&format!("Invalid output format '{}'", x)),
+                };
+            }
+
+            x => usage_msg(&format!("Unknown argument '{}'", x)),
+        }
+    }
+
+    Subcommand::Check(CheckArgs { format })
+}
+
 /// Parses arguments for the 'reboot' subcommand.
 fn parse_reboot_args(args: Vec<String>) -> Subcommand {
     if !args.is_empty() {
@@ -540,6 +585,24 @@ async fn run() -> Result<()> {
                 .context(error::Apply)?;
         }
 
+        Subcommand::Check(check) => {
+            let report = check::check(&args.socket_path)
+                .await
+                .context(error::Check)?;
+
+            match check.format {
+                CheckFormat::Text => println!("{}", report),
+                CheckFormat::Json => println!(
+                    "{}",
+                    serde_json::to_string_pretty(&report).context(error::Serialize)?
+                ),
+            }
+
+            // Let callers rely on the exit status rather than parsing the report.
+            let failed = report.failed();
+            ensure!(failed == 0, error::ChecksFailed { failed });
+        }
+
         Subcommand::Reboot(_reboot) => {
             reboot::reboot(&args.socket_path)
                 .await
@@ -625,7 +688,7 @@ async fn main() {
 }
 
 mod error {
-    use apiclient::{apply, reboot, set, update};
+    use apiclient::{apply, check, reboot, set, update};
     use snafu::Snafu;
 
     #[derive(Debug, Snafu)]
@@ -634,6 +697,12 @@ mod error {
         #[snafu(display("Failed to apply settings: {}", source))]
         Apply { source: apply::Error },
 
+        #[snafu(display("Failed to check host: {}", source))]
+        Check { source: check::Error },
+
+        #[snafu(display("{} host checks failed", failed))]
+        ChecksFailed { failed: usize },
+
         #[snafu(display("Unable to deserialize input JSON into model: {}", source))]
         DeserializeJson { source: serde_json::Error },
 
@@ -5,14 +5,21 @@ use bottlerocket_release::BottlerocketRelease;
 use serde::de::DeserializeOwned;
 use snafu::{ensure, OptionExt, ResultExt};
 use std::collections::{HashMap, HashSet};
-use std::io::Write;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
 use std::process::{Command, Stdio};
+use std::time::Duration;
 
 use crate::server::error::{self, Result};
 use actix_web::HttpResponse;
 use datastore::deserialization::{from_map, from_map_with_prefix};
 use datastore::serialization::to_pairs;
-use datastore::{deserialize_scalar, Committed, DataStore, Key, KeyType, ScalarError, Value};
+use datastore::{
+    deserialize_scalar, serialize_scalar, Committed, DataStore, Key, KeyType, ScalarError, Value,
+};
+use fs2::FileExt;
 use model::{ConfigurationFiles, Services, Settings};
 use num::FromPrimitive;
 use std::os::unix::process::ExitStatusExt;
@@ -82,6 +89,18 @@ pub(crate) fn get_os_info() -> Result<BottlerocketRelease> {
     BottlerocketRelease::new().context(error::ReleaseData)
 }
 
+/// Read the migrator's reports of its recent runs.  They're passed through as they are, so we can
+/// show reports written by other versions of the migrator.  If there's no report, no migrations
+/// have run, so there are no runs to report.
+pub(crate) fn get_migration_report<P: AsRef<Path>>(path: P) -> Result<Value> {
+    let path = path.as_ref();
+    match fs::read(path) {
+        Ok(data) => serde_json::from_slice(&data).context(error::MigrationReportParse { path }),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Value::Array(Vec::new())),
+        Err(e) => Err(e).context(error::MigrationReportRead { path }),
+    }
+}
+
 /// Build a Services based on the data in the datastore.
 pub(crate) fn get_services<D: DataStore>(datastore: &D) -> Result<Services> {
     get_prefix(
@@ -314,14 +333,43 @@ pub(crate) fn get_metadata_for_all_data_keys<D: DataStore, S: AsRef<str>>(
     Ok(result)
 }
 
+/// The metadata key recording where each setting's live value came from.
+pub(crate) const SETTING_ORIGIN: &str = "setting-origin";
+
 /// Makes live any pending settings in the datastore, returning the changed keys.
+///
+/// The origin of each changed setting is recorded in its `setting-origin` metadata: "generated"
+/// if it was committed from the transaction sundog uses, "default" if it was committed from the
+/// transaction storewolf uses, otherwise "user".  This lets sundog refresh generated values
+/// without clobbering values the user set.
 pub(crate) fn commit_transaction<D>(datastore: &mut D, transaction: &str) -> Result<HashSet<Key>>
 where
     D: DataStore,
 {
-    datastore
+    let changes = datastore
         .commit_transaction(transaction)
-        .context(error::DataStore { op: "commit" })
+        .context(error::DataStore { op: "commit" })?;
+
+    let origin = match transaction {
+        constants::GENERATED_TRANSACTION => "generated",
+        constants::DEFAULTS_TRANSACTION => "default",
+        _ => "user",
+    };
+    let origin_str =
+        serialize_scalar::<_, ScalarError>(&origin).context(error::MetadataSerialization {
+            key: SETTING_ORIGIN,
+        })?;
+    let md_key = Key::new(KeyType::Meta, SETTING_ORIGIN).context(error::NewKey {
+        key_type: "meta",
+        name: SETTING_ORIGIN,
+    })?;
+    for data_key in &changes {
+        datastore
+            .set_metadata(&md_key, data_key, &origin_str)
+            .context(error::DataStore { op: "set_metadata" })?;
+    }
+
+    Ok(changes)
 }
 
 /// Launches the config applier to make appropriate changes to the system based on any settings
@@ -330,12 +378,20 @@ where
 ///
 /// If `keys_limit` is Some, gives those keys to the applier so only changes relevant to those
 /// keys are made.  Otherwise, tells the applier to apply changes for all known keys.
+///
+/// If thar-be-settings is running as a listener, the change is sent to it over its socket so it
+/// can coalesce bursts of commits; otherwise a new thar-be-settings process is started.
 pub(crate) fn apply_changes<S>(keys_limit: Option<&HashSet<S>>) -> Result<()>
 where
     S: AsRef<str>,
 {
+    let keys_limit: Option<Vec<&str>> =
+        keys_limit.map(|keys| keys.iter().map(|s| s.as_ref()).collect());
+    if notify_config_applier(keys_limit.as_deref()) {
+        return Ok(());
+    }
+
     if let Some(keys_limit) = keys_limit {
-        let keys_limit: Vec<&str> = keys_limit.iter().map(|s| s.as_ref()).collect();
         // Prepare input to config applier; it uses the changed keys to update the right config
         trace!("Serializing the commit's changed keys: {:?}", keys_limit);
         let cmd_input =
@@ -403,6 +459,73 @@ where
     Ok(())
 }
 
+/// Sends the changed keys, or None for all keys, to a listening thar-be-settings as a line of JSON.
+/// Returns false if no listener could be reached, so the caller can start the config applier
+/// itself.
+fn notify_config_applier(keys_limit: Option<&[&str]>) -> bool {
+    let socket_path = constants::THAR_BE_SETTINGS_SOCKET;
+    let mut message = serde_json::json!({ "keys": keys_limit }).to_string();
+    message.push('\n');
+
+    let result = UnixStream::connect(socket_path).and_then(|mut stream| {
+        stream.set_write_timeout(Some(Duration::from_secs(5)))?;
+        stream.write_all(message.as_bytes())
+    });
+    match result {
+        Ok(()) => {
+            debug!("Sent changes to config applier at {}", socket_path);
+            true
+        }
+        Err(e) => {
+            debug!(
+                "Unable to send changes to config applier at {}: {}",
+                socket_path, e
+            );
+            false
+        }
+    }
+}
+
+/// Starts sundog to refresh generated settings that have an `every-boot` or `on-demand` refresh
+/// policy.  sundog commits and applies the refreshed settings itself, through the API, so we
+/// don't wait for it.
+///
+/// sundog runs share a transaction, so only one runs at a time.  sundog holds an exclusive lock on
+/// its lock file while it generates settings at boot, and we hold it for as long as a refresh we
+/// started is running; returns an error if it's already held.
+pub(crate) fn refresh_generated() -> Result<()> {
+    let mut command = Command::new(constants::SUNDOG_BIN);
+    command.arg("--refresh");
+    start_refresh(command, constants::SUNDOG_LOCKFILE)
+}
+
+fn start_refresh<P: AsRef<Path>>(mut command: Command, lockfile_path: P) -> Result<()> {
+    let lockfile = File::create(lockfile_path.as_ref()).context(error::SundogLockOpen)?;
+    // Newer Rust has its own File::try_lock_exclusive, with a different error type; use fs2's.
+    match FileExt::try_lock_exclusive(&lockfile) {
+        Ok(()) => {}
+        Err(e) if e.kind() == fs2::lock_contended_error().kind() => {
+            return error::RefreshInProgress.fail()
+        }
+        Err(e) => return Err(e).context(error::SundogLock),
+    }
+
+    debug!("Launching sundog to refresh generated settings");
+    let mut child = command.spawn().context(error::SundogStart)?;
+
+    // Reap sundog when it's done, so it doesn't linger as a zombie, then drop the lock file to
+    // allow another run.
+    std::thread::spawn(move || {
+        match child.wait() {
+            Ok(status) if status.success() => debug!("sundog refreshed generated settings"),
+            Ok(status) => warn!("sundog failed to refresh generated settings: {}", status),
+            Err(e) => warn!("Failed to wait for sundog: {}", e),
+        }
+        drop(lockfile);
+    });
+    Ok(())
+}
+
 /// Dispatches an update command via `thar-be-updates`
 pub(crate) fn dispatch_update_command(args: &[&str]) -> Result<HttpResponse> {
     let status = Command::new("/usr/bin/thar-be-updates")
@@ -436,6 +559,7 @@ mod test {
     use maplit::{hashmap, hashset};
     use model::Service;
     use std::convert::TryInto;
+    use std::time::Instant;
 
     #[test]
     fn get_settings_works() {
@@ -524,7 +648,9 @@ mod test {
             services,
             hashmap!("foo".to_string() => Service {
                 configuration_files: vec!["file1".try_into().unwrap()],
-                restart_commands: vec!["echo hi".to_string()]
+                restart_commands: vec!["echo hi".to_string()],
+                restart_after: vec![],
+                restart_only_on_file_change: false,
             })
         );
     }
@@ -623,4 +749,90 @@ mod test {
         let settings = get_settings(&ds, &Committed::Live).unwrap();
         assert_eq!(settings.motd, Some("json string".try_into().unwrap()));
     }
+
+    #[test]
+    fn commit_records_origin() {
+        let mut ds = MemoryDataStore::new();
+        let motd = Key::new(KeyType::Data, "settings.motd").unwrap();
+        let defaults = Committed::Pending {
+            tx: constants::DEFAULTS_TRANSACTION.into(),
+        };
+        ds.set_key(&motd, "\"default motd\"", &defaults).unwrap();
+        commit_transaction(&mut ds, constants::DEFAULTS_TRANSACTION).unwrap();
+        assert_eq!(
+            get_metadata_for_all_data_keys(&ds, SETTING_ORIGIN).unwrap(),
+            hashmap!("settings.motd".to_string() => "default".into())
+        );
+
+        let generated = Committed::Pending {
+            tx: constants::GENERATED_TRANSACTION.into(),
+        };
+        ds.set_key(&motd, "\"generated motd\"", &generated).unwrap();
+        commit_transaction(&mut ds, constants::GENERATED_TRANSACTION).unwrap();
+        assert_eq!(
+            get_metadata_for_all_data_keys(&ds, SETTING_ORIGIN).unwrap(),
+            hashmap!("settings.motd".to_string() => "generated".into())
+        );
+
+        // Setting it any other way means it's no longer generated.
+        let user = Committed::Pending {
+            tx: "user transaction".into(),
+        };
+        ds.set_key(&motd, "\"user motd\"", &user).unwrap();
+        commit_transaction(&mut ds, "user transaction").unwrap();
+        assert_eq!(
+            get_metadata_for_all_data_keys(&ds, SETTING_ORIGIN).unwrap(),
+            hashmap!("settings.motd".to_string() => "user".into())
+        );
+    }
+
+    #[test]
+    fn get_migration_report_works() {
+        let tmp = tempfile::TempDir::new().unwrap();
+        let path = tmp.path().join("migration-report.json");
+        assert_eq!(get_migration_report(&path).unwrap(), serde_json::json!([]));
+
+        fs::write(
+            &path,
+            r#"[{"from-version": "1.0.0", "to-version": "1.1.0"}]"#,
+        )
+        .unwrap();
+        assert_eq!(
+            get_migration_report(&path).unwrap(),
+            serde_json::json!([{"from-version": "1.0.0", "to-version": "1.1.0"}])
+        );
+
+        fs::write(&path, "not json").unwrap();
+        get_migration_report(&path).unwrap_err();
+    }
+
+    #[test]
+    fn one_refresh_at_a_time() {
+        let tmp = tempfile::TempDir::new().unwrap();
+        let lockfile_path = tmp.path().join("sundog.lock");
+
+        let mut slow = Command::new("/bin/sleep");
+        slow.arg("1");
+        start_refresh(slow, &lockfile_path).unwrap();
+        assert!(matches!(
+            start_refresh(Command::new("/bin/true"), &lockfile_path),
+            Err(error::Error::RefreshInProgress)
+        ));
+
+        // Once the first is done, the lock is free for sundog at boot; while sundog holds it,
+        // a refresh can't start.
+        let boot = File::create(&lockfile_path).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(30);
+        while FileExt::try_lock_exclusive(&boot).is_err() {
+            assert!(Instant::now() < deadline, "refresh never finished");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(matches!(
+            start_refresh(Command::new("/bin/true"), &lockfile_path),
+            Err(error::Error::RefreshInProgress)
+        ));
+
+        drop(boot);
+        start_refresh(Command::new("/bin/true"), &lockfile_path).unwrap();
+    }
 }
@@ -58,6 +58,15 @@ pub enum Error {
     #[snafu(display("Unable to get OS release data: {}", source))]
     ReleaseData { source: bottlerocket_release::Error },
 
+    #[snafu(display("Unable to read migration report '{}': {}", path.display(), source))]
+    MigrationReportRead { path: PathBuf, source: io::Error },
+
+    #[snafu(display("Unable to parse migration report '{}': {}", path.display(), source))]
+    MigrationReportParse {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+
     // =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
 
     // Controller errors
@@ -107,6 +116,24 @@ pub enum Error {
         source: serde_json::Error,
     },
 
+    #[snafu(display("Unable to serialize metadata '{}': {}", key, source))]
+    MetadataSerialization {
+        key: String,
+        source: serde_json::Error,
+    },
+
+    #[snafu(display("Unable to start sundog: {}", source))]
+    SundogStart { source: io::Error },
+
+    #[snafu(display("Unable to open sundog lock file: {}", source))]
+    SundogLockOpen { source: io::Error },
+
+    #[snafu(display("Unable to lock sundog lock file: {}", source))]
+    SundogLock { source: io::Error },
+
+    #[snafu(display("A refresh of generated settings is already running"))]
+    RefreshInProgress,
+
     #[snafu(display("Config applier was unable to fork child, returned {}", code))]
     ConfigApplierFork { code: String },
 
@@ -101,11 +101,16 @@ where
                         web::post().to(commit_transaction_and_apply),
                     ),
             )
-            .service(web::scope("/os").route("", web::get().to(get_os_info)))
+            .service(
+                web::scope("/os")
+                    .route("", web::get().to(get_os_info))
+                    .route("/migrations", web::get().to(get_migration_report)),
+            )
             .service(
                 web::scope("/metadata")
                     .route("/affected-services", web::get().to(get_affected_services))
                     .route("/setting-generators", web::get().to(get_setting_generators))
+                    .route("/setting-origins", web::get().to(get_setting_origins))
                     .route("/templates", web::get().to(get_templates)),
             )
             .service(web::scope("/services").route("", web::get().to(get_services)))
@@ -117,6 +122,7 @@ where
                 web::scope("/actions")
                     .route("/reboot", web::post().to(reboot))
                     .route("/refresh-updates", web::post().to(refresh_updates))
+                    .route("/refresh-generated", web::post().to(refresh_generated))
                     .route("/prepare-update", web::post().to(prepare_update))
                     .route("/activate-update", web::post().to(activate_update))
                     .route("/deactivate-update", web::post().to(deactivate_update)),
@@ -290,6 +296,13 @@ async fn get_os_info() -> Result<BottlerocketReleaseResponse> {
     Ok(BottlerocketReleaseResponse(controller::get_os_info()?))
 }
 
+/// Get the migrator's reports of its recent runs
+async fn get_migration_report() -> Result<MigrationReportResponse> {
+    Ok(MigrationReportResponse(controller::get_migration_report(
+        constants::MIGRATION_REPORT,
+    )?))
+}
+
 /// Get the affected services for a list of data keys
 async fn get_affected_services(
     query: web::Query<HashMap<String, String>>,
@@ -314,6 +327,13 @@ async fn get_setting_generators(data: web::Data<SharedDataStore>) -> Result<Meta
     Ok(MetadataResponse(resp))
 }
 
+/// Get the origin of each setting's live value, "generated" or "user"
+async fn get_setting_origins(data: web::Data<SharedDataStore>) -> Result<MetadataResponse> {
+    let datastore = data.ds.read().ok().context(error::DataStoreLock)?;
+    let resp = controller::get_metadata_for_all_data_keys(&*datastore, controller::SETTING_ORIGIN)?;
+    Ok(MetadataResponse(resp))
+}
+
 /// Get the template metadata for a list of data keys
 async fn get_templates(
     query: web::Query<HashMap<String, String>>,
@@ -367,7 +387,8 @@ async fn get_configuration_files(
 /// Get the update status from 'thar-be-updates'
 async fn get_update_status() -> Result<UpdateStatusResponse> {
     let lockfile = File::create(UPDATE_LOCKFILE).context(error::UpdateLockOpen)?;
-    lockfile.try_lock_shared().context(error::UpdateShareLock)?;
+    // Newer Rust has its own File::try_lock_shared, with a different error type; use fs2's.
+    FileExt::try_lock_shared(&lockfile).context(error::UpdateShareLock)?;
     let result = thar_be_updates::status::get_update_status(&lockfile);
     match result {
         Ok(update_status) => Ok(UpdateStatusResponse(update_status)),
@@ -385,6 +406,12 @@ async fn refresh_updates() -> Result<HttpResponse> {
     controller::dispatch_update_command(&["refresh"])
 }
 
+/// Starts sundog to refresh generated settings with a refresh policy
+async fn refresh_generated() -> Result<HttpResponse> {
+    controller::refresh_generated()?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
 /// Prepares update by downloading the images to the staging partition set
 async fn prepare_update() -> Result<HttpResponse> {
     controller::dispatch_update_command(&["prepare"])
@@ -467,6 +494,7 @@ impl ResponseError for error::Error {
 
             // 409 Conflict
             DisallowCommand { .. } => StatusCode::CONFLICT,
+            RefreshInProgress => StatusCode::CONFLICT,
 
             // 500 Internal Server Error
             DataStoreLock => StatusCode::INTERNAL_SERVER_ERROR,
@@ -479,6 +507,10 @@ impl ResponseError for error::Error {
             DataStoreSerialization { .. } => StatusCode::INTERNAL_SERVER_ERROR,
             CommandSerialization { .. } => StatusCode::INTERNAL_SERVER_ERROR,
             InvalidMetadata { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+            MetadataSerialization { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+            SundogStart { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+            SundogLockOpen { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+            SundogLock { .. } => StatusCode::INTERNAL_SERVER_ERROR,
             ConfigApplierFork { .. } => StatusCode::INTERNAL_SERVER_ERROR,
             ConfigApplierStart { .. } => StatusCode::INTERNAL_SERVER_ERROR,
             ConfigApplierStdin {} => StatusCode::INTERNAL_SERVER_ERROR,
@@ -489,6 +521,8 @@ impl ResponseError for error::Error {
             SetPermissions { .. } => StatusCode::INTERNAL_SERVER_ERROR,
             SetGroup { .. } => StatusCode::INTERNAL_SERVER_ERROR,
             ReleaseData { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+            MigrationReportRead { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+            MigrationReportParse { .. } => StatusCode::INTERNAL_SERVER_ERROR,
             Shutdown { .. } => StatusCode::INTERNAL_SERVER_ERROR,
             Reboot { .. } => StatusCode::INTERNAL_SERVER_ERROR,
             UpdateDispatcher { .. } => StatusCode::INTERNAL_SERVER_ERROR,
@@ -539,6 +573,10 @@ impl_responder_for!(SettingsResponse, self, self.0);
 struct BottlerocketReleaseResponse(BottlerocketRelease);
 impl_responder_for!(BottlerocketReleaseResponse, self, self.0);
 
+/// This lets us respond from our handler methods with the migrator's reports (or Result<Value>)
+struct MigrationReportResponse(Value);
+impl_responder_for!(MigrationReportResponse, self, self.0);
+
 /// This lets us respond from our handler methods with a HashMap (or Result<HashMap>) for metadata
 struct MetadataResponse(HashMap<String, Value>);
 impl_responder_for!(MetadataResponse, self, self.0);
@@ -0,0 +1,40 @@
+//! Contains the Error and Result types used by the migration harness.
+
+use migration_helpers::MigrationType;
+use snafu::Snafu;
+use std::io;
+use std::path::PathBuf;
+
+/// Error contains the errors that can happen while running a migration chain.
+#[derive(Debug, Snafu)]
+#[snafu(visibility = "pub(crate)")]
+pub enum Error {
+    #[snafu(display("Unable to create working directory: {}", source))]
+    WorkDir { source: io::Error },
+
+    #[snafu(display("Unable to copy data store fixture '{}': {}", path.display(), source))]
+    CopyFixture { path: PathBuf, source: io::Error },
+
+    #[snafu(display("Migration '{}' failed running {}: {}", name, direction, source))]
+    Migration {
+        name: String,
+        direction: MigrationType,
+        source: migration_helpers::error::Error,
+    },
+
+    #[snafu(display("Unable to read data store '{}': {}", path.display(), source))]
+    ReadDataStore {
+        path: PathBuf,
+        source: datastore::Error,
+    },
+
+    #[snafu(display("Unable to deserialize value '{}' in '{}': {}", input, path.display(), source))]
+    Deserialize {
+        input: String,
+        path: PathBuf,
+        source: datastore::ScalarError,
+    },
+}
+
+/// Result alias containing our Error type.
+pub type Result<T> = std::result::Result<T, Error>;
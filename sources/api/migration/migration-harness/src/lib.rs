@@ -0,0 +1,386 @@
+//! migration-harness tests a chain of migrations against a real data store fixture.  It runs the
+//! migrations in-process through migration-helpers, the same way the migration binaries do, so it
+//! can run in CI without building the sealed binaries the migrator runs.
+//!
+//! A fixture is a filesystem data store: a directory with a `live` subdirectory and, optionally,
+//! a `pending` subdirectory of transactions.  It can be copied from a host, or written by hand.
+//!
+//! The harness:
+//! * copies the fixture to a working directory, so the fixture itself is never changed
+//! * runs each migration forward, in the given order, recording what it changed
+//! * compares the result with an expected data store, if one was given
+//! * runs each migration backward, in reverse order, recording what it changed
+//! * compares the result with the fixture, to make sure the migrations round-trip
+//!
+//! Some migrations can't be undone, for example when they remove a setting that the old version
+//! regenerates; keys under any prefix declared lossy are left out of the round-trip comparison.
+//!
+//! The "os" values that migrations are given come from the release rather than the data store,
+//! so they're left out of all comparisons.  The release defaults to a development build of the
+//! variant given in the `VARIANT` environment variable, and can be set if a migration cares.
+//!
+//! ```no_run
+//! use migration_harness::MigrationChain;
+//! use migration_helpers::common_migrations::RemoveSettingsMigration;
+//!
+//! let report = MigrationChain::new("tests/data/v1.2.0")
+//!     .migration(
+//!         "migrate_v1.3.0_remove-old",
+//!         RemoveSettingsMigration(&["settings.old"]),
+//!     )
+//!     .expected("tests/data/v1.3.0")
+//!     .lossy("settings.old")
+//!     .run()
+//!     .unwrap();
+//! report.assert_ok();
+//! ```
+
+#![deny(rust_2018_idioms)]
+
+pub mod error;
+mod snapshot;
+
+use bottlerocket_release::BottlerocketRelease;
+use migration_helpers::{run_migration_with_release, Args, Migration, MigrationType};
+use semver::Version;
+use snafu::ResultExt;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+
+pub use error::{Error, Result};
+use snapshot::Snapshot;
+pub use snapshot::{Change, Diff, Entry, Location};
+
+/// Describes a chain of migrations to test and the data store fixture to test them with.
+pub struct MigrationChain<'a> {
+    fixture: PathBuf,
+    expected: Option<PathBuf>,
+    migrations: Vec<(String, Box<dyn Migration + 'a>)>,
+    lossy: Vec<String>,
+    release: BottlerocketRelease,
+}
+
+impl<'a> MigrationChain<'a> {
+    /// Starts a chain that migrates the data store fixture at the given path.
+    pub fn new<P: AsRef<Path>>(fixture: P) -> Self {
+        Self {
+            fixture: fixture.as_ref().to_path_buf(),
+            expected: None,
+            migrations: Vec::new(),
+            lossy: Vec::new(),
+            release: BottlerocketRelease {
+                pretty_name: "Bottlerocket OS".to_string(),
+                variant_id: env::var("VARIANT").unwrap_or_else(|_| "aws-dev".to_string()),
+                version_id: Version::new(0, 0, 0),
+                build_id: "test".to_string(),
+                arch: env::consts::ARCH.to_string(),
+            },
+        }
+    }
+
+    /// Adds a migration to the end of the chain.  The name is only used in reports.
+    pub fn migration<S, M>(mut self, name: S, migration: M) -> Self
+    where
+        S: Into<String>,
+        M: Migration + 'a,
+    {
+        self.migrations.push((name.into(), Box::new(migration)));
+        self
+    }
+
+    /// Compares the result of the forward migrations with the data store at the given path.
+    pub fn expected<P: AsRef<Path>>(mut self, expected: P) -> Self {
+        self.expected = Some(expected.as_ref().to_path_buf());
+        self
+    }
+
+    /// Leaves data keys under the given prefix, and their metadata, out of the round-trip
+    /// comparison, because the migrations can't restore them.
+    pub fn lossy<S: Into<String>>(mut self, prefix: S) -> Self {
+        self.lossy.push(prefix.into());
+        self
+    }
+
+    /// Sets the release data used for the "os" values given to migrations.
+    pub fn release(mut self, release: BottlerocketRelease) -> Self {
+        self.release = release;
+        self
+    }
+
+    /// Runs the migrations forward and then backward, and reports the results.  Returns Err if
+    /// a migration fails or a data store can't be read; differences from the expected results are
+    /// given in the Report.
+    pub fn run(mut self) -> Result<Report> {
+        let work = TempDir::new().context(error::WorkDir)?;
+        let mut source = work.path().join("fixture");
+        copy_dir(&self.fixture, &source)?;
+
+        let original = Snapshot::load(&source)?;
+        let mut current = original.clone();
+        let mut steps = Vec::new();
+
+        // Run forward in the given order, then backward in reverse order, each migration taking
+        // the output of the previous one.
+        let count = self.migrations.len();
+        let order = (0..count)
+            .map(|i| (i, MigrationType::Forward))
+            .chain((0..count).rev().map(|i| (i, MigrationType::Backward)));
+        let mut forward = None;
+        for (step, (i, direction)) in order.enumerate() {
+            if let (MigrationType::Backward, None) = (direction, &forward) {
+                forward = Some(current.clone());
+            }
+
+            let (name, migration) = &mut self.migrations[i];
+            let target = work.path().join(format!("step-{}", step));
+            let args = Args {
+                source_datastore: source.display().to_string(),
+                target_datastore: target.display().to_string(),
+                migration_type: direction,
+                validate: false,
+            };
+            run_migration_with_release(&mut **migration, &args, &self.release).context(
+                error::Migration {
+                    name: name.clone(),
+                    direction,
+                },
+            )?;
+
+            let migrated = Snapshot::load(&target)?;
+            steps.push(Step {
+                migration: name.clone(),
+                direction,
+                diff: current.diff(&migrated, &[]),
+            });
+            current = migrated;
+            source = target;
+        }
+        let forward = forward.unwrap_or_else(|| current.clone());
+
+        let expected = match &self.expected {
+            Some(path) => Some(Snapshot::load(path)?.diff(&forward, &[])),
+            None => None,
+        };
+        let round_trip = original.diff(&current, &self.lossy);
+
+        Ok(Report {
+            steps,
+            expected,
+            round_trip,
+        })
+    }
+}
+
+/// What a single migration changed when run in the given direction.
+#[derive(Debug, Clone)]
+pub struct Step {
+    pub migration: String,
+    pub direction: MigrationType,
+    pub diff: Diff,
+}
+
+/// The results of running a migration chain.
+#[derive(Debug, Clone)]
+pub struct Report {
+    /// What each migration changed, in the order they ran.
+    pub steps: Vec<Step>,
+    /// How the result of the forward migrations differs from the expected data store, if one was
+    /// given.  Added entries are in the result but not expected, and removed entries are expected
+    /// but missing from the result.
+    pub expected: Option<Diff>,
+    /// How the result of the backward migrations differs from the fixture, leaving out keys
+    /// declared lossy.
+    pub round_trip: Diff,
+}
+
+impl Report {
+    /// Returns whether the forward migrations gave the expected result and the backward
+    /// migrations restored the fixture.
+    pub fn is_ok(&self) -> bool {
+        self.expected.as_ref().map(Diff::is_empty).unwrap_or(true) && self.round_trip.is_empty()
+    }
+
+    /// Panics with the full report unless is_ok() is true; meant for use in tests.
+    pub fn assert_ok(&self) {
+        assert!(self.is_ok(), "migration chain failed:\n{}", self);
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for step in &self.steps {
+            writeln!(f, "{} ({}):", step.migration, step.direction)?;
+            write!(f, "{}", step.diff)?;
+        }
+        if let Some(expected) = &self.expected {
+            writeln!(f, "Differences from expected data store:")?;
+            write!(f, "{}", expected)?;
+        }
+        writeln!(f, "Differences after round trip:")?;
+        write!(f, "{}", self.round_trip)
+    }
+}
+
+/// Recursively copies the directory at `from` to `to`.
+fn copy_dir(from: &Path, to: &Path) -> Result<()> {
+    fs::create_dir_all(to).context(error::CopyFixture { path: from })?;
+    for entry in fs::read_dir(from).context(error::CopyFixture { path: from })? {
+        let entry = entry.context(error::CopyFixture { path: from })?;
+        let path = entry.path();
+        let file_type = entry
+            .file_type()
+            .context(error::CopyFixture { path: &path })?;
+        if file_type.is_dir() {
+            copy_dir(&path, &to.join(entry.file_name()))?;
+        } else {
+            fs::copy(&path, to.join(entry.file_name()))
+                .context(error::CopyFixture { path: &path })?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Change, Entry, Location, MigrationChain};
+    use migration_helpers::common_migrations::RemoveSettingsMigration;
+    use migration_helpers::{error, Migration, MigrationData, MigrationType, Result};
+    use serde_json::json;
+    use std::path::PathBuf;
+
+    fn fixture(name: &str) -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("tests")
+            .join("data")
+            .join(name)
+    }
+
+    /// Changes the motd in both directions, so it round-trips.
+    struct ShoutMotd;
+
+    impl Migration for ShoutMotd {
+        fn forward(&mut self, mut input: MigrationData) -> Result<MigrationData> {
+            if let Some(motd) = input.data.get_mut("settings.motd") {
+                *motd = json!(motd.as_str().unwrap().to_uppercase());
+            }
+            Ok(input)
+        }
+
+        fn backward(&mut self, mut input: MigrationData) -> Result<MigrationData> {
+            if let Some(motd) = input.data.get_mut("settings.motd") {
+                *motd = json!(motd.as_str().unwrap().to_lowercase());
+            }
+            Ok(input)
+        }
+    }
+
+    struct Broken;
+
+    impl Migration for Broken {
+        fn forward(&mut self, input: MigrationData) -> Result<MigrationData> {
+            Ok(input)
+        }
+
+        fn backward(&mut self, _input: MigrationData) -> Result<MigrationData> {
+            error::Migration { msg: "broken" }.fail()
+        }
+    }
+
+    #[test]
+    fn chain_ok() {
+        let report = MigrationChain::new(fixture("v1"))
+            .migration("remove-old", RemoveSettingsMigration(&["settings.old"]))
+            .expected(fixture("v2"))
+            .lossy("settings.old")
+            .run()
+            .unwrap();
+        report.assert_ok();
+
+        let directions: Vec<_> = report
+            .steps
+            .iter()
+            .map(|step| (step.migration.as_str(), step.direction.to_string()))
+            .collect();
+        assert_eq!(
+            directions,
+            vec![
+                ("remove-old", "forward".into()),
+                ("remove-old", "backward".into())
+            ]
+        );
+        assert_eq!(
+            report.steps[0].diff.0,
+            vec![Change::Removed {
+                entry: Entry {
+                    location: Location::Live,
+                    key: "settings.old".into(),
+                },
+                value: json!("x"),
+            }]
+        );
+        assert!(report.steps[1].diff.is_empty());
+    }
+
+    #[test]
+    fn per_migration_diffs() {
+        let report = MigrationChain::new(fixture("v1"))
+            .migration("shout", ShoutMotd)
+            .migration("remove-old", RemoveSettingsMigration(&["settings.old"]))
+            .lossy("settings.old")
+            .run()
+            .unwrap();
+        report.assert_ok();
+
+        // The motd changes in live data and in the pending transaction.
+        let shout = &report.steps[0];
+        assert_eq!(shout.diff.0.len(), 2);
+        let last = report.steps.last().unwrap();
+        assert_eq!(last.migration, "shout");
+        assert!(matches!(last.direction, MigrationType::Backward));
+        assert_eq!(
+            last.diff.0[0],
+            Change::Changed {
+                entry: Entry {
+                    location: Location::Live,
+                    key: "settings.motd".into(),
+                },
+                old: json!("HI"),
+                new: json!("hi"),
+            }
+        );
+    }
+
+    #[test]
+    fn round_trip_loss() {
+        let report = MigrationChain::new(fixture("v1"))
+            .migration("remove-old", RemoveSettingsMigration(&["settings.old"]))
+            .run()
+            .unwrap();
+        assert!(!report.is_ok());
+        assert_eq!(report.round_trip.0.len(), 1);
+    }
+
+    #[test]
+    fn unexpected_result() {
+        let report = MigrationChain::new(fixture("v1"))
+            .migration("shout", ShoutMotd)
+            .expected(fixture("v1"))
+            .run()
+            .unwrap();
+        assert!(!report.is_ok());
+        assert!(report.round_trip.is_empty());
+        assert_eq!(report.expected.unwrap().0.len(), 2);
+    }
+
+    #[test]
+    fn migration_failure() {
+        let err = MigrationChain::new(fixture("v1"))
+            .migration("broken", Broken)
+            .run()
+            .unwrap_err();
+        assert!(err.to_string().contains("'broken' failed running backward"));
+    }
+}
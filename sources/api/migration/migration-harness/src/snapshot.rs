@@ -0,0 +1,268 @@
+//! This module loads the contents of a data store so they can be compared, and describes the
+//! differences between two data stores.
+
+use crate::{error, Result};
+use datastore::{deserialize_scalar, Committed, DataStore, FilesystemDataStore};
+use serde_json::Value;
+use snafu::ResultExt;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+
+/// Where a value lives in a data store.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Location {
+    /// Live data.
+    Live,
+    /// Data in the named pending transaction.
+    Pending(String),
+    /// Metadata of the named data key.
+    Metadata(String),
+}
+
+/// Identifies a single value in a data store: a data key in live data or a pending transaction,
+/// or a metadata key of a data key.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Entry {
+    pub location: Location,
+    pub key: String,
+}
+
+impl Entry {
+    /// Returns the data key this entry belongs to.
+    fn data_key(&self) -> &str {
+        match &self.location {
+            Location::Live | Location::Pending(_) => &self.key,
+            Location::Metadata(data_key) => data_key,
+        }
+    }
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.location {
+            Location::Live => write!(f, "{}", self.key),
+            Location::Pending(tx) => write!(f, "{} in transaction '{}'", self.key, tx),
+            Location::Metadata(data_key) => write!(f, "metadata '{}' of {}", self.key, data_key),
+        }
+    }
+}
+
+/// A single difference between two data stores.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change {
+    Added {
+        entry: Entry,
+        value: Value,
+    },
+    Removed {
+        entry: Entry,
+        value: Value,
+    },
+    Changed {
+        entry: Entry,
+        old: Value,
+        new: Value,
+    },
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Change::Added { entry, value } => write!(f, "+ {} = {}", entry, value),
+            Change::Removed { entry, value } => write!(f, "- {} = {}", entry, value),
+            Change::Changed { entry, old, new } => write!(f, "~ {}: {} -> {}", entry, old, new),
+        }
+    }
+}
+
+/// The differences between two data stores, ordered by entry.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Diff(pub Vec<Change>);
+
+impl Diff {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl fmt::Display for Diff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return writeln!(f, "  (no changes)");
+        }
+        for change in &self.0 {
+            writeln!(f, "  {}", change)?;
+        }
+        Ok(())
+    }
+}
+
+/// The contents of a data store: live data, the data of each pending transaction, and metadata.
+///
+/// The "os" values that migrations are given come from the release rather than the data store,
+/// but are written along with the migrated data, so they're left out.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct Snapshot(BTreeMap<Entry, Value>);
+
+impl Snapshot {
+    /// Loads the filesystem data store at the given path.
+    pub(crate) fn load(path: &Path) -> Result<Self> {
+        let datastore = FilesystemDataStore::new(path);
+        let mut entries = BTreeMap::new();
+
+        let mut committeds = vec![(Location::Live, Committed::Live)];
+        let transactions = datastore
+            .list_transactions()
+            .context(error::ReadDataStore { path })?;
+        committeds.extend(
+            transactions
+                .into_iter()
+                .map(|tx| (Location::Pending(tx.clone()), Committed::Pending { tx })),
+        );
+
+        for (location, committed) in committeds {
+            let data = datastore
+                .get_prefix("", &committed)
+                .context(error::ReadDataStore { path })?;
+            for (key, value) in data {
+                if key.name().starts_with("os.") {
+                    continue;
+                }
+                let entry = Entry {
+                    location: location.clone(),
+                    key: key.name().clone(),
+                };
+                entries.insert(entry, deserialize(path, value)?);
+            }
+        }
+
+        let metadata = datastore
+            .get_metadata_prefix("", &None as &Option<&str>)
+            .context(error::ReadDataStore { path })?;
+        for (data_key, meta_map) in metadata {
+            for (metadata_key, value) in meta_map {
+                let entry = Entry {
+                    location: Location::Metadata(data_key.name().clone()),
+                    key: metadata_key.name().clone(),
+                };
+                entries.insert(entry, deserialize(path, value)?);
+            }
+        }
+
+        Ok(Self(entries))
+    }
+
+    /// Returns the changes that turn this snapshot into the other one, ignoring entries that
+    /// belong to data keys under any of the given prefixes.
+    pub(crate) fn diff(&self, other: &Snapshot, ignore: &[String]) -> Diff {
+        let ignored = |entry: &Entry| {
+            ignore
+                .iter()
+                .any(|prefix| under_prefix(entry.data_key(), prefix))
+        };
+
+        let mut changes = Vec::new();
+        for (entry, old) in self.0.iter().filter(|(entry, _)| !ignored(entry)) {
+            match other.0.get(entry) {
+                None => changes.push(Change::Removed {
+                    entry: entry.clone(),
+                    value: old.clone(),
+                }),
+                Some(new) if new != old => changes.push(Change::Changed {
+                    entry: entry.clone(),
+                    old: old.clone(),
+                    new: new.clone(),
+                }),
+                Some(_) => {}
+            }
+        }
+        for (entry, new) in other.0.iter().filter(|(entry, _)| !ignored(entry)) {
+            if !self.0.contains_key(entry) {
+                changes.push(Change::Added {
+                    entry: entry.clone(),
+                    value: new.clone(),
+                });
+            }
+        }
+
+        changes.sort_by(|a, b| change_entry(a).cmp(change_entry(b)));
+        Diff(changes)
+    }
+}
+
+fn change_entry(change: &Change) -> &Entry {
+    match change {
+        Change::Added { entry, .. }
+        | Change::Removed { entry, .. }
+        | Change::Changed { entry, .. } => entry,
+    }
+}
+
+fn deserialize(path: &Path, input: String) -> Result<Value> {
+    deserialize_scalar(&input).context(error::Deserialize {
+        input: &input,
+        path,
+    })
+}
+
+/// Returns whether the given data key is the prefix itself or falls under it.
+fn under_prefix(key: &str, prefix: &str) -> bool {
+    key == prefix
+        || key
+            .strip_prefix(prefix)
+            .map(|rest| rest.starts_with('.'))
+            .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Change, Entry, Location, Snapshot};
+    use serde_json::json;
+    use std::collections::BTreeMap;
+
+    fn entry(location: Location, key: &str) -> Entry {
+        Entry {
+            location,
+            key: key.to_string(),
+        }
+    }
+
+    #[test]
+    fn diff() {
+        let mut old = BTreeMap::new();
+        old.insert(entry(Location::Live, "settings.a"), json!("a"));
+        old.insert(entry(Location::Live, "settings.b"), json!("b"));
+        old.insert(entry(Location::Live, "settings.lossy.c"), json!("c"));
+        let mut new = BTreeMap::new();
+        new.insert(entry(Location::Live, "settings.a"), json!("a2"));
+        new.insert(
+            entry(Location::Pending("tx".into()), "settings.b"),
+            json!("b"),
+        );
+        new.insert(
+            entry(Location::Metadata("settings.lossy".into()), "template"),
+            json!("t"),
+        );
+
+        let diff = Snapshot(old).diff(&Snapshot(new), &["settings.lossy".to_string()]);
+        assert_eq!(
+            diff.0,
+            vec![
+                Change::Changed {
+                    entry: entry(Location::Live, "settings.a"),
+                    old: json!("a"),
+                    new: json!("a2"),
+                },
+                Change::Removed {
+                    entry: entry(Location::Live, "settings.b"),
+                    value: json!("b"),
+                },
+                Change::Added {
+                    entry: entry(Location::Pending("tx".into()), "settings.b"),
+                    value: json!("b"),
+                },
+            ]
+        );
+    }
+}
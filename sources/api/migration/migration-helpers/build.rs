@@ -0,0 +1,96 @@
+/// This build script generates a unified TOML file representing the default settings and metadata
+/// of the variant being built, just like storewolf's, so migrations can reset data to the new
+/// version's defaults with `defaults_for`.
+use merge_toml::merge_values;
+use snafu::ResultExt;
+use std::env;
+use std::fs;
+use std::path::Path;
+use toml::{map::Map, Value};
+use walkdir::WalkDir;
+
+/// A variant stores its default settings in .toml files in this directory.  It can link to shared
+/// files if desired.  Entries are sorted by filename, and later entries take precedence.
+const DEFAULTS_DIR: &str = "../../../models/src/variant/current/defaults.d";
+
+fn main() -> Result<()> {
+    generate_defaults_toml()?;
+
+    // Reflect that we need to rerun if variant has changed to pick up the new default settings.
+    println!("cargo:rerun-if-env-changed=VARIANT");
+
+    Ok(())
+}
+
+/// Merge the variant's default settings files into a single TOML value.  The result is serialized
+/// to a file in OUT_DIR for `defaults_for` to read.
+fn generate_defaults_toml() -> Result<()> {
+    // Find TOML config files specified by the variant.
+    let walker = WalkDir::new(DEFAULTS_DIR)
+        .follow_links(true) // we expect users to link to shared files
+        .min_depth(1) // only read files in defaults.d, not doing inheritance yet
+        .max_depth(1)
+        .sort_by(|a, b| a.file_name().cmp(b.file_name())) // allow ordering by prefix
+        .into_iter()
+        .filter_entry(|e| e.file_name().to_string_lossy().ends_with(".toml")); // looking for TOML config
+
+    // Merge the files into a single TOML value, in order.
+    let mut defaults = Value::Table(Map::new());
+    for entry in walker {
+        let entry = entry.context(error::ListFiles { dir: DEFAULTS_DIR })?;
+
+        // Reflect that we need to rerun if any of the default settings files have changed.
+        println!("cargo:rerun-if-changed={}", entry.path().display());
+
+        let data = fs::read_to_string(entry.path()).context(error::File {
+            op: "read",
+            path: entry.path(),
+        })?;
+        let value = toml::from_str(&data).context(error::TomlDeserialize { path: entry.path() })?;
+        merge_values(&mut defaults, &value).context(error::TomlMerge)?;
+    }
+
+    // Serialize to disk for defaults_for to read.
+    let data = toml::to_string(&defaults).context(error::TomlSerialize)?;
+    let out_dir = env::var("OUT_DIR").expect("OUT_DIR not set; are you not using cargo?");
+    let path = Path::new(&out_dir).join("defaults.toml");
+    fs::write(&path, &data).context(error::File { op: "write", path })?;
+
+    Ok(())
+}
+
+mod error {
+    use snafu::Snafu;
+    use std::path::PathBuf;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility = "pub(super)")]
+    pub(super) enum Error {
+        #[snafu(display("Failed to {} {}: {}", op, path.display(), source))]
+        File {
+            op: String,
+            path: PathBuf,
+            source: std::io::Error,
+        },
+
+        #[snafu(display("Failed to list files in {}: {}", dir.display(), source))]
+        ListFiles {
+            dir: PathBuf,
+            source: walkdir::Error,
+        },
+
+        #[snafu(display("{} is not valid TOML: {}", path.display(), source))]
+        TomlDeserialize {
+            path: PathBuf,
+            source: toml::de::Error,
+        },
+
+        #[snafu(display("Failed to merge TOML: {}", source))]
+        TomlMerge { source: merge_toml::Error },
+
+        #[snafu(display("Failed to serialize default settings: {}", source))]
+        TomlSerialize { source: toml::ser::Error },
+    }
+}
+
+type Result<T> = std::result::Result<T, error::Error>;
@@ -10,6 +10,8 @@ pub struct Args {
     pub source_datastore: String,
     pub target_datastore: String,
     pub migration_type: MigrationType,
+    /// Whether to check the migrated data against the model before saving it.
+    pub validate: bool,
 }
 
 /// Informs the user about proper usage of the program and exits.
@@ -19,7 +21,8 @@ fn usage() -> ! {
         r"Usage: {}
             --source-datastore PATH
             --target-datastore PATH
-            ( --forward | --backward )",
+            ( --forward | --backward )
+            [ --validate ]",
         program_name
     );
     process::exit(2);
@@ -36,6 +39,7 @@ pub(crate) fn parse_args(args: env::Args) -> Result<Args> {
     let mut migration_type = None;
     let mut source_datastore = None;
     let mut target_datastore = None;
+    let mut validate = false;
 
     let mut iter = args.skip(1);
     while let Some(arg) = iter.next() {
@@ -57,6 +61,8 @@ pub(crate) fn parse_args(args: env::Args) -> Result<Args> {
             "--forward" => migration_type = Some(MigrationType::Forward),
             "--backward" => migration_type = Some(MigrationType::Backward),
 
+            "--validate" => validate = true,
+
             _ => usage(),
         }
     }
@@ -70,5 +76,6 @@ pub(crate) fn parse_args(args: env::Args) -> Result<Args> {
         source_datastore: source_datastore.unwrap_or_else(|| usage()),
         target_datastore: target_datastore.unwrap_or_else(|| usage()),
         migration_type: migration_type.unwrap_or_else(|| usage()),
+        validate,
     })
 }
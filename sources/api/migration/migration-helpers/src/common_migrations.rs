@@ -1,11 +1,12 @@
-use crate::{error, Metadata, Migration, MigrationData, Result};
+use crate::defaults::under_prefix;
+use crate::{defaults_for, error, Metadata, Migration, MigrationData, Result};
 use serde::Serialize;
-use snafu::{OptionExt, ResultExt};
+use snafu::{ensure, OptionExt, ResultExt};
 use std::collections::HashMap;
 
 /// We use this migration when we add settings and want to make sure they're removed before we go
 /// back to old versions that don't understand them.
-pub struct AddSettingsMigration<'a>(pub &'a [&'static str]);
+pub struct AddSettingsMigration<'a>(pub &'a [&'a str]);
 
 impl Migration for AddSettingsMigration<'_> {
     /// New versions must either have a default for the settings or generate them; we don't need to
@@ -176,7 +177,7 @@ mod test_add_prefixes_migration {
 
 /// We use this migration when we remove settings from the model, so the new version doesn't see
 /// them and error.
-pub struct RemoveSettingsMigration<'a>(pub &'a [&'static str]);
+pub struct RemoveSettingsMigration<'a>(pub &'a [&'a str]);
 
 impl Migration for RemoveSettingsMigration<'_> {
     /// Newer versions don't know about the settings; we remove them so that new versions don't see
@@ -555,13 +556,13 @@ mod test_replace_list {
 // =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
 
 /// We use this migration when we replace an existing template for generating some setting.
-pub struct ReplaceTemplateMigration {
-    pub setting: &'static str,
-    pub old_template: &'static str,
-    pub new_template: &'static str,
+pub struct ReplaceTemplateMigration<'a> {
+    pub setting: &'a str,
+    pub old_template: &'a str,
+    pub new_template: &'a str,
 }
 
-impl ReplaceTemplateMigration {
+impl ReplaceTemplateMigration<'_> {
     /// Helper to retrieve a setting's template
     fn get_setting_template(&self, input: &MigrationData) -> Option<String> {
         if let Some(metadata) = input.metadata.get(self.setting) {
@@ -688,7 +689,7 @@ impl ReplaceTemplateMigration {
     }
 }
 
-impl Migration for ReplaceTemplateMigration {
+impl Migration for ReplaceTemplateMigration<'_> {
     fn forward(&mut self, mut input: MigrationData) -> Result<MigrationData> {
         if let Some(input_value) = input.data.get(self.setting) {
             let data = input_value
@@ -745,14 +746,14 @@ impl Migration for ReplaceTemplateMigration {
 /// We use this migration when we add metadata and want to make sure they're removed before we go
 /// back to old versions that don't understand them.
 #[derive(Debug)]
-pub struct SettingMetadata {
-    pub setting: &'static str,
-    pub metadata: &'static [&'static str],
+pub struct SettingMetadata<'a> {
+    pub setting: &'a str,
+    pub metadata: &'a [&'a str],
 }
 
-pub struct AddMetadataMigration(pub &'static [SettingMetadata]);
+pub struct AddMetadataMigration<'a>(pub &'a [SettingMetadata<'a>]);
 
-impl Migration for AddMetadataMigration {
+impl Migration for AddMetadataMigration<'_> {
     /// New versions must have the metadata already defined in defaults.
     fn forward(&mut self, input: MigrationData) -> Result<MigrationData> {
         println!(
@@ -1145,3 +1146,510 @@ mod test_replace_metadata_list {
         .unwrap_err();
     }
 }
+
+// =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
+
+/// We use this migration when we want to reset the settings under some prefixes, and their
+/// metadata, to the new version's defaults, for example when their structure changed in a way
+/// that's hard to migrate value by value.  Any values the user set under the prefixes are lost.
+///
+/// On downgrade, we remove the settings under the prefixes and the metadata from the new version's
+/// defaults, and the old version's storewolf populates its own defaults for them at boot.
+pub struct ResetToDefaultsMigration(pub &'static [&'static str]);
+
+impl ResetToDefaultsMigration {
+    /// Replaces the settings under the prefix with the given defaults, and sets their metadata.
+    fn reset(prefix: &str, input: &mut MigrationData, defaults: MigrationData) {
+        // Don't add defaults to data that had nothing under the prefix, like unrelated pending
+        // transactions.
+        if !input.data.keys().any(|key| under_prefix(key, prefix)) {
+            println!("Found no settings under '{}' to reset", prefix);
+            return;
+        }
+
+        input.data.retain(|key, value| {
+            let keep = !under_prefix(key, prefix);
+            if !keep {
+                println!("Removed {}, which was set to '{}'", key, value);
+            }
+            keep
+        });
+        for (key, value) in defaults.data {
+            println!("Set {} to default '{}'", key, value);
+            input.data.insert(key, value);
+        }
+
+        // Metadata is only given for live data.
+        if input.metadata.is_empty() {
+            return;
+        }
+        for (key, metadata) in defaults.metadata {
+            let existing = input
+                .metadata
+                .entry(key.clone())
+                .or_insert_with(Metadata::new);
+            for (md_key, value) in metadata {
+                println!(
+                    "Set metadata '{}' on {} to default '{}'",
+                    md_key, key, value
+                );
+                existing.insert(md_key, value);
+            }
+        }
+    }
+
+    /// Removes the settings under the prefix, and the metadata from the given defaults.
+    fn remove(prefix: &str, input: &mut MigrationData, defaults: MigrationData) {
+        input.data.retain(|key, value| {
+            let keep = !under_prefix(key, prefix);
+            if !keep {
+                println!("Removed {}, which was set to '{}'", key, value);
+            }
+            keep
+        });
+
+        for (key, metadata) in defaults.metadata {
+            if let Some(existing) = input.metadata.get_mut(&key) {
+                for md_key in metadata.keys() {
+                    if let Some(value) = existing.remove(md_key) {
+                        println!(
+                            "Removed metadata '{}' on {}, which was set to '{}'",
+                            md_key, key, value
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Migration for ResetToDefaultsMigration {
+    fn forward(&mut self, mut input: MigrationData) -> Result<MigrationData> {
+        for prefix in self.0 {
+            let defaults = defaults_for(prefix)?;
+            Self::reset(prefix, &mut input, defaults);
+        }
+        Ok(input)
+    }
+
+    fn backward(&mut self, mut input: MigrationData) -> Result<MigrationData> {
+        for prefix in self.0 {
+            let defaults = defaults_for(prefix)?;
+            Self::remove(prefix, &mut input, defaults);
+        }
+        Ok(input)
+    }
+}
+
+#[cfg(test)]
+mod test_reset_to_defaults {
+    use super::ResetToDefaultsMigration;
+    use crate::defaults::parse_defaults;
+    use crate::MigrationData;
+    use maplit::hashmap;
+    use std::collections::HashMap;
+
+    fn defaults() -> MigrationData {
+        parse_defaults(
+            r#"
+            [settings.ntp]
+            time-servers = ["pool.example.com"]
+
+            [metadata.settings.ntp]
+            affected-services = ["chronyd"]
+            "#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn reset() {
+        let mut data = MigrationData {
+            data: hashmap! {
+                "settings.ntp.time-servers".into() => vec!["mine.example.com"].into(),
+                "settings.ntp.old".into() => 0.into(),
+                "settings.motd".into() => "hi".into(),
+            },
+            metadata: hashmap! {
+                "settings.ntp".into() => hashmap!{"affected-services".into() => vec!["ntpd"].into()},
+            },
+        };
+        ResetToDefaultsMigration::reset("settings.ntp", &mut data, defaults());
+        assert_eq!(
+            data.data,
+            hashmap! {
+                "settings.ntp.time-servers".into() => vec!["pool.example.com"].into(),
+                "settings.motd".into() => "hi".into(),
+            }
+        );
+        assert_eq!(
+            data.metadata,
+            hashmap! {
+                "settings.ntp".into() => hashmap!{"affected-services".into() => vec!["chronyd"].into()},
+            }
+        );
+    }
+
+    #[test]
+    fn reset_leaves_unrelated_pending() {
+        let mut data = MigrationData {
+            data: hashmap! {
+                "settings.motd".into() => "hi".into(),
+            },
+            metadata: HashMap::new(),
+        };
+        ResetToDefaultsMigration::reset("settings.ntp", &mut data, defaults());
+        assert_eq!(
+            data.data,
+            hashmap! {
+                "settings.motd".into() => "hi".into(),
+            }
+        );
+    }
+
+    #[test]
+    fn remove() {
+        let mut data = MigrationData {
+            data: hashmap! {
+                "settings.ntp.time-servers".into() => vec!["pool.example.com"].into(),
+                "settings.motd".into() => "hi".into(),
+            },
+            metadata: hashmap! {
+                "settings.ntp".into() => hashmap!{
+                    "affected-services".into() => vec!["chronyd"].into(),
+                    "other".into() => "kept".into(),
+                },
+            },
+        };
+        ResetToDefaultsMigration::remove("settings.ntp", &mut data, defaults());
+        assert_eq!(
+            data.data,
+            hashmap! {
+                "settings.motd".into() => "hi".into(),
+            }
+        );
+        assert_eq!(
+            data.metadata,
+            hashmap! {
+                "settings.ntp".into() => hashmap!{"other".into() => "kept".into()},
+            }
+        );
+    }
+}
+
+// =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
+
+/// A function that changes a setting's value as it's moved; see SettingMove.
+pub type ValueTransform = fn(serde_json::Value) -> Result<serde_json::Value>;
+
+/// Describes a setting, or all settings under a prefix, that moved to a new key.
+pub struct SettingMove<'a> {
+    /// The setting or prefix in the old version.
+    pub from: &'a str,
+    /// The setting or prefix in the new version.  Settings under a prefix keep their names below
+    /// it, so moving "settings.a" to "settings.b" moves "settings.a.x" to "settings.b.x".
+    pub to: &'a str,
+    /// If given, changes each value when migrating forward.
+    pub forward: Option<ValueTransform>,
+    /// If given, changes each value when migrating backward; it should undo `forward`.
+    pub backward: Option<ValueTransform>,
+}
+
+/// We use this migration when a setting, or a group of settings, moves to a new key in the model.
+/// The values move, along with their metadata, such as affected-services and setting-generator.
+/// A schnauzer-style setting-generator that names the setting as an argument is updated to name
+/// the new key.
+///
+/// Templates of other settings that refer to the moved settings aren't changed; use
+/// ReplaceTemplateMigration for those.  If a moved setting's new key already has a different
+/// value, the migration fails rather than lose either value.
+pub struct MoveSettingsMigration<'a>(pub &'a [SettingMove<'a>]);
+
+impl MoveSettingsMigration<'_> {
+    /// Moves data and metadata under `from` to the same place under `to`.
+    fn move_settings(
+        input: &mut MigrationData,
+        from: &str,
+        to: &str,
+        transform: Option<ValueTransform>,
+    ) -> Result<()> {
+        let data_keys: Vec<String> = input
+            .data
+            .keys()
+            .filter(|key| under_prefix(key, from))
+            .cloned()
+            .collect();
+        if data_keys.is_empty() {
+            println!("Found no '{}' to move", from);
+        }
+        for old_key in data_keys {
+            if let Some(mut value) = input.data.remove(&old_key) {
+                if let Some(transform) = transform {
+                    value = transform(value)?;
+                }
+                let new_key = Self::moved_key(&old_key, from, to);
+                if let Some(existing) = input.data.get(&new_key) {
+                    ensure!(
+                        *existing == value,
+                        error::MoveConflict {
+                            from: old_key,
+                            to: new_key,
+                            existing: existing.clone(),
+                            moved: value,
+                        }
+                    );
+                }
+                println!("Moved {} to {}", old_key, new_key);
+                input.data.insert(new_key, value);
+            }
+        }
+
+        let metadata_keys: Vec<String> = input
+            .metadata
+            .keys()
+            .filter(|key| under_prefix(key, from))
+            .cloned()
+            .collect();
+        for old_key in metadata_keys {
+            if let Some(mut metadata) = input.metadata.remove(&old_key) {
+                let new_key = Self::moved_key(&old_key, from, to);
+                if let Some(generator) = metadata.get_mut("setting-generator") {
+                    Self::retarget_generator(generator, &old_key, &new_key);
+                }
+                println!("Moved metadata of {} to {}", old_key, new_key);
+                input
+                    .metadata
+                    .entry(new_key)
+                    .or_insert_with(Metadata::new)
+                    .extend(metadata);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the name of the given key, which is `from` or under it, after moving to `to`.
+    fn moved_key(key: &str, from: &str, to: &str) -> String {
+        format!("{}{}", to, &key[from.len()..])
+    }
+
+    /// Updates a setting-generator command that names the old key as an argument, like
+    /// "schnauzer settings.x", to name the new key instead.
+    fn retarget_generator(generator: &mut serde_json::Value, old_key: &str, new_key: &str) {
+        let command = match generator {
+            serde_json::Value::String(command) => command,
+            serde_json::Value::Object(table) => match table.get_mut("command") {
+                Some(serde_json::Value::String(command)) => command,
+                _ => return,
+            },
+            _ => return,
+        };
+        if command.split_whitespace().any(|word| word == old_key) {
+            let retargeted: Vec<&str> = command
+                .split_whitespace()
+                .map(|word| if word == old_key { new_key } else { word })
+                .collect();
+            *command = retargeted.join(" ");
+        }
+    }
+}
+
+impl Migration for MoveSettingsMigration<'_> {
+    fn forward(&mut self, mut input: MigrationData) -> Result<MigrationData> {
+        for setting_move in self.0 {
+            Self::move_settings(
+                &mut input,
+                setting_move.from,
+                setting_move.to,
+                setting_move.forward,
+            )?;
+        }
+        Ok(input)
+    }
+
+    fn backward(&mut self, mut input: MigrationData) -> Result<MigrationData> {
+        for setting_move in self.0.iter().rev() {
+            Self::move_settings(
+                &mut input,
+                setting_move.to,
+                setting_move.from,
+                setting_move.backward,
+            )?;
+        }
+        Ok(input)
+    }
+}
+
+#[cfg(test)]
+mod test_move_settings {
+    use super::{MoveSettingsMigration, SettingMove};
+    use crate::{error, Migration, MigrationData, Result};
+    use maplit::hashmap;
+    use serde_json::{json, Value};
+    use std::collections::HashMap;
+
+    fn input() -> MigrationData {
+        MigrationData {
+            data: hashmap! {
+                "settings.network.noproxy".into() => json!(["localhost"]),
+                "settings.network.hostname".into() => json!("host"),
+                "settings.old.a".into() => json!("a"),
+                "settings.old.b.c".into() => json!("c"),
+                "settings.other".into() => json!("other"),
+            },
+            metadata: hashmap! {
+                "settings.network.noproxy".into() => hashmap! {
+                    "affected-services".into() => json!(["containerd"]),
+                },
+                "settings.old".into() => hashmap! {
+                    "affected-services".into() => json!(["old"]),
+                },
+                "settings.old.a".into() => hashmap! {
+                    "setting-generator".into() => json!("schnauzer settings.old.a"),
+                    "template".into() => json!("{{settings.other}}"),
+                },
+            },
+        }
+    }
+
+    const MOVES: &[SettingMove<'_>] = &[
+        SettingMove {
+            from: "settings.network.noproxy",
+            to: "settings.network.no-proxy",
+            forward: None,
+            backward: None,
+        },
+        SettingMove {
+            from: "settings.old",
+            to: "settings.new.nested",
+            forward: None,
+            backward: None,
+        },
+    ];
+
+    #[test]
+    fn forward() {
+        let result = MoveSettingsMigration(MOVES).forward(input()).unwrap();
+        assert_eq!(
+            result.data,
+            hashmap! {
+                "settings.network.no-proxy".into() => json!(["localhost"]),
+                "settings.network.hostname".into() => json!("host"),
+                "settings.new.nested.a".into() => json!("a"),
+                "settings.new.nested.b.c".into() => json!("c"),
+                "settings.other".into() => json!("other"),
+            }
+        );
+        assert_eq!(
+            result.metadata,
+            hashmap! {
+                "settings.network.no-proxy".into() => hashmap! {
+                    "affected-services".into() => json!(["containerd"]),
+                },
+                "settings.new.nested".into() => hashmap! {
+                    "affected-services".into() => json!(["old"]),
+                },
+                "settings.new.nested.a".into() => hashmap! {
+                    "setting-generator".into() => json!("schnauzer settings.new.nested.a"),
+                    "template".into() => json!("{{settings.other}}"),
+                },
+            }
+        );
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut migration = MoveSettingsMigration(MOVES);
+        let moved = migration.forward(input()).unwrap();
+        let result = migration.backward(moved).unwrap();
+        assert_eq!(result.data, input().data);
+        assert_eq!(result.metadata, input().metadata);
+    }
+
+    #[test]
+    fn destination_set() {
+        let mut data = input();
+        data.data.insert("settings.new.nested.a".into(), json!("a"));
+        let moved = MoveSettingsMigration(MOVES).forward(data).unwrap();
+        assert_eq!(moved.data["settings.new.nested.a"], json!("a"));
+
+        let mut data = input();
+        data.data
+            .insert("settings.new.nested.a".into(), json!("different"));
+        let err = MoveSettingsMigration(MOVES).forward(data).unwrap_err();
+        assert!(matches!(err, error::Error::MoveConflict { .. }));
+    }
+
+    #[test]
+    fn generator_table() {
+        let mut data = MigrationData {
+            data: HashMap::new(),
+            metadata: hashmap! {
+                "settings.old.a".into() => hashmap! {
+                    "setting-generator".into() => json!({"command": "schnauzer settings.old.a", "timeout": 10}),
+                },
+            },
+        };
+        data = MoveSettingsMigration(MOVES).forward(data).unwrap();
+        assert_eq!(
+            data.metadata["settings.new.nested.a"]["setting-generator"],
+            json!({"command": "schnauzer settings.new.nested.a", "timeout": 10})
+        );
+    }
+
+    fn to_list(value: Value) -> Result<Value> {
+        Ok(json!([value]))
+    }
+
+    fn from_list(value: Value) -> Result<Value> {
+        match value {
+            Value::Array(mut list) if list.len() == 1 => Ok(list.remove(0)),
+            _ => error::Migration {
+                msg: format!("expected a list with one item, got {}", value),
+            }
+            .fail(),
+        }
+    }
+
+    #[test]
+    fn transform() {
+        let moves = &[SettingMove {
+            from: "settings.network.hostname",
+            to: "settings.network.hostnames",
+            forward: Some(to_list),
+            backward: Some(from_list),
+        }];
+        let mut migration = MoveSettingsMigration(moves);
+        let moved = migration.forward(input()).unwrap();
+        assert_eq!(moved.data["settings.network.hostnames"], json!(["host"]));
+        let result = migration.backward(moved).unwrap();
+        assert_eq!(result.data, input().data);
+    }
+
+    #[test]
+    fn transform_error() {
+        let moves = &[SettingMove {
+            from: "settings.other",
+            to: "settings.others",
+            forward: Some(from_list),
+            backward: None,
+        }];
+        MoveSettingsMigration(moves).forward(input()).unwrap_err();
+    }
+
+    #[test]
+    fn missing() {
+        let data = MigrationData {
+            data: hashmap! {
+                "settings.other".into() => json!("other"),
+            },
+            metadata: HashMap::new(),
+        };
+        let result = MoveSettingsMigration(MOVES).forward(data).unwrap();
+        assert_eq!(
+            result.data,
+            hashmap! {
+                "settings.other".into() => json!("other"),
+            }
+        );
+    }
+}
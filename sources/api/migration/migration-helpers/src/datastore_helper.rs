@@ -19,6 +19,7 @@ use datastore::{
 pub(crate) fn get_input_data<D: DataStore>(
     datastore: &D,
     committed: &Committed,
+    release: &BottlerocketRelease,
 ) -> Result<MigrationData> {
     let raw_data = datastore
         .get_prefix("", committed)
@@ -40,8 +41,7 @@ pub(crate) fn get_input_data<D: DataStore>(
     }
 
     // We also want to make "os.*" values, like variant and arch, available to migrations.
-    let release = BottlerocketRelease::new().context(error::BottlerocketRelease)?;
-    let os_pairs = to_pairs_with_prefix("os", &release).context(error::SerializeRelease)?;
+    let os_pairs = to_pairs_with_prefix("os", release).context(error::SerializeRelease)?;
     for (data_key, value_str) in os_pairs.into_iter() {
         let value =
             deserialize_scalar(&value_str).context(error::Deserialize { input: value_str })?;
@@ -0,0 +1,305 @@
+//! This module lets a migration be declared in TOML as a list of common operations, rather than
+//! compiled into a binary.  The migrator reads these directly, and runs them with the same common
+//! migrations a binary would use.
+//!
+//! Operations run in the order they're listed when migrating forward, and in reverse order when
+//! migrating backward.  For example:
+//!
+//! ```toml
+//! [[operation]]
+//! type = "add-settings"
+//! settings = ["settings.new-setting"]
+//!
+//! [[operation]]
+//! type = "remove-settings"
+//! settings = ["settings.old-setting"]
+//!
+//! [[operation]]
+//! type = "replace-template"
+//! setting = "settings.host-containers.admin.source"
+//! old-template = "{{ ecr-prefix settings.aws.region }}/bottlerocket-admin:v0.7.0"
+//! new-template = "{{ ecr-prefix settings.aws.region }}/bottlerocket-admin:v0.7.1"
+//!
+//! [[operation]]
+//! type = "add-metadata"
+//! setting = "settings.new-setting"
+//! metadata = ["setting-generator"]
+//! ```
+
+use crate::common_migrations::{
+    AddMetadataMigration, AddSettingsMigration, RemoveSettingsMigration, ReplaceTemplateMigration,
+    SettingMetadata,
+};
+use crate::{error, Migration, MigrationData, Result};
+use serde::Deserialize;
+use snafu::ResultExt;
+use std::str::FromStr;
+
+/// A migration made of common operations, parsed from TOML.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeclarativeMigration {
+    #[serde(rename = "operation")]
+    operations: Vec<Operation>,
+}
+
+/// The operations a declarative migration can use; each matches one of the common migrations.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case", deny_unknown_fields)]
+enum Operation {
+    AddSettings {
+        settings: Vec<String>,
+    },
+    RemoveSettings {
+        settings: Vec<String>,
+    },
+    #[serde(rename_all = "kebab-case")]
+    ReplaceTemplate {
+        setting: String,
+        old_template: String,
+        new_template: String,
+    },
+    AddMetadata {
+        setting: String,
+        metadata: Vec<String>,
+    },
+}
+
+impl FromStr for DeclarativeMigration {
+    type Err = error::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        toml::from_str(s).context(error::DeclarativeFormat)
+    }
+}
+
+impl Migration for DeclarativeMigration {
+    fn forward(&mut self, mut input: MigrationData) -> Result<MigrationData> {
+        for operation in self.operations.iter_mut() {
+            input = operation.forward(input)?;
+        }
+        Ok(input)
+    }
+
+    fn backward(&mut self, mut input: MigrationData) -> Result<MigrationData> {
+        for operation in self.operations.iter_mut().rev() {
+            input = operation.backward(input)?;
+        }
+        Ok(input)
+    }
+}
+
+impl Operation {
+    /// Runs the given function with the common migration that implements this operation.
+    fn with_migration<F>(&self, f: F) -> Result<MigrationData>
+    where
+        F: FnOnce(&mut dyn Migration) -> Result<MigrationData>,
+    {
+        match self {
+            Operation::AddSettings { settings } => {
+                let settings: Vec<&str> = settings.iter().map(String::as_str).collect();
+                f(&mut AddSettingsMigration(&settings))
+            }
+            Operation::RemoveSettings { settings } => {
+                let settings: Vec<&str> = settings.iter().map(String::as_str).collect();
+                f(&mut RemoveSettingsMigration(&settings))
+            }
+            Operation::ReplaceTemplate {
+                setting,
+                old_template,
+                new_template,
+            } => f(&mut ReplaceTemplateMigration {
+                setting,
+                old_template,
+                new_template,
+            }),
+            Operation::AddMetadata { setting, metadata } => {
+                let metadata: Vec<&str> = metadata.iter().map(String::as_str).collect();
+                f(&mut AddMetadataMigration(&[SettingMetadata {
+                    setting,
+                    metadata: &metadata,
+                }]))
+            }
+        }
+    }
+}
+
+impl Migration for Operation {
+    fn forward(&mut self, input: MigrationData) -> Result<MigrationData> {
+        self.with_migration(|migration| migration.forward(input))
+    }
+
+    fn backward(&mut self, input: MigrationData) -> Result<MigrationData> {
+        self.with_migration(|migration| migration.backward(input))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::DeclarativeMigration;
+    use crate::{Migration, MigrationData};
+    use maplit::hashmap;
+    use semver::Version;
+    use std::collections::HashMap;
+    use std::fs;
+    use std::path::Path;
+
+    /// Migrators older than this can't run declarative migrations, so they can't be used for this
+    /// version or earlier; the older version's migrator runs backward migrations on rollback.
+    const FIRST_DECLARATIVE_VERSION: &str = "1.3.0";
+
+    const EXAMPLE: &str = r#"
+        [[operation]]
+        type = "add-settings"
+        settings = ["settings.a"]
+
+        [[operation]]
+        type = "remove-settings"
+        settings = ["settings.b"]
+
+        [[operation]]
+        type = "add-metadata"
+        setting = "settings.c"
+        metadata = ["setting-generator"]
+    "#;
+
+    #[test]
+    fn forward() {
+        let mut migration: DeclarativeMigration = EXAMPLE.parse().unwrap();
+        let data = MigrationData {
+            data: hashmap! {
+                "settings.a".into() => "a".into(),
+                "settings.b".into() => "b".into(),
+            },
+            metadata: HashMap::new(),
+        };
+        let result = migration.forward(data).unwrap();
+        assert_eq!(
+            result.data,
+            hashmap! {
+                "settings.a".into() => "a".into(),
+            }
+        );
+    }
+
+    #[test]
+    fn backward() {
+        let mut migration: DeclarativeMigration = EXAMPLE.parse().unwrap();
+        let data = MigrationData {
+            data: hashmap! {
+                "settings.a".into() => "a".into(),
+                "settings.c".into() => "c".into(),
+            },
+            metadata: hashmap! {
+                "settings.c".into() => hashmap! {
+                    "setting-generator".into() => "generator".into(),
+                    "affected-services".into() => vec!["c"].into(),
+                },
+            },
+        };
+        let result = migration.backward(data).unwrap();
+        assert_eq!(
+            result.data,
+            hashmap! {
+                "settings.c".into() => "c".into(),
+            }
+        );
+        assert_eq!(
+            result.metadata,
+            hashmap! {
+                "settings.c".into() => hashmap! {
+                    "affected-services".into() => vec!["c"].into(),
+                },
+            }
+        );
+    }
+
+    #[test]
+    fn replace_template() {
+        let mut migration: DeclarativeMigration = r#"
+            [[operation]]
+            type = "replace-template"
+            setting = "settings.a"
+            old-template = "old {{settings.b}}"
+            new-template = "new {{settings.b}}"
+        "#
+        .parse()
+        .unwrap();
+        let data = MigrationData {
+            data: hashmap! {
+                "settings.a".into() => "old b".into(),
+                "settings.b".into() => "b".into(),
+            },
+            metadata: hashmap! {
+                "settings.a".into() => hashmap! {
+                    "template".into() => "old {{settings.b}}".into(),
+                },
+            },
+        };
+        let result = migration.forward(data).unwrap();
+        assert_eq!(result.data["settings.a"], "new b");
+        assert_eq!(
+            result.metadata["settings.a"]["template"],
+            "new {{settings.b}}"
+        );
+    }
+
+    #[test]
+    fn unknown_operation() {
+        r#"
+            [[operation]]
+            type = "frobnicate-settings"
+            settings = ["settings.a"]
+        "#
+        .parse::<DeclarativeMigration>()
+        .unwrap_err();
+    }
+
+    #[test]
+    fn unknown_field() {
+        r#"
+            [[operation]]
+            type = "add-settings"
+            settings = ["settings.a"]
+            metadata = ["setting-generator"]
+        "#
+        .parse::<DeclarativeMigration>()
+        .unwrap_err();
+    }
+
+    /// Makes sure every declarative migration in the tree parses, and is for a version whose
+    /// predecessor's migrator can run it.
+    #[test]
+    fn migrations_in_tree() {
+        let migrations_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("../migrations");
+        let first = Version::parse(FIRST_DECLARATIVE_VERSION).unwrap();
+        for version_dir in fs::read_dir(&migrations_dir).unwrap() {
+            let version_dir = version_dir.unwrap().path();
+            let version = match version_dir
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_prefix('v'))
+                .and_then(|version| Version::parse(version).ok())
+            {
+                Some(version) => version,
+                // Not a version directory, e.g. "archived"
+                None => continue,
+            };
+            for entry in fs::read_dir(&version_dir).unwrap() {
+                let path = entry.unwrap().path();
+                if path.extension().map(|ext| ext != "toml").unwrap_or(true) {
+                    continue;
+                }
+                assert!(
+                    version > first,
+                    "Declarative migration '{}' is for a version whose predecessor can't run it",
+                    path.display()
+                );
+                fs::read_to_string(&path)
+                    .unwrap()
+                    .parse::<DeclarativeMigration>()
+                    .unwrap_or_else(|e| panic!("Invalid migration '{}': {}", path.display(), e));
+            }
+        }
+    }
+}
@@ -0,0 +1,143 @@
+//! Parses the default settings and metadata of the variant being built, merged from its
+//! defaults.d directory by build.rs, so migrations can reset data to the new version's defaults.
+
+use crate::{error, Metadata, MigrationData, Result};
+use datastore::deserialize_scalar;
+use datastore::serialization::to_pairs_with_prefix;
+use snafu::{OptionExt, ResultExt};
+use std::collections::HashMap;
+
+/// The merged defaults of the variant being built, in the format storewolf reads.
+const DEFAULTS_TOML: &str = include_str!(concat!(env!("OUT_DIR"), "/defaults.toml"));
+
+/// Returns the default settings and metadata of the variant being built.
+pub(crate) fn defaults() -> Result<MigrationData> {
+    parse_defaults(DEFAULTS_TOML)
+}
+
+/// Parses defaults in the TOML format of defaults.d, with optional `settings` and `metadata`
+/// tables, into the data and metadata of a MigrationData.
+pub(crate) fn parse_defaults(defaults_str: &str) -> Result<MigrationData> {
+    let mut defaults_val: toml::Value =
+        toml::from_str(defaults_str).context(error::DefaultsFormatting)?;
+    let table = defaults_val
+        .as_table_mut()
+        .context(error::DefaultsNotTable { key: "root" })?;
+
+    let mut data = HashMap::new();
+    if let Some(settings_val) = table.remove("settings") {
+        let settings_table = settings_val
+            .as_table()
+            .context(error::DefaultsNotTable { key: "settings" })?;
+        let pairs = to_pairs_with_prefix("settings", settings_table)
+            .context(error::DefaultsSerialization)?;
+        for (key, value_str) in pairs {
+            let value = deserialize_scalar(&value_str).context(error::Deserialize {
+                input: value_str.clone(),
+            })?;
+            data.insert(key.name().clone(), value);
+        }
+    }
+
+    let mut metadata = HashMap::new();
+    if let Some(metadata_val) = table.remove("metadata") {
+        parse_metadata(Vec::new(), metadata_val, &mut metadata)?;
+    }
+
+    Ok(MigrationData { data, metadata })
+}
+
+/// Walks the metadata table, adding each metadata value to `metadata` under its data key.  The
+/// last key of the path to a non-table value is the metadata key, and the rest is the data key.
+fn parse_metadata(
+    mut path: Vec<String>,
+    value: toml::Value,
+    metadata: &mut HashMap<String, Metadata>,
+) -> Result<()> {
+    // A setting generator can be a table giving its command and how to run it, which is itself
+    // the metadata value, rather than more levels of data key.
+    let is_generator_table = value.is_table()
+        && path
+            .last()
+            .map(|k| k == "setting-generator")
+            .unwrap_or(false);
+
+    match value {
+        toml::Value::Table(table) if !is_generator_table => {
+            for (key, value) in table {
+                let mut path = path.clone();
+                path.push(key);
+                parse_metadata(path, value, metadata)?;
+            }
+        }
+        value => {
+            let md_key = path
+                .pop()
+                .context(error::DefaultsNotTable { key: "metadata" })?;
+            let data_key = path.join(".");
+            let value = serde_json::to_value(&value).context(error::Serialize)?;
+            metadata
+                .entry(data_key)
+                .or_insert_with(Metadata::new)
+                .insert(md_key, value);
+        }
+    }
+    Ok(())
+}
+
+/// Returns whether the given key is the given prefix, or is under it.
+pub(crate) fn under_prefix(key: &str, prefix: &str) -> bool {
+    prefix.is_empty()
+        || key == prefix
+        || (key.starts_with(prefix) && key[prefix.len()..].starts_with('.'))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use maplit::hashmap;
+
+    #[test]
+    fn parse() {
+        let defaults = parse_defaults(
+            r#"
+            [settings.kubernetes]
+            cluster-domain = "cluster.local"
+            standalone-mode = false
+
+            [metadata.settings.kubernetes]
+            affected-services = ["kubernetes"]
+            max-pods.setting-generator = { command = "pluto max-pods", retries = 3 }
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            defaults.data,
+            hashmap! {
+                "settings.kubernetes.cluster-domain".to_string() => "cluster.local".into(),
+                "settings.kubernetes.standalone-mode".to_string() => false.into(),
+            }
+        );
+        assert_eq!(
+            defaults.metadata,
+            hashmap! {
+                "settings.kubernetes".to_string() => hashmap! {
+                    "affected-services".to_string() => serde_json::json!(["kubernetes"]),
+                },
+                "settings.kubernetes.max-pods".to_string() => hashmap! {
+                    "setting-generator".to_string() =>
+                        serde_json::json!({"command": "pluto max-pods", "retries": 3}),
+                },
+            }
+        );
+    }
+
+    #[test]
+    fn prefix() {
+        assert!(under_prefix("settings.motd", "settings.motd"));
+        assert!(under_prefix("settings.ntp.time-servers", "settings.ntp"));
+        assert!(!under_prefix("settings.ntp-other", "settings.ntp"));
+        assert!(under_prefix("settings.motd", ""));
+    }
+}
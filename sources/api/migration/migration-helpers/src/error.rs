@@ -45,6 +45,12 @@ pub enum Error {
     #[snafu(display("Migrated data failed validation: {}", msg))]
     Validation { msg: String },
 
+    #[snafu(display("Migrated {:?} settings don't match the model: {}", committed, source))]
+    ModelValidation {
+        committed: datastore::Committed,
+        source: datastore::deserialization::Error,
+    },
+
     // Generic error variant for migration authors
     #[snafu(display("Migration returned error: {}", msg))]
     Migration { msg: String },
@@ -73,7 +79,7 @@ pub enum Error {
     },
 
     #[snafu(display("'{}' is set to non-string value", setting))]
-    NonStringSettingDataType { setting: &'static str },
+    NonStringSettingDataType { setting: String },
 
     #[snafu(display("Unable to deserialize datastore data: {}", source))]
     DeserializeDatastore {
@@ -83,6 +89,20 @@ pub enum Error {
     #[snafu(display("Unable to create new key: {}", source))]
     NewKey { source: datastore::error::Error },
 
+    #[snafu(display(
+        "Unable to move '{}' to '{}', which is already set to {} instead of {}",
+        from,
+        to,
+        existing,
+        moved
+    ))]
+    MoveConflict {
+        from: String,
+        to: String,
+        existing: serde_json::Value,
+        moved: serde_json::Value,
+    },
+
     #[snafu(display("Setting '{}' contains non-string item: {:?}", setting, data))]
     ReplaceListContents {
         setting: String,
@@ -101,6 +121,20 @@ pub enum Error {
         data: Vec<serde_json::Value>,
     },
 
+    #[snafu(display("Default settings are not valid TOML: {}", source))]
+    DefaultsFormatting { source: toml::de::Error },
+
+    #[snafu(display("'{}' in default settings is not a TOML table", key))]
+    DefaultsNotTable { key: &'static str },
+
+    #[snafu(display("Unable to serialize default settings: {}", source))]
+    DefaultsSerialization {
+        source: datastore::serialization::Error,
+    },
+
+    #[snafu(display("Invalid declarative migration: {}", source))]
+    DeclarativeFormat { source: toml::de::Error },
+
     #[snafu(display("Failed to delete file '{}': '{}'", path.display(), source))]
     RemoveFile {
         path: PathBuf,
@@ -14,8 +14,12 @@
 mod args;
 pub mod common_migrations;
 mod datastore_helper;
+pub mod declarative;
+mod defaults;
 pub mod error;
+mod validate;
 
+use bottlerocket_release::BottlerocketRelease;
 use snafu::ResultExt;
 use std::collections::HashMap;
 use std::env;
@@ -24,9 +28,11 @@ use std::fmt;
 use datastore::{Committed, Value};
 pub use datastore::{DataStore, FilesystemDataStore};
 
-use args::{parse_args, Args};
+use args::parse_args;
+pub use args::Args;
 use datastore_helper::{get_input_data, set_output_data};
 pub use error::Result;
+use validate::validate_migrated_data;
 
 /// The data store implementation currently in use.  Used by the simpler `migrate` interface; can
 /// be overridden by using the `run_migration` interface.
@@ -53,6 +59,17 @@ pub trait Migration {
     fn backward(&mut self, input: MigrationData) -> Result<MigrationData>;
 }
 
+/// Lets a caller keep ownership of a migration, for example to run it in both directions.
+impl<M: Migration + ?Sized> Migration for &mut M {
+    fn forward(&mut self, input: MigrationData) -> Result<MigrationData> {
+        (**self).forward(input)
+    }
+
+    fn backward(&mut self, input: MigrationData) -> Result<MigrationData> {
+        (**self).backward(input)
+    }
+}
+
 /// Mapping of metadata key name to arbitrary value.  Each data key can have a Metadata describing
 /// its metadata keys.
 pub type Metadata = HashMap<String, Value>;
@@ -68,25 +85,41 @@ pub struct MigrationData {
     pub metadata: HashMap<String, Metadata>,
 }
 
-/// Returns the default settings for a given path so you can easily replace a given section of the
-/// datastore with new defaults.  For example, you could request "settings" to get all new default
-/// settings, or "settings.serviceX.subsection" to scope it down.
-pub fn defaults_for<S: AsRef<str>>(_path: S) -> Result<Value> {
-    unimplemented!()
-}
-
-/// Ensures we can use the migrated data in the new data store.  Can use this result to stop the
-/// migration process before saving any data.
-fn validate_migrated_data(_migrated: &MigrationData) -> Result<()> {
-    // No validations yet.
-    // You can check the migrated data and throw error::Validation if anything seems wrong.
-    Ok(())
+/// Returns the default settings and metadata for a given path so you can easily replace a given
+/// section of the datastore with new defaults.  For example, you could request "settings" to get
+/// all new default settings, or "settings.serviceX.subsection" to scope it down.  Metadata is
+/// returned for data keys under the path.
+///
+/// The defaults are those of the variant the migration was built for, that is, the version the
+/// migration moves to on upgrade; they're merged from its defaults.d at build time.
+pub fn defaults_for<S: AsRef<str>>(path: S) -> Result<MigrationData> {
+    let path = path.as_ref();
+    let mut defaults = defaults::defaults()?;
+    defaults
+        .data
+        .retain(|key, _| defaults::under_prefix(key, path));
+    defaults
+        .metadata
+        .retain(|key, _| defaults::under_prefix(key, path));
+    Ok(defaults)
 }
 
 /// If you need a little more control over a migration than with migrate, or you're using this
 /// module as a library, you can call run_migration directly with the arguments that would
 /// normally be parsed from the migration binary's command line.
-pub fn run_migration(mut migration: impl Migration, args: &Args) -> Result<()> {
+pub fn run_migration(migration: impl Migration, args: &Args) -> Result<()> {
+    let release = BottlerocketRelease::new().context(error::BottlerocketRelease)?;
+    run_migration_with_release(migration, args, &release)
+}
+
+/// Like run_migration, but uses the given release data for the "os" values given to the
+/// migration, rather than reading it from the running system.  This is useful for running
+/// migrations in tests.
+pub fn run_migration_with_release(
+    mut migration: impl Migration,
+    args: &Args,
+    release: &BottlerocketRelease,
+) -> Result<()> {
     let source = DataStoreImplementation::new(&args.source_datastore);
     let mut target = DataStoreImplementation::new(&args.target_datastore);
 
@@ -98,7 +131,7 @@ pub fn run_migration(mut migration: impl Migration, args: &Args) -> Result<()> {
     committeds.extend(transactions.into_iter().map(|tx| Committed::Pending { tx }));
 
     for committed in committeds {
-        let input = get_input_data(&source, &committed)?;
+        let input = get_input_data(&source, &committed, release)?;
 
         let mut migrated = input.clone();
         migrated = match args.migration_type {
@@ -106,7 +139,13 @@ pub fn run_migration(mut migration: impl Migration, args: &Args) -> Result<()> {
             MigrationType::Backward => migration.backward(migrated),
         }?;
 
-        validate_migrated_data(&migrated)?;
+        // Make sure the version we're migrating to can use the migrated data before saving any of
+        // it.  The migrator only asks for this from the last migration to the new version; earlier
+        // migrations in the chain produce data for intermediate versions, whose models can differ
+        // from the one this migration was built with.
+        if args.validate {
+            validate_migrated_data(&migrated, &committed)?;
+        }
 
         set_output_data(&mut target, &migrated, &committed)?;
     }
@@ -0,0 +1,230 @@
+//! This module checks migrated data against the model of the version being migrated to, so that a
+//! migration that leaves data the new version can't use stops the migration process before the
+//! new data store is put in place.
+//!
+//! Settings are deserialized into the model's Settings, which checks that every key is known and
+//! that every value has the right type and passes any modeled type's validation.  Settings in the
+//! model are optional, so this works for pending transactions as well as live data.
+//!
+//! Services and configuration files aren't checked against the model because storewolf fills in
+//! anything new from the defaults after migrations run.
+//!
+//! Metadata is checked for the keys the system relies on: "affected-services" must be a list of
+//! service names, "template" must be a valid template, "setting-generator" must be a command or a
+//! table giving one, and settings generated by schnauzer must have a template.
+
+use crate::{error, MigrationData, Result};
+use datastore::deserialization::from_map;
+use datastore::{serialize_scalar, Committed, Key, KeyType};
+use model::Settings;
+use serde_json::Value;
+use snafu::{ensure, OptionExt, ResultExt};
+use std::collections::HashMap;
+
+/// Ensures the migrated data for the given live data or pending transaction can be used by the
+/// version being migrated to.
+pub(crate) fn validate_migrated_data(
+    migrated: &MigrationData,
+    committed: &Committed,
+) -> Result<()> {
+    validate_settings(migrated, committed)?;
+    for (data_key, metadata) in &migrated.metadata {
+        for (metadata_key, value) in metadata {
+            Key::new(KeyType::Meta, metadata_key).context(error::InvalidKey {
+                key_type: KeyType::Meta,
+                key: metadata_key,
+            })?;
+            validate_metadata(data_key, metadata_key, value)?;
+        }
+        validate_generator(data_key, metadata)?;
+    }
+    Ok(())
+}
+
+/// Deserializes the migrated settings into the model's Settings.
+fn validate_settings(migrated: &MigrationData, committed: &Committed) -> Result<()> {
+    let mut settings = HashMap::new();
+    for (name, value) in &migrated.data {
+        if !name.starts_with("settings.") {
+            continue;
+        }
+        let key = Key::new(KeyType::Data, name).context(error::InvalidKey {
+            key_type: KeyType::Data,
+            key: name,
+        })?;
+        settings.insert(key, serialize_scalar(value).context(error::Serialize)?);
+    }
+
+    if settings.is_empty() {
+        return Ok(());
+    }
+    from_map::<_, _, Settings, _>(&settings).context(error::ModelValidation {
+        committed: committed.clone(),
+    })?;
+    Ok(())
+}
+
+/// Checks the shape of the metadata keys we know about.
+fn validate_metadata(data_key: &str, metadata_key: &str, value: &Value) -> Result<()> {
+    match metadata_key {
+        "affected-services" => {
+            let valid = value
+                .as_array()
+                .map(|services| services.iter().all(Value::is_string))
+                .unwrap_or(false);
+            ensure!(
+                valid,
+                error::Validation {
+                    msg: format!(
+                        "'affected-services' for '{}' is not a list of service names: {}",
+                        data_key, value
+                    )
+                }
+            );
+        }
+        "template" => {
+            let template = value.as_str().context(error::Validation {
+                msg: format!("'template' for '{}' is not a string: {}", data_key, value),
+            })?;
+            if let Err(e) = handlebars::Template::compile(template) {
+                return error::Validation {
+                    msg: format!("'template' for '{}' is invalid: {}", data_key, e),
+                }
+                .fail();
+            }
+        }
+        "setting-generator" => {
+            generator_command(value).context(error::Validation {
+                msg: format!(
+                    "'setting-generator' for '{}' is not a command or a table with a command: {}",
+                    data_key, value
+                ),
+            })?;
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Settings generated by schnauzer are rendered from their template, so they must have one.
+fn validate_generator(data_key: &str, metadata: &HashMap<String, Value>) -> Result<()> {
+    let command = match metadata
+        .get("setting-generator")
+        .and_then(generator_command)
+    {
+        Some(command) => command,
+        None => return Ok(()),
+    };
+    let program = command.split_whitespace().next().unwrap_or_default();
+    if program.rsplit('/').next() == Some("schnauzer") {
+        ensure!(
+            metadata.contains_key("template"),
+            error::Validation {
+                msg: format!(
+                    "'{}' is generated by schnauzer but has no 'template' metadata",
+                    data_key
+                )
+            }
+        );
+    }
+    Ok(())
+}
+
+/// Returns the command of a setting generator, which is either given directly or in the
+/// "command" field of a table.
+fn generator_command(value: &Value) -> Option<&str> {
+    match value {
+        Value::String(command) => Some(command),
+        Value::Object(table) => table.get("command").and_then(Value::as_str),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::validate_migrated_data;
+    use crate::MigrationData;
+    use datastore::Committed;
+    use maplit::hashmap;
+    use serde_json::json;
+    use std::collections::HashMap;
+
+    fn data(data: HashMap<String, serde_json::Value>) -> MigrationData {
+        MigrationData {
+            data,
+            metadata: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn valid_settings() {
+        let migrated = data(hashmap! {
+            "settings.motd".into() => json!("hi"),
+            "settings.host-containers.admin.enabled".into() => json!(true),
+            "services.foo.restart-commands".into() => json!([]),
+            "os.arch".into() => json!("x86_64"),
+        });
+        validate_migrated_data(&migrated, &Committed::Live).unwrap();
+    }
+
+    #[test]
+    fn unknown_setting() {
+        let migrated = data(hashmap! {
+            "settings.motd".into() => json!("hi"),
+            "settings.not-a-setting".into() => json!("hi"),
+        });
+        validate_migrated_data(&migrated, &Committed::Pending { tx: "test".into() }).unwrap_err();
+    }
+
+    #[test]
+    fn wrong_type() {
+        let migrated = data(hashmap! {
+            "settings.host-containers.admin.enabled".into() => json!("yes"),
+        });
+        validate_migrated_data(&migrated, &Committed::Live).unwrap_err();
+    }
+
+    #[test]
+    fn modeled_type() {
+        let migrated = data(hashmap! {
+            "settings.network.hostname".into() => json!("not a hostname!"),
+        });
+        validate_migrated_data(&migrated, &Committed::Live).unwrap_err();
+    }
+
+    #[test]
+    fn valid_metadata() {
+        let mut migrated = data(hashmap! {
+            "settings.motd".into() => json!("hi"),
+        });
+        migrated.metadata = hashmap! {
+            "settings.motd".into() => hashmap! {
+                "affected-services".into() => json!(["motd"]),
+            },
+            "settings.a".into() => hashmap! {
+                "setting-generator".into() => json!("schnauzer settings.a"),
+                "template".into() => json!("{{settings.motd}}"),
+            },
+            "settings.b".into() => hashmap! {
+                "setting-generator".into() => json!({"command": "pluto max-pods", "timeout": 10}),
+            },
+        };
+        validate_migrated_data(&migrated, &Committed::Live).unwrap();
+    }
+
+    #[test]
+    fn invalid_metadata() {
+        for metadata in vec![
+            hashmap! { "affected-services".into() => json!("motd") },
+            hashmap! { "affected-services".into() => json!([1]) },
+            hashmap! { "template".into() => json!(["{{settings.motd}}"]) },
+            hashmap! { "template".into() => json!("{{#if settings.motd}}") },
+            hashmap! { "setting-generator".into() => json!({"timeout": 10}) },
+            hashmap! { "setting-generator".into() => json!("/usr/bin/schnauzer settings.a") },
+        ] {
+            let mut migrated = data(HashMap::new());
+            migrated.metadata = hashmap! { "settings.a".into() => metadata };
+            validate_migrated_data(&migrated, &Committed::Live).unwrap_err();
+        }
+    }
+}
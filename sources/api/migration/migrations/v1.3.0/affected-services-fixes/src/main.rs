@@ -0,0 +1,102 @@
+#![deny(rust_2018_idioms)]
+
+use migration_helpers::common_migrations::{
+    MetadataListReplacement, ReplaceMetadataListsMigration,
+};
+use migration_helpers::{migrate, Result};
+use std::process;
+
+/// We corrected the 'affected-services' list metadata for settings used by templates of services
+/// that weren't listed: 'settings.ntp' named a nonexistent "chronyd" service rather than "ntp",
+/// metricdog reads 'settings.updates', and the updog and metricdog configuration files include the
+/// proxy settings from 'settings.network'.  The 'settings.network' list varies by variant, so we
+/// replace each variant's list.
+fn run() -> Result<()> {
+    migrate(ReplaceMetadataListsMigration(vec![
+        MetadataListReplacement {
+            setting: "settings.ntp",
+            metadata: "affected-services",
+            old_vals: &["chronyd"],
+            new_vals: &["ntp"],
+        },
+        MetadataListReplacement {
+            setting: "settings.updates",
+            metadata: "affected-services",
+            old_vals: &["updog"],
+            new_vals: &["updog", "metricdog"],
+        },
+        MetadataListReplacement {
+            setting: "settings.network",
+            metadata: "affected-services",
+            old_vals: &["containerd", "host-containerd", "host-containers"],
+            new_vals: &[
+                "containerd",
+                "host-containerd",
+                "host-containers",
+                "metricdog",
+                "updog",
+            ],
+        },
+        MetadataListReplacement {
+            setting: "settings.network",
+            metadata: "affected-services",
+            old_vals: &[
+                "containerd",
+                "kubernetes",
+                "host-containerd",
+                "host-containers",
+            ],
+            new_vals: &[
+                "containerd",
+                "kubernetes",
+                "host-containerd",
+                "host-containers",
+                "metricdog",
+                "updog",
+            ],
+        },
+        MetadataListReplacement {
+            setting: "settings.network",
+            metadata: "affected-services",
+            old_vals: &["containerd", "docker", "host-containerd", "host-containers"],
+            new_vals: &[
+                "containerd",
+                "docker",
+                "host-containerd",
+                "host-containers",
+                "metricdog",
+                "updog",
+            ],
+        },
+        MetadataListReplacement {
+            setting: "settings.network",
+            metadata: "affected-services",
+            old_vals: &[
+                "containerd",
+                "docker",
+                "ecs",
+                "host-containerd",
+                "host-containers",
+            ],
+            new_vals: &[
+                "containerd",
+                "docker",
+                "ecs",
+                "host-containerd",
+                "host-containers",
+                "metricdog",
+                "updog",
+            ],
+        },
+    ]))
+}
+
+// Returning a Result from main makes it print a Debug representation of the error, but with Snafu
+// we have nice Display representations of the error, so we wrap "main" (run) and print any error.
+// https://github.com/shepmaster/snafu/issues/110
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,24 @@
+#![deny(rust_2018_idioms)]
+
+use migration_helpers::common_migrations::AddSettingsMigration;
+use migration_helpers::{migrate, Result};
+use std::process;
+
+/// We added `restart-after` to services so they restart in dependency order.  Older versions
+/// don't know about it, so it's removed on downgrade.
+fn run() -> Result<()> {
+    migrate(AddSettingsMigration(&[
+        "services.kubernetes.restart-after",
+        "services.ecs.restart-after",
+    ]))
+}
+
+// Returning a Result from main makes it print a Debug representation of the error, but with Snafu
+// we have nice Display representations of the error, so we wrap "main" (run) and print any error.
+// https://github.com/shepmaster/snafu/issues/110
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
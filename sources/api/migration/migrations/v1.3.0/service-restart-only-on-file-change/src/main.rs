@@ -0,0 +1,29 @@
+#![deny(rust_2018_idioms)]
+
+use migration_helpers::common_migrations::AddSettingsMigration;
+use migration_helpers::{migrate, Result};
+use std::process;
+
+/// We added `restart-only-on-file-change` to services whose restart commands only read their
+/// configuration files, so they aren't restarted when those files don't change.  Older versions
+/// don't know about it, so it's removed on downgrade.
+fn run() -> Result<()> {
+    migrate(AddSettingsMigration(&[
+        "services.containerd.restart-only-on-file-change",
+        "services.host-containerd.restart-only-on-file-change",
+        "services.ntp.restart-only-on-file-change",
+        "services.docker.restart-only-on-file-change",
+        "services.kubernetes.restart-only-on-file-change",
+        "services.metricdog.restart-only-on-file-change",
+    ]))
+}
+
+// Returning a Result from main makes it print a Debug representation of the error, but with Snafu
+// we have nice Display representations of the error, so we wrap "main" (run) and print any error.
+// https://github.com/shepmaster/snafu/issues/110
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
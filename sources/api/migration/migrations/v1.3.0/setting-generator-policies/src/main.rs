@@ -0,0 +1,95 @@
+#![deny(rust_2018_idioms)]
+
+use migration_helpers::{migrate, Migration, MigrationData, Result};
+use serde_json::{json, Value};
+use std::process;
+
+/// Settings whose generators we now run with a timeout and retries, and their commands.
+const GENERATORS: &[(&str, &str)] = &[
+    ("settings.kubernetes.max-pods", "pluto max-pods"),
+    ("settings.kubernetes.cluster-dns-ip", "pluto cluster-dns-ip"),
+    ("settings.kubernetes.node-ip", "pluto node-ip"),
+];
+const TIMEOUT: u64 = 120;
+const RETRIES: u64 = 3;
+
+/// sundog learned to read a table for setting-generator metadata, giving the command along with
+/// a timeout and retry policy, and we use one for the pluto generators.  Older versions of sundog
+/// only understand a command string, so on downgrade we convert any table back to its command.
+pub struct SettingGeneratorPolicies;
+
+impl Migration for SettingGeneratorPolicies {
+    fn forward(&mut self, mut input: MigrationData) -> Result<MigrationData> {
+        for (setting, command) in GENERATORS {
+            // Check if we have this setting at all.
+            let metadata_value = match input
+                .metadata
+                .get_mut(*setting)
+                .and_then(|metadata| metadata.get_mut("setting-generator"))
+            {
+                Some(metadata_value) => metadata_value,
+                None => {
+                    println!("Found no setting-generator for '{}'", setting);
+                    continue;
+                }
+            };
+
+            // Only change the generator if it's what we expect.
+            if metadata_value.as_str() == Some(*command) {
+                let table = json!({"command": command, "timeout": TIMEOUT, "retries": RETRIES});
+                println!(
+                    "Changed setting-generator for '{}' from {} to {} on upgrade",
+                    setting, metadata_value, table
+                );
+                *metadata_value = table;
+            } else {
+                println!(
+                    "setting-generator for '{}' is not set to {:?}, leaving alone",
+                    setting, command
+                );
+            }
+        }
+        Ok(input)
+    }
+
+    fn backward(&mut self, mut input: MigrationData) -> Result<MigrationData> {
+        // Any table, not just the ones we added, would break the older sundog.
+        for (setting, metadata) in input.metadata.iter_mut() {
+            if let Some(metadata_value) = metadata.get_mut("setting-generator") {
+                if let Value::Object(table) = metadata_value {
+                    match table.get("command").cloned() {
+                        Some(command @ Value::String(_)) => {
+                            println!(
+                                "Changed setting-generator for '{}' from {} to {} on downgrade",
+                                setting, metadata_value, command
+                            );
+                            *metadata_value = command;
+                        }
+                        _ => {
+                            println!(
+                                "setting-generator for '{}' has no command string; removing {}",
+                                setting, metadata_value
+                            );
+                            metadata.remove("setting-generator");
+                        }
+                    }
+                }
+            }
+        }
+        Ok(input)
+    }
+}
+
+fn run() -> Result<()> {
+    migrate(SettingGeneratorPolicies)
+}
+
+// Returning a Result from main makes it print a Debug representation of the error, but with Snafu
+// we have nice Display representations of the error, so we wrap "main" (run) and print any error.
+// https://github.com/shepmaster/snafu/issues/110
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,105 @@
+#![deny(rust_2018_idioms)]
+
+use migration_helpers::{migrate, Migration, MigrationData, Result};
+use serde_json::Value;
+use std::process;
+
+/// Settings whose pluto generators we now run again to refresh their values, and when.
+const REFRESH_POLICIES: &[(&str, &str, &str)] = &[
+    (
+        "settings.kubernetes.max-pods",
+        "pluto max-pods",
+        "every-boot",
+    ),
+    ("settings.kubernetes.node-ip", "pluto node-ip", "every-boot"),
+    (
+        "settings.kubernetes.cluster-dns-ip",
+        "pluto cluster-dns-ip",
+        "on-demand",
+    ),
+];
+
+const GENERATOR: &str = "setting-generator";
+const ORIGIN: &str = "setting-origin";
+const GENERATED: &str = "generated";
+
+/// sundog learned to generate settings again according to a `refresh` policy in their
+/// setting-generator table, but only replaces values whose `setting-origin` metadata says they
+/// were generated.  On upgrade, we add the refresh policy to the pluto generators, and mark the
+/// existing values of settings that have a generator as generated, since that's where they came
+/// from unless the user set them.  On downgrade, we remove both, since older versions of sundog
+/// reject unknown fields in the table.
+pub struct SettingOrigins;
+
+impl Migration for SettingOrigins {
+    fn forward(&mut self, mut input: MigrationData) -> Result<MigrationData> {
+        for (setting, command, refresh) in REFRESH_POLICIES {
+            // Only change the generator if it's the table we expect.
+            match input
+                .metadata
+                .get_mut(*setting)
+                .and_then(|metadata| metadata.get_mut(GENERATOR))
+            {
+                Some(Value::Object(table))
+                    if table.get("command").and_then(Value::as_str) == Some(*command) =>
+                {
+                    println!(
+                        "Set refresh policy of setting-generator for '{}' to '{}' on upgrade",
+                        setting, refresh
+                    );
+                    table.insert("refresh".to_string(), (*refresh).into());
+                }
+                Some(metadata_value) => println!(
+                    "setting-generator for '{}' is not a table with command {:?}, leaving alone: {}",
+                    setting, command, metadata_value
+                ),
+                None => println!("Found no setting-generator for '{}'", setting),
+            }
+        }
+
+        for (setting, metadata) in input.metadata.iter_mut() {
+            if metadata.contains_key(GENERATOR)
+                && !metadata.contains_key(ORIGIN)
+                && input.data.contains_key(setting)
+            {
+                println!("Marked value of '{}' as generated on upgrade", setting);
+                metadata.insert(ORIGIN.to_string(), GENERATED.into());
+            }
+        }
+        Ok(input)
+    }
+
+    fn backward(&mut self, mut input: MigrationData) -> Result<MigrationData> {
+        for (setting, metadata) in input.metadata.iter_mut() {
+            if let Some(Value::Object(table)) = metadata.get_mut(GENERATOR) {
+                if let Some(refresh) = table.remove("refresh") {
+                    println!(
+                        "Removed refresh policy {} from setting-generator for '{}' on downgrade",
+                        refresh, setting
+                    );
+                }
+            }
+            if let Some(origin) = metadata.remove(ORIGIN) {
+                println!(
+                    "Removed setting-origin {} from '{}' on downgrade",
+                    origin, setting
+                );
+            }
+        }
+        Ok(input)
+    }
+}
+
+fn run() -> Result<()> {
+    migrate(SettingOrigins)
+}
+
+// Returning a Result from main makes it print a Debug representation of the error, but with Snafu
+// we have nice Display representations of the error, so we wrap "main" (run) and print any error.
+// https://github.com/shepmaster/snafu/issues/110
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
@@ -19,10 +19,19 @@ fn usage() -> ! {
             --root-path PATH
             --metadata-directory PATH
             (--migrate-to-version x.y | --migrate-to-version-from-os-release)
+            [ --dry-run ]
             [ --no-color ]
             [ --log-level trace|debug|info|warn|error ]",
         program_name
     );
+    #[cfg(feature = "local-migrations")]
+    eprintln!(
+        r"
+Development builds can load unsigned, optionally uncompressed migrations from a local directory
+instead of --migration-directory, --root-path, and --metadata-directory:
+            --local-migration-directory PATH
+            --manifest PATH"
+    );
     process::exit(2);
 }
 
@@ -36,10 +45,26 @@ fn usage_msg<S: AsRef<str>>(msg: S) -> ! {
 pub(crate) struct Args {
     pub(crate) datastore_path: PathBuf,
     pub(crate) log_level: LevelFilter,
-    pub(crate) migration_directory: PathBuf,
     pub(crate) migrate_to_version: Version,
-    pub(crate) root_path: PathBuf,
-    pub(crate) metadata_directory: PathBuf,
+    pub(crate) source: MigrationSource,
+    pub(crate) dry_run: bool,
+}
+
+/// Where to find the manifest and migrations.
+pub(crate) enum MigrationSource {
+    /// The TUF repository cached by updog, holding signed, LZ4-compressed migrations.
+    Repository {
+        migration_directory: PathBuf,
+        root_path: PathBuf,
+        metadata_directory: PathBuf,
+    },
+    /// A plain local directory of migrations, which don't need to be signed or compressed, and a
+    /// manifest file.  Only for testing migrations during development.
+    #[cfg(feature = "local-migrations")]
+    LocalDirectory {
+        migration_directory: PathBuf,
+        manifest_path: PathBuf,
+    },
 }
 
 impl Args {
@@ -52,6 +77,11 @@ impl Args {
         let mut migrate_to_version = None;
         let mut root_path = None;
         let mut metadata_path = None;
+        let mut dry_run = false;
+        #[cfg(feature = "local-migrations")]
+        let mut local_migration_directory = None;
+        #[cfg(feature = "local-migrations")]
+        let mut manifest_path = None;
 
         let mut iter = args.skip(1);
         while let Some(arg) = iter.next() {
@@ -131,25 +161,71 @@ impl Args {
                     trace!("Given --metadata-directory: {}", path_str);
                     metadata_path = Some(PathBuf::from(path_str));
                 }
+
+                "--dry-run" => dry_run = true,
+
+                #[cfg(feature = "local-migrations")]
+                "--local-migration-directory" => {
+                    let path_str = iter.next().unwrap_or_else(|| {
+                        usage_msg("Did not give argument to --local-migration-directory")
+                    });
+                    trace!("Given --local-migration-directory: {}", path_str);
+                    local_migration_directory = Some(PathBuf::from(path_str));
+                }
+
+                #[cfg(feature = "local-migrations")]
+                "--manifest" => {
+                    let path_str = iter
+                        .next()
+                        .unwrap_or_else(|| usage_msg("Did not give argument to --manifest"));
+                    trace!("Given --manifest: {}", path_str);
+                    manifest_path = Some(PathBuf::from(path_str));
+                }
+
                 _ => usage_msg(format!("Unable to parse input '{}'", arg)),
             }
         }
 
+        #[cfg(feature = "local-migrations")]
+        let source = match local_migration_directory {
+            Some(migration_directory) => MigrationSource::LocalDirectory {
+                migration_directory,
+                manifest_path: manifest_path.unwrap_or_else(|| {
+                    usage_msg("--manifest must be specified with --local-migration-directory")
+                }),
+            },
+            None => repository_source(migration_directory, root_path, metadata_path),
+        };
+        #[cfg(not(feature = "local-migrations"))]
+        let source = repository_source(migration_directory, root_path, metadata_path);
+
         Self {
             datastore_path: datastore_path
                 .unwrap_or_else(|| usage_msg("--datastore-path must be specified")),
             log_level: log_level.unwrap_or_else(|| LevelFilter::Info),
-            migration_directory: migration_directory
-                .unwrap_or_else(|| usage_msg("--migration-directory must be specified")),
             migrate_to_version: migrate_to_version.unwrap_or_else(|| {
                 usage_msg(
                     "Desired version could not be determined; pass --migrate-to-version or \
                     --migrate-to-version-from-os-release",
                 )
             }),
-            root_path: root_path.unwrap_or_else(|| usage_msg("--root-path must be specified")),
-            metadata_directory: metadata_path
-                .unwrap_or_else(|| usage_msg("--metadata-directory must be specified")),
+            source,
+            dry_run,
         }
     }
 }
+
+/// Builds the standard migration source, making sure all of its arguments were given.
+fn repository_source(
+    migration_directory: Option<PathBuf>,
+    root_path: Option<PathBuf>,
+    metadata_directory: Option<PathBuf>,
+) -> MigrationSource {
+    MigrationSource::Repository {
+        migration_directory: migration_directory
+            .unwrap_or_else(|| usage_msg("--migration-directory must be specified")),
+        root_path: root_path.unwrap_or_else(|| usage_msg("--root-path must be specified")),
+        metadata_directory: metadata_directory
+            .unwrap_or_else(|| usage_msg("--metadata-directory must be specified")),
+    }
+}
@@ -23,6 +23,14 @@ impl fmt::Display for Direction {
 }
 
 impl Direction {
+    /// Returns the name of the direction, for humans.
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Direction::Forward => "forward",
+            Direction::Backward => "backward",
+        }
+    }
+
     /// Determines the migration direction, given the outgoing ("from') and incoming ("to")
     /// versions.
     pub(crate) fn from_versions(from: &Version, to: &Version) -> Option<Self> {
@@ -19,6 +19,9 @@ pub(crate) enum Error {
     #[snafu(display("Unable to open data store directory '{}': {}", path.display(), source))]
     DataStoreDirOpen { path: PathBuf, source: nix::Error },
 
+    #[snafu(display("Unable to sync data store directory '{}': {}", path.display(), source))]
+    DataStoreDirSync { path: PathBuf, source: nix::Error },
+
     #[snafu(display("Data store link '{}' points to /", path.display()))]
     DataStoreLinkToRoot { path: PathBuf },
 
@@ -42,9 +45,36 @@ pub(crate) enum Error {
         source: semver::Error,
     },
 
+    #[snafu(display("Failed to read migration journal '{}': {}", path.display(), source))]
+    JournalRead { path: PathBuf, source: io::Error },
+
+    #[snafu(display("Failed to serialize migration journal: {}", source))]
+    JournalSerialize { source: serde_json::Error },
+
+    #[snafu(display("Failed to write migration journal '{}': {}", path.display(), source))]
+    JournalWrite { path: PathBuf, source: io::Error },
+
     #[snafu(display("Data store for new version {} already exists at {}", version, path.display()))]
     NewVersionAlreadyExists { version: Version, path: PathBuf },
 
+    #[snafu(display("Failed to read declarative migration {}: {}", migration, source))]
+    ReadMigration {
+        migration: String,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Failed to parse declarative migration {}: {}", migration, source))]
+    ParseMigration {
+        migration: String,
+        source: migration_helpers::error::Error,
+    },
+
+    #[snafu(display("Declarative migration {} failed: {}", migration, source))]
+    DeclarativeMigration {
+        migration: String,
+        source: migration_helpers::error::Error,
+    },
+
     #[snafu(display("Unable to seal migration command: {}", source))]
     SealMigration { source: std::io::Error },
 
@@ -64,6 +94,9 @@ pub(crate) enum Error {
     #[snafu(display("Failed to swap symlink at {} to new version: {}", link.display(), source))]
     LinkSwap { link: PathBuf, source: io::Error },
 
+    #[snafu(display("Failed to remove symlink at {}: {}", link.display(), source))]
+    LinkRemove { link: PathBuf, source: io::Error },
+
     #[snafu(display("Failed to read symlink at {} to find version: {}", link.display(), source))]
     LinkRead { link: PathBuf, source: io::Error },
 
@@ -76,6 +109,9 @@ pub(crate) enum Error {
         source: tough::error::Error,
     },
 
+    #[snafu(display("Failed to open migration '{}': {}", path.display(), source))]
+    OpenMigration { path: PathBuf, source: io::Error },
+
     #[snafu(display("Failed to decode LZ4-compressed migration {}: {}", migration, source))]
     Lz4Decode {
         migration: String,
@@ -105,6 +141,21 @@ pub(crate) enum Error {
     #[snafu(display("Failed reading migration directory entry: {}", source))]
     ReadMigrationEntry { source: io::Error },
 
+    #[snafu(display("Failed to read data store '{}' for report: {}", path.display(), source))]
+    ReportDataStore {
+        path: PathBuf,
+        source: datastore::Error,
+    },
+
+    #[snafu(display("Failed to read migration report '{}': {}", path.display(), source))]
+    ReportRead { path: PathBuf, source: io::Error },
+
+    #[snafu(display("Failed to serialize migration report: {}", source))]
+    ReportSerialize { source: serde_json::Error },
+
+    #[snafu(display("Failed to write migration report '{}': {}", path.display(), source))]
+    ReportWrite { path: PathBuf, source: io::Error },
+
     #[snafu(display("Failed to load TUF repo: {}", source))]
     RepoLoad { source: tough::error::Error },
 
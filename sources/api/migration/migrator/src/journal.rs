@@ -0,0 +1,244 @@
+//! This module owns the journal the migrator keeps while it works, so that a run interrupted by
+//! losing power can be resumed or rolled back on the next boot.
+//!
+//! The journal lives in the data store directory and records the migrations the run intends to
+//! do, the data store each completed migration produced, and the previous target of each version
+//! link before it's flipped.  It's written atomically, and written *before* the step it describes
+//! takes effect for link flips, so that after a crash it always covers everything that may have
+//! happened.  Once the run finishes, the journal is removed.
+
+use crate::error::{self, Result};
+use nix::{dir::Dir, fcntl::OFlag, sys::stat::Mode, unistd::fsync};
+use semver::Version;
+use serde::{Deserialize, Serialize};
+use snafu::{OptionExt, ResultExt};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::os::unix::fs::symlink;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+/// The name of the journal file in the data store directory.
+const JOURNAL_NAME: &str = "migration-journal.json";
+
+/// The record of a single migrator run.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct Journal {
+    /// The data store directory the journal lives in.
+    #[serde(skip)]
+    dir: PathBuf,
+    pub(crate) from_version: Version,
+    pub(crate) to_version: Version,
+    /// The name of the data store the run started from.
+    source: String,
+    /// The migrations the run intends to run, in order.
+    pub(crate) migrations: Vec<String>,
+    /// The names of the data stores written by each completed migration, in order.
+    completed: Vec<String>,
+    /// The version links that may have been flipped, in order.
+    flipped: Vec<FlippedLink>,
+}
+
+/// A version link that may have been flipped, and what it pointed to beforehand.
+#[derive(Debug, Serialize, Deserialize)]
+struct FlippedLink {
+    link: String,
+    previous: Option<String>,
+}
+
+impl Journal {
+    /// Starts a journal for a run that migrates the given data store with the given migrations.
+    /// It isn't written until the first call to `save`.
+    pub(crate) fn new(
+        from_version: Version,
+        to_version: Version,
+        source_datastore: &Path,
+        migrations: Vec<String>,
+    ) -> Result<Self> {
+        let dir = source_datastore
+            .parent()
+            .context(error::DataStoreLinkToRoot {
+                path: source_datastore,
+            })?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            from_version,
+            to_version,
+            source: file_name(source_datastore)?,
+            migrations,
+            completed: Vec::new(),
+            flipped: Vec::new(),
+        })
+    }
+
+    /// Loads the journal left in the given data store directory by an interrupted run, if any.
+    pub(crate) fn load(datastore_dir: &Path) -> Result<Option<Self>> {
+        let path = datastore_dir.join(JOURNAL_NAME);
+        let data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).context(error::JournalRead { path }),
+        };
+        // The journal is replaced atomically, so this shouldn't happen; if it does, we can't
+        // undo anything, but the version links are still consistent, so start over.
+        let mut journal: Self = match serde_json::from_slice(&data) {
+            Ok(journal) => journal,
+            Err(e) => {
+                warn!("Ignoring unreadable journal at '{}': {}", path.display(), e);
+                return Ok(None);
+            }
+        };
+        journal.dir = datastore_dir.to_path_buf();
+        Ok(Some(journal))
+    }
+
+    /// Atomically writes the journal to disk.
+    pub(crate) fn save(&self) -> Result<()> {
+        let path = self.dir.join(JOURNAL_NAME);
+        let temp_path = self.dir.join(format!("{}.new", JOURNAL_NAME));
+        let data = serde_json::to_vec_pretty(self).context(error::JournalSerialize)?;
+
+        let mut file =
+            File::create(&temp_path).context(error::JournalWrite { path: &temp_path })?;
+        file.write_all(&data)
+            .context(error::JournalWrite { path: &temp_path })?;
+        file.sync_all()
+            .context(error::JournalWrite { path: &temp_path })?;
+        fs::rename(&temp_path, &path).context(error::JournalWrite { path: &path })?;
+        fsync_dir(&self.dir)?;
+
+        #[cfg(test)]
+        test::interruption_point(&path)?;
+        Ok(())
+    }
+
+    /// Removes the journal once the run it describes is finished or rolled back.
+    pub(crate) fn remove(&self) -> Result<()> {
+        let path = self.dir.join(JOURNAL_NAME);
+        match fs::remove_file(&path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).context(error::JournalWrite { path }),
+        }
+        fsync_dir(&self.dir)
+    }
+
+    /// Returns the data store the next migration should start from, or that the version links
+    /// should point to if all migrations are complete.
+    pub(crate) fn latest_datastore(&self) -> PathBuf {
+        self.dir.join(self.completed.last().unwrap_or(&self.source))
+    }
+
+    /// Returns the number of migrations that have completed.
+    pub(crate) fn completed_migrations(&self) -> usize {
+        self.completed.len()
+    }
+
+    /// Returns whether the given data store name is the source or the output of a completed
+    /// migration, which we need to keep while the run is unfinished.
+    pub(crate) fn uses_datastore(&self, name: &str) -> bool {
+        self.source == name || self.completed.iter().any(|completed| completed == name)
+    }
+
+    /// Records that a migration finished writing the given data store.
+    pub(crate) fn complete_migration(&mut self, datastore: &Path) -> Result<()> {
+        self.completed.push(file_name(datastore)?);
+        self.save()
+    }
+
+    /// Records the current target of the given version link, before it's flipped.
+    pub(crate) fn record_flip(&mut self, link: &Path) -> Result<()> {
+        let previous = match fs::read_link(link) {
+            Ok(target) => Some(file_name(&target)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e).context(error::LinkRead { link }),
+        };
+        self.flipped.push(FlippedLink {
+            link: file_name(link)?,
+            previous,
+        });
+        self.save()
+    }
+
+    /// Points each flipped version link back at what it pointed to before the run, or removes it
+    /// if it didn't exist, and then removes the journal.
+    pub(crate) fn roll_back(&self) -> Result<()> {
+        // Links may be recorded more than once if flips were retried; undoing them in reverse
+        // leaves each at its earliest recorded target.
+        for flipped in self.flipped.iter().rev() {
+            let link = self.dir.join(&flipped.link);
+            match &flipped.previous {
+                Some(previous) => {
+                    info!("Restoring {} to point to {}", link.display(), previous);
+                    let temp_link = self.dir.join(crate::rando());
+                    symlink(previous, &temp_link)
+                        .context(error::LinkCreate { path: &temp_link })?;
+                    fs::rename(&temp_link, &link).context(error::LinkSwap { link: &link })?;
+                }
+                None => {
+                    info!("Removing {}", link.display());
+                    match fs::remove_file(&link) {
+                        Ok(()) => {}
+                        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                        Err(e) => return Err(e).context(error::LinkRemove { link }),
+                    }
+                }
+            }
+        }
+        fsync_dir(&self.dir)?;
+        self.remove()
+    }
+}
+
+/// Returns the final component of the given path as a string.
+fn file_name(path: &Path) -> Result<String> {
+    let name = path
+        .file_name()
+        .context(error::DataStoreLinkToRoot { path })?;
+    Ok(name
+        .to_str()
+        .context(error::DataStorePathNotUTF8 { path })?
+        .to_string())
+}
+
+/// fsyncs the given directory so changes to its entries survive a crash.
+fn fsync_dir(dir: &Path) -> Result<()> {
+    let raw_dir = Dir::open(dir, OFlag::O_DIRECTORY, Mode::empty())
+        .context(error::DataStoreDirOpen { path: dir })?;
+    fsync(raw_dir.as_raw_fd()).context(error::DataStoreDirSync { path: dir })
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use crate::error::{self, Result};
+    use snafu::ResultExt;
+    use std::cell::Cell;
+    use std::io;
+    use std::path::Path;
+
+    thread_local! {
+        /// If set, the number of journal saves that succeed before the run is interrupted, as if
+        /// the host lost power right after the last one.
+        pub(crate) static SAVES_BEFORE_INTERRUPTION: Cell<Option<usize>> = Cell::new(None);
+    }
+
+    /// Fails if the run should be interrupted after this save.
+    pub(super) fn interruption_point(path: &Path) -> Result<()> {
+        SAVES_BEFORE_INTERRUPTION.with(|saves| match saves.get() {
+            Some(n) if n <= 1 => {
+                saves.set(None);
+                Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "simulated interruption",
+                ))
+                .context(error::JournalWrite { path })
+            }
+            Some(n) => {
+                saves.set(Some(n - 1));
+                Ok(())
+            }
+            None => Ok(()),
+        })
+    }
+}
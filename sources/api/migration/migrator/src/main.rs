@@ -11,10 +11,41 @@
 //! * find migrations between the two versions
 //! * if there are migrations:
 //!   * run the migrations; the transformed data becomes the new data store
+//!   * migration binaries are run from memory, and declarative migrations (`.toml`) are
+//!     interpreted directly
 //! * if there are *no* migrations:
 //!   * just symlink to the old data store
 //! * do symlink flips so the new version takes the place of the original
 //!
+//! Progress is kept in a journal in the data store directory.  If a run is interrupted, for
+//! example by losing power, the next run finishes it if the symlink flips made the new version
+//! current, resumes it if it's still migrating to the same version, and otherwise rolls back any
+//! symlink flips.  Data stores that no version link points to are then removed.
+//!
+//! Each run that migrates is reported in `migration-report.json` in the data store directory,
+//! which keeps the last few runs.  A run's report gives its versions and direction, and for each
+//! migration, its duration, exit status, output, and the number of keys it added, removed, and
+//! changed.  The API serves the reports at `/os/migrations`, and logdog collects them.
+//!
+//! `--dry-run` prints the migrations that would run, according to the manifest, without touching
+//! the data store.
+//!
+//! To test a new migration end-to-end on a workstation, build the migrator with the
+//! `local-migrations` feature, which is never enabled for images.  It can then load the manifest
+//! and migrations from a plain local directory, where migrations don't need to be signed or
+//! compressed, instead of from a TUF repository:
+//!
+//! ```text
+//! cargo run -p migrator --features local-migrations -- \
+//!     --datastore-path /path/to/datastore/current \
+//!     --local-migration-directory /path/to/migrations \
+//!     --manifest /path/to/manifest.json \
+//!     --migrate-to-version 1.4.0
+//! ```
+//!
+//! If the manifest names a migration `foo.lz4` and there's no such file, an uncompressed `foo` is
+//! used instead.
+//!
 //! To understand motivation and more about the overall process, look at the migration system
 //! documentation, one level up.
 
@@ -26,25 +57,38 @@ extern crate log;
 use args::Args;
 use direction::Direction;
 use error::Result;
-use nix::{dir::Dir, fcntl::OFlag, sys::stat::Mode, unistd::fsync};
+use journal::Journal;
+use migration_helpers::declarative::DeclarativeMigration;
+use migration_helpers::{Args as MigrationArgs, MigrationType};
+use nix::{
+    dir::Dir,
+    fcntl::OFlag,
+    sys::stat::Mode,
+    unistd::{fsync, sync},
+};
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use report::{MigrationReport, RunReport};
 use semver::Version;
 use simplelog::{Config as LogConfig, SimpleLogger};
 use snafu::{ensure, OptionExt, ResultExt};
+use source::Source;
 use std::collections::HashSet;
 use std::env;
-use std::fs::{self, File};
+use std::fs;
+use std::io::Read;
 use std::os::unix::fs::symlink;
 use std::os::unix::io::AsRawFd;
 use std::path::{Path, PathBuf};
-use std::process;
-use tough::{ExpirationEnforcement, FilesystemTransport, RepositoryLoader};
+use std::process::{self, Output};
+use std::time::Instant;
 use update_metadata::Manifest;
-use url::Url;
 
 mod args;
 mod direction;
 mod error;
+mod journal;
+mod report;
+mod source;
 #[cfg(test)]
 mod test;
 
@@ -103,82 +147,265 @@ pub(crate) fn run(args: &Args) -> Result<()> {
         })?;
 
     let current_version = get_current_version(&datastore_dir)?;
-    let direction = Direction::from_versions(&current_version, &args.migrate_to_version)
-        .unwrap_or_else(|| {
+
+    // A dry run only describes what would happen, so it mustn't touch the data store.
+    if args.dry_run {
+        let source = Source::load(&args.source)?;
+        print!(
+            "{}",
+            plan(&source, &current_version, &args.migrate_to_version)?
+        );
+        return Ok(());
+    }
+
+    // If a previous run was interrupted, finish it, roll it back, or pick it up again below.
+    let journal =
+        recover_interrupted_run(datastore_dir, &current_version, &args.migrate_to_version)?;
+    collect_garbage(datastore_dir, journal.as_ref());
+
+    let direction = match Direction::from_versions(&current_version, &args.migrate_to_version) {
+        Some(direction) => direction,
+        None => {
             info!(
                 "Requested version {} matches version of given datastore at '{}'; nothing to do",
                 args.migrate_to_version,
                 args.datastore_path.display()
             );
-            process::exit(0);
-        });
-
-    // create URLs from the metadata and targets directory paths
-    let metadata_base_url = Url::from_directory_path(&args.metadata_directory).map_err(|_| {
-        error::Error::DirectoryUrl {
-            path: args.metadata_directory.clone(),
+            return Ok(());
         }
-    })?;
-    let targets_base_url =
-        url::Url::from_directory_path(&args.migration_directory).map_err(|_| {
-            error::Error::DirectoryUrl {
-                path: args.migration_directory.clone(),
-            }
-        })?;
-
-    // open a reader to the root.json file
-    let root_file = File::open(&args.root_path).with_context(|| error::OpenRoot {
-        path: args.root_path.clone(),
-    })?;
+    };
 
-    // We will load the locally cached TUF repository to obtain the manifest. The Repository is
-    // loaded using a `TempDir` for its internal Datastore (this is the default). Part of using a
-    // `TempDir` is disabling timestamp checking, because we want an instance to still come up and
-    // run migrations regardless of the how the system time relates to what we have cached (for
-    // example if someone runs an update, then shuts down the instance for several weeks, beyond the
-    // expiration of at least the cached timestamp.json before booting it back up again). We also
-    // use a `TempDir` because see no value in keeping a datastore around. The latest  known
-    // versions of the repository metadata will always be the versions of repository metadata we
-    // have cached on the disk. More info at `ExpirationEnforcement::Unsafe` below.
-
-    // Failure to load the TUF repo at the expected location is a serious issue because updog should
-    // always create a TUF repo that contains at least the manifest, even if there are no migrations.
-    let repo = RepositoryLoader::new(root_file, metadata_base_url, targets_base_url)
-        .transport(FilesystemTransport)
-        // The threats TUF mitigates are more than the threats we are attempting to mitigate
-        // here by caching signatures for migrations locally and using them after a reboot but
-        // prior to Internet connectivity. We are caching the TUF repo and use it while offline
-        // after a reboot to mitigate binaries being added or modified in the migrations
-        // directory; the TUF repo is simply a code signing method we already have in place,
-        // even if it's not one that initially makes sense for this use case. So, we don't care
-        // if the targets expired between updog downloading them and now.
-        .expiration_enforcement(ExpirationEnforcement::Unsafe)
-        .load()
-        .context(error::RepoLoad)?;
-    let manifest = load_manifest(&repo)?;
+    let source = Source::load(&args.source)?;
+    let manifest = source.manifest()?;
     let migrations =
         update_metadata::find_migrations(&current_version, &args.migrate_to_version, &manifest)
             .context(error::FindMigrations)?;
 
-    if migrations.is_empty() {
-        // Not all new OS versions need to change the data store format.  If there's been no
-        // change, we can just link to the last version rather than making a copy.
-        // (Note: we link to the fully resolved directory, args.datastore_path,  so we don't
-        // have a chain of symlinks that could go past the maximum depth.)
-        flip_to_new_version(&args.migrate_to_version, &args.datastore_path)?;
-    } else {
-        let copy_path = run_migrations(
-            &repo,
-            direction,
-            &migrations,
-            &args.datastore_path,
-            &args.migrate_to_version,
-        )?;
-        flip_to_new_version(&args.migrate_to_version, &copy_path)?;
+    let resumed = matches!(&journal, Some(journal) if journal.migrations == migrations);
+    let mut journal = match journal {
+        Some(journal) if resumed => {
+            info!(
+                "Resuming interrupted migration from {} to {}",
+                journal.from_version, journal.to_version
+            );
+            journal
+        }
+        journal => {
+            if let Some(journal) = journal {
+                info!("Migrations to run have changed; rolling back interrupted migration");
+                journal.roll_back()?;
+            }
+            let journal = Journal::new(
+                current_version.clone(),
+                args.migrate_to_version.clone(),
+                &args.datastore_path,
+                migrations.clone(),
+            )?;
+            journal.save()?;
+            journal
+        }
+    };
+
+    // Keep a report of what happened, even if a migration failed; it's only for observability, so
+    // failing to write it doesn't fail the run.
+    let mut report = RunReport::new(
+        &current_version,
+        &args.migrate_to_version,
+        direction,
+        resumed,
+    );
+    let result = migrate(
+        &source,
+        &manifest,
+        &migrations,
+        direction,
+        &mut journal,
+        &mut report,
+    );
+    report.finish(&result);
+    if let Err(e) = report.save(datastore_dir) {
+        warn!("Unable to save migration report: {}", e);
     }
+    result?;
+
+    collect_garbage(datastore_dir, None);
     Ok(())
 }
 
+/// Runs the given migrations, picking up after any the journal records as complete, and flips the
+/// version links to the new data store.
+fn migrate(
+    source: &Source,
+    manifest: &Manifest,
+    migrations: &[String],
+    direction: Direction,
+    journal: &mut Journal,
+    report: &mut RunReport,
+) -> Result<()> {
+    let new_version = journal.to_version.clone();
+    if !migrations.is_empty() {
+        let validate = validating_migration(direction, migrations, manifest, &new_version);
+        run_migrations(source, direction, journal, validate, report)?;
+    }
+    // Not all new OS versions need to change the data store format.  If there's been no
+    // change, this is the old data store, and we just link to it rather than making a copy.
+    // (Note: we link to the fully resolved directory so we don't have a chain of symlinks that
+    // could go past the maximum depth.)
+    let new_datastore = journal.latest_datastore();
+    flip_to_new_version(&new_version, &new_datastore, journal)?;
+    journal.remove()
+}
+
+/// Describes the migrations that would run to move between the given versions, according to the
+/// manifest in the given source.
+fn plan(source: &Source, from_version: &Version, to_version: &Version) -> Result<String> {
+    let direction = match Direction::from_versions(from_version, to_version) {
+        Some(direction) => direction,
+        None => {
+            return Ok(format!(
+                "Already at version {}; nothing to do\n",
+                to_version
+            ))
+        }
+    };
+    let manifest = source.manifest()?;
+    let migrations = update_metadata::find_migrations(from_version, to_version, &manifest)
+        .context(error::FindMigrations)?;
+    let validate = validating_migration(direction, &migrations, &manifest, to_version);
+
+    let mut plan = format!(
+        "Migrating {} from {} to {}:\n",
+        direction.name(),
+        from_version,
+        to_version
+    );
+    if migrations.is_empty() {
+        plan.push_str("  no migrations; the new version would use the existing data store\n");
+    }
+    for (i, migration) in migrations.iter().enumerate() {
+        let kind = if is_declarative(migration) {
+            "declarative"
+        } else {
+            "binary"
+        };
+        let validates = if validate == Some(migration.as_str()) {
+            ", validates migrated data"
+        } else {
+            ""
+        };
+        plan.push_str(&format!(
+            "  {}. {} ({}{})\n",
+            i + 1,
+            migration,
+            kind,
+            validates
+        ));
+    }
+    Ok(plan)
+}
+
+/// Deals with the journal left by an interrupted run, if any.
+///
+/// If the interrupted run got far enough through its link flips that its new version is current,
+/// the flips are finished.  If it was migrating from the current version to the requested version,
+/// its journal is returned so it can be resumed.  Otherwise, it's rolled back.
+fn recover_interrupted_run(
+    datastore_dir: &Path,
+    current_version: &Version,
+    requested_version: &Version,
+) -> Result<Option<Journal>> {
+    let mut journal = match Journal::load(datastore_dir)? {
+        Some(journal) => journal,
+        None => return Ok(None),
+    };
+
+    if &journal.to_version == current_version {
+        info!(
+            "Finishing interrupted migration from {} to {}",
+            journal.from_version, journal.to_version
+        );
+        let to_version = journal.to_version.clone();
+        let new_datastore = journal.latest_datastore();
+        flip_to_new_version(&to_version, &new_datastore, &mut journal)?;
+        journal.remove()?;
+        return Ok(None);
+    }
+
+    if &journal.from_version == current_version && &journal.to_version == requested_version {
+        return Ok(Some(journal));
+    }
+
+    info!(
+        "Rolling back interrupted migration from {} to {}",
+        journal.from_version, journal.to_version
+    );
+    journal.roll_back()?;
+    Ok(None)
+}
+
+/// Removes data stores that no version link points to, and that aren't in use by the given
+/// journal; these are left behind by failed or interrupted runs, or by migrating to the same
+/// version more than once.
+///
+/// Failing to remove one doesn't stop the migration; we just let someone know for later cleanup.
+fn collect_garbage(datastore_dir: &Path, journal: Option<&Journal>) {
+    let entries = match fs::read_dir(datastore_dir) {
+        Ok(entries) => entries.filter_map(|entry| entry.ok()).collect::<Vec<_>>(),
+        Err(e) => {
+            error!(
+                "Failed to list data store directory '{}' for cleanup: {}",
+                datastore_dir.display(),
+                e
+            );
+            return;
+        }
+    };
+
+    // Find everything the version links point to.
+    let mut linked = HashSet::new();
+    for entry in &entries {
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        let is_version_link = name == "current"
+            || name
+                .strip_prefix('v')
+                .map(|version| version.chars().all(|c| c.is_ascii_digit() || c == '.'))
+                .unwrap_or(false);
+        if !is_version_link {
+            continue;
+        }
+        if let Ok(target) = fs::read_link(entry.path()) {
+            linked.insert(target.to_string_lossy().into_owned());
+        }
+    }
+
+    for entry in entries {
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        let is_datastore = entry.file_type().map(|t| t.is_dir()).unwrap_or(false)
+            && name
+                .strip_prefix('v')
+                .and_then(|name| name.rsplit_once('_'))
+                .map(|(version, _)| Version::parse(version).is_ok())
+                .unwrap_or(false);
+        if !is_datastore
+            || linked.contains(name.as_ref())
+            || journal.map(|j| j.uses_datastore(&name)).unwrap_or(false)
+        {
+            continue;
+        }
+
+        info!("Removing unused data store at {}", entry.path().display());
+        if let Err(e) = fs::remove_dir_all(entry.path()) {
+            error!(
+                "Failed to remove unused data store at '{}': {}",
+                entry.path().display(),
+                e
+            );
+        }
+    }
+}
+
 // =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
 
 /// Generates a random ID, affectionately known as a 'rando', that can be used to avoid timing
@@ -215,107 +442,207 @@ where
     Ok(to)
 }
 
-/// Runs the given migrations in their given order.  The given direction is passed to each
-/// migration so it knows which direction we're migrating.
-///
-/// The given data store is used as a starting point; each migration is given the output of the
-/// previous migration, and the final output becomes the new data store.
-fn run_migrations<P, S>(
-    repository: &tough::Repository,
+/// Returns the migration that should check the migrated data against the model of the new version
+/// before it's saved, if any.  That's the last migration when moving forward, as long as it was
+/// built for the new version; when moving backward we don't have the old version's model, and
+/// earlier migrations produce data for intermediate versions.
+fn validating_migration<'a, S>(
     direction: Direction,
-    migrations: &[S],
-    source_datastore: P,
+    migrations: &'a [S],
+    manifest: &Manifest,
     new_version: &Version,
-) -> Result<PathBuf>
+) -> Option<&'a str>
 where
-    P: AsRef<Path>,
     S: AsRef<str>,
 {
-    // We start with the given source_datastore, updating this after each migration to point to the
-    // output of the previous one.
-    let mut source_datastore = source_datastore.as_ref();
-    // We create a new data store (below) to serve as the target of each migration.  (Start at
-    // source just to have the right type; we know we have migrations at this point.)
-    let mut target_datastore = source_datastore.to_owned();
-    // Any data stores we create that aren't the final one, i.e. intermediate data stores, will be
-    // removed at the end.  (If we fail and return early, they're left for debugging purposes.)
-    let mut intermediate_datastores = HashSet::new();
-
-    for migration in migrations {
-        let migration = migration.as_ref();
-        // get the migration from the repo
-        let lz4_bytes = repository
-            .read_target(migration)
-            .context(error::LoadMigration { migration })?
-            .context(error::MigrationNotFound { migration })?;
-
-        // Add an LZ4 decoder so the bytes will be deflated on read
-        let mut reader = lz4::Decoder::new(lz4_bytes).context(error::Lz4Decode { migration })?;
-
-        // Create a sealed command with pentacle, so we can run the verified bytes from memory
-        let mut command =
-            pentacle::SealedCommand::new(&mut reader).context(error::SealMigration)?;
-
-        // Point each migration in the right direction, and at the given data store.
-        command.arg(direction.to_string());
-        command.args(&[
-            "--source-datastore".to_string(),
-            source_datastore.display().to_string(),
-        ]);
-
-        // Create a new output location for this migration.
-        target_datastore = new_datastore_location(&source_datastore, &new_version)?;
-        intermediate_datastores.insert(target_datastore.clone());
-
-        command.args(&[
-            "--target-datastore".to_string(),
-            target_datastore.display().to_string(),
-        ]);
-
-        info!("Running migration command: {:?}", command);
+    if direction != Direction::Forward {
+        return None;
+    }
+    let last = migrations.last()?.as_ref();
+    let built_for_new_version = manifest
+        .migrations
+        .iter()
+        .any(|((_, to), names)| to == new_version && names.iter().any(|name| name == last));
+    if built_for_new_version {
+        Some(last)
+    } else {
+        None
+    }
+}
 
-        let output = command.output().context(error::StartMigration)?;
+/// Runs the journal's migrations in their given order, skipping any it records as complete.
+/// The given direction is passed to each migration so it knows which direction we're migrating.
+///
+/// The journal's source data store is used as a starting point; each migration is given the output
+/// of the previous migration, and the final output becomes the new data store.  Each output is
+/// synced to disk and recorded in the journal before moving on.
+///
+/// If `validate` names one of the migrations, it's asked to check the migrated data against the
+/// model of the new version.
+///
+/// The outcome of each migration is added to the given report.
+fn run_migrations(
+    source: &Source,
+    direction: Direction,
+    journal: &mut Journal,
+    validate: Option<&str>,
+    report: &mut RunReport,
+) -> Result<PathBuf> {
+    let new_version = journal.to_version.clone();
+    let migrations = journal.migrations.clone();
+    // We start with the latest data store in the journal, updating this after each migration to
+    // point to the output of the previous one.
+    let mut source_datastore = journal.latest_datastore();
+
+    for migration in migrations.iter().skip(journal.completed_migrations()) {
+        let migration = migration.as_str();
+        // get the migration from its source
+        let reader = source.read_migration(migration)?;
 
-        if !output.stdout.is_empty() {
-            debug!(
-                "Migration stdout: {}",
-                String::from_utf8_lossy(&output.stdout)
-            );
+        // Create a new output location for this migration.
+        let target_datastore = new_datastore_location(&source_datastore, &new_version)?;
+
+        let validate = validate == Some(migration);
+        let started = Instant::now();
+        let result = if is_declarative(migration) {
+            run_declarative_migration(
+                reader,
+                migration,
+                direction,
+                &source_datastore,
+                &target_datastore,
+                validate,
+            )
+            .map(|()| None)
         } else {
-            debug!("No migration stdout");
+            run_binary_migration(
+                reader,
+                direction,
+                &source_datastore,
+                &target_datastore,
+                validate,
+            )
+            .map(Some)
+        };
+
+        let mut migration_report = MigrationReport::new(migration, started.elapsed());
+        match &result {
+            Ok(Some(output)) => migration_report.output(output),
+            Ok(None) => migration_report.success = true,
+            Err(e) => migration_report.error = Some(e.to_string()),
         }
-        if !output.stderr.is_empty() {
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            // We want to see migration stderr on the console, so log at error level.
-            error!("Migration stderr: {}", stderr);
-        } else {
-            debug!("No migration stderr");
+        if migration_report.success {
+            match report::count_changes(&source_datastore, &target_datastore) {
+                Ok(keys) => migration_report.keys = Some(keys),
+                Err(e) => warn!(
+                    "Unable to count keys changed by migration {}: {}",
+                    migration, e
+                ),
+            }
         }
+        report.migrations.push(migration_report);
+        if let Some(output) = result? {
+            ensure!(output.status.success(), error::MigrationFailure { output });
+        }
+
+        // Make sure the migrated data is on disk before the journal says it's there.
+        sync();
+        journal.complete_migration(&target_datastore)?;
+        source_datastore = target_datastore;
+    }
+
+    Ok(source_datastore)
+}
 
-        ensure!(output.status.success(), error::MigrationFailure { output });
-        source_datastore = &target_datastore;
+/// Returns whether the named migration is declared in TOML rather than built as a binary.
+fn is_declarative(migration: &str) -> bool {
+    let name = migration.strip_suffix(".lz4").unwrap_or(migration);
+    name.ends_with(".toml")
+}
+
+/// Runs a migration binary from the given reader, pointing it in the given direction and at the
+/// given data stores, and returns its output.  The caller decides what to do if it failed.
+fn run_binary_migration<R: Read>(
+    mut reader: R,
+    direction: Direction,
+    source_datastore: &Path,
+    target_datastore: &Path,
+    validate: bool,
+) -> Result<Output> {
+    // Create a sealed command with pentacle, so we can run the verified bytes from memory
+    let mut command = pentacle::SealedCommand::new(&mut reader).context(error::SealMigration)?;
+
+    // Point each migration in the right direction, and at the given data store.
+    command.arg(direction.to_string());
+    command.args(&[
+        "--source-datastore".to_string(),
+        source_datastore.display().to_string(),
+    ]);
+    command.args(&[
+        "--target-datastore".to_string(),
+        target_datastore.display().to_string(),
+    ]);
+
+    if validate {
+        command.arg("--validate");
     }
 
-    // Remove the intermediate data stores
-    intermediate_datastores.remove(&target_datastore);
-    for intermediate_datastore in intermediate_datastores {
-        // Even if we fail to remove an intermediate data store, we've still migrated
-        // successfully, and we don't want to fail the upgrade - just let someone know for
-        // later cleanup.
-        trace!(
-            "Removing intermediate data store at {}",
-            intermediate_datastore.display()
+    info!("Running migration command: {:?}", command);
+
+    let output = command.output().context(error::StartMigration)?;
+
+    if !output.stdout.is_empty() {
+        debug!(
+            "Migration stdout: {}",
+            String::from_utf8_lossy(&output.stdout)
         );
-        if let Err(e) = fs::remove_dir_all(&intermediate_datastore) {
-            error!(
-                "Failed to remove intermediate data store at '{}': {}",
-                intermediate_datastore.display(),
-                e
-            );
-        }
+    } else {
+        debug!("No migration stdout");
     }
+    if !output.stderr.is_empty() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        // We want to see migration stderr on the console, so log at error level.
+        error!("Migration stderr: {}", stderr);
+    } else {
+        debug!("No migration stderr");
+    }
+
+    Ok(output)
+}
 
-    Ok(target_datastore)
+/// Runs a declarative migration from the given reader in-process, using the same helpers that
+/// migration binaries use.
+fn run_declarative_migration<R: Read>(
+    mut reader: R,
+    migration: &str,
+    direction: Direction,
+    source_datastore: &Path,
+    target_datastore: &Path,
+    validate: bool,
+) -> Result<()> {
+    let mut toml_str = String::new();
+    reader
+        .read_to_string(&mut toml_str)
+        .context(error::ReadMigration { migration })?;
+    let declarative: DeclarativeMigration = toml_str
+        .parse()
+        .context(error::ParseMigration { migration })?;
+
+    let args = MigrationArgs {
+        source_datastore: source_datastore.display().to_string(),
+        target_datastore: target_datastore.display().to_string(),
+        migration_type: match direction {
+            Direction::Forward => MigrationType::Forward,
+            Direction::Backward => MigrationType::Backward,
+        },
+        validate,
+    };
+    info!(
+        "Running declarative migration {} ({:?})",
+        migration, direction
+    );
+    migration_helpers::run_migration(declarative, &args)
+        .context(error::DeclarativeMigration { migration })
 }
 
 /// Atomically flips version symlinks to point to the given "to" datastore so that it becomes live.
@@ -326,7 +653,10 @@ where
 /// * pointing the major version to the minor version
 /// * pointing the 'current' link to the major version
 /// * fsyncing the directory to disk
-fn flip_to_new_version<P>(version: &Version, to_datastore: P) -> Result<()>
+///
+/// Each link's current target is recorded in the journal before it's flipped, so an interrupted
+/// flip can be finished or undone.  Flipping again to the same data store is harmless.
+fn flip_to_new_version<P>(version: &Version, to_datastore: P, journal: &mut Journal) -> Result<()>
 where
     P: AsRef<Path>,
 {
@@ -408,6 +738,7 @@ where
     symlink(&to_target, &temp_link).context(error::LinkCreate { path: &temp_link })?;
     // Atomically swap the link into place, so that the patch version link points to the new data
     // store copy.
+    journal.record_flip(&patch_version_link)?;
     fs::rename(&temp_link, &patch_version_link).context(error::LinkSwap {
         link: &patch_version_link,
     })?;
@@ -425,6 +756,7 @@ where
     symlink(&patch_target, &temp_link).context(error::LinkCreate { path: &temp_link })?;
     // Atomically swap the link into place, so that the minor version link points to the new patch
     // version.
+    journal.record_flip(&minor_version_link)?;
     fs::rename(&temp_link, &minor_version_link).context(error::LinkSwap {
         link: &minor_version_link,
     })?;
@@ -442,6 +774,7 @@ where
     symlink(&minor_target, &temp_link).context(error::LinkCreate { path: &temp_link })?;
     // Atomically swap the link into place, so that the major version link points to the new minor
     // version.
+    journal.record_flip(&major_version_link)?;
     fs::rename(&temp_link, &major_version_link).context(error::LinkSwap {
         link: &major_version_link,
     })?;
@@ -458,6 +791,7 @@ where
     // This will point at, for example, /path/to/datastore/v1
     symlink(&major_target, &temp_link).context(error::LinkCreate { path: &temp_link })?;
     // Atomically swap the link into place, so that 'current' points to the new major version.
+    journal.record_flip(&current_version_link)?;
     fs::rename(&temp_link, &current_version_link).context(error::LinkSwap {
         link: &current_version_link,
     })?;
@@ -477,14 +811,3 @@ where
 
     Ok(())
 }
-
-fn load_manifest(repository: &tough::Repository) -> Result<Manifest> {
-    let target = "manifest.json";
-    Manifest::from_json(
-        repository
-            .read_target(target)
-            .context(error::ManifestLoad)?
-            .context(error::ManifestNotFound)?,
-    )
-    .context(error::ManifestParse)
-}
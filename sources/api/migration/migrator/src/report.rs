@@ -0,0 +1,253 @@
+//! This module owns the report the migrator writes about each run, so that when an update goes
+//! wrong we can tell which migration did what.
+//!
+//! Reports of recent runs are kept, oldest first, in a JSON file in the data store directory.
+//! Each covers the versions and direction of the run, and for each migration, its duration, exit
+//! status, output, and the number of keys it added, removed, and changed.
+
+use crate::direction::Direction;
+use crate::error::{self, Result};
+use datastore::{Committed, DataStore, FilesystemDataStore};
+use semver::Version;
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process::Output;
+use std::time::Duration;
+
+/// The name of the report file in the data store directory.
+pub(crate) const REPORT_NAME: &str = "migration-report.json";
+
+/// The number of runs to keep in the report file.
+const MAX_RUNS: usize = 10;
+
+/// The report of a single migrator run.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct RunReport {
+    pub(crate) started: String,
+    pub(crate) from_version: Version,
+    pub(crate) to_version: Version,
+    pub(crate) direction: String,
+    /// Whether this run picked up where an interrupted run left off; migrations that run
+    /// completed are in the interrupted run's report, if it got to write one.
+    pub(crate) resumed: bool,
+    pub(crate) migrations: Vec<MigrationReport>,
+    pub(crate) success: bool,
+    pub(crate) error: Option<String>,
+}
+
+/// The report of a single migration.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct MigrationReport {
+    pub(crate) name: String,
+    pub(crate) duration_ms: u64,
+    /// The exit code of a migration binary.  Declarative migrations run in the migrator, and
+    /// binaries killed by a signal have no exit code.
+    pub(crate) exit_status: Option<i32>,
+    pub(crate) success: bool,
+    pub(crate) stdout: String,
+    pub(crate) stderr: String,
+    pub(crate) error: Option<String>,
+    /// The keys the migration changed, if it succeeded.
+    pub(crate) keys: Option<KeyChanges>,
+}
+
+/// Counts of the keys a migration changed, across live data, pending transactions, and metadata.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub(crate) struct KeyChanges {
+    pub(crate) added: usize,
+    pub(crate) removed: usize,
+    pub(crate) changed: usize,
+}
+
+impl RunReport {
+    pub(crate) fn new(
+        from_version: &Version,
+        to_version: &Version,
+        direction: Direction,
+        resumed: bool,
+    ) -> Self {
+        Self {
+            started: chrono::Utc::now().to_rfc3339(),
+            from_version: from_version.clone(),
+            to_version: to_version.clone(),
+            direction: direction.name().to_string(),
+            resumed,
+            migrations: Vec::new(),
+            success: false,
+            error: None,
+        }
+    }
+
+    /// Records the outcome of the run.
+    pub(crate) fn finish<T>(&mut self, result: &Result<T>) {
+        self.success = result.is_ok();
+        self.error = result.as_ref().err().map(|e| e.to_string());
+    }
+
+    /// Adds this report to the report file in the given data store directory, dropping the oldest
+    /// runs if there are too many.
+    pub(crate) fn save(self, datastore_dir: &Path) -> Result<()> {
+        let path = datastore_dir.join(REPORT_NAME);
+        let mut runs = match fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+                warn!("Replacing unreadable report at '{}': {}", path.display(), e);
+                Vec::new()
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e).context(error::ReportRead { path }),
+        };
+        runs.push(self);
+        if runs.len() > MAX_RUNS {
+            runs.drain(..runs.len() - MAX_RUNS);
+        }
+
+        let data = serde_json::to_vec_pretty(&runs).context(error::ReportSerialize)?;
+        let temp_path = datastore_dir.join(format!("{}.new", REPORT_NAME));
+        fs::write(&temp_path, data).context(error::ReportWrite { path: &temp_path })?;
+        fs::rename(&temp_path, &path).context(error::ReportWrite { path })
+    }
+}
+
+impl MigrationReport {
+    /// Starts the report of the named migration, which took the given time.
+    pub(crate) fn new(name: &str, duration: Duration) -> Self {
+        Self {
+            name: name.to_string(),
+            duration_ms: duration.as_millis() as u64,
+            exit_status: None,
+            success: false,
+            stdout: String::new(),
+            stderr: String::new(),
+            error: None,
+            keys: None,
+        }
+    }
+
+    /// Records the output of a migration binary.
+    pub(crate) fn output(&mut self, output: &Output) {
+        self.exit_status = output.status.code();
+        self.success = output.status.success();
+        self.stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+        self.stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+    }
+}
+
+/// Counts the keys that differ between the source and target data stores of a migration.
+///
+/// The "os" values come from the release rather than the source data store, so they're left out.
+pub(crate) fn count_changes(source: &Path, target: &Path) -> Result<KeyChanges> {
+    let source = load(source)?;
+    let target = load(target)?;
+
+    let mut changes = KeyChanges::default();
+    for (key, old) in &source {
+        match target.get(key) {
+            None => changes.removed += 1,
+            Some(new) if new != old => changes.changed += 1,
+            Some(_) => {}
+        }
+    }
+    changes.added = target.keys().filter(|k| !source.contains_key(*k)).count();
+    Ok(changes)
+}
+
+/// Loads all data and metadata from the data store at the given path, keyed by where it lives.
+fn load(path: &Path) -> Result<HashMap<String, String>> {
+    let datastore = FilesystemDataStore::new(path);
+    let mut values = HashMap::new();
+
+    let mut committeds = vec![Committed::Live];
+    let transactions = datastore
+        .list_transactions()
+        .context(error::ReportDataStore { path })?;
+    committeds.extend(transactions.into_iter().map(|tx| Committed::Pending { tx }));
+    for committed in committeds {
+        let location = match &committed {
+            Committed::Live => "live".to_string(),
+            Committed::Pending { tx } => format!("pending/{}", tx),
+        };
+        let data = datastore
+            .get_prefix("", &committed)
+            .context(error::ReportDataStore { path })?;
+        for (key, value) in data {
+            if key.name().starts_with("os.") {
+                continue;
+            }
+            values.insert(format!("{}/{}", location, key.name()), value);
+        }
+    }
+
+    let metadata = datastore
+        .get_metadata_prefix("", &None as &Option<&str>)
+        .context(error::ReportDataStore { path })?;
+    for (data_key, meta_map) in metadata {
+        for (metadata_key, value) in meta_map {
+            values.insert(
+                format!("metadata/{}/{}", data_key.name(), metadata_key.name()),
+                value,
+            );
+        }
+    }
+
+    Ok(values)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{count_changes, KeyChanges};
+    use datastore::{Committed, DataStore, FilesystemDataStore, Key, KeyType};
+    use tempfile::TempDir;
+
+    #[test]
+    fn counts_changes() {
+        let tmp = TempDir::new().unwrap();
+        let source_path = tmp.path().join("source");
+        let target_path = tmp.path().join("target");
+        let data = |name| Key::new(KeyType::Data, name).unwrap();
+        let meta = |name| Key::new(KeyType::Meta, name).unwrap();
+        let pending = Committed::Pending { tx: "tx".into() };
+
+        let mut source = FilesystemDataStore::new(&source_path);
+        source
+            .set_key(&data("settings.a"), "\"a\"", &Committed::Live)
+            .unwrap();
+        source
+            .set_key(&data("settings.b"), "\"b\"", &Committed::Live)
+            .unwrap();
+        source
+            .set_key(&data("os.version"), "\"1.0.0\"", &Committed::Live)
+            .unwrap();
+        source
+            .set_metadata(&meta("template"), &data("settings.a"), "\"{{x}}\"")
+            .unwrap();
+
+        let mut target = FilesystemDataStore::new(&target_path);
+        target
+            .set_key(&data("settings.a"), "\"a2\"", &Committed::Live)
+            .unwrap();
+        target
+            .set_key(&data("settings.b"), "\"b\"", &pending)
+            .unwrap();
+        target
+            .set_key(&data("os.version"), "\"1.1.0\"", &Committed::Live)
+            .unwrap();
+        target
+            .set_metadata(&meta("template"), &data("settings.a"), "\"{{x}}\"")
+            .unwrap();
+
+        assert_eq!(
+            count_changes(&source_path, &target_path).unwrap(),
+            KeyChanges {
+                added: 1,
+                removed: 1,
+                changed: 1,
+            }
+        );
+    }
+}
@@ -0,0 +1,163 @@
+//! This module loads the manifest and migrations the migrator needs.
+//!
+//! Normally they come from the TUF repository that updog caches, which holds signed,
+//! LZ4-compressed migrations.  Development builds (with the "local-migrations" feature) can
+//! instead load them from a plain local directory, so a new migration can be tested end-to-end on
+//! a workstation without building and signing a repository.
+
+use crate::args::MigrationSource;
+use crate::error::{self, Result};
+use snafu::{OptionExt, ResultExt};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+#[cfg(feature = "local-migrations")]
+use std::path::PathBuf;
+use tough::{ExpirationEnforcement, FilesystemTransport, RepositoryLoader};
+use update_metadata::Manifest;
+use url::Url;
+
+/// A loaded source of migrations.
+pub(crate) enum Source {
+    Repository(tough::Repository),
+    #[cfg(feature = "local-migrations")]
+    LocalDirectory {
+        migration_directory: PathBuf,
+        manifest_path: PathBuf,
+    },
+}
+
+impl Source {
+    /// Loads the given source of migrations.
+    pub(crate) fn load(source: &MigrationSource) -> Result<Self> {
+        match source {
+            MigrationSource::Repository {
+                migration_directory,
+                root_path,
+                metadata_directory,
+            } => load_repository(migration_directory, root_path, metadata_directory),
+            #[cfg(feature = "local-migrations")]
+            MigrationSource::LocalDirectory {
+                migration_directory,
+                manifest_path,
+            } => {
+                warn!(
+                    "Loading unsigned migrations from local directory '{}'",
+                    migration_directory.display()
+                );
+                Ok(Source::LocalDirectory {
+                    migration_directory: migration_directory.clone(),
+                    manifest_path: manifest_path.clone(),
+                })
+            }
+        }
+    }
+
+    /// Loads the manifest that lists the migrations for each version.
+    pub(crate) fn manifest(&self) -> Result<Manifest> {
+        match self {
+            Source::Repository(repository) => {
+                let target = "manifest.json";
+                Manifest::from_json(
+                    repository
+                        .read_target(target)
+                        .context(error::ManifestLoad)?
+                        .context(error::ManifestNotFound)?,
+                )
+                .context(error::ManifestParse)
+            }
+            #[cfg(feature = "local-migrations")]
+            Source::LocalDirectory { manifest_path, .. } => {
+                update_metadata::load_file(manifest_path).context(error::ManifestParse)
+            }
+        }
+    }
+
+    /// Returns a reader for the contents of the named migration, decompressing it if needed.
+    pub(crate) fn read_migration(&self, migration: &str) -> Result<Box<dyn Read + '_>> {
+        match self {
+            Source::Repository(repository) => {
+                let lz4_bytes = repository
+                    .read_target(migration)
+                    .context(error::LoadMigration { migration })?
+                    .context(error::MigrationNotFound { migration })?;
+
+                // Add an LZ4 decoder so the bytes will be deflated on read
+                let reader =
+                    lz4::Decoder::new(lz4_bytes).context(error::Lz4Decode { migration })?;
+                Ok(Box::new(reader))
+            }
+            #[cfg(feature = "local-migrations")]
+            Source::LocalDirectory {
+                migration_directory,
+                ..
+            } => {
+                // Use the migration as named if it's there, decompressing it if it's named as
+                // compressed; otherwise, look for an uncompressed copy.
+                let path = migration_directory.join(migration);
+                if path.exists() {
+                    let file = File::open(&path).context(error::OpenMigration { path: &path })?;
+                    if migration.ends_with(".lz4") {
+                        let reader =
+                            lz4::Decoder::new(file).context(error::Lz4Decode { migration })?;
+                        return Ok(Box::new(reader));
+                    }
+                    return Ok(Box::new(file));
+                }
+
+                let path = migration_directory.join(migration.trim_end_matches(".lz4"));
+                if !path.exists() {
+                    return error::MigrationNotFound { migration }.fail();
+                }
+                let file = File::open(&path).context(error::OpenMigration { path: &path })?;
+                Ok(Box::new(file))
+            }
+        }
+    }
+}
+
+/// Loads the locally cached TUF repository.
+fn load_repository(
+    migration_directory: &Path,
+    root_path: &Path,
+    metadata_directory: &Path,
+) -> Result<Source> {
+    // create URLs from the metadata and targets directory paths
+    let metadata_base_url =
+        Url::from_directory_path(metadata_directory).map_err(|_| error::Error::DirectoryUrl {
+            path: metadata_directory.to_path_buf(),
+        })?;
+    let targets_base_url =
+        Url::from_directory_path(migration_directory).map_err(|_| error::Error::DirectoryUrl {
+            path: migration_directory.to_path_buf(),
+        })?;
+
+    // open a reader to the root.json file
+    let root_file = File::open(root_path).context(error::OpenRoot { path: root_path })?;
+
+    // We will load the locally cached TUF repository to obtain the manifest. The Repository is
+    // loaded using a `TempDir` for its internal Datastore (this is the default). Part of using a
+    // `TempDir` is disabling timestamp checking, because we want an instance to still come up and
+    // run migrations regardless of the how the system time relates to what we have cached (for
+    // example if someone runs an update, then shuts down the instance for several weeks, beyond the
+    // expiration of at least the cached timestamp.json before booting it back up again). We also
+    // use a `TempDir` because see no value in keeping a datastore around. The latest  known
+    // versions of the repository metadata will always be the versions of repository metadata we
+    // have cached on the disk. More info at `ExpirationEnforcement::Unsafe` below.
+
+    // Failure to load the TUF repo at the expected location is a serious issue because updog should
+    // always create a TUF repo that contains at least the manifest, even if there are no migrations.
+    let repository = RepositoryLoader::new(root_file, metadata_base_url, targets_base_url)
+        .transport(FilesystemTransport)
+        // The threats TUF mitigates are more than the threats we are attempting to mitigate
+        // here by caching signatures for migrations locally and using them after a reboot but
+        // prior to Internet connectivity. We are caching the TUF repo and use it while offline
+        // after a reboot to mitigate binaries being added or modified in the migrations
+        // directory; the TUF repo is simply a code signing method we already have in place,
+        // even if it's not one that initially makes sense for this use case. So, we don't care
+        // if the targets expired between updog downloading them and now.
+        .expiration_enforcement(ExpirationEnforcement::Unsafe)
+        .load()
+        .context(error::RepoLoad)?;
+    Ok(Source::Repository(repository))
+}
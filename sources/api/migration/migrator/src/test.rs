@@ -1,7 +1,11 @@
 //! Provides an end-to-end test of `migrator` via the `run` function. This module is conditionally
 //! compiled for cfg(test) only.
-use crate::args::Args;
-use crate::run;
+use crate::args::{Args, MigrationSource};
+use crate::error::Error;
+use crate::journal::test::SAVES_BEFORE_INTERRUPTION;
+use crate::report::{KeyChanges, RunReport, REPORT_NAME};
+use crate::source::Source;
+use crate::{get_current_version, plan, run};
 use chrono::{DateTime, Utc};
 use semver::Version;
 use std::fs;
@@ -37,8 +41,12 @@ const FIRST_MIGRATION: &str = "b-first-migration";
 /// The name of a test migration. The prefix `a-` ensures we are not alphabetically sorting.
 const SECOND_MIGRATION: &str = "a-second-migration";
 
-/// Creates a script that will serve as a migration during testing. The script writes its migrations
-/// name to a file named `result.txt` in the parent directory of the datastore. `pentacle` does not
+/// The name of a declarative test migration, which the migrator interprets rather than runs.
+const DECLARATIVE_MIGRATION: &str = "c-declarative-migration.toml";
+
+/// Creates a script that will serve as a migration during testing. The script copies the source
+/// datastore to the target datastore, and writes its migrations name to a file named `result.txt`
+/// in the parent directory of the datastore. `pentacle` does not
 /// retain the name of the executing binary or script, so we take the `migration_name` as input,
 /// and 'hardcode' it into the script.
 fn create_test_migration<S: AsRef<str>>(migration_name: S) -> String {
@@ -49,6 +57,7 @@ migration_name="{}"
 datastore_parent_dir="$(dirname "${{3}}")"
 outfile="${{datastore_parent_dir}}/result.txt"
 echo "${{migration_name}}:" "${{@}}" >> "${{outfile}}"
+cp -r "${{3}}" "${{5}}"
 "#,
         migration_name.as_ref()
     )
@@ -63,10 +72,12 @@ struct TestDatastore {
 
 impl TestDatastore {
     /// Creates a `TempDir`, sets up the datastore links needed to represent the `from_version`
-    /// and returns a `TestDatastore` populated with this information.
+    /// with empty live data, and returns a `TestDatastore` populated with this information.
     fn new(from_version: Version) -> Self {
         let tmp = TempDir::new().unwrap();
         let datastore = storewolf::create_new_datastore(tmp.path(), Some(from_version)).unwrap();
+        // storewolf populates live data after creating the data store
+        fs::create_dir(datastore.join("live")).unwrap();
         TestDatastore { tmp, datastore }
     }
 }
@@ -115,6 +126,10 @@ fn create_test_repo() -> TestRepo {
         (Version::new(0, 99, 0), Version::new(0, 99, 1)),
         vec![FIRST_MIGRATION.into(), SECOND_MIGRATION.into()],
     );
+    manifest.migrations.insert(
+        (Version::new(0, 99, 1), Version::new(0, 99, 2)),
+        vec![DECLARATIVE_MIGRATION.into()],
+    );
     update_metadata::write_file(tuf_indir.join("manifest.json").as_path(), &manifest).unwrap();
 
     // Create an script that we can use as the 'migration' that migrator will run. This script will
@@ -129,6 +144,12 @@ fn create_test_repo() -> TestRepo {
     // Save lz4 compressed copies of the migration script into the tuftool_indir.
     compress(migration_a.as_bytes(), &tuf_indir.join(FIRST_MIGRATION));
     compress(migration_b.as_bytes(), &tuf_indir.join(SECOND_MIGRATION));
+    // The declarative migration uses an unknown operation, so we can see that the migrator tried
+    // to interpret it.
+    compress(
+        b"[[operation]]\ntype = \"unknown\"\n",
+        &tuf_indir.join(DECLARATIVE_MIGRATION),
+    );
 
     // Create and sign the TUF repository.
     let mut editor = tough::editor::RepositoryEditor::new(root()).unwrap();
@@ -205,10 +226,9 @@ fn migrate_forward() {
     let args = Args {
         datastore_path: test_datastore.datastore.clone(),
         log_level: log::LevelFilter::Info,
-        migration_directory: test_repo.targets_path.clone(),
         migrate_to_version: to_version,
-        root_path: root(),
-        metadata_directory: test_repo.metadata_path.clone(),
+        source: repository_source(&test_repo),
+        dry_run: false,
     };
     run(&args).unwrap();
     // the migrations should write to a file named result.txt.
@@ -224,6 +244,9 @@ fn migrate_forward() {
     let want = format!("{}: --forward", SECOND_MIGRATION);
     let got: String = second_line.chars().take(want.len()).collect();
     assert_eq!(got, want);
+    // Only the last migration to the new version validates the migrated data.
+    assert!(!first_line.ends_with("--validate"));
+    assert!(second_line.ends_with("--validate"));
 }
 
 /// This test ensures that migrations run when migrating from a newer to an older version.
@@ -237,10 +260,9 @@ fn migrate_backward() {
     let args = Args {
         datastore_path: test_datastore.datastore.clone(),
         log_level: log::LevelFilter::Info,
-        migration_directory: test_repo.targets_path.clone(),
         migrate_to_version: to_version,
-        root_path: root(),
-        metadata_directory: test_repo.metadata_path.clone(),
+        source: repository_source(&test_repo),
+        dry_run: false,
     };
     run(&args).unwrap();
     let output_file = test_datastore.tmp.path().join("result.txt");
@@ -255,4 +277,266 @@ fn migrate_backward() {
     let want = format!("{}: --backward", FIRST_MIGRATION);
     let got: String = second_line.chars().take(want.len()).collect();
     assert_eq!(got, want);
+    // We don't have the old version's model, so backward migrations aren't validated.
+    assert!(!contents.contains("--validate"));
+}
+
+/// This test ensures that declarative migrations are interpreted by the migrator rather than run
+/// as binaries.  See `migrate_forward` for a description of how these tests work.
+#[test]
+fn migrate_declarative() {
+    let from_version = Version::parse("0.99.1").unwrap();
+    let to_version = Version::parse("0.99.2").unwrap();
+    let test_datastore = TestDatastore::new(from_version);
+    let test_repo = create_test_repo();
+    let args = Args {
+        datastore_path: test_datastore.datastore.clone(),
+        log_level: log::LevelFilter::Info,
+        migrate_to_version: to_version,
+        source: repository_source(&test_repo),
+        dry_run: false,
+    };
+    let err = run(&args).unwrap_err();
+    assert!(matches!(err, Error::ParseMigration { .. }));
+    assert!(err.to_string().contains(DECLARATIVE_MIGRATION));
+}
+
+/// Returns the migration source for the given test repo.
+fn repository_source(test_repo: &TestRepo) -> MigrationSource {
+    MigrationSource::Repository {
+        migration_directory: test_repo.targets_path.clone(),
+        root_path: root(),
+        metadata_directory: test_repo.metadata_path.clone(),
+    }
+}
+
+/// Builds the arguments for migrating the given datastore to the given version with the given repo.
+fn test_args(test_datastore: &TestDatastore, test_repo: &TestRepo, to_version: &Version) -> Args {
+    Args {
+        datastore_path: test_datastore.datastore.clone(),
+        log_level: log::LevelFilter::Info,
+        migrate_to_version: to_version.clone(),
+        source: repository_source(test_repo),
+        dry_run: false,
+    }
+}
+
+/// Runs the migrator, interrupting it after the given number of journal saves.  Returns whether it
+/// was interrupted, rather than running to completion.
+fn run_interrupted(args: &Args, saves: usize) -> bool {
+    SAVES_BEFORE_INTERRUPTION.with(|s| s.set(Some(saves)));
+    let result = run(args);
+    SAVES_BEFORE_INTERRUPTION.with(|s| s.set(None));
+    match result {
+        Ok(()) => false,
+        Err(Error::JournalWrite { .. }) => true,
+        Err(e) => panic!("unexpected error: {}", e),
+    }
+}
+
+/// Checks that the datastore directory is at the given version, and that no journal or unused
+/// datastores were left behind.
+fn assert_finished(datastore_dir: &Path, version: &Version) {
+    assert_eq!(&get_current_version(datastore_dir).unwrap(), version);
+    let mut linked = Vec::new();
+    let mut datastores = Vec::new();
+    for entry in fs::read_dir(datastore_dir).unwrap() {
+        let entry = entry.unwrap();
+        let name = entry.file_name().to_string_lossy().into_owned();
+        assert!(
+            !name.starts_with("migration-journal"),
+            "{} left behind",
+            name
+        );
+        let file_type = entry.file_type().unwrap();
+        if file_type.is_symlink() {
+            let target = fs::read_link(entry.path()).unwrap();
+            linked.push(target.to_string_lossy().into_owned());
+        } else if file_type.is_dir() {
+            datastores.push(name);
+        }
+    }
+    for datastore in datastores {
+        assert!(linked.contains(&datastore), "{} left behind", datastore);
+    }
+}
+
+/// This test interrupts a forward migration after each step it records in its journal, as if the
+/// host lost power, and then runs the migrator again.  The second run should pick up where the
+/// first left off, without running completed migrations again.
+#[test]
+fn migrate_forward_interrupted() {
+    let from_version = Version::parse("0.99.0").unwrap();
+    let to_version = Version::parse("0.99.1").unwrap();
+    let mut interruptions = 0;
+    loop {
+        let test_datastore = TestDatastore::new(from_version.clone());
+        let test_repo = create_test_repo();
+        let args = test_args(&test_datastore, &test_repo, &to_version);
+        if !run_interrupted(&args, interruptions + 1) {
+            break;
+        }
+        interruptions += 1;
+
+        run(&args).unwrap();
+        assert_finished(test_datastore.tmp.path(), &to_version);
+        let contents = fs::read_to_string(test_datastore.tmp.path().join("result.txt")).unwrap();
+        assert_eq!(contents.matches(FIRST_MIGRATION).count(), 1);
+        assert_eq!(contents.matches(SECOND_MIGRATION).count(), 1);
+    }
+    // The journal is saved when starting, after each of two migrations, and before each of four
+    // link flips.
+    assert_eq!(interruptions, 7);
+}
+
+/// This test interrupts a forward migration after each step it records in its journal, and then
+/// asks the migrator to go back to the original version instead, as it would if we rolled back
+/// to the previous OS image.
+#[test]
+fn migrate_forward_interrupted_then_rolled_back() {
+    let from_version = Version::parse("0.99.0").unwrap();
+    let to_version = Version::parse("0.99.1").unwrap();
+    for saves in 1..=7 {
+        let test_datastore = TestDatastore::new(from_version.clone());
+        let test_repo = create_test_repo();
+        let datastore_dir = test_datastore.tmp.path();
+        let args = test_args(&test_datastore, &test_repo, &to_version);
+        assert!(run_interrupted(&args, saves));
+        let reached_new_version = get_current_version(datastore_dir).unwrap() == to_version;
+
+        let mut args = test_args(&test_datastore, &test_repo, &from_version);
+        args.datastore_path = fs::canonicalize(datastore_dir.join("current")).unwrap();
+        run(&args).unwrap();
+        assert_finished(datastore_dir, &from_version);
+        if !reached_new_version {
+            // The links for the new version didn't exist before, so they're removed.
+            assert!(fs::symlink_metadata(datastore_dir.join("v0.99.1")).is_err());
+        }
+    }
+}
+
+/// This test ensures that a dry run describes the migrations that would run without touching the
+/// datastore.
+#[test]
+fn dry_run() {
+    let from_version = Version::parse("0.99.0").unwrap();
+    let to_version = Version::parse("0.99.1").unwrap();
+    let test_datastore = TestDatastore::new(from_version.clone());
+    let test_repo = create_test_repo();
+    let mut args = test_args(&test_datastore, &test_repo, &to_version);
+    args.dry_run = true;
+    run(&args).unwrap();
+    assert!(!test_datastore.tmp.path().join("result.txt").exists());
+    assert_finished(test_datastore.tmp.path(), &from_version);
+
+    let source = Source::load(&args.source).unwrap();
+    assert_eq!(
+        plan(&source, &from_version, &to_version).unwrap(),
+        format!(
+            "Migrating forward from 0.99.0 to 0.99.1:\n  1. {} (binary)\n  2. {} (binary, validates migrated data)\n",
+            FIRST_MIGRATION, SECOND_MIGRATION
+        )
+    );
+    assert_eq!(
+        plan(&source, &to_version, &from_version).unwrap(),
+        format!(
+            "Migrating backward from 0.99.1 to 0.99.0:\n  1. {} (binary)\n  2. {} (binary)\n",
+            SECOND_MIGRATION, FIRST_MIGRATION
+        )
+    );
+}
+
+/// This test runs migrations from a plain local directory, where they aren't signed, and can be
+/// uncompressed even if the manifest names them as compressed.
+#[cfg(feature = "local-migrations")]
+#[test]
+fn migrate_local_directory() {
+    let from_version = Version::parse("0.99.0").unwrap();
+    let to_version = Version::parse("0.99.1").unwrap();
+    let test_datastore = TestDatastore::new(from_version.clone());
+
+    let migration_dir = TempDir::new().unwrap();
+    let first_migration = format!("{}.lz4", FIRST_MIGRATION);
+    compress(
+        create_test_migration(FIRST_MIGRATION).as_bytes(),
+        &migration_dir.path().join(&first_migration),
+    );
+    let second_migration = format!("{}.lz4", SECOND_MIGRATION);
+    fs::write(
+        migration_dir.path().join(SECOND_MIGRATION),
+        create_test_migration(SECOND_MIGRATION),
+    )
+    .unwrap();
+    let mut manifest = update_metadata::Manifest::default();
+    manifest.migrations.insert(
+        (from_version, to_version.clone()),
+        vec![first_migration, second_migration],
+    );
+    let manifest_path = migration_dir.path().join("manifest.json");
+    update_metadata::write_file(&manifest_path, &manifest).unwrap();
+
+    let args = Args {
+        datastore_path: test_datastore.datastore.clone(),
+        log_level: log::LevelFilter::Info,
+        migrate_to_version: to_version.clone(),
+        source: MigrationSource::LocalDirectory {
+            migration_directory: migration_dir.path().to_path_buf(),
+            manifest_path,
+        },
+        dry_run: false,
+    };
+    run(&args).unwrap();
+    assert_finished(test_datastore.tmp.path(), &to_version);
+    let contents = fs::read_to_string(test_datastore.tmp.path().join("result.txt")).unwrap();
+    let lines: Vec<&str> = contents.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].starts_with(&format!("{}: --forward", FIRST_MIGRATION)));
+    assert!(lines[1].starts_with(&format!("{}: --forward", SECOND_MIGRATION)));
+}
+
+/// This test ensures the migrator reports what each migration did, whether the run succeeded or
+/// failed.
+#[test]
+fn migration_report() {
+    let test_repo = create_test_repo();
+
+    let from_version = Version::parse("0.99.0").unwrap();
+    let to_version = Version::parse("0.99.1").unwrap();
+    let test_datastore = TestDatastore::new(from_version);
+    run(&test_args(&test_datastore, &test_repo, &to_version)).unwrap();
+    let report_path = test_datastore.tmp.path().join(REPORT_NAME);
+    let runs: Vec<RunReport> = serde_json::from_slice(&fs::read(&report_path).unwrap()).unwrap();
+    assert_eq!(runs.len(), 1);
+    let run_report = &runs[0];
+    assert_eq!(run_report.to_version, to_version);
+    assert_eq!(run_report.direction, "forward");
+    assert!(run_report.success);
+    let names: Vec<&str> = run_report
+        .migrations
+        .iter()
+        .map(|m| m.name.as_str())
+        .collect();
+    assert_eq!(names, vec![FIRST_MIGRATION, SECOND_MIGRATION]);
+    for migration in &run_report.migrations {
+        assert!(migration.success);
+        assert_eq!(migration.exit_status, Some(0));
+        // The test migrations copy the data store without changing it.
+        assert_eq!(migration.keys, Some(KeyChanges::default()));
+    }
+
+    let from_version = Version::parse("0.99.1").unwrap();
+    let to_version = Version::parse("0.99.2").unwrap();
+    let test_datastore = TestDatastore::new(from_version);
+    run(&test_args(&test_datastore, &test_repo, &to_version)).unwrap_err();
+    let report_path = test_datastore.tmp.path().join(REPORT_NAME);
+    let runs: Vec<RunReport> = serde_json::from_slice(&fs::read(&report_path).unwrap()).unwrap();
+    let run_report = &runs[0];
+    assert!(!run_report.success);
+    assert!(run_report.error.is_some());
+    let migration = &run_report.migrations[0];
+    assert_eq!(migration.name, DECLARATIVE_MIGRATION);
+    assert!(!migration.success);
+    assert_eq!(migration.exit_status, None);
+    assert!(migration.error.is_some());
+    assert!(migration.keys.is_none());
 }
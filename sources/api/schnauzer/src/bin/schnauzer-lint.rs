@@ -0,0 +1,167 @@
+//! schnauzer-lint checks a variant's configuration file templates at build time.  It's built only
+//! with the `lint` feature, so hosts don't carry the linter or its dependencies.  See the
+//! schnauzer README for details.
+
+#![deny(rust_2018_idioms)]
+
+use schnauzer::lint::{self, LintConfig};
+use snafu::{ensure, ResultExt};
+use std::path::PathBuf;
+use std::{env, process};
+
+mod error {
+    use snafu::Snafu;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility = "pub(super)")]
+    pub(super) enum Error {
+        #[snafu(display("Failed to find the packages of the variant: {}", source))]
+        VariantDirs { source: schnauzer::lint::Error },
+
+        #[snafu(display("Failed to lint templates: {}", source))]
+        Lint { source: schnauzer::lint::Error },
+
+        #[snafu(display("Template lint found {} problem(s)", count))]
+        LintProblems { count: usize },
+    }
+}
+type Result<T> = std::result::Result<T, error::Error>;
+
+/// Print usage message.
+fn usage() -> ! {
+    let program_name = env::args().next().unwrap_or_else(|| "program".to_string());
+    eprintln!(
+        r"Usage: {}
+           --defaults-dir DIR
+           [ --variant-dir DIR ]
+           [ --template-dir DIR ... ]
+           [ --settings-file FILE ... ]
+           [ --variant-id VARIANT ]
+           [ --arch ARCH ]
+
+    Templates are looked for in the given template directories, then in the
+    directories of the packages the variant depends on.",
+        program_name,
+    );
+    process::exit(2);
+}
+
+/// Prints a more specific message before exiting through usage().
+fn usage_msg<S: AsRef<str>>(msg: S) -> ! {
+    eprintln!("{}\n", msg.as_ref());
+    usage();
+}
+
+/// The parsed arguments, and the variant directory whose packages' templates we also lint.
+struct Args {
+    config: LintConfig,
+    variant_dir: Option<PathBuf>,
+}
+
+/// Parses args for the lint config.
+fn parse_args(args: env::Args) -> Args {
+    let mut defaults_dir = None;
+    let mut settings_files = Vec::new();
+    let mut template_dirs = Vec::new();
+    let mut variant_dir = None;
+    let mut variant_id = None;
+    let mut arch = None;
+
+    let mut iter = args.skip(1);
+    while let Some(arg) = iter.next() {
+        match arg.as_ref() {
+            "--help" | "-h" => usage(),
+
+            "--defaults-dir" => {
+                defaults_dir =
+                    Some(PathBuf::from(iter.next().unwrap_or_else(|| {
+                        usage_msg("Did not give argument to --defaults-dir")
+                    })))
+            }
+
+            "--settings-file" => settings_files
+                .push(PathBuf::from(iter.next().unwrap_or_else(|| {
+                    usage_msg("Did not give argument to --settings-file")
+                }))),
+
+            "--template-dir" => template_dirs
+                .push(PathBuf::from(iter.next().unwrap_or_else(|| {
+                    usage_msg("Did not give argument to --template-dir")
+                }))),
+
+            "--variant-dir" => {
+                variant_dir =
+                    Some(PathBuf::from(iter.next().unwrap_or_else(|| {
+                        usage_msg("Did not give argument to --variant-dir")
+                    })))
+            }
+
+            "--variant-id" => {
+                variant_id = Some(
+                    iter.next()
+                        .unwrap_or_else(|| usage_msg("Did not give argument to --variant-id")),
+                )
+            }
+
+            "--arch" => {
+                arch = Some(
+                    iter.next()
+                        .unwrap_or_else(|| usage_msg("Did not give argument to --arch")),
+                )
+            }
+
+            x => usage_msg(format!("Unknown argument '{}'", x)),
+        }
+    }
+
+    if template_dirs.is_empty() && variant_dir.is_none() {
+        usage_msg("Must give --variant-dir or at least one --template-dir");
+    }
+
+    Args {
+        config: LintConfig {
+            defaults_dir: defaults_dir.unwrap_or_else(|| usage_msg("Must give --defaults-dir")),
+            settings_files,
+            template_dirs,
+            variant_id: variant_id
+                .or_else(|| option_env!("VARIANT").map(String::from))
+                .unwrap_or_else(|| usage_msg("Must give --variant-id")),
+            arch: arch.unwrap_or_else(|| env::consts::ARCH.to_string()),
+        },
+        variant_dir,
+    }
+}
+
+/// Lints templates, printing any problems found.
+fn run() -> Result<()> {
+    let Args {
+        mut config,
+        variant_dir,
+    } = parse_args(env::args());
+    if let Some(variant_dir) = variant_dir {
+        let package_dirs = lint::variant_template_dirs(&variant_dir).context(error::VariantDirs)?;
+        config.template_dirs.extend(package_dirs);
+    }
+
+    let problems = lint::lint(&config).context(error::Lint)?;
+    for problem in &problems {
+        println!("{}", problem);
+    }
+    ensure!(
+        problems.is_empty(),
+        error::LintProblems {
+            count: problems.len()
+        }
+    );
+    Ok(())
+}
+
+// Returning a Result from main makes it print a Debug representation of the error, but with Snafu
+// we have nice Display representations of the error, so we wrap "main" (run) and print any error.
+// https://github.com/shepmaster/snafu/issues/110
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
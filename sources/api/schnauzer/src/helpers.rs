@@ -3,7 +3,10 @@
 // text at render time.
 
 use dns_lookup::lookup_host;
-use handlebars::{Context, Handlebars, Helper, Output, RenderContext, RenderError};
+use handlebars::{
+    Context, Handlebars, Helper, HelperDef, Output, RenderContext, RenderError, ScopedJson,
+};
+use ipnet::IpNet;
 use lazy_static::lazy_static;
 use num_cpus;
 use serde_json::value::Value;
@@ -168,6 +171,48 @@ mod error {
             template: String,
         },
 
+        #[snafu(display(
+            "Host {} is outside of CIDR block '{}' in template '{}'",
+            host,
+            cidr,
+            template
+        ))]
+        CidrHostOutOfRange {
+            host: u64,
+            cidr: String,
+            template: String,
+        },
+
+        #[snafu(display(
+            "Invalid CIDR block '{}' in template '{}': {}",
+            value,
+            template,
+            source
+        ))]
+        InvalidCidr {
+            value: String,
+            template: String,
+            source: ipnet::AddrParseError,
+        },
+
+        #[snafu(display(
+            "Invalid IP address '{}' in template '{}': {}",
+            value,
+            template,
+            source
+        ))]
+        InvalidIpAddress {
+            value: String,
+            template: String,
+            source: std::net::AddrParseError,
+        },
+
+        #[snafu(display("Unable to serialize JSON in template '{}': {}", template, source))]
+        JsonSerialize {
+            template: String,
+            source: serde_json::Error,
+        },
+
         #[snafu(display("Missing param {} for helper '{}'", index, helper_name))]
         MissingParam { index: usize, helper_name: String },
 
@@ -947,139 +992,609 @@ pub fn add_unresolvable_hostname(
     Ok(())
 }
 
-// =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
-// helpers to the helpers
+/// `toml_string` renders a scalar value as a quoted TOML basic string, escaping any characters
+/// that would otherwise end the string or make the file invalid.
+///
+/// # Example
+///
+/// In a TOML template, write `key = {{ toml_string settings.foo }}`.  If `settings.foo` is
+/// `say "hi"`, this renders `key = "say \"hi\""`.
+pub fn toml_string(
+    helper: &Helper<'_, '_>,
+    _: &Handlebars,
+    _: &Context,
+    renderctx: &mut RenderContext<'_, '_>,
+    out: &mut dyn Output,
+) -> Result<(), RenderError> {
+    trace!("Starting toml_string helper");
+    let template_name = template_name(renderctx);
+    check_param_count(helper, template_name, 1)?;
 
-/// Gets the value at `idx` and unwraps it. Returns an error if the param cannot be unwrapped.
-fn get_param<'a>(helper: &'a Helper<'_, '_>, idx: usize) -> Result<&'a Value, RenderError> {
-    Ok(helper
-        .param(idx)
-        .map(|v| v.value())
-        .context(error::MissingParam {
-            index: idx,
-            helper_name: helper.name(),
-        })?)
+    let value = scalar_param(helper, 0, template_name)?;
+    let mut result = String::with_capacity(value.len() + 2);
+    result.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\u{8}' => result.push_str("\\b"),
+            '\t' => result.push_str("\\t"),
+            '\n' => result.push_str("\\n"),
+            '\u{c}' => result.push_str("\\f"),
+            '\r' => result.push_str("\\r"),
+            // TOML doesn't allow any other control characters in a basic string.
+            c if c.is_control() && (c as u32) < 0x80 => {
+                result.push_str(&format!("\\u{:04X}", c as u32))
+            }
+            c => result.push(c),
+        }
+    }
+    result.push('"');
+
+    out.write(&result).with_context(|| error::TemplateWrite {
+        template: template_name.to_owned(),
+    })?;
+    Ok(())
 }
 
-/// Get the template name if there is one, otherwise return "dynamic template"
-fn template_name<'a>(renderctx: &'a RenderContext<'_, '_>) -> &'a str {
-    match renderctx.get_root_template_name() {
-        Some(s) => s.as_str(),
-        None => "dynamic template",
-    }
+/// `json` renders any value, including arrays and maps, as compact JSON.  Since JSON is a subset
+/// of YAML, and JSON arrays of strings are valid TOML arrays, this is also a safe way to write
+/// lists into YAML and TOML files.
+///
+/// # Example
+///
+/// `allowedUnsafeSysctls: {{ json settings.kubernetes.allowed-unsafe-sysctls }}`
+///
+/// If the setting is `["net.core.somaxconn", "net.ipv4.ip_local_port_range"]`, this renders
+/// `allowedUnsafeSysctls: ["net.core.somaxconn","net.ipv4.ip_local_port_range"]`.
+pub fn json(
+    helper: &Helper<'_, '_>,
+    _: &Handlebars,
+    _: &Context,
+    renderctx: &mut RenderContext<'_, '_>,
+    out: &mut dyn Output,
+) -> Result<(), RenderError> {
+    trace!("Starting json helper");
+    let template_name = template_name(renderctx);
+    check_param_count(helper, template_name, 1)?;
+
+    let value = get_param(helper, 0)?;
+    let result = serde_json::to_string(value).context(error::JsonSerialize {
+        template: template_name.to_owned(),
+    })?;
+
+    out.write(&result).with_context(|| error::TemplateWrite {
+        template: template_name.to_owned(),
+    })?;
+    Ok(())
 }
 
-/// Creates a an `IncorrectNumberofParams` error if the number of `helper`
-/// params does not equal `expected`. Template name is only used in constructing
-/// the error message.
-fn check_param_count<S: AsRef<str>>(
+/// `yaml_string` renders a scalar value as a double-quoted YAML string, escaping any characters
+/// that would otherwise end the string or change how it's parsed.  Numbers and booleans are
+/// rendered as strings, too, so "true" stays a string rather than becoming a boolean.
+///
+/// # Example
+///
+/// `server: {{ yaml_string settings.kubernetes.api-server }}`
+pub fn yaml_string(
     helper: &Helper<'_, '_>,
-    template_name: S,
-    expected: usize,
+    _: &Handlebars,
+    _: &Context,
+    renderctx: &mut RenderContext<'_, '_>,
+    out: &mut dyn Output,
 ) -> Result<(), RenderError> {
-    if helper.params().len() != expected {
-        return Err(RenderError::from(
-            error::TemplateHelperError::IncorrectNumberOfParams {
-                expected,
-                received: helper.params().len(),
-                helper: helper.name().to_string(),
-                template: template_name.as_ref().into(),
-            },
-        ));
+    trace!("Starting yaml_string helper");
+    let template_name = template_name(renderctx);
+    check_param_count(helper, template_name, 1)?;
+
+    let value = scalar_param(helper, 0, template_name)?;
+    let mut result = String::with_capacity(value.len() + 2);
+    result.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\0' => result.push_str("\\0"),
+            '\t' => result.push_str("\\t"),
+            '\n' => result.push_str("\\n"),
+            '\r' => result.push_str("\\r"),
+            // YAML parsers treat these as line breaks, so they must be escaped.
+            '\u{85}' => result.push_str("\\N"),
+            '\u{2028}' => result.push_str("\\L"),
+            '\u{2029}' => result.push_str("\\P"),
+            '\u{feff}' => result.push_str("\\uFEFF"),
+            c if c.is_control() => result.push_str(&format!("\\x{:02X}", c as u32)),
+            c => result.push(c),
+        }
     }
+    result.push('"');
+
+    out.write(&result).with_context(|| error::TemplateWrite {
+        template: template_name.to_owned(),
+    })?;
     Ok(())
 }
 
-/// Constructs the fully qualified domain name for the ECR registry for the
-/// given region. Returns a default ECR registry if the region is not mapped.
-fn ecr_registry<S: AsRef<str>>(region: S) -> String {
-    // lookup the ecr registry ID or fallback to the default region and id
-    let (region, registry_id) = match ECR_MAP.borrow().get(region.as_ref()) {
-        None => (ECR_FALLBACK_REGION, ECR_FALLBACK_REGISTRY),
-        Some(registry_id) => (region.as_ref(), *registry_id),
-    };
-    format!("{}.dkr.ecr.{}.amazonaws.com", registry_id, region)
-}
+/// `shell_quote` renders a scalar value in single quotes, so that a POSIX shell, or systemd when
+/// reading an `EnvironmentFile`, treats it as a single literal word.  Single quotes in the value
+/// are written as `'\''`.
+///
+/// # Example
+///
+/// `HOSTNAME={{ shell_quote settings.network.hostname }}`
+pub fn shell_quote(
+    helper: &Helper<'_, '_>,
+    _: &Handlebars,
+    _: &Context,
+    renderctx: &mut RenderContext<'_, '_>,
+    out: &mut dyn Output,
+) -> Result<(), RenderError> {
+    trace!("Starting shell_quote helper");
+    let template_name = template_name(renderctx);
+    check_param_count(helper, template_name, 1)?;
 
-/// Constructs the fully qualified domain name for the pause container (pod infra
-/// container) for the given region. Returns a default if the region is not mapped.
-fn pause_registry<S: AsRef<str>>(region: S) -> String {
-    // lookup the registry ID or fallback to the default region and id
-    let (region, registry_id) = match PAUSE_CONTAINER_MAP.borrow().get(region.as_ref()) {
-        None => (PAUSE_FALLBACK_REGION, PAUSE_FALLBACK_REGISTRY),
-        Some(registry_id) => (region.as_ref(), *registry_id),
-    };
-    format!("{}.dkr.ecr.{}.amazonaws.com", registry_id, region)
-}
+    let value = scalar_param(helper, 0, template_name)?;
+    let result = format!("'{}'", value.replace('\'', "'\\''"));
 
-/// Calculates and returns the amount of CPU to reserve
-fn kube_cpu_helper(num_cores: usize) -> Result<String, TemplateHelperError> {
-    let num_cores =
-        u16::try_from(num_cores).context(error::ConvertUsizeToU16 { number: num_cores })?;
-    let millicores_unit = "m";
-    let cpu_to_reserve = match num_cores {
-        0 => 0.0,
-        1 => KUBE_RESERVE_1_CORE,
-        2 => KUBE_RESERVE_2_CORES,
-        3 => KUBE_RESERVE_3_CORES,
-        4 => KUBE_RESERVE_4_CORES,
-        _ => {
-            let num_cores = f32::from(num_cores);
-            KUBE_RESERVE_4_CORES + ((num_cores - 4.0) * KUBE_RESERVE_ADDITIONAL)
-        }
-    };
-    Ok(format!(
-        "{}{}",
-        cpu_to_reserve.floor().to_string(),
-        millicores_unit
-    ))
+    out.write(&result).with_context(|| error::TemplateWrite {
+        template: template_name.to_owned(),
+    })?;
+    Ok(())
 }
 
-// =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
+/// `indent` prefixes each line of a string with the given number of spaces, for example to nest
+/// a multi-line value inside a YAML block.  Empty lines are left empty, so the output doesn't
+/// have trailing whitespace.
+///
+/// The first parameter is the number of spaces; the second is the string.  The output of other
+/// helpers can be indented with a subexpression.
+///
+/// # Example
+///
+/// ```text
+/// data: |
+/// {{ indent 2 settings.foo }}
+/// ```
+///
+/// If `settings.foo` is "a\nb", this renders:
+///
+/// ```text
+/// data: |
+///   a
+///   b
+/// ```
+pub fn indent(
+    helper: &Helper<'_, '_>,
+    _: &Handlebars,
+    _: &Context,
+    renderctx: &mut RenderContext<'_, '_>,
+    out: &mut dyn Output,
+) -> Result<(), RenderError> {
+    trace!("Starting indent helper");
+    let template_name = template_name(renderctx);
+    check_param_count(helper, template_name, 2)?;
 
-#[cfg(test)]
-mod test_base64_decode {
-    use super::*;
-    use handlebars::RenderError;
-    use serde::Serialize;
-    use serde_json::json;
+    let spaces_val = get_param(helper, 0)?;
+    let spaces = spaces_val
+        .as_u64()
+        .with_context(|| error::InvalidTemplateValue {
+            expected: "non-negative number",
+            value: spaces_val.to_owned(),
+            template: template_name.to_owned(),
+        })?;
+    let spaces = usize::try_from(spaces)
+        .ok()
+        .with_context(|| error::ConvertNumber {
+            what: "indent",
+            number: spaces.to_string(),
+            target: "usize",
+        })?;
+    let prefix = " ".repeat(spaces);
+
+    let value = scalar_param(helper, 1, template_name)?;
+    let result = value
+        .split('\n')
+        .map(|line| {
+            if line.is_empty() {
+                line.to_string()
+            } else {
+                format!("{}{}", prefix, line)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
 
-    // A thin wrapper around the handlebars render_template method that includes
-    // setup and registration of helpers
-    fn setup_and_render_template<T>(tmpl: &str, data: &T) -> Result<String, RenderError>
-    where
-        T: Serialize,
-    {
-        let mut registry = Handlebars::new();
-        registry.register_helper("base64_decode", Box::new(base64_decode));
+    out.write(&result).with_context(|| error::TemplateWrite {
+        template: template_name.to_owned(),
+    })?;
+    Ok(())
+}
 
-        registry.render_template(tmpl, data)
-    }
+/// `cidr_host` renders the address at the given offset from the start of a CIDR block, for
+/// example to derive a well-known address like the cluster DNS IP from a service CIDR.  The
+/// offset must fit within the block.
+///
+/// # Example
+///
+/// `{{ cidr_host "10.100.0.0/16" 10 }}` renders `10.100.0.10`, and
+/// `{{ cidr_host "fd00:1::/108" 10 }}` renders `fd00:1::a`.
+pub fn cidr_host(
+    helper: &Helper<'_, '_>,
+    _: &Handlebars,
+    _: &Context,
+    renderctx: &mut RenderContext<'_, '_>,
+    out: &mut dyn Output,
+) -> Result<(), RenderError> {
+    trace!("Starting cidr_host helper");
+    let template_name = template_name(renderctx);
+    check_param_count(helper, template_name, 2)?;
 
-    #[test]
-    fn renders_decoded_base64() {
-        let result =
-            setup_and_render_template("{{base64_decode var}}", &json!({"var": "SGk="})).unwrap();
-        assert_eq!(result, "Hi")
-    }
+    let cidr = cidr_param(helper, 0, template_name)?;
+    let host_val = get_param(helper, 1)?;
+    let host = host_val
+        .as_u64()
+        .with_context(|| error::InvalidTemplateValue {
+            expected: "non-negative number",
+            value: host_val.to_owned(),
+            template: template_name.to_owned(),
+        })?;
 
-    #[test]
-    fn does_not_render_invalid_base64() {
-        assert!(setup_and_render_template("{{base64_decode var}}", &json!({"var": "hi"})).is_err())
+    // The number of bits available for hosts in the block; any offset fits if it's 64 or more.
+    let host_bits = cidr.max_prefix_len() - cidr.prefix_len();
+    if host_bits < 64 && host >> host_bits != 0 {
+        return Err(RenderError::from(
+            error::TemplateHelperError::CidrHostOutOfRange {
+                host,
+                cidr: cidr.to_string(),
+                template: template_name.to_owned(),
+            },
+        ));
     }
+    let address = match cidr {
+        IpNet::V4(net) => IpAddr::V4(Ipv4Addr::from(u32::from(net.network()) + host as u32)),
+        IpNet::V6(net) => IpAddr::V6(Ipv6Addr::from(u128::from(net.network()) + u128::from(host))),
+    };
 
-    #[test]
-    fn does_not_render_invalid_utf8() {
-        // "wygk" is the invalid UTF8 string "\xc3\x28" base64 encoded
-        assert!(
-            setup_and_render_template("{{base64_decode var}}", &json!({"var": "wygK"})).is_err()
-        )
-    }
+    out.write(&address.to_string())
+        .with_context(|| error::TemplateWrite {
+            template: template_name.to_owned(),
+        })?;
+    Ok(())
+}
 
-    #[test]
-    fn base64_helper_with_missing_param() {
-        assert!(setup_and_render_template("{{base64_decode}}", &json!({"var": "foo"})).is_err());
-    }
+/// `ip_family` renders "ipv4" or "ipv6" for an IP address or CIDR block, so templates can choose
+/// settings for the address family in use.
+///
+/// # Example
+///
+/// ```text
+/// {{#if (eq (ip_family settings.kubernetes.node-ip) "ipv6")}}
+/// ...
+/// {{/if}}
+/// ```
+pub fn ip_family(
+    helper: &Helper<'_, '_>,
+    _: &Handlebars,
+    _: &Context,
+    renderctx: &mut RenderContext<'_, '_>,
+    out: &mut dyn Output,
+) -> Result<(), RenderError> {
+    trace!("Starting ip_family helper");
+    let template_name = template_name(renderctx);
+    check_param_count(helper, template_name, 1)?;
+
+    let value_param = get_param(helper, 0)?;
+    let value = value_param
+        .as_str()
+        .with_context(|| error::InvalidTemplateValue {
+            expected: "string",
+            value: value_param.to_owned(),
+            template: template_name.to_owned(),
+        })?;
+    // Accept either form; we only need to know which family it belongs to.
+    let is_ipv4 = match value.parse::<IpNet>() {
+        Ok(cidr) => matches!(cidr, IpNet::V4(_)),
+        Err(_) => ip_param(helper, 0, template_name)?.is_ipv4(),
+    };
+    let family = if is_ipv4 { "ipv4" } else { "ipv6" };
+
+    out.write(family).with_context(|| error::TemplateWrite {
+        template: template_name.to_owned(),
+    })?;
+    Ok(())
+}
+
+/// `in_cidr` returns whether an IP address is inside a CIDR block.  Unlike most of our helpers,
+/// it returns a boolean rather than rendering text, so that it can be used as the condition of an
+/// `if` block.  An address from the other IP family is never inside the block.
+///
+/// # Example
+///
+/// ```text
+/// {{#if (in_cidr settings.kubernetes.cluster-dns-ip "10.0.0.0/8")}}
+/// ...
+/// {{/if}}
+/// ```
+pub struct InCidr;
+
+impl HelperDef for InCidr {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        helper: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        renderctx: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'reg, 'rc>, RenderError> {
+        trace!("Starting in_cidr helper");
+        let template_name = template_name(renderctx);
+        check_param_count(helper, template_name, 2)?;
+
+        let address = ip_param(helper, 0, template_name)?;
+        let cidr = cidr_param(helper, 1, template_name)?;
+        Ok(ScopedJson::Derived(Value::Bool(cidr.contains(&address))))
+    }
+}
+
+/// `cidr_netmask` renders the netmask of a CIDR block in address form, for configuration files
+/// that don't accept prefix lengths.
+///
+/// # Example
+///
+/// `{{ cidr_netmask "10.0.0.0/8" }}` renders `255.0.0.0`, and `{{ cidr_netmask "fd00::/64" }}`
+/// renders `ffff:ffff:ffff:ffff::`.
+pub fn cidr_netmask(
+    helper: &Helper<'_, '_>,
+    _: &Handlebars,
+    _: &Context,
+    renderctx: &mut RenderContext<'_, '_>,
+    out: &mut dyn Output,
+) -> Result<(), RenderError> {
+    trace!("Starting cidr_netmask helper");
+    let template_name = template_name(renderctx);
+    check_param_count(helper, template_name, 1)?;
+
+    let cidr = cidr_param(helper, 0, template_name)?;
+
+    out.write(&cidr.netmask().to_string())
+        .with_context(|| error::TemplateWrite {
+            template: template_name.to_owned(),
+        })?;
+    Ok(())
+}
+
+/// `bracket_ipv6` wraps an IPv6 address in square brackets, as required when it's used as the
+/// host part of a URL or with a port.  Anything else, like an IPv4 address or a hostname, is
+/// rendered unchanged, so it's safe to use on any host setting.
+///
+/// # Example
+///
+/// `https://{{ bracket_ipv6 settings.foo.host }}:443/` renders `https://[fd00::1]:443/` for an
+/// IPv6 address and `https://10.0.0.1:443/` for an IPv4 address.
+pub fn bracket_ipv6(
+    helper: &Helper<'_, '_>,
+    _: &Handlebars,
+    _: &Context,
+    renderctx: &mut RenderContext<'_, '_>,
+    out: &mut dyn Output,
+) -> Result<(), RenderError> {
+    trace!("Starting bracket_ipv6 helper");
+    let template_name = template_name(renderctx);
+    check_param_count(helper, template_name, 1)?;
+
+    let host_param = get_param(helper, 0)?;
+    let host = host_param
+        .as_str()
+        .with_context(|| error::InvalidTemplateValue {
+            expected: "string",
+            value: host_param.to_owned(),
+            template: template_name.to_owned(),
+        })?;
+    let result = match host.parse::<Ipv6Addr>() {
+        Ok(_) => format!("[{}]", host),
+        Err(_) => host.to_string(),
+    };
+
+    out.write(&result).with_context(|| error::TemplateWrite {
+        template: template_name.to_owned(),
+    })?;
+    Ok(())
+}
+
+// =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
+// helpers to the helpers
+
+/// Gets the value at `idx` and unwraps it. Returns an error if the param cannot be unwrapped.
+fn get_param<'a>(helper: &'a Helper<'_, '_>, idx: usize) -> Result<&'a Value, RenderError> {
+    Ok(helper
+        .param(idx)
+        .map(|v| v.value())
+        .context(error::MissingParam {
+            index: idx,
+            helper_name: helper.name(),
+        })?)
+}
+
+/// Gets the value at `idx` as a string.  Numbers and booleans are converted to their string form;
+/// other types are an error, since they have no single obvious string form.
+fn scalar_param(
+    helper: &Helper<'_, '_>,
+    idx: usize,
+    template_name: &str,
+) -> Result<String, RenderError> {
+    let value = get_param(helper, idx)?;
+    match value {
+        Value::String(s) => Ok(s.to_string()),
+        Value::Number(n) => Ok(n.to_string()),
+        Value::Bool(b) => Ok(b.to_string()),
+        _ => Err(RenderError::from(
+            error::TemplateHelperError::InvalidTemplateValue {
+                expected: "string, number, or boolean",
+                value: value.to_owned(),
+                template: template_name.to_owned(),
+            },
+        )),
+    }
+}
+
+/// Gets the value at `idx` and parses it as an IP address.
+fn ip_param(
+    helper: &Helper<'_, '_>,
+    idx: usize,
+    template_name: &str,
+) -> Result<IpAddr, RenderError> {
+    let value = get_param(helper, idx)?;
+    let value_str = value
+        .as_str()
+        .with_context(|| error::InvalidTemplateValue {
+            expected: "string",
+            value: value.to_owned(),
+            template: template_name.to_owned(),
+        })?;
+    Ok(value_str.parse().context(error::InvalidIpAddress {
+        value: value_str,
+        template: template_name,
+    })?)
+}
+
+/// Gets the value at `idx` and parses it as a CIDR block, e.g. "10.0.0.0/8".
+fn cidr_param(
+    helper: &Helper<'_, '_>,
+    idx: usize,
+    template_name: &str,
+) -> Result<IpNet, RenderError> {
+    let value = get_param(helper, idx)?;
+    let value_str = value
+        .as_str()
+        .with_context(|| error::InvalidTemplateValue {
+            expected: "string",
+            value: value.to_owned(),
+            template: template_name.to_owned(),
+        })?;
+    Ok(value_str.parse().context(error::InvalidCidr {
+        value: value_str,
+        template: template_name,
+    })?)
+}
+
+/// Get the template name if there is one, otherwise return "dynamic template"
+fn template_name<'a>(renderctx: &'a RenderContext<'_, '_>) -> &'a str {
+    match renderctx.get_root_template_name() {
+        Some(s) => s.as_str(),
+        None => "dynamic template",
+    }
+}
+
+/// Creates a an `IncorrectNumberofParams` error if the number of `helper`
+/// params does not equal `expected`. Template name is only used in constructing
+/// the error message.
+fn check_param_count<S: AsRef<str>>(
+    helper: &Helper<'_, '_>,
+    template_name: S,
+    expected: usize,
+) -> Result<(), RenderError> {
+    if helper.params().len() != expected {
+        return Err(RenderError::from(
+            error::TemplateHelperError::IncorrectNumberOfParams {
+                expected,
+                received: helper.params().len(),
+                helper: helper.name().to_string(),
+                template: template_name.as_ref().into(),
+            },
+        ));
+    }
+    Ok(())
+}
+
+/// Constructs the fully qualified domain name for the ECR registry for the
+/// given region. Returns a default ECR registry if the region is not mapped.
+fn ecr_registry<S: AsRef<str>>(region: S) -> String {
+    // lookup the ecr registry ID or fallback to the default region and id
+    let (region, registry_id) = match ECR_MAP.borrow().get(region.as_ref()) {
+        None => (ECR_FALLBACK_REGION, ECR_FALLBACK_REGISTRY),
+        Some(registry_id) => (region.as_ref(), *registry_id),
+    };
+    format!("{}.dkr.ecr.{}.amazonaws.com", registry_id, region)
+}
+
+/// Constructs the fully qualified domain name for the pause container (pod infra
+/// container) for the given region. Returns a default if the region is not mapped.
+fn pause_registry<S: AsRef<str>>(region: S) -> String {
+    // lookup the registry ID or fallback to the default region and id
+    let (region, registry_id) = match PAUSE_CONTAINER_MAP.borrow().get(region.as_ref()) {
+        None => (PAUSE_FALLBACK_REGION, PAUSE_FALLBACK_REGISTRY),
+        Some(registry_id) => (region.as_ref(), *registry_id),
+    };
+    format!("{}.dkr.ecr.{}.amazonaws.com", registry_id, region)
+}
+
+/// Calculates and returns the amount of CPU to reserve
+fn kube_cpu_helper(num_cores: usize) -> Result<String, TemplateHelperError> {
+    let num_cores =
+        u16::try_from(num_cores).context(error::ConvertUsizeToU16 { number: num_cores })?;
+    let millicores_unit = "m";
+    let cpu_to_reserve = match num_cores {
+        0 => 0.0,
+        1 => KUBE_RESERVE_1_CORE,
+        2 => KUBE_RESERVE_2_CORES,
+        3 => KUBE_RESERVE_3_CORES,
+        4 => KUBE_RESERVE_4_CORES,
+        _ => {
+            let num_cores = f32::from(num_cores);
+            KUBE_RESERVE_4_CORES + ((num_cores - 4.0) * KUBE_RESERVE_ADDITIONAL)
+        }
+    };
+    Ok(format!(
+        "{}{}",
+        cpu_to_reserve.floor().to_string(),
+        millicores_unit
+    ))
+}
+
+// =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
+
+#[cfg(test)]
+mod test_base64_decode {
+    use super::*;
+    use handlebars::RenderError;
+    use serde::Serialize;
+    use serde_json::json;
+
+    // A thin wrapper around the handlebars render_template method that includes
+    // setup and registration of helpers
+    fn setup_and_render_template<T>(tmpl: &str, data: &T) -> Result<String, RenderError>
+    where
+        T: Serialize,
+    {
+        let mut registry = Handlebars::new();
+        registry.register_helper("base64_decode", Box::new(base64_decode));
+
+        registry.render_template(tmpl, data)
+    }
+
+    #[test]
+    fn renders_decoded_base64() {
+        let result =
+            setup_and_render_template("{{base64_decode var}}", &json!({"var": "SGk="})).unwrap();
+        assert_eq!(result, "Hi")
+    }
+
+    #[test]
+    fn does_not_render_invalid_base64() {
+        assert!(setup_and_render_template("{{base64_decode var}}", &json!({"var": "hi"})).is_err())
+    }
+
+    #[test]
+    fn does_not_render_invalid_utf8() {
+        // "wygk" is the invalid UTF8 string "\xc3\x28" base64 encoded
+        assert!(
+            setup_and_render_template("{{base64_decode var}}", &json!({"var": "wygK"})).is_err()
+        )
+    }
+
+    #[test]
+    fn base64_helper_with_missing_param() {
+        assert!(setup_and_render_template("{{base64_decode}}", &json!({"var": "foo"})).is_err());
+    }
 
     #[test]
     fn base64_helper_with_extra_param() {
@@ -1280,6 +1795,515 @@ mod test_default {
     }
 }
 
+#[cfg(test)]
+mod test_toml_string {
+    use super::*;
+    use handlebars::RenderError;
+    use serde::Serialize;
+    use serde_json::json;
+
+    // A thin wrapper around the handlebars render_template method that includes
+    // setup and registration of helpers
+    fn setup_and_render_template<T>(tmpl: &str, data: &T) -> Result<String, RenderError>
+    where
+        T: Serialize,
+    {
+        let mut registry = Handlebars::new();
+        registry.register_helper("toml_string", Box::new(toml_string));
+
+        registry.render_template(tmpl, data)
+    }
+
+    #[test]
+    fn plain() {
+        let result =
+            setup_and_render_template("{{toml_string s}}", &json!({"s": "hi there"})).unwrap();
+        assert_eq!(result, r#""hi there""#)
+    }
+
+    #[test]
+    fn quotes_and_backslashes() {
+        let result =
+            setup_and_render_template("{{toml_string s}}", &json!({"s": r#"a"b\c"#})).unwrap();
+        assert_eq!(result, r#""a\"b\\c""#)
+    }
+
+    #[test]
+    fn control_characters() {
+        let result =
+            setup_and_render_template("{{toml_string s}}", &json!({"s": "a\nb\tc\u{1}\u{7f}"}))
+                .unwrap();
+        assert_eq!(result, r#""a\nb\tc\u0001\u007F""#)
+    }
+
+    #[test]
+    fn parses_as_toml() {
+        let value = "x\" = 1\n[evil]\ny = \"\\";
+        let result =
+            setup_and_render_template("key = {{toml_string s}}", &json!({ "s": value })).unwrap();
+        let parsed: toml::Value = toml::from_str(&result).unwrap();
+        assert_eq!(parsed["key"].as_str(), Some(value));
+    }
+
+    #[test]
+    fn number() {
+        let result = setup_and_render_template("{{toml_string n}}", &json!({"n": 42})).unwrap();
+        assert_eq!(result, r#""42""#)
+    }
+
+    #[test]
+    fn map_fails() {
+        setup_and_render_template("{{toml_string m}}", &json!({"m": {"a": "b"}})).unwrap_err();
+    }
+}
+
+#[cfg(test)]
+mod test_json {
+    use super::*;
+    use handlebars::RenderError;
+    use serde::Serialize;
+    use serde_json::json;
+
+    // A thin wrapper around the handlebars render_template method that includes
+    // setup and registration of helpers
+    fn setup_and_render_template<T>(tmpl: &str, data: &T) -> Result<String, RenderError>
+    where
+        T: Serialize,
+    {
+        let mut registry = Handlebars::new();
+        registry.register_helper("json", Box::new(json));
+
+        registry.render_template(tmpl, data)
+    }
+
+    #[test]
+    fn string() {
+        let result = setup_and_render_template("{{json s}}", &json!({"s": "a\"b"})).unwrap();
+        assert_eq!(result, r#""a\"b""#)
+    }
+
+    #[test]
+    fn array() {
+        let result = setup_and_render_template("{{json a}}", &json!({"a": ["x", "y\"z"]})).unwrap();
+        assert_eq!(result, r#"["x","y\"z"]"#)
+    }
+
+    #[test]
+    fn map() {
+        let result =
+            setup_and_render_template("{{json m}}", &json!({"m": {"a": 1, "b": true}})).unwrap();
+        assert_eq!(result, r#"{"a":1,"b":true}"#)
+    }
+
+    #[test]
+    fn array_parses_as_toml() {
+        let result =
+            setup_and_render_template("key = {{json a}}", &json!({"a": ["x", "y\"z\n"]})).unwrap();
+        let parsed: toml::Value = toml::from_str(&result).unwrap();
+        assert_eq!(parsed["key"][1].as_str(), Some("y\"z\n"));
+    }
+}
+
+#[cfg(test)]
+mod test_yaml_string {
+    use super::*;
+    use handlebars::RenderError;
+    use serde::Serialize;
+    use serde_json::json;
+
+    // A thin wrapper around the handlebars render_template method that includes
+    // setup and registration of helpers
+    fn setup_and_render_template<T>(tmpl: &str, data: &T) -> Result<String, RenderError>
+    where
+        T: Serialize,
+    {
+        let mut registry = Handlebars::new();
+        registry.register_helper("yaml_string", Box::new(yaml_string));
+
+        registry.render_template(tmpl, data)
+    }
+
+    #[test]
+    fn plain() {
+        let result =
+            setup_and_render_template("{{yaml_string s}}", &json!({"s": "hi: there"})).unwrap();
+        assert_eq!(result, r#""hi: there""#)
+    }
+
+    #[test]
+    fn quotes_and_backslashes() {
+        let result =
+            setup_and_render_template("{{yaml_string s}}", &json!({"s": r#"a"b\c"#})).unwrap();
+        assert_eq!(result, r#""a\"b\\c""#)
+    }
+
+    #[test]
+    fn line_breaks() {
+        let result = setup_and_render_template(
+            "{{yaml_string s}}",
+            &json!({"s": "a\nb\u{85}c\u{2028}d\u{1b}"}),
+        )
+        .unwrap();
+        assert_eq!(result, r#""a\nb\Nc\Ld\x1B""#)
+    }
+
+    #[test]
+    fn boolean_stays_string() {
+        let result = setup_and_render_template("{{yaml_string b}}", &json!({"b": true})).unwrap();
+        assert_eq!(result, r#""true""#)
+    }
+
+    #[test]
+    fn array_fails() {
+        setup_and_render_template("{{yaml_string a}}", &json!({"a": ["x"]})).unwrap_err();
+    }
+}
+
+#[cfg(test)]
+mod test_shell_quote {
+    use super::*;
+    use handlebars::RenderError;
+    use serde::Serialize;
+    use serde_json::json;
+
+    // A thin wrapper around the handlebars render_template method that includes
+    // setup and registration of helpers
+    fn setup_and_render_template<T>(tmpl: &str, data: &T) -> Result<String, RenderError>
+    where
+        T: Serialize,
+    {
+        let mut registry = Handlebars::new();
+        registry.register_helper("shell_quote", Box::new(shell_quote));
+
+        registry.render_template(tmpl, data)
+    }
+
+    #[test]
+    fn plain() {
+        let result =
+            setup_and_render_template("{{shell_quote s}}", &json!({"s": "a b $c"})).unwrap();
+        assert_eq!(result, "'a b $c'")
+    }
+
+    #[test]
+    fn single_quotes() {
+        let result = setup_and_render_template("{{shell_quote s}}", &json!({"s": "it's"})).unwrap();
+        assert_eq!(result, r#"'it'\''s'"#)
+    }
+
+    #[test]
+    fn empty() {
+        let result = setup_and_render_template("{{shell_quote s}}", &json!({"s": ""})).unwrap();
+        assert_eq!(result, "''")
+    }
+
+    #[test]
+    fn missing_fails() {
+        setup_and_render_template("{{shell_quote s}}", &json!({})).unwrap_err();
+    }
+}
+
+#[cfg(test)]
+mod test_indent {
+    use super::*;
+    use handlebars::RenderError;
+    use serde::Serialize;
+    use serde_json::json;
+
+    // A thin wrapper around the handlebars render_template method that includes
+    // setup and registration of helpers
+    fn setup_and_render_template<T>(tmpl: &str, data: &T) -> Result<String, RenderError>
+    where
+        T: Serialize,
+    {
+        let mut registry = Handlebars::new();
+        registry.register_helper("indent", Box::new(indent));
+        registry.register_helper("json", Box::new(json));
+
+        registry.render_template(tmpl, data)
+    }
+
+    #[test]
+    fn lines() {
+        let result =
+            setup_and_render_template("{{indent 2 s}}", &json!({"s": "a\nb\n\nc\n"})).unwrap();
+        assert_eq!(result, "  a\n  b\n\n  c\n")
+    }
+
+    #[test]
+    fn zero() {
+        let result = setup_and_render_template("{{indent 0 s}}", &json!({"s": "a\nb"})).unwrap();
+        assert_eq!(result, "a\nb")
+    }
+
+    #[test]
+    fn subexpression() {
+        let result =
+            setup_and_render_template("{{indent 4 (json s)}}", &json!({"s": "a"})).unwrap();
+        assert_eq!(result, r#"    "a""#)
+    }
+
+    #[test]
+    fn negative_fails() {
+        setup_and_render_template("{{indent -1 s}}", &json!({"s": "a"})).unwrap_err();
+    }
+
+    #[test]
+    fn bad_param_count() {
+        setup_and_render_template("{{indent s}}", &json!({"s": "a"})).unwrap_err();
+    }
+}
+
+#[cfg(test)]
+mod test_cidr_host {
+    use super::*;
+    use handlebars::RenderError;
+    use serde::Serialize;
+    use serde_json::json;
+
+    // A thin wrapper around the handlebars render_template method that includes
+    // setup and registration of helpers
+    fn setup_and_render_template<T>(tmpl: &str, data: &T) -> Result<String, RenderError>
+    where
+        T: Serialize,
+    {
+        let mut registry = Handlebars::new();
+        registry.register_helper("cidr_host", Box::new(cidr_host));
+
+        registry.render_template(tmpl, data)
+    }
+
+    #[test]
+    fn ipv4() {
+        let result =
+            setup_and_render_template("{{cidr_host cidr 10}}", &json!({"cidr": "10.100.0.0/16"}))
+                .unwrap();
+        assert_eq!(result, "10.100.0.10")
+    }
+
+    #[test]
+    fn ipv4_uses_network_address() {
+        let result =
+            setup_and_render_template("{{cidr_host cidr 1}}", &json!({"cidr": "172.20.5.7/16"}))
+                .unwrap();
+        assert_eq!(result, "172.20.0.1")
+    }
+
+    #[test]
+    fn ipv6() {
+        let result =
+            setup_and_render_template("{{cidr_host cidr 10}}", &json!({"cidr": "fd00:1::/108"}))
+                .unwrap();
+        assert_eq!(result, "fd00:1::a")
+    }
+
+    #[test]
+    fn last_host() {
+        let result =
+            setup_and_render_template("{{cidr_host cidr 255}}", &json!({"cidr": "10.0.0.0/24"}))
+                .unwrap();
+        assert_eq!(result, "10.0.0.255")
+    }
+
+    #[test]
+    fn out_of_range() {
+        setup_and_render_template("{{cidr_host cidr 256}}", &json!({"cidr": "10.0.0.0/24"}))
+            .unwrap_err();
+    }
+
+    #[test]
+    fn invalid_cidr() {
+        setup_and_render_template("{{cidr_host cidr 1}}", &json!({"cidr": "10.0.0.1"}))
+            .unwrap_err();
+    }
+}
+
+#[cfg(test)]
+mod test_ip_family {
+    use super::*;
+    use handlebars::RenderError;
+    use serde::Serialize;
+    use serde_json::json;
+
+    // A thin wrapper around the handlebars render_template method that includes
+    // setup and registration of helpers
+    fn setup_and_render_template<T>(tmpl: &str, data: &T) -> Result<String, RenderError>
+    where
+        T: Serialize,
+    {
+        let mut registry = Handlebars::new();
+        registry.register_helper("ip_family", Box::new(ip_family));
+
+        registry.render_template(tmpl, data)
+    }
+
+    #[test]
+    fn addresses() {
+        let data = json!({"a": "10.0.0.1", "b": "fd00::1"});
+        let result = setup_and_render_template("{{ip_family a}} {{ip_family b}}", &data).unwrap();
+        assert_eq!(result, "ipv4 ipv6")
+    }
+
+    #[test]
+    fn cidrs() {
+        let data = json!({"a": "10.0.0.0/8", "b": "fd00::/64"});
+        let result = setup_and_render_template("{{ip_family a}} {{ip_family b}}", &data).unwrap();
+        assert_eq!(result, "ipv4 ipv6")
+    }
+
+    #[test]
+    fn hostname_fails() {
+        setup_and_render_template("{{ip_family a}}", &json!({"a": "example.com"})).unwrap_err();
+    }
+}
+
+#[cfg(test)]
+mod test_in_cidr {
+    use super::*;
+    use handlebars::RenderError;
+    use serde::Serialize;
+    use serde_json::json;
+
+    // A thin wrapper around the handlebars render_template method that includes
+    // setup and registration of helpers
+    fn setup_and_render_template<T>(tmpl: &str, data: &T) -> Result<String, RenderError>
+    where
+        T: Serialize,
+    {
+        let mut registry = Handlebars::new();
+        registry.register_helper("in_cidr", Box::new(InCidr));
+
+        registry.render_template(tmpl, data)
+    }
+
+    const TEMPLATE: &str = "{{#if (in_cidr ip cidr)}}in{{else}}out{{/if}}";
+
+    #[test]
+    fn inside() {
+        let result =
+            setup_and_render_template(TEMPLATE, &json!({"ip": "10.1.2.3", "cidr": "10.0.0.0/8"}))
+                .unwrap();
+        assert_eq!(result, "in")
+    }
+
+    #[test]
+    fn outside() {
+        let result = setup_and_render_template(
+            TEMPLATE,
+            &json!({"ip": "172.20.0.10", "cidr": "10.0.0.0/8"}),
+        )
+        .unwrap();
+        assert_eq!(result, "out")
+    }
+
+    #[test]
+    fn other_family() {
+        let result =
+            setup_and_render_template(TEMPLATE, &json!({"ip": "fd00::1", "cidr": "10.0.0.0/8"}))
+                .unwrap();
+        assert_eq!(result, "out")
+    }
+
+    #[test]
+    fn renders_bool() {
+        let result = setup_and_render_template(
+            "{{in_cidr ip cidr}}",
+            &json!({"ip": "fd00::1", "cidr": "fd00::/64"}),
+        )
+        .unwrap();
+        assert_eq!(result, "true")
+    }
+
+    #[test]
+    fn invalid_ip() {
+        setup_and_render_template(TEMPLATE, &json!({"ip": "nope", "cidr": "10.0.0.0/8"}))
+            .unwrap_err();
+    }
+}
+
+#[cfg(test)]
+mod test_cidr_netmask {
+    use super::*;
+    use handlebars::RenderError;
+    use serde::Serialize;
+    use serde_json::json;
+
+    // A thin wrapper around the handlebars render_template method that includes
+    // setup and registration of helpers
+    fn setup_and_render_template<T>(tmpl: &str, data: &T) -> Result<String, RenderError>
+    where
+        T: Serialize,
+    {
+        let mut registry = Handlebars::new();
+        registry.register_helper("cidr_netmask", Box::new(cidr_netmask));
+
+        registry.render_template(tmpl, data)
+    }
+
+    #[test]
+    fn ipv4() {
+        let result =
+            setup_and_render_template("{{cidr_netmask c}}", &json!({"c": "10.100.0.0/20"}))
+                .unwrap();
+        assert_eq!(result, "255.255.240.0")
+    }
+
+    #[test]
+    fn ipv6() {
+        let result =
+            setup_and_render_template("{{cidr_netmask c}}", &json!({"c": "fd00::/64"})).unwrap();
+        assert_eq!(result, "ffff:ffff:ffff:ffff::")
+    }
+}
+
+#[cfg(test)]
+mod test_bracket_ipv6 {
+    use super::*;
+    use handlebars::RenderError;
+    use serde::Serialize;
+    use serde_json::json;
+
+    // A thin wrapper around the handlebars render_template method that includes
+    // setup and registration of helpers
+    fn setup_and_render_template<T>(tmpl: &str, data: &T) -> Result<String, RenderError>
+    where
+        T: Serialize,
+    {
+        let mut registry = Handlebars::new();
+        registry.register_helper("bracket_ipv6", Box::new(bracket_ipv6));
+
+        registry.render_template(tmpl, data)
+    }
+
+    #[test]
+    fn ipv6() {
+        let result =
+            setup_and_render_template("{{bracket_ipv6 h}}:443", &json!({"h": "fd00::1"})).unwrap();
+        assert_eq!(result, "[fd00::1]:443")
+    }
+
+    #[test]
+    fn ipv4() {
+        let result =
+            setup_and_render_template("{{bracket_ipv6 h}}:443", &json!({"h": "10.0.0.1"})).unwrap();
+        assert_eq!(result, "10.0.0.1:443")
+    }
+
+    #[test]
+    fn hostname() {
+        let result =
+            setup_and_render_template("{{bracket_ipv6 h}}", &json!({"h": "example.com"})).unwrap();
+        assert_eq!(result, "example.com")
+    }
+
+    #[test]
+    fn already_bracketed() {
+        let result =
+            setup_and_render_template("{{bracket_ipv6 h}}", &json!({"h": "[fd00::1]"})).unwrap();
+        assert_eq!(result, "[fd00::1]")
+    }
+}
+
 #[cfg(test)]
 mod test_ecr_registry {
     use super::*;
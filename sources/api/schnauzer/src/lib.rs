@@ -1,17 +1,31 @@
 /// The schnauzer library can be used to render file- or string-based templates that contain
 /// settings references, e.g. "foo-{{ settings.bar }}", and contains common helper functions for
 /// use inside the templates.
+///
+/// Templates can include shared snippets with Handlebars partials, e.g. "{{> no-proxy}}".  Each
+/// partial is a file named with a ".partial" extension in PARTIALS_DIR, and is registered under
+/// its file name without the extension.
 
 #[macro_use]
 extern crate log;
 
 mod helpers;
+#[cfg(feature = "lint")]
+pub mod lint;
+pub mod references;
+pub mod render;
 
 use handlebars::Handlebars;
 use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
 use serde::de::DeserializeOwned;
 use snafu::ResultExt;
-use std::path::Path;
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The directory of partial templates that `build_template_registry` registers.
+pub const PARTIALS_DIR: &str = "/usr/share/templates/partials";
+const PARTIAL_EXTENSION: &str = "partial";
 
 // https://url.spec.whatwg.org/#query-percent-encode-set
 const ENCODE_QUERY_CHARS: &AsciiSet = &CONTROLS.add(b' ').add(b'"').add(b'#').add(b'<').add(b'>');
@@ -49,6 +63,24 @@ pub mod error {
             uri: String,
             source: serde_json::Error,
         },
+
+        #[snafu(display("Failed to list partials in '{}': {}", dir.display(), source))]
+        ListPartials {
+            dir: std::path::PathBuf,
+            source: std::io::Error,
+        },
+
+        #[snafu(display("Failed to read partial '{}': {}", path.display(), source))]
+        ReadPartial {
+            path: std::path::PathBuf,
+            source: std::io::Error,
+        },
+
+        #[snafu(display("Invalid partial '{}': {}", path.display(), source))]
+        RegisterPartial {
+            path: std::path::PathBuf,
+            source: handlebars::TemplateError,
+        },
     }
 }
 pub use error::Error;
@@ -136,10 +168,56 @@ pub fn build_template_registry() -> Result<handlebars::Handlebars<'static>> {
         "add_unresolvable_hostname",
         Box::new(helpers::add_unresolvable_hostname),
     );
+    template_registry.register_helper("toml_string", Box::new(helpers::toml_string));
+    template_registry.register_helper("json", Box::new(helpers::json));
+    template_registry.register_helper("yaml_string", Box::new(helpers::yaml_string));
+    template_registry.register_helper("shell_quote", Box::new(helpers::shell_quote));
+    template_registry.register_helper("indent", Box::new(helpers::indent));
+    template_registry.register_helper("cidr_host", Box::new(helpers::cidr_host));
+    template_registry.register_helper("ip_family", Box::new(helpers::ip_family));
+    template_registry.register_helper("in_cidr", Box::new(helpers::InCidr));
+    template_registry.register_helper("cidr_netmask", Box::new(helpers::cidr_netmask));
+    template_registry.register_helper("bracket_ipv6", Box::new(helpers::bracket_ipv6));
+
+    // Partials are installed with the templates that use them, so there may be none.
+    let partials_dir = Path::new(PARTIALS_DIR);
+    if partials_dir.is_dir() {
+        register_partials(&mut template_registry, partials_dir)?;
+    }
 
     Ok(template_registry)
 }
 
+/// Registers each partial template in the given directory, returning their names.
+pub fn register_partials(registry: &mut Handlebars<'_>, dir: &Path) -> Result<Vec<String>> {
+    let mut names = Vec::new();
+    for (name, path) in partial_files(dir)? {
+        let partial = fs::read_to_string(&path).context(error::ReadPartial { path: &path })?;
+        registry
+            .register_partial(&name, partial)
+            .context(error::RegisterPartial { path: &path })?;
+        names.push(name);
+    }
+    Ok(names)
+}
+
+/// Lists the partial template files in the given directory, with the name of each partial, sorted
+/// by name.
+pub(crate) fn partial_files(dir: &Path) -> Result<Vec<(String, PathBuf)>> {
+    let mut partials = Vec::new();
+    for entry in fs::read_dir(dir).context(error::ListPartials { dir })? {
+        let path = entry.context(error::ListPartials { dir })?.path();
+        if path.extension() != Some(OsStr::new(PARTIAL_EXTENSION)) || !path.is_file() {
+            continue;
+        }
+        if let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) {
+            partials.push((name.to_string(), path.clone()));
+        }
+    }
+    partials.sort();
+    Ok(partials)
+}
+
 #[cfg(test)]
 mod test {
     use handlebars::Handlebars;
@@ -0,0 +1,1146 @@
+//! The lint module renders every configuration file template of a variant against the variant's
+//! default settings, so that template problems are found at build time rather than when
+//! thar-be-settings renders them on a host.
+//!
+//! The defaults are loaded from a `defaults.d` directory and merged in filename order, the same
+//! way storewolf builds its defaults.  Settings that are normally generated at runtime can't be
+//! known in advance; those generated by schnauzer are rendered from their `template` metadata, and
+//! others can be given in additional settings files, in the same TOML form as user data.
+//!
+//! Partial templates are loaded from the template directories, and a template's settings include
+//! those used by the partials it includes.
+
+use crate::references::references;
+use crate::render::find_template;
+use crate::{build_template_registry, partial_files};
+use handlebars::template::Template;
+use handlebars::RenderError;
+use serde_json::{json, Map, Value};
+use snafu::ResultExt;
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error as _;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+/// Describes what to lint.
+#[derive(Debug)]
+pub struct LintConfig {
+    /// Directory of TOML defaults files, e.g. a variant's `defaults.d`.
+    pub defaults_dir: PathBuf,
+    /// TOML files with settings to merge over the defaults, in order.
+    pub settings_files: Vec<PathBuf>,
+    /// Directories to search, in order, for the files named by each `template-path`.
+    pub template_dirs: Vec<PathBuf>,
+    /// The variant and architecture to use for the `os` section of the model.
+    pub variant_id: String,
+    pub arch: String,
+}
+
+/// The kinds of problems the linter can find.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProblemKind {
+    MissingKey,
+    UnknownHelper,
+    InvalidTemplate,
+    RenderFailure,
+    MissingTemplateFile,
+    UnknownService,
+    UnknownConfigurationFile,
+    InvalidMetadata,
+    MissingAffectedService,
+    MissingPartial,
+    PartialConflict,
+}
+
+impl fmt::Display for ProblemKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            ProblemKind::MissingKey => "missing key",
+            ProblemKind::UnknownHelper => "unknown helper",
+            ProblemKind::InvalidTemplate => "invalid template",
+            ProblemKind::RenderFailure => "render failure",
+            ProblemKind::MissingTemplateFile => "missing template file",
+            ProblemKind::UnknownService => "unknown service",
+            ProblemKind::UnknownConfigurationFile => "unknown configuration file",
+            ProblemKind::InvalidMetadata => "invalid metadata",
+            ProblemKind::MissingAffectedService => "missing affected service",
+            ProblemKind::MissingPartial => "missing partial",
+            ProblemKind::PartialConflict => "partial conflict",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// A single problem found by the linter.  `location` is the name of the configuration file,
+/// service, or metadata entry that has the problem.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Problem {
+    pub location: String,
+    pub kind: ProblemKind,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}: {}", self.location, self.kind, self.message)
+    }
+}
+
+/// A metadata entry from the defaults, e.g. "affected-services" for "settings.motd".
+#[derive(Debug)]
+struct Metadata {
+    key: String,
+    /// The segments of `key`, for matching against the settings referenced by templates.
+    segments: Vec<String>,
+    md: String,
+    val: toml::Value,
+}
+
+/// Lints the templates and metadata described by the given config, returning any problems found.
+/// Returns Err only if the inputs couldn't be loaded, for example if the defaults don't match the
+/// API model.
+pub fn lint(config: &LintConfig) -> Result<Vec<Problem>> {
+    let (table, metadata) = load_settings(&config.defaults_dir, &config.settings_files)?;
+    let mut data = serde_json::to_value(&table).context(error::Convert)?;
+    data["os"] = json!({
+        "pretty_name": format!("Bottlerocket OS ({})", config.variant_id),
+        "variant_id": config.variant_id,
+        "version_id": "0.0.0",
+        "build_id": "lint",
+        "arch": config.arch,
+    });
+
+    let mut registry = build_template_registry().context(error::BuildRegistry)?;
+    let mut problems = Vec::new();
+
+    let partials = load_partials(&config.template_dirs, &mut problems)?;
+    for (name, partial) in &partials {
+        registry.register_template(name, partial.clone());
+    }
+
+    let generators: BTreeMap<&str, &str> = metadata
+        .iter()
+        .filter(|m| m.md == "setting-generator")
+        .filter_map(|m| {
+            // A generator is a command, or a table with the command and how to run it.
+            m.val
+                .as_str()
+                .or_else(|| m.val.get("command").and_then(|c| c.as_str()))
+                .map(|command| (m.key.as_str(), command))
+        })
+        .collect();
+    generate_settings(&registry, &metadata, &generators, &mut data, &mut problems);
+
+    let model: model::Model = serde_json::from_value(data).context(error::Model)?;
+    // Sorted, so problems are reported in a stable order.
+    let files: BTreeMap<&str, _> = model
+        .configuration_files
+        .iter()
+        .flatten()
+        .map(|(name, file)| (name.as_str(), file))
+        .collect();
+    let services: BTreeMap<&str, _> = model
+        .services
+        .iter()
+        .flatten()
+        .map(|(name, service)| (name.as_str(), service))
+        .collect();
+
+    // Render each configuration file.
+    for (name, file) in &files {
+        let location = format!("configuration-files.{}", name);
+        let template_path = Path::new(file.template_path.as_ref());
+        let (template_file, template) =
+            match read_template(&config.template_dirs, &location, template_path)? {
+                Ok(found) => found,
+                Err(problem) => {
+                    problems.push(problem);
+                    continue;
+                }
+            };
+
+        if let Err(e) = registry.register_template_string(name, template) {
+            problems.push(Problem {
+                location,
+                kind: ProblemKind::InvalidTemplate,
+                message: format!("{}: {}", template_file.display(), e),
+            });
+            continue;
+        }
+        if let Err(e) = registry.render(name, &model) {
+            problems.push(render_problem(location, &e, &generators));
+        }
+    }
+
+    // Any problem with a template that isn't registered was reported above.
+    let service_files = services
+        .iter()
+        .map(|(name, service)| {
+            let files = service.configuration_files.iter().map(|f| f.as_ref());
+            (*name, files.collect())
+        })
+        .collect();
+    let templates = files
+        .keys()
+        .filter_map(|name| registry.get_template(name).map(|t| (*name, t)))
+        .collect();
+    problems.extend(partial_problems(&templates, &partials));
+    problems.extend(affected_service_problems(
+        &table,
+        &metadata,
+        &service_files,
+        &templates,
+        &partials,
+    ));
+
+    // Make sure metadata and services only refer to things that exist.
+    for md in metadata.iter().filter(|m| m.md == "affected-services") {
+        let location = format!("metadata.{}.affected-services", md.key);
+        let names = match md.val.as_array() {
+            Some(names) => names,
+            None => {
+                problems.push(Problem {
+                    location,
+                    kind: ProblemKind::InvalidMetadata,
+                    message: "expected a list of service names".to_string(),
+                });
+                continue;
+            }
+        };
+        for name in names {
+            let service = name.as_str().unwrap_or_default();
+            if !services.contains_key(service) {
+                problems.push(Problem {
+                    location: location.clone(),
+                    kind: ProblemKind::UnknownService,
+                    message: format!("'{}' is not a known service", service),
+                });
+            }
+        }
+    }
+
+    for (name, service) in &services {
+        for file in &service.configuration_files {
+            if !files.contains_key(file.as_ref() as &str) {
+                problems.push(Problem {
+                    location: format!("services.{}", name),
+                    kind: ProblemKind::UnknownConfigurationFile,
+                    message: format!("'{}' is not a known configuration file", file),
+                });
+            }
+        }
+    }
+
+    Ok(problems)
+}
+
+/// Checks that each setting used by a configuration file's template lists, in its
+/// `affected-services` metadata, every service that uses the file.  Otherwise, changing the
+/// setting doesn't render the file again or restart the service, and the file silently goes stale.
+///
+/// Unlike `lint`, this only parses templates rather than rendering them, so it doesn't need values
+/// for settings that are generated at runtime, and it doesn't depend on the API model the crate
+/// was built for.  The defaults and settings files are loaded as they are for `lint`.
+pub fn check_affected_services(
+    defaults_dir: &Path,
+    settings_files: &[PathBuf],
+    template_dirs: &[PathBuf],
+) -> Result<Vec<Problem>> {
+    let (table, metadata) = load_settings(defaults_dir, settings_files)?;
+    let mut problems = Vec::new();
+    let partials = load_partials(template_dirs, &mut problems)?;
+
+    let service_files: BTreeMap<&str, Vec<&str>> = table_entries(&table, "services")
+        .map(|(name, service)| {
+            let files = service
+                .get("configuration-files")
+                .and_then(|files| files.as_array())
+                .map(|files| files.iter().filter_map(|f| f.as_str()).collect())
+                .unwrap_or_default();
+            (name, files)
+        })
+        .collect();
+
+    let mut compiled = Vec::new();
+    for (name, file) in table_entries(&table, "configuration-files") {
+        let location = format!("configuration-files.{}", name);
+        let template_path = match file.get("template-path").and_then(|p| p.as_str()) {
+            Some(template_path) => Path::new(template_path),
+            None => continue,
+        };
+        let (template_file, template) =
+            match read_template(template_dirs, &location, template_path)? {
+                Ok(found) => found,
+                Err(problem) => {
+                    problems.push(problem);
+                    continue;
+                }
+            };
+        match Template::compile(&template) {
+            Ok(template) => compiled.push((name, template)),
+            Err(e) => problems.push(Problem {
+                location,
+                kind: ProblemKind::InvalidTemplate,
+                message: format!("{}: {}", template_file.display(), e),
+            }),
+        }
+    }
+
+    let templates = compiled.iter().map(|(name, t)| (*name, t)).collect();
+    problems.extend(partial_problems(&templates, &partials));
+    problems.extend(affected_service_problems(
+        &table,
+        &metadata,
+        &service_files,
+        &templates,
+        &partials,
+    ));
+    Ok(problems)
+}
+
+/// Loads the defaults and any settings files, returning the merged settings and the metadata.
+fn load_settings(
+    defaults_dir: &Path,
+    settings_files: &[PathBuf],
+) -> Result<(toml::value::Table, Vec<Metadata>)> {
+    let mut defaults = load_defaults(defaults_dir)?;
+    for path in settings_files {
+        let settings = read_toml(path)?;
+        merge_toml::merge_values(&mut defaults, &settings).context(error::Merge { path })?;
+    }
+
+    let mut table = match defaults {
+        toml::Value::Table(table) => table,
+        _ => return error::DefaultsNotTable.fail(),
+    };
+    let metadata = match table.remove("metadata") {
+        Some(md) => parse_metadata(md),
+        None => Vec::new(),
+    };
+    Ok((table, metadata))
+}
+
+/// Iterates over the entries of a top-level table like "services", if it exists.
+fn table_entries<'a>(
+    table: &'a toml::value::Table,
+    name: &str,
+) -> impl Iterator<Item = (&'a str, &'a toml::Value)> {
+    table
+        .get(name)
+        .and_then(|t| t.as_table())
+        .into_iter()
+        .flatten()
+        .map(|(key, val)| (key.as_str(), val))
+}
+
+/// Finds and reads the template for a configuration file.  The inner Err is a problem to report,
+/// while the outer Err means we couldn't read a template file that exists.
+fn read_template(
+    dirs: &[PathBuf],
+    location: &str,
+    template_path: &Path,
+) -> Result<std::result::Result<(PathBuf, String), Problem>> {
+    let template_file = match find_template(dirs, template_path) {
+        Some(path) => path,
+        None => {
+            return Ok(Err(Problem {
+                location: location.to_string(),
+                kind: ProblemKind::MissingTemplateFile,
+                message: format!(
+                    "no file found in template directories for '{}'",
+                    template_path.display()
+                ),
+            }))
+        }
+    };
+    let template = fs::read_to_string(&template_file).context(error::ReadFile {
+        path: &template_file,
+    })?;
+    Ok(Ok((template_file, template)))
+}
+
+/// Finds the settings used by each template that don't list, in their `affected-services`, every
+/// service that uses the template's file.
+fn affected_service_problems(
+    table: &toml::value::Table,
+    metadata: &[Metadata],
+    service_files: &BTreeMap<&str, Vec<&str>>,
+    templates: &BTreeMap<&str, &Template>,
+    partials: &BTreeMap<String, Template>,
+) -> Vec<Problem> {
+    let affected = affected_services(metadata);
+    let mut problems = Vec::new();
+    for (name, file_services) in file_services(service_files) {
+        let template = match templates.get(name) {
+            Some(template) => template,
+            None => continue,
+        };
+        for reference in references(template, partials).settings {
+            if !defines(table, metadata, &reference) {
+                continue;
+            }
+            for service in &file_services {
+                if !covers(&affected, &reference, service) {
+                    problems.push(Problem {
+                        location: format!("configuration-files.{}", name),
+                        kind: ProblemKind::MissingAffectedService,
+                        message: format!(
+                            "uses '{}', but '{}' isn't in its affected-services, so the file won't be updated when it changes",
+                            reference.join("."),
+                            service
+                        ),
+                    });
+                }
+            }
+        }
+    }
+    problems
+}
+
+/// Loads the partial templates in the template directories.  If more than one directory has a
+/// partial with the same name, the first is used, as with templates.
+fn load_partials(
+    dirs: &[PathBuf],
+    problems: &mut Vec<Problem>,
+) -> Result<BTreeMap<String, Template>> {
+    let mut partials = BTreeMap::new();
+    for dir in dirs.iter().filter(|dir| dir.is_dir()) {
+        for (name, path) in partial_files(dir).context(error::ListPartials)? {
+            if partials.contains_key(&name) {
+                continue;
+            }
+            let partial = fs::read_to_string(&path).context(error::ReadFile { path: &path })?;
+            match Template::compile_with_name(&partial, name.clone()) {
+                Ok(template) => {
+                    partials.insert(name, template);
+                }
+                Err(e) => problems.push(Problem {
+                    location: format!("partials.{}", name),
+                    kind: ProblemKind::InvalidTemplate,
+                    message: format!("{}: {}", path.display(), e),
+                }),
+            }
+        }
+    }
+    Ok(partials)
+}
+
+/// Finds partials that templates include but that don't exist, which Handlebars would silently
+/// render as nothing, and partials named like a configuration file, which would be replaced by the
+/// file's template when thar-be-settings registers it.
+fn partial_problems(
+    templates: &BTreeMap<&str, &Template>,
+    partials: &BTreeMap<String, Template>,
+) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    for (name, template) in templates {
+        let location = format!("configuration-files.{}", name);
+        if partials.contains_key(*name) {
+            problems.push(Problem {
+                location: location.clone(),
+                kind: ProblemKind::PartialConflict,
+                message: format!("a partial is also named '{}'", name),
+            });
+        }
+        let references = references(template, partials);
+        for partial in references.partials.difference(&references.inline) {
+            if !partials.contains_key(partial) {
+                problems.push(Problem {
+                    location: location.clone(),
+                    kind: ProblemKind::MissingPartial,
+                    message: format!("includes '{}', but no partial has that name", partial),
+                });
+            }
+        }
+    }
+    problems
+}
+
+/// Checks whether the defaults mention the top-level setting containing `reference`, like
+/// "settings.kubernetes" for "settings.kubernetes.api-server".  Templates shared between variants
+/// can refer to settings that some variants don't have at all, and those never change.
+fn defines(table: &toml::value::Table, metadata: &[Metadata], reference: &[String]) -> bool {
+    let top = match reference {
+        [settings, top, ..] if settings == "settings" => top,
+        _ => return true,
+    };
+    let in_settings = table
+        .get("settings")
+        .and_then(|settings| settings.as_table())
+        .map(|settings| settings.contains_key(top))
+        .unwrap_or(false);
+    in_settings
+        || metadata
+            .iter()
+            .any(|m| m.segments.starts_with(&reference[..2]))
+}
+
+/// Lists the directories of the packages a variant depends on, directly or through other
+/// packages, from the path dependencies in the Cargo.toml of each, so a variant's templates can be
+/// found without listing them.
+pub fn variant_template_dirs(variant_dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut dirs = BTreeSet::new();
+    package_dirs(variant_dir, &mut dirs)?;
+    Ok(dirs.into_iter().collect())
+}
+
+fn package_dirs(manifest_dir: &Path, dirs: &mut BTreeSet<PathBuf>) -> Result<()> {
+    let manifest = read_toml(&manifest_dir.join("Cargo.toml"))?;
+    let deps = ["dependencies", "build-dependencies"]
+        .iter()
+        .filter_map(|table| manifest.get(table).and_then(|deps| deps.as_table()))
+        .flatten();
+    for (_, dep) in deps {
+        let dir = match dep.get("path").and_then(|p| p.as_str()) {
+            Some(path) => manifest_dir.join(path),
+            None => continue,
+        };
+        // Some packages come from outside this repo.
+        if let Ok(dir) = fs::canonicalize(dir) {
+            if dirs.insert(dir.clone()) {
+                package_dirs(&dir, dirs)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Loads and merges the TOML files in the given directory, in filename order, so later files
+/// take precedence.
+fn load_defaults(dir: &Path) -> Result<toml::Value> {
+    let walker = WalkDir::new(dir)
+        .follow_links(true)
+        .min_depth(1)
+        .max_depth(1)
+        .sort_by(|a, b| a.file_name().cmp(b.file_name()))
+        .into_iter()
+        .filter_entry(|e| e.file_name().to_string_lossy().ends_with(".toml"));
+
+    let mut defaults = toml::Value::Table(toml::map::Map::new());
+    for entry in walker {
+        let entry = entry.context(error::ListFiles { dir })?;
+        let data = read_toml(entry.path())?;
+        merge_toml::merge_values(&mut defaults, &data)
+            .context(error::Merge { path: entry.path() })?;
+    }
+    Ok(defaults)
+}
+
+fn read_toml(path: &Path) -> Result<toml::Value> {
+    let data = fs::read_to_string(path).context(error::ReadFile { path })?;
+    toml::from_str(&data).context(error::InvalidToml { path })
+}
+
+/// Flattens the metadata table into a list of entries.  A table represents more data key
+/// segments, and any other value is the metadata value, with the last segment as its name.
+fn parse_metadata(md: toml::Value) -> Vec<Metadata> {
+    let mut metadata = Vec::new();
+    let mut to_process = vec![(Vec::new(), md)];
+    while let Some((path, value)) = to_process.pop() {
+        // Like storewolf, treat a setting generator table as a value, not more data keys.
+        let is_generator_table = value.is_table()
+            && path
+                .last()
+                .map(|k| k == "setting-generator")
+                .unwrap_or(false);
+        match value {
+            toml::Value::Table(table) if !is_generator_table => {
+                for (key, val) in table {
+                    let mut path = path.clone();
+                    path.push(key);
+                    to_process.push((path, val));
+                }
+            }
+            val => {
+                if let Some((md, key)) = path.split_last() {
+                    metadata.push(Metadata {
+                        key: key.join("."),
+                        segments: key.to_vec(),
+                        md: md.clone(),
+                        val,
+                    });
+                }
+            }
+        }
+    }
+    metadata.sort_by(|a, b| (&a.key, &a.md).cmp(&(&b.key, &b.md)));
+    metadata
+}
+
+/// Fills in settings that schnauzer would generate from their template metadata, if they don't
+/// already have a value.  This lints the setting templates, too.
+fn generate_settings(
+    registry: &handlebars::Handlebars<'_>,
+    metadata: &[Metadata],
+    generators: &BTreeMap<&str, &str>,
+    data: &mut Value,
+    problems: &mut Vec<Problem>,
+) {
+    for md in metadata.iter().filter(|m| m.md == "template") {
+        let is_schnauzer = generators
+            .get(md.key.as_str())
+            .map(|g| g.split_whitespace().next() == Some("schnauzer"))
+            .unwrap_or(false);
+        let segments: Vec<&str> = md.key.split('.').collect();
+        if !is_schnauzer || lookup(data, &segments).is_some() {
+            continue;
+        }
+
+        let location = format!("metadata.{}.template", md.key);
+        let template = match md.val.as_str() {
+            Some(template) => template,
+            None => {
+                problems.push(Problem {
+                    location,
+                    kind: ProblemKind::InvalidMetadata,
+                    message: "expected a template string".to_string(),
+                });
+                continue;
+            }
+        };
+        match registry.render_template(template, data) {
+            Ok(value) => insert(data, &segments, Value::String(value)),
+            Err(e) => problems.push(render_problem(location, &e, generators)),
+        }
+    }
+}
+
+fn lookup<'a>(data: &'a Value, segments: &[&str]) -> Option<&'a Value> {
+    segments
+        .iter()
+        .try_fold(data, |value, segment| value.get(segment))
+}
+
+fn insert(data: &mut Value, segments: &[&str], value: Value) {
+    if let Some((last, parents)) = segments.split_last() {
+        let mut current = data;
+        for segment in parents {
+            if !current.is_object() {
+                *current = Value::Object(Map::new());
+            }
+            current = current
+                .as_object_mut()
+                .unwrap()
+                .entry(segment.to_string())
+                .or_insert_with(|| Value::Object(Map::new()));
+        }
+        if let Some(map) = current.as_object_mut() {
+            map.insert(last.to_string(), value);
+        }
+    }
+}
+
+/// Inverts a map of service to configuration files, listing the services that use each file.
+fn file_services<'a>(
+    service_files: &BTreeMap<&'a str, Vec<&'a str>>,
+) -> BTreeMap<&'a str, Vec<&'a str>> {
+    let mut result: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+    for (service, files) in service_files {
+        for file in files {
+            result.entry(*file).or_default().push(*service);
+        }
+    }
+    result
+}
+
+/// Gets the key segments and service names of each valid `affected-services` entry.
+fn affected_services(metadata: &[Metadata]) -> Vec<(&[String], Vec<&str>)> {
+    metadata
+        .iter()
+        .filter(|m| m.md == "affected-services")
+        .filter_map(|m| {
+            let services = m
+                .val
+                .as_array()?
+                .iter()
+                .filter_map(|s| s.as_str())
+                .collect();
+            Some((m.segments.as_slice(), services))
+        })
+        .collect()
+}
+
+/// Checks whether a change to any data key under `reference` would affect `service`.
+///
+/// The API uses the `affected-services` entry of the longest prefix of a changed key, so the entry
+/// that applies to `reference` itself, and any entries for keys under it, must all list `service`.
+fn covers(affected: &[(&[String], Vec<&str>)], reference: &[String], service: &str) -> bool {
+    let applies = affected
+        .iter()
+        .filter(|(key, _)| reference.starts_with(key))
+        .max_by_key(|(key, _)| key.len());
+    let applies_ok = matches!(applies, Some((_, services)) if services.contains(&service));
+
+    let below_ok = affected
+        .iter()
+        .filter(|(key, _)| key.len() > reference.len() && key.starts_with(reference))
+        .all(|(_, services)| services.contains(&service));
+
+    applies_ok && below_ok
+}
+
+/// Classifies a rendering error into a problem.  Missing keys that are normally generated at
+/// runtime are called out, since the fix is to give a value rather than change the template.
+fn render_problem(location: String, e: &RenderError, generators: &BTreeMap<&str, &str>) -> Problem {
+    let position = match (e.line_no, e.column_no) {
+        (Some(line), Some(col)) => format!(" (line {}, column {})", line, col),
+        _ => String::new(),
+    };
+
+    if let Some(key) = missing_key(&e.desc) {
+        let generated = generators
+            .iter()
+            .find(|(setting, _)| key == **setting || key.starts_with(&format!("{}.", setting)));
+        let hint = match generated {
+            Some((_, generator)) => format!(
+                "; it's generated at runtime by '{}', so give a value in a settings file",
+                generator
+            ),
+            None => String::new(),
+        };
+        Problem {
+            location,
+            kind: ProblemKind::MissingKey,
+            message: format!("'{}' has no value{}{}", key, position, hint),
+        }
+    } else if e.desc.starts_with("Helper not defined") {
+        Problem {
+            location,
+            kind: ProblemKind::UnknownHelper,
+            message: format!("{}{}", e.desc, position),
+        }
+    } else {
+        // Helper failures only say which helper failed; the reason is in the error's source.
+        let message = match e.source() {
+            Some(source) => format!("{}: {}{}", e.desc, source, position),
+            None => format!("{}{}", e.desc, position),
+        };
+        Problem {
+            location,
+            kind: ProblemKind::RenderFailure,
+            message,
+        }
+    }
+}
+
+/// Pulls the variable name out of handlebars' strict mode error, which looks like:
+/// Variable "settings.motd" not found in strict mode.
+fn missing_key(desc: &str) -> Option<&str> {
+    let rest = desc.strip_prefix("Variable \"")?;
+    if !desc.ends_with("not found in strict mode.") {
+        return None;
+    }
+    rest.split('"').next()
+}
+
+mod error {
+    use snafu::Snafu;
+    use std::path::PathBuf;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility = "pub(super)")]
+    pub enum Error {
+        #[snafu(display("Failed to build template registry: {}", source))]
+        BuildRegistry { source: crate::Error },
+
+        #[snafu(display("Failed to convert defaults to JSON: {}", source))]
+        Convert { source: serde_json::Error },
+
+        #[snafu(display("Defaults are not a TOML table"))]
+        DefaultsNotTable,
+
+        #[snafu(display("Invalid TOML in '{}': {}", path.display(), source))]
+        InvalidToml {
+            path: PathBuf,
+            source: toml::de::Error,
+        },
+
+        #[snafu(display("Failed to list partials: {}", source))]
+        ListPartials { source: crate::Error },
+
+        #[snafu(display("Failed to list files in '{}': {}", dir.display(), source))]
+        ListFiles {
+            dir: PathBuf,
+            source: walkdir::Error,
+        },
+
+        #[snafu(display("Failed to merge '{}' into defaults: {}", path.display(), source))]
+        Merge {
+            path: PathBuf,
+            source: merge_toml::Error,
+        },
+
+        #[snafu(display("Defaults don't match the API model: {}", source))]
+        Model { source: serde_json::Error },
+
+        #[snafu(display("Failed to read '{}': {}", path.display(), source))]
+        ReadFile {
+            path: PathBuf,
+            source: std::io::Error,
+        },
+    }
+}
+pub use error::Error;
+pub type Result<T> = std::result::Result<T, error::Error>;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::TempDir;
+
+    const DEFAULTS: &str = r#"
+[settings]
+motd = "hello"
+
+[services.motd]
+configuration-files = ["motd"]
+restart-commands = []
+
+[configuration-files.motd]
+path = "/etc/motd"
+template-path = "/usr/share/templates/motd"
+
+[metadata.settings.motd]
+affected-services = ["motd"]
+"#;
+
+    /// Writes the given defaults and templates to a temporary directory and lints them.
+    fn lint_with(defaults: &[(&str, &str)], templates: &[(&str, &str)]) -> Vec<Problem> {
+        let tmp = TempDir::new().unwrap();
+        let defaults_dir = tmp.path().join("defaults.d");
+        let template_dir = tmp.path().join("templates");
+        fs::create_dir(&defaults_dir).unwrap();
+        fs::create_dir(&template_dir).unwrap();
+        for (name, data) in defaults {
+            fs::write(defaults_dir.join(name), data).unwrap();
+        }
+        for (name, data) in templates {
+            fs::write(template_dir.join(name), data).unwrap();
+        }
+
+        lint(&LintConfig {
+            defaults_dir,
+            settings_files: Vec::new(),
+            template_dirs: vec![template_dir],
+            variant_id: "test".to_string(),
+            arch: "x86_64".to_string(),
+        })
+        .unwrap()
+    }
+
+    fn kinds(problems: &[Problem]) -> Vec<ProblemKind> {
+        problems.iter().map(|p| p.kind).collect()
+    }
+
+    #[test]
+    fn clean() {
+        let problems = lint_with(
+            &[("10-defaults.toml", DEFAULTS)],
+            &[("motd", "{{settings.motd}} from {{os.variant_id}}")],
+        );
+        assert!(problems.is_empty(), "{:?}", problems);
+    }
+
+    #[test]
+    fn template_suffix() {
+        let problems = lint_with(
+            &[("10-defaults.toml", DEFAULTS)],
+            &[("motd.template", "{{settings.motd}}")],
+        );
+        assert!(problems.is_empty(), "{:?}", problems);
+    }
+
+    #[test]
+    fn missing_key() {
+        let metadata = "[metadata.settings]\naffected-services = [\"motd\"]\n";
+        let problems = lint_with(
+            &[
+                ("10-defaults.toml", DEFAULTS),
+                ("20-metadata.toml", metadata),
+            ],
+            &[("motd", "{{settings.motd}}\n{{settings.ntp.time-servers}}")],
+        );
+        assert_eq!(kinds(&problems), vec![ProblemKind::MissingKey]);
+        assert_eq!(problems[0].location, "configuration-files.motd");
+        assert!(problems[0].message.contains("settings.ntp.time-servers"));
+        assert!(problems[0].message.contains("line 2"));
+    }
+
+    #[test]
+    fn generated_missing_key_hint() {
+        let generated = "[metadata.settings.motd]\nsetting-generator = \"motdgen\"\n";
+        let defaults = DEFAULTS.replace("motd = \"hello\"", "");
+        let problems = lint_with(
+            &[("10-defaults.toml", &defaults), ("20-gen.toml", generated)],
+            &[("motd", "{{settings.motd}}")],
+        );
+        assert_eq!(kinds(&problems), vec![ProblemKind::MissingKey]);
+        assert!(problems[0].message.contains("'motdgen'"));
+    }
+
+    #[test]
+    fn later_defaults_win() {
+        // A later file can supply a value an earlier file's template needs.
+        let defaults = DEFAULTS.replace("motd = \"hello\"", "");
+        let problems = lint_with(
+            &[
+                ("10-defaults.toml", &defaults),
+                ("20-motd.toml", "[settings]\nmotd = \"hi\"\n"),
+            ],
+            &[("motd", "{{settings.motd}}")],
+        );
+        assert!(problems.is_empty(), "{:?}", problems);
+    }
+
+    #[test]
+    fn schnauzer_generated_setting() {
+        let generated = r#"
+[metadata.settings.motd]
+setting-generator = "schnauzer settings.motd"
+template = "{{os.arch}}"
+"#;
+        let defaults = DEFAULTS.replace("motd = \"hello\"", "");
+        let problems = lint_with(
+            &[("10-defaults.toml", &defaults), ("20-gen.toml", generated)],
+            &[("motd", "{{settings.motd}}")],
+        );
+        assert!(problems.is_empty(), "{:?}", problems);
+    }
+
+    #[test]
+    fn unknown_helper_and_invalid_template() {
+        let files = r#"
+[services.motd]
+configuration-files = ["motd", "broken"]
+restart-commands = []
+
+[configuration-files.broken]
+path = "/etc/broken"
+template-path = "/usr/share/templates/broken"
+"#;
+        let problems = lint_with(
+            &[("10-defaults.toml", DEFAULTS), ("20-files.toml", files)],
+            &[
+                ("motd", "{{no_such_helper settings.motd}}"),
+                ("broken", "{{#if}"),
+            ],
+        );
+        assert_eq!(
+            kinds(&problems),
+            vec![ProblemKind::InvalidTemplate, ProblemKind::UnknownHelper]
+        );
+        assert_eq!(problems[0].location, "configuration-files.broken");
+    }
+
+    #[test]
+    fn helper_failure() {
+        let problems = lint_with(
+            &[("10-defaults.toml", DEFAULTS)],
+            &[("motd", "{{base64_decode settings.motd}}")],
+        );
+        assert_eq!(kinds(&problems), vec![ProblemKind::RenderFailure]);
+    }
+
+    #[test]
+    fn missing_template_file() {
+        let problems = lint_with(&[("10-defaults.toml", DEFAULTS)], &[]);
+        assert_eq!(kinds(&problems), vec![ProblemKind::MissingTemplateFile]);
+    }
+
+    #[test]
+    fn unknown_references() {
+        let refs = r#"
+[services.motd]
+configuration-files = ["motd", "nope"]
+restart-commands = []
+
+[metadata.settings.motd]
+affected-services = ["motd", "chronyd"]
+"#;
+        let problems = lint_with(
+            &[("10-defaults.toml", DEFAULTS), ("20-refs.toml", refs)],
+            &[("motd", "{{settings.motd}}")],
+        );
+        assert_eq!(
+            kinds(&problems),
+            vec![
+                ProblemKind::UnknownService,
+                ProblemKind::UnknownConfigurationFile
+            ]
+        );
+        assert!(problems[0].message.contains("chronyd"));
+        assert!(problems[1].message.contains("nope"));
+    }
+
+    #[test]
+    fn parse_metadata_keys() {
+        let md: toml::Value = toml::from_str(
+            r#"
+[settings.a.b]
+affected-services = ["x"]
+[settings.c]
+template = "t"
+[settings.d.setting-generator]
+command = "pluto d"
+timeout = 30
+"#,
+        )
+        .unwrap();
+        let md = parse_metadata(md);
+        let parsed: Vec<_> = md.iter().map(|m| (m.key.as_str(), m.md.as_str())).collect();
+        assert_eq!(
+            parsed,
+            vec![
+                ("settings.a.b", "affected-services"),
+                ("settings.c", "template"),
+                ("settings.d", "setting-generator")
+            ]
+        );
+    }
+
+    #[test]
+    fn missing_affected_service() {
+        let ntp = r#"
+[settings.ntp]
+time-servers = ["time.example.com"]
+
+[services.motd]
+configuration-files = ["motd"]
+restart-commands = []
+"#;
+        let problems = lint_with(
+            &[("10-defaults.toml", DEFAULTS), ("20-ntp.toml", ntp)],
+            &[(
+                "motd",
+                "{{settings.motd}}{{#each settings.ntp.time-servers}}{{this}}{{/each}}",
+            )],
+        );
+        assert_eq!(kinds(&problems), vec![ProblemKind::MissingAffectedService]);
+        assert!(problems[0].message.contains("'settings.ntp.time-servers'"));
+    }
+
+    #[test]
+    fn deeper_metadata_overrides() {
+        // The entry for a key under the one the template uses applies to changes under it, so it
+        // has to list the service, too.
+        let deeper = r#"
+[settings.ntp]
+time-servers = ["time.example.com"]
+
+[metadata.settings]
+affected-services = ["motd"]
+
+[metadata.settings.ntp.time-servers]
+affected-services = ["chronyd"]
+
+[services.chronyd]
+configuration-files = []
+restart-commands = []
+"#;
+        let problems = lint_with(
+            &[("10-defaults.toml", DEFAULTS), ("20-deeper.toml", deeper)],
+            &[("motd", "{{#if settings.ntp}}{{settings.motd}}{{/if}}")],
+        );
+        assert_eq!(kinds(&problems), vec![ProblemKind::MissingAffectedService]);
+        assert!(problems[0].message.contains("'settings.ntp'"));
+    }
+
+    #[test]
+    fn parent_metadata_covers() {
+        let parent = r#"
+[metadata.settings]
+affected-services = ["motd"]
+"#;
+        let defaults = DEFAULTS.replace("[metadata.settings.motd]", "[metadata.settings.other]");
+        let problems = lint_with(
+            &[("10-defaults.toml", &defaults), ("20-parent.toml", parent)],
+            &[("motd", "{{settings.motd}}")],
+        );
+        assert!(problems.is_empty(), "{:?}", problems);
+    }
+
+    #[test]
+    fn partial() {
+        let problems = lint_with(
+            &[("10-defaults.toml", DEFAULTS)],
+            &[
+                ("motd", "{{> greeting}}"),
+                (
+                    "greeting.partial",
+                    "{{settings.motd}} from {{os.variant_id}}",
+                ),
+            ],
+        );
+        assert!(problems.is_empty(), "{:?}", problems);
+    }
+
+    #[test]
+    fn partial_settings_need_affected_service() {
+        let defaults = DEFAULTS.replace("[metadata.settings.motd]", "[metadata.settings.other]");
+        let problems = lint_with(
+            &[("10-defaults.toml", &defaults)],
+            &[
+                ("motd", "{{> greeting}}"),
+                ("greeting.partial", "{{settings.motd}}"),
+            ],
+        );
+        assert_eq!(kinds(&problems), vec![ProblemKind::MissingAffectedService]);
+    }
+
+    #[test]
+    fn missing_partial() {
+        let problems = lint_with(
+            &[("10-defaults.toml", DEFAULTS)],
+            &[("motd", "{{settings.motd}}{{> greeting}}")],
+        );
+        assert_eq!(kinds(&problems), vec![ProblemKind::MissingPartial]);
+    }
+
+    #[test]
+    fn inline_partial() {
+        let problems = lint_with(
+            &[("10-defaults.toml", DEFAULTS)],
+            &[(
+                "motd",
+                r#"{{#*inline "greeting"}}{{settings.motd}}{{/inline}}{{> greeting}}"#,
+            )],
+        );
+        assert!(problems.is_empty(), "{:?}", problems);
+    }
+
+    #[test]
+    fn partial_conflict() {
+        let problems = lint_with(
+            &[("10-defaults.toml", DEFAULTS)],
+            &[("motd", "{{settings.motd}}"), ("motd.partial", "hi")],
+        );
+        assert_eq!(kinds(&problems), vec![ProblemKind::PartialConflict]);
+    }
+
+    // This check is only enforced in CI, where `cargo make lint-templates` runs the lint's unit
+    // tests; it fails if a template uses a setting whose affected-services don't cover the
+    // template, for any variant.  Variant builds don't run it.
+    #[test]
+    fn variants_affect_services() {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("../../..");
+        let models = root.join("sources/models/src");
+        let mut problems = Vec::new();
+        for entry in fs::read_dir(&models).unwrap() {
+            let defaults_dir = entry.unwrap().path().join("defaults.d");
+            let variant_dir = root
+                .join("variants")
+                .join(defaults_dir.parent().unwrap().file_name().unwrap());
+            if !defaults_dir.is_dir() || !variant_dir.is_dir() {
+                continue;
+            }
+
+            let template_dirs = variant_template_dirs(&variant_dir).unwrap();
+            for problem in check_affected_services(&defaults_dir, &[], &template_dirs).unwrap() {
+                problems.push(format!("{}: {}", variant_dir.display(), problem));
+            }
+        }
+        assert!(problems.is_empty(), "\n{}", problems.join("\n"));
+    }
+}
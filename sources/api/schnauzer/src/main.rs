@@ -11,13 +11,82 @@ For example, if we're generating "settings.x" and we have template "foo-{{ setti
 If the returned value is "baz", our generated value will be "foo-baz".
 
 (The name "schnauzer" comes from the fact that Schnauzers are search and rescue dogs (similar to this search and replace task) and because they have mustaches.)
+
+## Linting templates
+
+`schnauzer-lint` checks a variant's configuration file templates at build time, rather than waiting for thar-be-settings to find problems when it renders them on a host.
+It's only built with the `lint` feature, so hosts don't carry it.
+It loads the variant's `defaults.d` TOML files, builds the API model from them, and renders every configuration file's template, reporting missing keys, unknown helpers, and other rendering errors.
+It also checks that every `affected-services` entry names a known service, and that services only list known configuration files.
+
+Each template is also parsed for the settings it references, and every service using the template's file must be in the `affected-services` of each of those settings, or the file wouldn't be updated when the setting changes.
+The nearest metadata wins, so a service listed for "settings.network" is also needed in a "settings.network.hostname" list if the template uses the hostname.
+Settings a variant's defaults don't mention at all are skipped, since templates shared between variants can refer to settings that only exist in some.
+The lint's unit tests run this check for every variant's defaults and packages.
+It's only enforced in CI, by `cargo make lint-templates`; building a variant doesn't run it.
+
+It runs offline, and `cargo make lint-templates` runs it, along with its unit tests, for the variant given by `BUILDSYS_VARIANT`, as CI does for each variant.
+To run it directly:
+
+```sh
+VARIANT=aws-k8s-1.21 cargo run --features lint --bin schnauzer-lint -- \
+   --defaults-dir ../models/src/aws-k8s-1.21/defaults.d \
+   --variant-dir ../../variants/aws-k8s-1.21 \
+   --settings-file lint-settings/generated.toml \
+   --settings-file lint-settings/aws.toml \
+   --settings-file lint-settings/kubernetes.toml
+```
+
+The model is the one schnauzer-lint was built for, so the defaults directory should be for the same variant.
+Each configuration file's `template-path` is looked up by name in the directories given with `--template-dir`, in order, then in the directories of the packages the variant given with `--variant-dir` depends on.
+
+Some settings are normally generated at runtime.
+Those generated by schnauzer itself are rendered from their `template` metadata.
+Values for others, and for settings users are expected to provide, can be given in TOML files with `--settings-file`, in the same form as user data.
+The files in `lint-settings` give values for the settings variants generate at runtime.
+
+## Rendering offline
+
+`schnauzer render` renders templates without a running API server, using the same helpers as on a host.
+The settings come from a JSON file in the form the API returns for all of its data, which can be captured from a host with `apiclient raw -u /`.
+The `os` section can be replaced with the contents of an os-release file with `--os`.
+
+A single template is rendered to stdout:
+
+```sh
+schnauzer render --settings settings.json --template ../packages/release/motd.template
+```
+
+Or every configuration file in the settings can be rendered into an output directory, at each file's path, for comparing against expected output:
+
+```sh
+schnauzer render --settings settings.json --os os-release \
+   --template-dir ../packages/kubernetes-1.21 \
+   --template-dir ../packages/os \
+   --output-dir rendered/
+```
+
+Templates are found by name in the template directories, as with `schnauzer-lint`.
+Partials are loaded from the template directories, too, including when rendering a single template.
+
+## Partials
+
+Templates can share snippets with Handlebars partials, like `{{> no-proxy}}`.
+A partial is a file with a `.partial` extension, named for the partial, like `no-proxy.partial`.
+On a host, partials are installed to `/usr/share/templates/partials`, and are registered for every template schnauzer and thar-be-settings render.
+Offline, `schnauzer-lint` and `schnauzer render` load partials from the template directories.
+
+Handlebars renders a missing partial as nothing, so the linter reports templates that include partials that don't exist.
+It also counts the settings used by included partials as the template's own when checking `affected-services`, and reports partials named like a configuration file, since the file's template would replace the partial when thar-be-settings registers it.
 */
 
 #![deny(rust_2018_idioms)]
 
 use constants;
+use schnauzer::render::{self, RenderConfig, RenderTarget};
 use snafu::{ensure, OptionExt, ResultExt};
 use std::collections::HashMap;
+use std::path::PathBuf;
 use std::string::String;
 use std::{env, process};
 
@@ -71,6 +140,9 @@ mod error {
         #[snafu(display("Failed to get settings from API: {}", source))]
         GetSettings { source: schnauzer::Error },
 
+        #[snafu(display("Failed to render offline: {}", source))]
+        Render { source: schnauzer::render::Error },
+
         #[snafu(display(
             "Failed to render setting '{}' from template '{}': {}",
             setting_name,
@@ -126,24 +198,132 @@ async fn get_metadata(key: &str, meta: &str) -> Result<String> {
     Ok(response_str.to_string())
 }
 
+/// The modes schnauzer can run in.
+enum Subcommand {
+    Generate(String),
+    Render(RenderConfig),
+}
+
 /// Print usage message.
 fn usage() -> ! {
     let program_name = env::args().next().unwrap_or_else(|| "program".to_string());
-    eprintln!("Usage: {} SETTING_KEY", program_name);
+    eprintln!(
+        r"Usage: {} SETTING_KEY
+       {} render --settings FILE [ --os FILE ] --template FILE
+           [ --template-dir DIR ... ]
+       {} render --settings FILE [ --os FILE ]
+           --template-dir DIR [--template-dir DIR ...]
+           --output-dir DIR",
+        program_name, program_name, program_name,
+    );
     process::exit(2);
 }
 
-/// Parses args for the setting key name.
-fn parse_args(mut args: env::Args) -> String {
+/// Prints a more specific message before exiting through usage().
+fn usage_msg<S: AsRef<str>>(msg: S) -> ! {
+    eprintln!("{}\n", msg.as_ref());
+    usage();
+}
+
+/// Parses args for the setting key name, or the 'render' subcommand.
+fn parse_args(mut args: env::Args) -> Subcommand {
     let arg = args.nth(1).unwrap_or_else(|| usage());
-    if arg == "--help" || arg == "-h" {
-        usage()
+    match arg.as_ref() {
+        "--help" | "-h" => usage(),
+        "render" => Subcommand::Render(parse_render_args(args)),
+        _ => Subcommand::Generate(arg),
     }
-    arg
+}
+
+/// Parses args for the 'render' subcommand.
+fn parse_render_args(args: env::Args) -> RenderConfig {
+    let mut settings_file = None;
+    let mut os_file = None;
+    let mut template = None;
+    let mut template_dirs = Vec::new();
+    let mut output_dir = None;
+
+    let mut iter = args;
+    while let Some(arg) = iter.next() {
+        match arg.as_ref() {
+            "--settings" => {
+                settings_file =
+                    Some(PathBuf::from(iter.next().unwrap_or_else(|| {
+                        usage_msg("Did not give argument to --settings")
+                    })))
+            }
+
+            "--os" => {
+                os_file =
+                    Some(PathBuf::from(iter.next().unwrap_or_else(|| {
+                        usage_msg("Did not give argument to --os")
+                    })))
+            }
+
+            "--template" => {
+                template =
+                    Some(PathBuf::from(iter.next().unwrap_or_else(|| {
+                        usage_msg("Did not give argument to --template")
+                    })))
+            }
+
+            "--template-dir" => template_dirs
+                .push(PathBuf::from(iter.next().unwrap_or_else(|| {
+                    usage_msg("Did not give argument to --template-dir")
+                }))),
+
+            "--output-dir" => {
+                output_dir =
+                    Some(PathBuf::from(iter.next().unwrap_or_else(|| {
+                        usage_msg("Did not give argument to --output-dir")
+                    })))
+            }
+
+            x => usage_msg(format!("Unknown argument '{}'", x)),
+        }
+    }
+
+    let target = match (template, output_dir) {
+        (Some(template), None) => RenderTarget::Template(template),
+        (None, Some(output_dir)) if !template_dirs.is_empty() => RenderTarget::Files(output_dir),
+        _ => usage_msg("Must give either --template, or --template-dir and --output-dir"),
+    };
+
+    RenderConfig {
+        settings_file: settings_file.unwrap_or_else(|| usage_msg("Must give --settings")),
+        os_file,
+        template_dirs,
+        target,
+    }
+}
+
+/// Renders a template to stdout, or all configuration files to an output directory, printing
+/// the paths written.
+fn run_render(config: RenderConfig) -> Result<()> {
+    let model = render::load_model(&config.settings_file, config.os_file.as_deref())
+        .context(error::Render)?;
+    match config.target {
+        RenderTarget::Template(template) => {
+            let rendered = render::render_template(&template, &config.template_dirs, &model)
+                .context(error::Render)?;
+            print!("{}", rendered);
+        }
+        RenderTarget::Files(output_dir) => {
+            let written = render::render_files(&model, &config.template_dirs, &output_dir)
+                .context(error::Render)?;
+            for path in written {
+                println!("{}", path.display());
+            }
+        }
+    }
+    Ok(())
 }
 
 async fn run() -> Result<()> {
-    let setting_name = parse_args(env::args());
+    let setting_name = match parse_args(env::args()) {
+        Subcommand::Generate(setting_name) => setting_name,
+        Subcommand::Render(config) => return run_render(config),
+    };
 
     let registry = schnauzer::build_template_registry().context(error::BuildTemplateRegistry)?;
     let template = get_metadata(&setting_name, "templates").await?;
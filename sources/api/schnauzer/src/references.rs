@@ -0,0 +1,306 @@
+//! The references module finds the settings that templates refer to, including through the
+//! partials they include.  thar-be-settings uses it to skip rendering files whose templates don't
+//! use any of the settings that changed, and the linter uses it to check that a template's files
+//! are updated when the settings it uses change.
+//!
+//! Templates are parsed, not rendered, so this doesn't need values for the settings.
+
+use handlebars::template::{Parameter, Template, TemplateElement};
+use handlebars::Handlebars;
+use serde_json::Value;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeSet, HashMap};
+use std::iter;
+
+/// What a template refers to.
+#[derive(Debug, Default)]
+pub(crate) struct References {
+    /// Settings, as key segments, e.g. ["settings", "motd"].  References relative to a block's
+    /// context, like `{{this}}` inside `{{#each}}`, are covered by the reference that starts the
+    /// block.
+    pub(crate) settings: BTreeSet<Vec<String>>,
+    /// Names of the partials included.
+    pub(crate) partials: BTreeSet<String>,
+    /// Names of the partials defined inline, with `{{#*inline}}`.
+    pub(crate) inline: BTreeSet<String>,
+}
+
+/// Collects what a template refers to, including what's referred to by the partials it includes,
+/// and by the partials they include.
+#[cfg(feature = "lint")]
+pub(crate) fn references(
+    template: &Template,
+    partials: &std::collections::BTreeMap<String, Template>,
+) -> References {
+    let mut references = References::default();
+    collect_template(template, &mut references);
+
+    let mut visited = BTreeSet::new();
+    let mut to_visit: Vec<String> = references.partials.iter().cloned().collect();
+    while let Some(name) = to_visit.pop() {
+        let partial = match partials.get(&name) {
+            Some(partial) if visited.insert(name) => partial,
+            _ => continue,
+        };
+        let mut included = References::default();
+        collect_template(partial, &mut included);
+        to_visit.extend(included.partials.iter().cloned());
+        references.settings.append(&mut included.settings);
+        references.partials.append(&mut included.partials);
+        references.inline.append(&mut included.inline);
+    }
+    references
+}
+
+/// Finds the settings that templates registered in a Handlebars registry refer to, for deciding
+/// whether a settings change affects a template.  What each template and partial refers to
+/// directly is worked out once and kept, so a cache should only be used with one registry, and
+/// templates shouldn't be replaced once registered.
+#[derive(Debug, Default)]
+pub struct TemplateReferences {
+    /// What each template refers to directly, not counting the partials it includes.
+    direct: HashMap<String, References>,
+}
+
+impl TemplateReferences {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Lists the settings the named template refers to, as key segments, including those
+    /// referred to by the partials registered with it.  Returns None if there's no such template.
+    pub fn settings(
+        &mut self,
+        registry: &Handlebars<'_>,
+        name: &str,
+    ) -> Option<BTreeSet<Vec<String>>> {
+        if !registry.has_template(name) {
+            return None;
+        }
+
+        let mut settings = BTreeSet::new();
+        let mut visited = BTreeSet::new();
+        let mut to_visit = vec![name.to_string()];
+        while let Some(name) = to_visit.pop() {
+            if !visited.insert(name.clone()) {
+                continue;
+            }
+            let direct = match self.direct.entry(name) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => match registry.get_template(entry.key()) {
+                    Some(template) => {
+                        let mut direct = References::default();
+                        collect_template(template, &mut direct);
+                        entry.insert(direct)
+                    }
+                    // Handlebars renders a missing partial as nothing.
+                    None => continue,
+                },
+            };
+            settings.extend(direct.settings.iter().cloned());
+            to_visit.extend(direct.partials.iter().cloned());
+        }
+        Some(settings)
+    }
+}
+
+fn collect_template(template: &Template, references: &mut References) {
+    for element in &template.elements {
+        collect_element(element, references);
+    }
+}
+
+fn collect_element(element: &TemplateElement, references: &mut References) {
+    match element {
+        TemplateElement::HtmlExpression(helper)
+        | TemplateElement::Expression(helper)
+        | TemplateElement::HelperBlock(helper) => {
+            let params = iter::once(&helper.name)
+                .chain(&helper.params)
+                .chain(helper.hash.values());
+            collect_params(params, references);
+            for template in helper.template.iter().chain(&helper.inverse) {
+                collect_template(template, references);
+            }
+        }
+        TemplateElement::DecoratorExpression(decorator)
+        | TemplateElement::DecoratorBlock(decorator)
+        | TemplateElement::PartialExpression(decorator)
+        | TemplateElement::PartialBlock(decorator) => {
+            collect_params(
+                decorator.params.iter().chain(decorator.hash.values()),
+                references,
+            );
+            if let Some(template) = &decorator.template {
+                collect_template(template, references);
+            }
+
+            let name = match &decorator.name {
+                Parameter::Name(name) => name.as_str(),
+                _ => return,
+            };
+            match element {
+                // "@partial-block" is the content of a partial block, not a registered partial.
+                TemplateElement::PartialExpression(_) | TemplateElement::PartialBlock(_)
+                    if !name.starts_with('@') =>
+                {
+                    references.partials.insert(name.to_string());
+                }
+                TemplateElement::DecoratorBlock(_) if name == "inline" => {
+                    if let Some(Parameter::Literal(Value::String(inline))) =
+                        decorator.params.first()
+                    {
+                        references.inline.insert(inline.clone());
+                    }
+                }
+                _ => {}
+            }
+        }
+        TemplateElement::RawString(_) | TemplateElement::Comment(_) => {}
+    }
+}
+
+fn collect_params<'a, I>(params: I, references: &mut References)
+where
+    I: Iterator<Item = &'a Parameter>,
+{
+    for param in params {
+        let raw = match param {
+            // Simple names like "settings" are parsed as names, not paths.
+            Parameter::Name(raw) => raw,
+            Parameter::Path(handlebars::Path::Relative((_, raw))) => raw,
+            Parameter::Subexpression(subexpression) => {
+                collect_element(&subexpression.element, references);
+                continue;
+            }
+            Parameter::Path(handlebars::Path::Local(_)) | Parameter::Literal(_) => continue,
+        };
+        let segments = path_segments(raw);
+        if segments.first().map(String::as_str) == Some("settings") {
+            references.settings.insert(segments);
+        }
+    }
+}
+
+/// Splits a Handlebars path like "@root.settings.mirrors.[docker.io]" into its segments, ignoring
+/// the prefixes that only say where lookup starts.
+fn path_segments(raw: &str) -> Vec<String> {
+    let mut raw = raw;
+    while let Some(rest) = ["@root.", "@root/", "../", "./", "this.", "this/"]
+        .iter()
+        .find_map(|prefix| raw.strip_prefix(prefix))
+    {
+        raw = rest;
+    }
+
+    let mut segments = Vec::new();
+    let mut current = String::new();
+    let mut chars = raw.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '[' => current.extend(chars.by_ref().take_while(|c| *c != ']')),
+            '.' | '/' => segments.push(std::mem::take(&mut current)),
+            c => current.push(c),
+        }
+    }
+    segments.push(current);
+    segments
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn setting_references() {
+        let template = Template::compile(
+            r#"{{settings.a}} {{#if settings.b.c}}{{join_map "=" "," "no-fail-if-missing" settings.d}}{{else}}{{default "x" (toml_string @root.settings.e)}}{{/if}}
+{{#each settings.f}}{{this}}{{@key}}{{../settings.g}}{{/each}}{{settings.h.[i.j]}}{{os.arch}}{{k}}"#,
+        )
+        .unwrap();
+        let mut references = References::default();
+        collect_template(&template, &mut references);
+        let references: Vec<_> = references
+            .settings
+            .into_iter()
+            .map(|r| r.join("."))
+            .collect();
+        assert_eq!(
+            references,
+            vec![
+                "settings.a",
+                "settings.b.c",
+                "settings.d",
+                "settings.e",
+                "settings.f",
+                "settings.g",
+                "settings.h.i.j",
+            ]
+        );
+    }
+
+    #[cfg(feature = "lint")]
+    #[test]
+    fn partial_references() {
+        let template = Template::compile("{{settings.a}}{{> one}}{{> missing}}").unwrap();
+        let partials = vec![
+            ("one", "{{settings.b}}{{> two}}"),
+            ("two", "{{#each settings.c}}{{this}}{{/each}}{{> one}}"),
+        ]
+        .into_iter()
+        .map(|(name, p)| (name.to_string(), Template::compile(p).unwrap()))
+        .collect();
+
+        let references = references(&template, &partials);
+        let settings: Vec<_> = references.settings.iter().map(|r| r.join(".")).collect();
+        assert_eq!(settings, vec!["settings.a", "settings.b", "settings.c"]);
+        let included: Vec<_> = references.partials.iter().map(String::as_str).collect();
+        assert_eq!(included, vec!["missing", "one", "two"]);
+    }
+
+    #[test]
+    fn registered_template_settings() {
+        let mut registry = Handlebars::new();
+        registry
+            .register_template_string("motd", "{{settings.motd}}{{> greeting}}")
+            .unwrap();
+        registry
+            .register_partial("greeting", "{{settings.greeting.text}}")
+            .unwrap();
+
+        let mut references = TemplateReferences::new();
+        let settings: Vec<_> = references
+            .settings(&registry, "motd")
+            .unwrap()
+            .iter()
+            .map(|r| r.join("."))
+            .collect();
+        assert_eq!(settings, vec!["settings.greeting.text", "settings.motd"]);
+        assert!(references.settings(&registry, "missing").is_none());
+        // The partial's references are kept, and used for other templates that include it.
+        assert!(references.direct.contains_key("greeting"));
+        registry
+            .register_template_string("issue", "{{> greeting}}")
+            .unwrap();
+        let settings: Vec<_> = references
+            .settings(&registry, "issue")
+            .unwrap()
+            .iter()
+            .map(|r| r.join("."))
+            .collect();
+        assert_eq!(settings, vec!["settings.greeting.text"]);
+    }
+
+    #[test]
+    fn segments() {
+        assert_eq!(
+            path_segments("settings.a-b.c"),
+            vec!["settings", "a-b", "c"]
+        );
+        assert_eq!(path_segments("@root/settings/a"), vec!["settings", "a"]);
+        assert_eq!(
+            path_segments("../../settings.m.[docker.io]"),
+            vec!["settings", "m", "docker.io"]
+        );
+    }
+}
@@ -0,0 +1,345 @@
+//! The render module renders templates offline, from a file of settings rather than a running API
+//! server, so template authors and CI can see rendered output without booting a host.
+//!
+//! The settings file is JSON in the form the API returns for all data, `apiclient raw -u /`, so
+//! it can be captured from a real host.  The same template registry and helpers are used as on a
+//! host, so output matches what thar-be-settings would write.  Partials are loaded from the
+//! given template directories, rather than from where they're installed on a host.
+
+use crate::{build_template_registry, register_partials};
+use bottlerocket_release::BottlerocketRelease;
+use handlebars::Handlebars;
+use snafu::{OptionExt, ResultExt};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+/// Describes what to render.
+#[derive(Debug)]
+pub struct RenderConfig {
+    /// JSON file with the data to render against, in the form of the API's model.
+    pub settings_file: PathBuf,
+    /// An os-release file to use for the `os` section, replacing any in the settings file.
+    pub os_file: Option<PathBuf>,
+    /// Directories to search, in order, for partials and for the files named by each
+    /// `template-path`.
+    pub template_dirs: Vec<PathBuf>,
+    pub target: RenderTarget,
+}
+
+/// What to render from the settings.
+#[derive(Debug)]
+pub enum RenderTarget {
+    /// A single template file, rendered to stdout.
+    Template(PathBuf),
+    /// Every configuration file in the settings, written under the given directory at its path,
+    /// with templates found by name in the template directories, like `schnauzer-lint`.
+    Files(PathBuf),
+}
+
+/// Loads the model to render against from a settings file and, optionally, an os-release file.
+pub fn load_model(settings_file: &Path, os_file: Option<&Path>) -> Result<model::Model> {
+    let data = fs::read_to_string(settings_file).context(error::ReadFile {
+        path: settings_file,
+    })?;
+    let mut model: model::Model = serde_json::from_str(&data).context(error::Model {
+        path: settings_file,
+    })?;
+    if let Some(os_file) = os_file {
+        model.os = Some(
+            BottlerocketRelease::from_file(os_file).context(error::Release { path: os_file })?,
+        );
+    }
+    Ok(model)
+}
+
+/// Renders the given template file against the model.
+pub fn render_template(
+    template_file: &Path,
+    template_dirs: &[PathBuf],
+    model: &model::Model,
+) -> Result<String> {
+    let registry = registry(template_dirs)?;
+    let template = fs::read_to_string(template_file).context(error::ReadFile {
+        path: template_file,
+    })?;
+    registry
+        .render_template(&template, model)
+        .context(error::RenderTemplate {
+            template: template_file,
+        })
+}
+
+/// Renders every configuration file in the model into `output_dir`, each at its configured path
+/// relative to `output_dir`.  Returns the paths written, in order of configuration file name.
+pub fn render_files(
+    model: &model::Model,
+    template_dirs: &[PathBuf],
+    output_dir: &Path,
+) -> Result<Vec<PathBuf>> {
+    let mut registry = registry(template_dirs)?;
+    // Sorted, so output and errors are in a stable order.
+    let files: BTreeMap<&str, _> = model
+        .configuration_files
+        .as_ref()
+        .context(error::NoConfigurationFiles)?
+        .iter()
+        .map(|(name, file)| (name.as_str(), file))
+        .collect();
+
+    let mut written = Vec::new();
+    for (name, file) in files {
+        let template_path = Path::new(file.template_path.as_ref());
+        let template_file =
+            find_template(template_dirs, template_path).context(error::MissingTemplate {
+                name,
+                template_path,
+            })?;
+        registry
+            .register_template_file(name, &template_file)
+            .context(error::InvalidTemplate {
+                template: &template_file,
+            })?;
+        let rendered = registry
+            .render(name, model)
+            .context(error::RenderTemplate {
+                template: &template_file,
+            })?;
+
+        let path = output_dir.join(relative(Path::new(file.path.as_ref())));
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).context(error::WriteFile { path: parent })?;
+        }
+        fs::write(&path, rendered).context(error::WriteFile { path: &path })?;
+        written.push(path);
+    }
+    Ok(written)
+}
+
+/// Builds the template registry, with the partials from the template directories.
+fn registry(template_dirs: &[PathBuf]) -> Result<Handlebars<'static>> {
+    let mut registry = build_template_registry().context(error::BuildRegistry)?;
+    // Register in reverse, so partials in earlier directories replace those in later ones.
+    for dir in template_dirs.iter().rev().filter(|dir| dir.is_dir()) {
+        register_partials(&mut registry, dir).context(error::RegisterPartials)?;
+    }
+    Ok(registry)
+}
+
+/// Searches the template directories for the file named by a `template-path`.  Some packages
+/// keep their templates with a ".template" suffix in the source tree, so we accept that, too.
+pub(crate) fn find_template(dirs: &[PathBuf], template_path: &Path) -> Option<PathBuf> {
+    let name = template_path.file_name()?;
+    let suffixed = format!("{}.template", name.to_string_lossy());
+    dirs.iter()
+        .flat_map(|dir| vec![dir.join(name), dir.join(&suffixed)])
+        .find(|path| path.is_file())
+}
+
+/// Makes an absolute configuration file path relative, so it can be put under an output
+/// directory.  Parent references are dropped so files can't be written outside it.
+fn relative(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|c| matches!(c, Component::Normal(_)))
+        .collect()
+}
+
+mod error {
+    use snafu::Snafu;
+    use std::path::PathBuf;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility = "pub(super)")]
+    pub enum Error {
+        #[snafu(display("Failed to build template registry: {}", source))]
+        BuildRegistry { source: crate::Error },
+
+        #[snafu(display("Invalid template '{}': {}", template.display(), source))]
+        InvalidTemplate {
+            template: PathBuf,
+            source: handlebars::TemplateError,
+        },
+
+        #[snafu(display(
+            "No file found in template directories for '{}', used by configuration file '{}'",
+            template_path.display(),
+            name
+        ))]
+        MissingTemplate {
+            name: String,
+            template_path: PathBuf,
+        },
+
+        #[snafu(display("Settings in '{}' don't match the API model: {}", path.display(), source))]
+        Model {
+            path: PathBuf,
+            source: serde_json::Error,
+        },
+
+        #[snafu(display("Settings file has no configuration-files to render"))]
+        NoConfigurationFiles,
+
+        #[snafu(display("Failed to read '{}': {}", path.display(), source))]
+        ReadFile {
+            path: PathBuf,
+            source: std::io::Error,
+        },
+
+        #[snafu(display("Failed to load os-release from '{}': {}", path.display(), source))]
+        Release {
+            path: PathBuf,
+            source: bottlerocket_release::Error,
+        },
+
+        #[snafu(display("Failed to register partials: {}", source))]
+        RegisterPartials { source: crate::Error },
+
+        #[snafu(display("Failed to render template '{}': {}", template.display(), source))]
+        RenderTemplate {
+            template: PathBuf,
+            source: handlebars::RenderError,
+        },
+
+        #[snafu(display("Failed to write '{}': {}", path.display(), source))]
+        WriteFile {
+            path: PathBuf,
+            source: std::io::Error,
+        },
+    }
+}
+pub use error::Error;
+pub type Result<T> = std::result::Result<T, error::Error>;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::TempDir;
+
+    const SETTINGS: &str = r#"{
+        "settings": {"motd": "hello"},
+        "configuration-files": {
+            "motd": {"path": "/etc/motd", "template-path": "/usr/share/templates/motd"},
+            "issue": {"path": "/etc/issue", "template-path": "/usr/share/templates/issue"}
+        },
+        "os": {
+            "pretty_name": "Bottlerocket OS 1.2.3",
+            "variant_id": "aws-k8s-1.21",
+            "version_id": "1.2.3",
+            "build_id": "abcdef",
+            "arch": "x86_64"
+        }
+    }"#;
+
+    fn write(dir: &Path, name: &str, contents: &str) -> PathBuf {
+        let path = dir.join(name);
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn template() {
+        let dir = TempDir::new().unwrap();
+        let settings = write(dir.path(), "settings.json", SETTINGS);
+        let template = write(
+            dir.path(),
+            "motd",
+            "{{settings.motd}} from {{os.variant_id}} {{toml_string settings.motd}}",
+        );
+
+        let model = load_model(&settings, None).unwrap();
+        assert_eq!(
+            render_template(&template, &[], &model).unwrap(),
+            "hello from aws-k8s-1.21 \"hello\""
+        );
+    }
+
+    #[test]
+    fn os_file() {
+        let dir = TempDir::new().unwrap();
+        let settings = write(dir.path(), "settings.json", SETTINGS);
+        let os_release = write(
+            dir.path(),
+            "os-release",
+            "PRETTY_NAME=\"Bottlerocket OS 4.5.6\"\nVARIANT_ID=vmware-dev\nVERSION_ID=4.5.6\nBUILD_ID=123456\n",
+        );
+        let template = write(dir.path(), "os", "{{os.variant_id}} {{os.version_id}}");
+
+        let model = load_model(&settings, Some(&os_release)).unwrap();
+        assert_eq!(
+            render_template(&template, &[], &model).unwrap(),
+            "vmware-dev 4.5.6"
+        );
+    }
+
+    #[test]
+    fn missing_key() {
+        let dir = TempDir::new().unwrap();
+        let settings = write(dir.path(), "settings.json", SETTINGS);
+        let template = write(dir.path(), "bad", "{{settings.ntp.time-servers}}");
+
+        let model = load_model(&settings, None).unwrap();
+        assert!(matches!(
+            render_template(&template, &[], &model),
+            Err(Error::RenderTemplate { .. })
+        ));
+    }
+
+    #[test]
+    fn files() {
+        let dir = TempDir::new().unwrap();
+        let settings = write(dir.path(), "settings.json", SETTINGS);
+        let templates = dir.path().join("templates");
+        fs::create_dir(&templates).unwrap();
+        write(&templates, "motd", "{{settings.motd}}\n");
+        write(&templates, "issue.template", "{{os.pretty_name}}\n");
+        let output = dir.path().join("output");
+
+        let model = load_model(&settings, None).unwrap();
+        let written = render_files(&model, &[templates], &output).unwrap();
+        assert_eq!(
+            written,
+            vec![output.join("etc/issue"), output.join("etc/motd")]
+        );
+        assert_eq!(
+            fs::read_to_string(output.join("etc/motd")).unwrap(),
+            "hello\n"
+        );
+        assert_eq!(
+            fs::read_to_string(output.join("etc/issue")).unwrap(),
+            "Bottlerocket OS 1.2.3\n"
+        );
+    }
+
+    #[test]
+    fn partials() {
+        let dir = TempDir::new().unwrap();
+        let settings = write(dir.path(), "settings.json", SETTINGS);
+        let first = dir.path().join("first");
+        let second = dir.path().join("second");
+        fs::create_dir(&first).unwrap();
+        fs::create_dir(&second).unwrap();
+        write(&first, "greeting.partial", "{{settings.motd}}");
+        write(&second, "greeting.partial", "replaced");
+        write(&second, "os.partial", "{{os.variant_id}}");
+        let template = write(dir.path(), "motd", "{{> greeting}} from {{> os}}");
+
+        let model = load_model(&settings, None).unwrap();
+        assert_eq!(
+            render_template(&template, &[first, second], &model).unwrap(),
+            "hello from aws-k8s-1.21"
+        );
+    }
+
+    #[test]
+    fn files_missing_template() {
+        let dir = TempDir::new().unwrap();
+        let settings = write(dir.path(), "settings.json", SETTINGS);
+        let output = dir.path().join("output");
+
+        let model = load_model(&settings, None).unwrap();
+        assert!(matches!(
+            render_files(&model, &[dir.path().to_path_buf()], &output),
+            Err(Error::MissingTemplate { .. })
+        ));
+    }
+}
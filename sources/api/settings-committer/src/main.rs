@@ -5,6 +5,7 @@ settings-committer can be called to commit a pending transaction in the API.
 It logs any pending settings, then commits them to live.
 
 By default, it commits the 'bottlerocket-launch' transaction, which is used to organize boot-time services - this program is typically run as a pre-exec command by any services that depend on settings changes from previous services.
+Before the 'bottlerocket-launch' transaction, it commits the 'bottlerocket-defaults' transaction, which storewolf uses for default settings, so settings from user data replace defaults.
 
 The `--transaction` argument can be used to specify another transaction.
 */
@@ -205,11 +206,21 @@ async fn run() -> Result<()> {
     // SimpleLogger will send errors to stderr and anything less to stdout.
     SimpleLogger::init(args.log_level, LogConfig::default()).context(error::Logger)?;
 
-    info!("Checking pending settings.");
-    check_pending_settings(&args.socket_path, &args.transaction).await;
+    // Defaults go live first, so any setting from user data in the launch transaction replaces
+    // the default.
+    let mut transactions = Vec::new();
+    if args.transaction == constants::LAUNCH_TRANSACTION {
+        transactions.push(constants::DEFAULTS_TRANSACTION);
+    }
+    transactions.push(&args.transaction);
+
+    for transaction in transactions {
+        info!("Checking pending settings for {}.", transaction);
+        check_pending_settings(&args.socket_path, transaction).await;
 
-    info!("Committing settings.");
-    commit_pending_settings(&args.socket_path, &args.transaction).await?;
+        info!("Committing settings for {}.", transaction);
+        commit_pending_settings(&args.socket_path, transaction).await?;
+    }
 
     Ok(())
 }
@@ -149,10 +149,18 @@ fn parse_metadata_toml(md_toml_val: toml::Value) -> Result<Vec<model::Metadata>>
         let (mut path, toml_value) = to_process.pop().unwrap();
         trace!("Current metadata table path: {:#?}", &path);
 
+        // A setting generator can be a table giving its command and how to run it, which is
+        // itself the metadata value, rather than more levels of data key.
+        let is_generator_table = toml_value.is_table()
+            && path
+                .last()
+                .map(|k| k == "setting-generator")
+                .unwrap_or(false);
+
         match toml_value {
             // A table means there is more processing to do. Add the current
             // key and value to the Vec to be processed further.
-            toml::Value::Table(table) => {
+            toml::Value::Table(table) if !is_generator_table => {
                 for (key, val) in table {
                     trace!("Found table for key '{}'", &key);
                     let mut path = path.clone();
@@ -161,8 +169,11 @@ fn parse_metadata_toml(md_toml_val: toml::Value) -> Result<Vec<model::Metadata>>
                 }
             }
 
-            // An array or string means we're ready to create a model::Metadata
-            val @ toml::Value::Array(_) | val @ toml::Value::String(_) => {
+            // An array or string, or a setting generator table, means we're ready to create a
+            // model::Metadata
+            val @ toml::Value::Array(_)
+            | val @ toml::Value::String(_)
+            | val @ toml::Value::Table(_) => {
                 // Get the metadata key from the end of the path
                 let md_key = path.pop().context(error::Internal {
                     msg: "parse_metadata_toml found empty 'path' in the to_process vec - is 'metadata' not a Table?",
@@ -250,9 +261,11 @@ fn populate_default_datastore<P: AsRef<Path>>(
     let maybe_metadata_val = table.remove("metadata");
     let maybe_settings_val = table.remove("settings");
 
-    // If there are default settings, write them to the datastore in the shared pending
+    // If there are default settings, write them to the datastore in the pending defaults
     // transaction. This ensures the settings will go through a commit cycle when first-boot
-    // services run, which will create config files for default keys that require them.
+    // services run, which will create config files for default keys that require them.  The
+    // defaults transaction is committed before the shared launch transaction, so user data wins,
+    // and the API marks settings committed from it as defaults rather than set by the user.
     if let Some(def_settings_val) = maybe_settings_val {
         debug!("Serializing default settings and writing new ones to datastore");
         let def_settings_table = def_settings_val
@@ -284,7 +297,7 @@ fn populate_default_datastore<P: AsRef<Path>>(
             &settings_to_write
         );
         let pending = datastore::Committed::Pending {
-            tx: constants::LAUNCH_TRANSACTION.to_string(),
+            tx: constants::DEFAULTS_TRANSACTION.to_string(),
         };
         datastore
             .set_keys(&settings_to_write, &pending)
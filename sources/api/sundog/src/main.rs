@@ -5,6 +5,54 @@ sundog is a small program to handle settings that must be generated at OS runtim
 
 It requests settings generators from the API and runs them.
 The output is collected and sent to a known Bottlerocket API server endpoint.
+
+Generators for settings that already have a value aren't run.
+The rest are run in parallel, except where one generator depends on another's setting.
+
+A setting's `setting-generator` metadata can be the command to run, or a table saying how to run it:
+
+```toml
+[metadata.settings.kubernetes.max-pods.setting-generator]
+command = "pluto max-pods"
+# Seconds each run may take before it's killed; defaults to 300.
+timeout = 120
+# How many more times to run the generator if it fails; defaults to 0.
+retries = 3
+# Seconds to wait before the first retry, doubled for each later retry; defaults to 1.
+retry-backoff = 2
+# Settings whose generators must run first; defaults to none.
+depends-on = ["settings.kubernetes.node-ip"]
+# When to generate the setting again: once, every-boot, or on-demand; defaults to once.
+refresh = "every-boot"
+```
+
+Generators run in layers: a generator runs after the generators for the settings it lists in `depends-on`, and the settings from each layer are committed before the next layer runs, so dependent generators can read them from the API.
+Dependencies on settings that don't have a generator are ignored, since those settings come from defaults or user data.
+If a generator fails, the generators that depend on it aren't run, and are reported as blocked.
+sundog fails if generators depend on each other in a cycle.
+
+## Refreshing generated settings
+
+sundog commits generated settings in its own transaction, and the API records the origin of each committed setting in its `setting-origin` metadata: "generated" for settings from sundog, "default" for defaults from storewolf, and "user" for settings set any other way, including user data.
+A setting with a value is only generated again if its origin is "generated" or "default" and its generator's refresh policy applies:
+
+* `once` generators never run again once their setting has a value.
+* `every-boot` generators run again on every boot, and on demand.
+* `on-demand` generators run again only on demand.
+
+An on-demand refresh is requested through the API with `POST /actions/refresh-generated`, which runs `sundog --refresh`.
+It runs only the generators with an `every-boot` or `on-demand` policy, then commits and applies any changes.
+Only one sundog runs at a time: sundog holds a lock on `/run/lock/sundog.lock` while it generates settings at boot, and the API holds it while a refresh it started is running.
+The API returns 409 Conflict if the lock is already held.
+Values set before setting origins were recorded have no origin, so they're left alone, except that on upgrade, a migration marks the values of settings that have a generator as generated.
+
+A generator that exits 1, is killed by a signal, exits with an unexpected code, or times out is retried.
+A generator that exits 2 is asking sundog not to set the setting.
+Output that isn't valid JSON isn't retried.
+
+The result of each generator, including the number of attempts, time taken, and any error, is written to `/run/sundog/status.json`.
+Settings from the last layer, which is every generator if none have dependencies, are only sent to the API if every generator succeeded or was skipped.
+Settings from earlier layers have already been committed by then, so dependent generators could read them, and they stay committed if a later generator fails.
 */
 
 #![deny(rust_2018_idioms)]
@@ -13,13 +61,18 @@ The output is collected and sent to a known Bottlerocket API server endpoint.
 extern crate log;
 
 use constants;
+use fs2::FileExt;
+use serde::{Deserialize, Serialize};
 use simplelog::{Config as LogConfig, LevelFilter, SimpleLogger};
 use snafu::{ensure, OptionExt, ResultExt};
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::env;
+use std::fs::{self, File};
 use std::path::Path;
 use std::process;
 use std::str::{self, FromStr};
+use std::time::{Duration, Instant};
+use tokio::process::Command;
 
 use datastore::serialization::to_pairs_with_prefix;
 use datastore::{self, deserialization, Key, KeyType};
@@ -44,6 +97,34 @@ mod error {
         #[snafu(display("Generator command is invalid (empty, etc.) - '{}'", command))]
         InvalidCommand { command: String },
 
+        #[snafu(display("Invalid setting-generator for '{}': {}", setting, source))]
+        InvalidGenerator {
+            setting: String,
+            source: serde_json::Error,
+        },
+
+        #[snafu(display("Setting generator '{}' timed out after {} seconds", program, seconds))]
+        GeneratorTimeout {
+            program: String,
+            seconds: u64,
+            source: tokio::time::error::Elapsed,
+        },
+
+        #[snafu(display("Setting generators depend on each other in a cycle: {}", settings))]
+        GeneratorCycle { settings: String },
+
+        #[snafu(display("Setting generator task for '{}' failed: {}", setting, source))]
+        GeneratorTask {
+            setting: String,
+            source: tokio::task::JoinError,
+        },
+
+        #[snafu(display("Failed to write generator status to '{}': {}", path.display(), source))]
+        WriteStatus {
+            path: std::path::PathBuf,
+            source: std::io::Error,
+        },
+
         #[snafu(display(
             "Setting generator '{}' failed with exit code {} - stderr: {}",
             program,
@@ -125,6 +206,9 @@ mod error {
         #[snafu(display("Error serializing Settings to JSON: {}", source))]
         SerializeRequest { source: serde_json::error::Error },
 
+        #[snafu(display("Error serializing generator status: {}", source))]
+        SerializeStatus { source: serde_json::error::Error },
+
         #[snafu(display("Error serializing Settings: {} ", source))]
         SerializeSettings { source: serialization::Error },
 
@@ -141,6 +225,18 @@ mod error {
             source: datastore::Error,
         },
 
+        #[snafu(display("Failed to open lock file '{}': {}", path.display(), source))]
+        LockOpen {
+            path: std::path::PathBuf,
+            source: std::io::Error,
+        },
+
+        #[snafu(display("Failed to lock '{}': {}", path.display(), source))]
+        Lock {
+            path: std::path::PathBuf,
+            source: std::io::Error,
+        },
+
         #[snafu(display("Logger setup error: {}", source))]
         Logger { source: log::SetLoggerError },
     }
@@ -150,8 +246,111 @@ use error::SundogError;
 
 type Result<T> = std::result::Result<T, SundogError>;
 
+const API_COMMIT_URI: &str = "/tx/commit";
+const API_COMMIT_AND_APPLY_URI: &str = "/tx/commit_and_apply";
+
+/// The `setting-origin` values the API records for settings we committed, and for defaults;
+/// settings with any other origin were set by the user, so we never replace them.
+const REPLACEABLE_ORIGINS: &[&str] = &["generated", "default"];
+
+/// Where the result of each generator is written, so it's collected by logdog.
+const STATUS_FILE: &str = "/run/sundog/status.json";
+
+/// How long each run of a generator may take, unless its metadata says otherwise.
+const DEFAULT_TIMEOUT_SECONDS: u64 = 300;
+
+/// How long to wait before the first retry of a failed generator, unless its metadata says
+/// otherwise; the wait doubles for each later retry.
+const DEFAULT_RETRY_BACKOFF_SECONDS: u64 = 1;
+
+/// A generator's `setting-generator` metadata is either its command, or a table with its command
+/// and how to run it.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum GeneratorMetadata {
+    Command(String),
+    Table(Generator),
+}
+
+/// A command that generates a setting, and how to run it.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct Generator {
+    command: String,
+    /// Seconds each run may take before it's killed and considered failed.
+    #[serde(default = "default_timeout")]
+    timeout: u64,
+    /// How many times to run the generator again if it fails.
+    #[serde(default)]
+    retries: u32,
+    /// Seconds to wait before the first retry; doubled for each later retry.
+    #[serde(default = "default_retry_backoff")]
+    retry_backoff: u64,
+    /// Settings that must be generated and committed before this generator runs.
+    #[serde(default)]
+    depends_on: Vec<String>,
+    /// When to run the generator again for a setting that already has a generated value.
+    #[serde(default)]
+    refresh: Refresh,
+}
+
+/// When to run a generator again for a setting that already has a generated value.  Values set
+/// any other way, for example in user data, are never replaced.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum Refresh {
+    /// Only generate the setting if it has no value.
+    Once,
+    /// Generate the setting again on every boot, and on demand.
+    EveryBoot,
+    /// Generate the setting again only on demand, through the refresh-generated API action.
+    OnDemand,
+}
+
+impl Default for Refresh {
+    fn default() -> Self {
+        Refresh::Once
+    }
+}
+
+impl Refresh {
+    /// Whether a generated value should be replaced, given whether this is an on-demand refresh
+    /// rather than boot.
+    fn applies(self, on_demand: bool) -> bool {
+        match self {
+            Refresh::Once => false,
+            Refresh::EveryBoot => true,
+            Refresh::OnDemand => on_demand,
+        }
+    }
+}
+
+fn default_timeout() -> u64 {
+    DEFAULT_TIMEOUT_SECONDS
+}
+
+fn default_retry_backoff() -> u64 {
+    DEFAULT_RETRY_BACKOFF_SECONDS
+}
+
+impl From<GeneratorMetadata> for Generator {
+    fn from(metadata: GeneratorMetadata) -> Self {
+        match metadata {
+            GeneratorMetadata::Command(command) => Generator {
+                command,
+                timeout: DEFAULT_TIMEOUT_SECONDS,
+                retries: 0,
+                retry_backoff: DEFAULT_RETRY_BACKOFF_SECONDS,
+                depends_on: Vec::new(),
+                refresh: Refresh::Once,
+            },
+            GeneratorMetadata::Table(generator) => generator,
+        }
+    }
+}
+
 /// Request the setting generators from the API.
-async fn get_setting_generators<S>(socket_path: S) -> Result<HashMap<String, String>>
+async fn get_setting_generators<S>(socket_path: S) -> Result<HashMap<String, Generator>>
 where
     S: AsRef<str>,
 {
@@ -171,13 +370,51 @@ where
         }
     );
 
-    let generators: HashMap<String, String> =
+    let metadata: HashMap<String, serde_json::Value> =
         serde_json::from_str(&response_body).context(error::ResponseJson { method: "GET", uri })?;
+    let mut generators = HashMap::new();
+    for (setting, value) in metadata {
+        let generator: GeneratorMetadata =
+            serde_json::from_value(value).context(error::InvalidGenerator { setting: &setting })?;
+        generators.insert(setting, generator.into());
+    }
     trace!("Generators: {:?}", &generators);
 
     Ok(generators)
 }
 
+/// Request the origin of each setting's value from the API: "generated", "default", or "user".
+async fn get_setting_origins<S>(socket_path: S) -> Result<HashMap<String, String>>
+where
+    S: AsRef<str>,
+{
+    let uri = constants::API_SETTING_ORIGINS_URI;
+
+    debug!("Requesting setting origins from API");
+    let (code, response_body) = apiclient::raw_request(socket_path.as_ref(), uri, "GET", None)
+        .await
+        .context(error::APIRequest { method: "GET", uri })?;
+    ensure!(
+        code.is_success(),
+        error::APIResponse {
+            method: "GET",
+            uri,
+            code,
+            response_body,
+        }
+    );
+
+    let origins: HashMap<String, serde_json::Value> =
+        serde_json::from_str(&response_body).context(error::ResponseJson { method: "GET", uri })?;
+    trace!("Origins: {:?}", &origins);
+
+    // Anything unexpected is treated as not generated, so we leave it alone.
+    Ok(origins
+        .into_iter()
+        .filter_map(|(setting, origin)| origin.as_str().map(|o| (setting, o.to_string())))
+        .collect())
+}
+
 /// Given a list of settings, query the API for any that are currently set.
 async fn get_populated_settings<P>(socket_path: P, to_query: Vec<&str>) -> Result<HashSet<Key>>
 where
@@ -222,122 +459,451 @@ where
     Ok(populated_settings)
 }
 
-/// Run the setting generators and collect the output
-async fn get_dynamic_settings<P>(
-    socket_path: P,
-    generators: HashMap<String, String>,
-) -> Result<model::Settings>
-where
-    P: AsRef<Path>,
-{
-    let mut settings = HashMap::new();
+/// What happened when we went to generate a setting.
+#[derive(Debug)]
+enum Outcome {
+    /// The setting already had a value, so the generator wasn't run.
+    Populated,
+    /// The generator gave a value, in serialized datastore form.
+    Generated(String),
+    /// The generator asked us not to set the setting, by exiting 2.
+    Skipped,
+    /// The generator wasn't run because the generator for a setting it depends on failed.
+    Blocked(String),
+    Failed(SundogError),
+}
+
+/// The result of a setting's generator, including how many times it was run and for how long.
+#[derive(Debug)]
+struct GeneratorResult {
+    generator: Generator,
+    outcome: Outcome,
+    attempts: u32,
+    elapsed: Duration,
+    /// Whether the generated value was already committed for dependent generators.
+    committed: bool,
+}
+
+/// The status of a setting's generator, as written to the status file.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct GeneratorStatus<'a> {
+    command: &'a str,
+    status: &'static str,
+    attempts: u32,
+    elapsed_seconds: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl GeneratorResult {
+    fn status(&self) -> GeneratorStatus<'_> {
+        let (status, error) = match &self.outcome {
+            Outcome::Populated => ("populated", None),
+            Outcome::Generated(_) => ("generated", None),
+            Outcome::Skipped => ("skipped", None),
+            Outcome::Blocked(dependency) => (
+                "blocked",
+                Some(format!("generator for '{}' failed", dependency)),
+            ),
+            Outcome::Failed(e) => ("failed", Some(e.to_string())),
+        };
+        GeneratorStatus {
+            command: &self.generator.command,
+            status,
+            attempts: self.attempts,
+            elapsed_seconds: self.elapsed.as_secs_f64(),
+            error,
+        }
+    }
+}
 
+/// Run the setting generators and collect the results, keyed by setting name.  Generators for
+/// settings that are already populated aren't run.
+///
+/// Generators run in layers, in parallel within a layer, so that each runs after the generators
+/// for the settings it depends on.  The output of each layer is committed before the next layer
+/// runs, so dependent generators can read it from the API.  Generators whose dependencies failed
+/// aren't run.
+///
+/// Populated settings are generated again if their value was generated and their generator's
+/// refresh policy applies; `on_demand` says whether this is an on-demand refresh rather than boot.
+async fn run_generators(
+    socket_path: &str,
+    mut generators: HashMap<String, Generator>,
+    on_demand: bool,
+) -> Result<BTreeMap<String, GeneratorResult>> {
     // Build the list of settings to query from the datastore to see if they
     // are currently populated.
     // `generators` keys are setting names in the proper dotted
     // format, i.e. "settings.kubernetes.node-ip"
     let settings_to_query: Vec<&str> = generators.keys().map(|s| s.as_ref()).collect();
-    let populated_settings = get_populated_settings(&socket_path, settings_to_query).await?;
-
-    // For each generator, run it and capture the output
-    for (setting_str, generator) in generators {
-        let setting = Key::new(KeyType::Data, &setting_str).context(error::InvalidKey {
-            key_type: KeyType::Data,
-            key: &setting_str,
-        })?;
-        // Don't clobber settings that are already populated
-        if populated_settings.contains(&setting) {
-            debug!("Setting '{}' is already populated, skipping", setting);
-            continue;
-        }
+    let populated_settings = get_populated_settings(socket_path, settings_to_query).await?;
+    let origins = get_setting_origins(socket_path).await?;
+
+    let layers = generator_layers(&generators)?;
+    let mut results = BTreeMap::new();
+    let mut previous_layer = Vec::new();
+    for layer in layers {
+        commit_generated(socket_path, &previous_layer, &mut results, on_demand).await?;
+        previous_layer = layer.clone();
+
+        let mut tasks = Vec::new();
+        for setting_str in layer {
+            // Every setting in a layer came from the generators map, and is only in one layer.
+            let generator = match generators.remove(&setting_str) {
+                Some(generator) => generator,
+                None => continue,
+            };
+            let setting = Key::new(KeyType::Data, &setting_str).context(error::InvalidKey {
+                key_type: KeyType::Data,
+                key: &setting_str,
+            })?;
+            // Don't clobber settings that are already populated, unless the value wasn't set by
+            // the user and we're meant to refresh it.
+            let refresh = generator.refresh.applies(on_demand)
+                && origins
+                    .get(&setting_str)
+                    .map(|origin| REPLACEABLE_ORIGINS.contains(&origin.as_str()))
+                    .unwrap_or(false);
+            if populated_settings.contains(&setting) && !refresh {
+                debug!("Setting '{}' is already populated, skipping", setting);
+                results.insert(
+                    setting_str,
+                    GeneratorResult {
+                        generator,
+                        outcome: Outcome::Populated,
+                        attempts: 0,
+                        elapsed: Duration::default(),
+                        committed: false,
+                    },
+                );
+                continue;
+            }
 
-        debug!("Running generator: '{}'", &generator);
+            // Don't run generators that would be missing the settings they need.
+            let failed_dependency = generator.depends_on.iter().find(|dependency| {
+                results
+                    .get(*dependency)
+                    .map(|result: &GeneratorResult| {
+                        matches!(result.outcome, Outcome::Failed(_) | Outcome::Blocked(_))
+                    })
+                    .unwrap_or(false)
+            });
+            if let Some(dependency) = failed_dependency {
+                warn!(
+                    "Not running generator for '{}' because the generator for '{}' failed",
+                    setting, dependency
+                );
+                let outcome = Outcome::Blocked(dependency.clone());
+                results.insert(
+                    setting_str,
+                    GeneratorResult {
+                        generator,
+                        outcome,
+                        attempts: 0,
+                        elapsed: Duration::default(),
+                        committed: false,
+                    },
+                );
+                continue;
+            }
 
-        // Split on space, assume the first item is the command
-        // and the rest are args.
-        let mut command_strings = generator.split_whitespace();
-        let command = command_strings.next().context(error::InvalidCommand {
-            command: generator.as_str(),
-        })?;
+            let task = tokio::spawn(async move {
+                let start = Instant::now();
+                let (outcome, attempts) = run_with_retries(&generator).await;
+                GeneratorResult {
+                    generator,
+                    outcome,
+                    attempts,
+                    elapsed: start.elapsed(),
+                    committed: false,
+                }
+            });
+            tasks.push((setting_str, task));
+        }
 
-        let result = process::Command::new(command)
-            .args(command_strings)
-            .output()
-            .context(error::CommandFailure {
-                program: generator.as_str(),
+        for (setting, task) in tasks {
+            let result = task.await.context(error::GeneratorTask {
+                setting: setting.as_str(),
             })?;
+            results.insert(setting, result);
+        }
+    }
+    Ok(results)
+}
 
-        // Match on the generator's exit code. This code lays the foundation
-        // for handling alternative exit codes from generators.
-        match result.status.code() {
-            Some(0) => {}
-            Some(1) => {
-                return error::FailedSettingGenerator {
-                    program: generator.as_str(),
-                    code: 1.to_string(),
-                    stderr: String::from_utf8_lossy(&result.stderr),
-                }
-                .fail()
+/// Orders the generators into layers, where each generator comes after the generators for the
+/// settings it depends on.  Dependencies on settings without a generator are already satisfied.
+fn generator_layers(generators: &HashMap<String, Generator>) -> Result<Vec<Vec<String>>> {
+    // Sorted, so run order and logs are stable.
+    let mut remaining: BTreeMap<&str, HashSet<&str>> = generators
+        .iter()
+        .map(|(setting, generator)| {
+            let dependencies = generator
+                .depends_on
+                .iter()
+                .map(|dependency| dependency.as_str())
+                .filter(|dependency| generators.contains_key(*dependency))
+                .collect();
+            (setting.as_str(), dependencies)
+        })
+        .collect();
+
+    let mut layers = Vec::new();
+    while !remaining.is_empty() {
+        let layer: Vec<&str> = remaining
+            .iter()
+            .filter(|(_, dependencies)| dependencies.is_empty())
+            .map(|(setting, _)| *setting)
+            .collect();
+        ensure!(
+            !layer.is_empty(),
+            error::GeneratorCycle {
+                settings: remaining.keys().copied().collect::<Vec<_>>().join(", "),
+            }
+        );
+        for setting in &layer {
+            remaining.remove(setting);
+        }
+        for dependencies in remaining.values_mut() {
+            for setting in &layer {
+                dependencies.remove(setting);
             }
-            Some(2) => {
+        }
+        layers.push(layer.into_iter().map(String::from).collect());
+    }
+    Ok(layers)
+}
+
+/// Sends the settings generated by the given layer to the API and commits them, so later
+/// generators can read them.  If `apply` is true, the changes are also applied to the system.
+async fn commit_generated(
+    socket_path: &str,
+    layer: &[String],
+    results: &mut BTreeMap<String, GeneratorResult>,
+    apply: bool,
+) -> Result<()> {
+    let generated: HashMap<&str, &str> = layer
+        .iter()
+        .filter_map(
+            |setting| match results.get(setting).map(|result| &result.outcome) {
+                Some(Outcome::Generated(output)) => Some((setting.as_str(), output.as_str())),
+                _ => None,
+            },
+        )
+        .collect();
+    if generated.is_empty() {
+        return Ok(());
+    }
+
+    info!("Committing generated settings for dependent generators");
+    set_settings(socket_path, settings_from_map(generated)?).await?;
+    commit_settings(socket_path, apply).await?;
+    for setting in layer {
+        if let Some(result) = results.get_mut(setting) {
+            result.committed = true;
+        }
+    }
+    Ok(())
+}
+
+/// Runs a generator until it succeeds or it's out of retries, waiting between attempts.
+/// Returns the outcome of the last attempt and the number of attempts.
+async fn run_with_retries(generator: &Generator) -> (Outcome, u32) {
+    let mut attempts = 0;
+    let mut backoff = Duration::from_secs(generator.retry_backoff);
+    loop {
+        attempts += 1;
+        let outcome = match run_generator(generator).await {
+            Ok(Some(output)) => Outcome::Generated(output),
+            Ok(None) => Outcome::Skipped,
+            Err(e) => Outcome::Failed(e),
+        };
+        match outcome {
+            Outcome::Failed(ref e) if is_retryable(e) && attempts <= generator.retries => {
                 warn!(
-                    "'{}' returned 2, not setting '{}', continuing with other generators",
-                    command, generator
+                    "Generator '{}' failed on attempt {}, retrying in {}s: {}",
+                    generator.command,
+                    attempts,
+                    backoff.as_secs(),
+                    e
                 );
-                continue;
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
             }
-            Some(x) => {
-                return error::UnexpectedReturnCode {
-                    program: generator.as_str(),
-                    code: x.to_string(),
-                    stderr: String::from_utf8_lossy(&result.stderr),
-                }
-                .fail()
+            outcome => return (outcome, attempts),
+        }
+    }
+}
+
+/// Whether a generator failure could be transient, so it's worth running the generator again.
+/// Output we can't understand won't get better by asking again.
+fn is_retryable(error: &SundogError) -> bool {
+    matches!(
+        error,
+        SundogError::FailedSettingGenerator { .. }
+            | SundogError::UnexpectedReturnCode { .. }
+            | SundogError::GeneratorTimeout { .. }
+    )
+}
+
+/// Runs a generator once and returns its output, in serialized datastore form, or None if the
+/// generator asked us not to set the setting.
+async fn run_generator(generator: &Generator) -> Result<Option<String>> {
+    let command_str = generator.command.as_str();
+    debug!("Running generator: '{}'", command_str);
+
+    // Split on space, assume the first item is the command
+    // and the rest are args.
+    let mut command_strings = command_str.split_whitespace();
+    let command = command_strings.next().context(error::InvalidCommand {
+        command: command_str,
+    })?;
+
+    // Kill the generator if it runs too long.
+    let result = tokio::time::timeout(
+        Duration::from_secs(generator.timeout),
+        Command::new(command)
+            .args(command_strings)
+            .kill_on_drop(true)
+            .output(),
+    )
+    .await
+    .context(error::GeneratorTimeout {
+        program: command_str,
+        seconds: generator.timeout,
+    })?
+    .context(error::CommandFailure {
+        program: command_str,
+    })?;
+
+    // Match on the generator's exit code. This code lays the foundation
+    // for handling alternative exit codes from generators.
+    match result.status.code() {
+        Some(0) => {}
+        Some(1) => {
+            return error::FailedSettingGenerator {
+                program: command_str,
+                code: 1.to_string(),
+                stderr: String::from_utf8_lossy(&result.stderr),
             }
-            // A process will return None if terminated by a signal, regard this as
-            // a failure since we could have incomplete data
-            None => {
-                return error::FailedSettingGenerator {
-                    program: generator.as_str(),
-                    code: "signal",
-                    stderr: String::from_utf8_lossy(&result.stderr),
-                }
-                .fail()
+            .fail()
+        }
+        Some(2) => {
+            warn!(
+                "'{}' returned 2, not setting a value, continuing with other generators",
+                command_str
+            );
+            return Ok(None);
+        }
+        Some(x) => {
+            return error::UnexpectedReturnCode {
+                program: command_str,
+                code: x.to_string(),
+                stderr: String::from_utf8_lossy(&result.stderr),
             }
+            .fail()
         }
+        // A process will return None if terminated by a signal, regard this as
+        // a failure since we could have incomplete data
+        None => {
+            return error::FailedSettingGenerator {
+                program: command_str,
+                code: "signal",
+                stderr: String::from_utf8_lossy(&result.stderr),
+            }
+            .fail()
+        }
+    }
 
-        // Sundog programs are expected to output JSON, which allows them to represent types other
-        // than strings, which in turn allows our API model to use types more accurate than strings
-        // for generated settings.
-        //
-        // First, we pull the raw string from the process output.
-        let output_raw = str::from_utf8(&result.stdout)
-            .context(error::GeneratorOutput {
-                program: generator.as_str(),
-            })?
-            .trim()
-            .to_string();
-        trace!("Generator '{}' output: {}", &generator, &output_raw);
-
-        // Next, we deserialize the text into a Value that can represent any JSON type.
-        let output_value: serde_json::Value =
-            serde_json::from_str(&output_raw).context(error::CommandJson {
-                generator: &generator,
-                input: &output_raw,
-            })?;
+    // Sundog programs are expected to output JSON, which allows them to represent types other
+    // than strings, which in turn allows our API model to use types more accurate than strings
+    // for generated settings.
+    //
+    // First, we pull the raw string from the process output.
+    let output_raw = str::from_utf8(&result.stdout)
+        .context(error::GeneratorOutput {
+            program: command_str,
+        })?
+        .trim()
+        .to_string();
+    trace!("Generator '{}' output: {}", command_str, &output_raw);
+
+    // Next, we deserialize the text into a Value that can represent any JSON type.
+    let output_value: serde_json::Value =
+        serde_json::from_str(&output_raw).context(error::CommandJson {
+            generator: command_str,
+            input: &output_raw,
+        })?;
 
-        // Finally, we re-serialize the command output; we intend to call the datastore-level
-        // construct `from_map` on it, which expects serialized values.
-        //
-        // We have to go through the round-trip of serialization because the data store
-        // serialization format may not be the same as the format we choose for sundog.
-        let serialized_output =
-            datastore::serialize_scalar(&output_value).context(error::SerializeScalar {
-                value: output_value,
-            })?;
-        trace!("Serialized output: {}", &serialized_output);
+    // Finally, we re-serialize the command output; we intend to call the datastore-level
+    // construct `from_map` on it, which expects serialized values.
+    //
+    // We have to go through the round-trip of serialization because the data store
+    // serialization format may not be the same as the format we choose for sundog.
+    let serialized_output =
+        datastore::serialize_scalar(&output_value).context(error::SerializeScalar {
+            value: output_value,
+        })?;
+    trace!("Serialized output: {}", &serialized_output);
 
-        settings.insert(setting, serialized_output);
+    Ok(Some(serialized_output))
+}
+
+/// Writes the status of each generator to the given file as JSON, keyed by setting name.
+fn write_status<P>(path: P, results: &BTreeMap<String, GeneratorResult>) -> Result<()>
+where
+    P: AsRef<Path>,
+{
+    let path = path.as_ref();
+    let statuses: BTreeMap<&str, GeneratorStatus<'_>> = results
+        .iter()
+        .map(|(setting, result)| (setting.as_str(), result.status()))
+        .collect();
+    let status_json = serde_json::to_string_pretty(&statuses).context(error::SerializeStatus)?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).context(error::WriteStatus { path: parent })?;
+    }
+    fs::write(path, status_json).context(error::WriteStatus { path })
+}
+
+/// Collects the generated settings that weren't already committed into a Settings struct, or
+/// returns the first failure.  Returns None if there are no settings to send.
+fn generated_settings(
+    results: BTreeMap<String, GeneratorResult>,
+) -> Result<Option<model::Settings>> {
+    let mut generated = HashMap::new();
+    for (setting, result) in results {
+        match result.outcome {
+            Outcome::Generated(output) if !result.committed => {
+                generated.insert(setting, output);
+            }
+            Outcome::Failed(e) => return Err(e),
+            Outcome::Generated(_) | Outcome::Populated | Outcome::Skipped | Outcome::Blocked(_) => {
+            }
+        }
+    }
+    if generated.is_empty() {
+        return Ok(None);
+    }
+    settings_from_map(generated).map(Some)
+}
+
+/// Builds a Settings struct from setting names and their serialized values.
+fn settings_from_map<S, V>(generated: HashMap<S, V>) -> Result<model::Settings>
+where
+    S: AsRef<str>,
+    V: AsRef<str>,
+{
+    let mut settings = HashMap::new();
+    for (setting_str, output) in generated {
+        let setting_str = setting_str.as_ref();
+        let setting = Key::new(KeyType::Data, setting_str).context(error::InvalidKey {
+            key_type: KeyType::Data,
+            key: setting_str,
+        })?;
+        settings.insert(setting, output.as_ref().to_string());
     }
 
     // The API takes a properly nested Settings struct, so deserialize our map to a Settings
@@ -359,7 +925,7 @@ where
     let uri = &format!(
         "{}?tx={}",
         constants::API_SETTINGS_URI,
-        constants::LAUNCH_TRANSACTION
+        constants::GENERATED_TRANSACTION
     );
     let method = "PATCH";
     trace!("Settings to {} to {}: {}", method, uri, &request_body);
@@ -380,10 +946,41 @@ where
     Ok(())
 }
 
+/// Commit our transaction, so the settings in it are live, and if `apply` is true, apply the
+/// changes to the system
+async fn commit_settings<S>(socket_path: S, apply: bool) -> Result<()>
+where
+    S: AsRef<str>,
+{
+    let base_uri = if apply {
+        API_COMMIT_AND_APPLY_URI
+    } else {
+        API_COMMIT_URI
+    };
+    let uri = &format!("{}?tx={}", base_uri, constants::GENERATED_TRANSACTION);
+    let method = "POST";
+    trace!("{}-ing to {}", method, uri);
+    let (code, response_body) = apiclient::raw_request(socket_path.as_ref(), uri, method, None)
+        .await
+        .context(error::APIRequest { method, uri })?;
+    ensure!(
+        code.is_success(),
+        error::APIResponse {
+            method,
+            uri,
+            code,
+            response_body,
+        }
+    );
+
+    Ok(())
+}
+
 /// Store the args we receive on the command line
 struct Args {
     log_level: LevelFilter,
     socket_path: String,
+    refresh: bool,
 }
 
 /// Print a usage message in the event a bad arg is passed
@@ -393,8 +990,12 @@ fn usage() -> ! {
         r"Usage: {}
             [ --socket-path PATH ]
             [ --log-level trace|debug|info|warn|error ]
+            [ --refresh ]
+
+    Socket path defaults to {}
 
-    Socket path defaults to {}",
+    --refresh generates settings again whose values were generated and whose generators
+    have an every-boot or on-demand refresh policy, then applies the changes.",
         program_name,
         constants::API_SOCKET,
     );
@@ -411,6 +1012,7 @@ fn usage_msg<S: AsRef<str>>(msg: S) -> ! {
 fn parse_args(args: env::Args) -> Args {
     let mut log_level = None;
     let mut socket_path = None;
+    let mut refresh = false;
 
     let mut iter = args.skip(1);
     while let Some(arg) = iter.next() {
@@ -431,6 +1033,8 @@ fn parse_args(args: env::Args) -> Args {
                 )
             }
 
+            "--refresh" => refresh = true,
+
             _ => usage(),
         }
     }
@@ -438,6 +1042,7 @@ fn parse_args(args: env::Args) -> Args {
     Args {
         log_level: log_level.unwrap_or_else(|| LevelFilter::Info),
         socket_path: socket_path.unwrap_or_else(|| constants::API_SOCKET.to_string()),
+        refresh,
     }
 }
 
@@ -450,18 +1055,62 @@ async fn run() -> Result<()> {
 
     info!("Sundog started");
 
+    // Only one sundog generates settings at a time.  For a refresh, the API holds the lock for us,
+    // so it can tell the user if one is already running.
+    let lockfile_path = constants::SUNDOG_LOCKFILE;
+    let _lockfile = if args.refresh {
+        None
+    } else {
+        let lockfile = File::create(lockfile_path).context(error::LockOpen {
+            path: lockfile_path,
+        })?;
+        // Newer Rust has its own File::lock_exclusive, with a different error type; use fs2's.
+        FileExt::lock_exclusive(&lockfile).context(error::Lock {
+            path: lockfile_path,
+        })?;
+        Some(lockfile)
+    };
+
     info!("Retrieving setting generators");
-    let generators = get_setting_generators(&args.socket_path).await?;
+    let mut generators = get_setting_generators(&args.socket_path).await?;
+    if args.refresh {
+        // Generators that only run once have nothing to refresh.
+        generators.retain(|_, generator| generator.refresh != Refresh::Once);
+    }
     if generators.is_empty() {
         info!("No settings to generate, exiting");
         process::exit(0)
     }
 
-    info!("Retrieving settings values");
-    let settings = get_dynamic_settings(&args.socket_path, generators).await?;
+    info!("Running setting generators");
+    let results = run_generators(&args.socket_path, generators, args.refresh).await?;
+    for (setting, result) in &results {
+        let status = result.status();
+        match status.error {
+            Some(ref e) => error!(
+                "{}: {} after {} attempts: {}",
+                setting, status.status, status.attempts, e
+            ),
+            None => info!("{}: {}", setting, status.status),
+        }
+    }
+    // The status is useful for troubleshooting, but isn't worth failing over.
+    if let Err(e) = write_status(STATUS_FILE, &results) {
+        warn!("{}", e);
+    }
+    let settings = match generated_settings(results)? {
+        Some(settings) => settings,
+        None => {
+            info!("No settings were generated");
+            return Ok(());
+        }
+    };
 
     info!("Sending settings values to the API");
     set_settings(&args.socket_path, settings).await?;
+    // At boot, the settings are applied by the settings-applier service after we exit; for an
+    // on-demand refresh, we apply them ourselves.
+    commit_settings(&args.socket_path, args.refresh).await?;
 
     Ok(())
 }
@@ -476,3 +1125,184 @@ async fn main() {
         process::exit(1);
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    fn generator(command: &str, retries: u32) -> Generator {
+        Generator {
+            command: command.to_string(),
+            timeout: 5,
+            retries,
+            retry_backoff: 0,
+            depends_on: Vec::new(),
+            refresh: Refresh::Once,
+        }
+    }
+
+    fn dependent(depends_on: &[&str]) -> Generator {
+        Generator {
+            depends_on: depends_on.iter().map(|d| d.to_string()).collect(),
+            ..generator("true", 0)
+        }
+    }
+
+    #[test]
+    fn parse_metadata() {
+        let command: GeneratorMetadata = serde_json::from_value(json!("pluto max-pods")).unwrap();
+        assert_eq!(
+            Generator::from(command),
+            Generator {
+                command: "pluto max-pods".to_string(),
+                timeout: DEFAULT_TIMEOUT_SECONDS,
+                retries: 0,
+                retry_backoff: DEFAULT_RETRY_BACKOFF_SECONDS,
+                depends_on: Vec::new(),
+                refresh: Refresh::Once,
+            }
+        );
+
+        let table: GeneratorMetadata = serde_json::from_value(
+            json!({"command": "pluto max-pods", "timeout": 120, "retries": 3, "depends-on": ["settings.aws.region"], "refresh": "every-boot"}),
+        )
+        .unwrap();
+        assert_eq!(
+            Generator::from(table),
+            Generator {
+                command: "pluto max-pods".to_string(),
+                timeout: 120,
+                retries: 3,
+                retry_backoff: DEFAULT_RETRY_BACKOFF_SECONDS,
+                depends_on: vec!["settings.aws.region".to_string()],
+                refresh: Refresh::EveryBoot,
+            }
+        );
+
+        assert!(serde_json::from_value::<GeneratorMetadata>(
+            json!({"command": "pluto max-pods", "tries": 3})
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn refresh_policy() {
+        assert!(!Refresh::Once.applies(false));
+        assert!(!Refresh::Once.applies(true));
+        assert!(Refresh::EveryBoot.applies(false));
+        assert!(Refresh::EveryBoot.applies(true));
+        assert!(!Refresh::OnDemand.applies(false));
+        assert!(Refresh::OnDemand.applies(true));
+    }
+
+    #[test]
+    fn layers() {
+        let mut generators = HashMap::new();
+        generators.insert("settings.a".to_string(), dependent(&[]));
+        generators.insert("settings.b".to_string(), dependent(&["settings.a"]));
+        generators.insert(
+            "settings.c".to_string(),
+            dependent(&["settings.a", "settings.b"]),
+        );
+        // Dependencies on settings without generators are already satisfied.
+        generators.insert(
+            "settings.d".to_string(),
+            dependent(&["settings.aws.region"]),
+        );
+
+        assert_eq!(
+            generator_layers(&generators).unwrap(),
+            vec![
+                vec!["settings.a".to_string(), "settings.d".to_string()],
+                vec!["settings.b".to_string()],
+                vec!["settings.c".to_string()],
+            ]
+        );
+    }
+
+    #[test]
+    fn layers_cycle() {
+        let mut generators = HashMap::new();
+        generators.insert("settings.a".to_string(), dependent(&[]));
+        generators.insert("settings.b".to_string(), dependent(&["settings.c"]));
+        generators.insert("settings.c".to_string(), dependent(&["settings.b"]));
+
+        match generator_layers(&generators) {
+            Err(SundogError::GeneratorCycle { settings }) => {
+                assert_eq!(settings, "settings.b, settings.c")
+            }
+            other => panic!("expected cycle, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn failures_retried() {
+        let (outcome, attempts) = run_with_retries(&generator("false", 2)).await;
+        assert!(matches!(
+            outcome,
+            Outcome::Failed(SundogError::FailedSettingGenerator { .. })
+        ));
+        assert_eq!(attempts, 3);
+    }
+
+    #[tokio::test]
+    async fn bad_output_not_retried() {
+        // `true` succeeds without printing any JSON.
+        let (outcome, attempts) = run_with_retries(&generator("true", 2)).await;
+        assert!(matches!(
+            outcome,
+            Outcome::Failed(SundogError::CommandJson { .. })
+        ));
+        assert_eq!(attempts, 1);
+    }
+
+    #[tokio::test]
+    async fn timeout() {
+        let mut generator = generator("sleep 10", 1);
+        generator.timeout = 0;
+        let (outcome, attempts) = run_with_retries(&generator).await;
+        assert!(matches!(
+            outcome,
+            Outcome::Failed(SundogError::GeneratorTimeout { .. })
+        ));
+        assert_eq!(attempts, 2);
+    }
+
+    #[tokio::test]
+    async fn generated() {
+        let (outcome, attempts) = run_with_retries(&generator("echo 110", 0)).await;
+        assert!(matches!(outcome, Outcome::Generated(ref output) if output == "110"));
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn status_file() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("sundog").join("status.json");
+        let mut results = BTreeMap::new();
+        results.insert(
+            "settings.kubernetes.max-pods".to_string(),
+            GeneratorResult {
+                generator: generator("pluto max-pods", 0),
+                outcome: Outcome::Generated("110".to_string()),
+                attempts: 1,
+                elapsed: Duration::from_millis(500),
+                committed: false,
+            },
+        );
+        write_status(&path, &results).unwrap();
+
+        let status: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(
+            status,
+            json!({"settings.kubernetes.max-pods": {
+                "command": "pluto max-pods",
+                "status": "generated",
+                "attempts": 1,
+                "elapsed-seconds": 0.5,
+            }})
+        );
+    }
+}
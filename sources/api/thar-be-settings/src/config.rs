@@ -1,9 +1,16 @@
 use crate::service::Services;
 use crate::{error, Result};
+use datastore::{Key, KeyType};
 use itertools::join;
-use snafu::ResultExt;
+use nix::unistd::{fchown, Gid, Uid};
+use schnauzer::references::TemplateReferences;
+use snafu::{OptionExt, ResultExt};
 use std::collections::HashSet;
-use std::fs;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
 use std::path::{Path, PathBuf};
 
 /// Query the API for ConfigurationFile data
@@ -42,6 +49,34 @@ pub fn get_config_file_names(services: &Services) -> HashSet<String> {
     config_file_set
 }
 
+/// Checks whether the named template refers to any of the changed settings, directly or through
+/// the partials it includes.  A reference covers the keys under it, like `{{#each settings.a}}`
+/// covers `settings.a.b`, and a changed key covers the references under it.  Templates that can't
+/// be checked are treated as affected.  `references` keeps what each template refers to, so it
+/// should be kept along with the registry.
+#[allow(clippy::implicit_hasher)]
+pub fn template_affected(
+    registry: &handlebars::Handlebars<'_>,
+    references: &mut TemplateReferences,
+    name: &str,
+    changed_settings: &HashSet<String>,
+) -> bool {
+    let references = match references.settings(registry, name) {
+        Some(references) => references,
+        None => return true,
+    };
+    changed_settings.iter().any(|setting| {
+        let changed = match Key::new(KeyType::Data, setting) {
+            Ok(key) => key,
+            Err(_) => return true,
+        };
+        let changed = changed.segments();
+        references
+            .iter()
+            .any(|reference| changed.starts_with(reference) || reference.starts_with(changed))
+    })
+}
+
 /// Render the configuration files
 // If strict is True, return an error if we fail to render any template.
 // If strict is False, ignore failures, always returning an Ok value
@@ -59,12 +94,12 @@ pub fn render_config_files(
 
         let try_rendered = registry.render(&name, &settings);
         if strict {
-            let rendered = try_rendered.context(error::TemplateRender { template: name })?;
-            rendered_configs.push(RenderedConfigFile::new(&metadata.path, rendered));
+            let rendered = try_rendered.context(error::TemplateRender { template: &name })?;
+            rendered_configs.push(RenderedConfigFile::new(name, &metadata.path, rendered));
         } else {
             match try_rendered {
                 Ok(rendered) => {
-                    rendered_configs.push(RenderedConfigFile::new(&metadata.path, rendered))
+                    rendered_configs.push(RenderedConfigFile::new(name, &metadata.path, rendered))
                 }
                 Err(err) => warn!("Unable to render template '{}': {}", &name, err),
             }
@@ -74,33 +109,49 @@ pub fn render_config_files(
     Ok(rendered_configs)
 }
 
-/// Write all the configuration files to disk
-pub fn write_config_files(rendered_config: Vec<RenderedConfigFile>) -> Result<()> {
+/// Write all the configuration files to disk, returning the previous state of each file so the
+/// changes can be undone with `ConfigFileBackups::restore`.  Files whose rendered content matches
+/// what's already on disk are left alone, and have no backup.  If any file fails to write, the
+/// files already written are restored before returning the error.
+pub fn write_config_files(rendered_config: Vec<RenderedConfigFile>) -> Result<ConfigFileBackups> {
+    let mut backups = ConfigFileBackups::default();
     for cfg in rendered_config {
-        debug!("Writing {:?}", &cfg.path);
-        cfg.write_to_disk()?;
+        match cfg.write_to_disk() {
+            Ok(Some(backup)) => backups.0.push(backup),
+            Ok(None) => debug!("{:?} is unchanged, not writing", &cfg.path),
+            Err(e) => {
+                if let Err(restore_err) = backups.restore() {
+                    error!("Failed to restore configuration files: {}", restore_err);
+                }
+                return Err(e);
+            }
+        }
     }
-    Ok(())
+    Ok(backups)
 }
 
-/// RenderedConfigFile contains both the path to the config file
-/// and the rendered data to write.
+/// RenderedConfigFile contains the name of the config file, the path
+/// to the config file, and the rendered data to write.
 #[derive(Debug)]
 pub struct RenderedConfigFile {
+    name: String,
     path: PathBuf,
     rendered: String,
 }
 
 impl RenderedConfigFile {
-    fn new(path: &str, rendered: String) -> RenderedConfigFile {
+    fn new(name: String, path: &str, rendered: String) -> RenderedConfigFile {
         RenderedConfigFile {
+            name,
             path: PathBuf::from(&path),
             rendered,
         }
     }
 
-    /// Writes the rendered template at the proper location
-    fn write_to_disk(&self) -> Result<()> {
+    /// Writes the rendered template at the proper location, replacing any existing file
+    /// atomically, and returns a backup of what was there before.  Returns None, without
+    /// writing, if the file already has the rendered content.
+    fn write_to_disk(&self) -> Result<Option<ConfigFileBackup>> {
         if let Some(dirname) = self.path.parent() {
             fs::create_dir_all(dirname).context(error::TemplateWrite {
                 path: dirname,
@@ -108,11 +159,228 @@ impl RenderedConfigFile {
             })?;
         };
 
-        fs::write(&self.path, self.rendered.as_bytes()).context(error::TemplateWrite {
-            path: &self.path,
-            pathtype: "file",
+        let previous = match File::open(&self.path) {
+            Ok(file) => {
+                Some(PreviousFile::read(file).context(error::ConfigBackup { path: &self.path })?)
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e).context(error::ConfigBackup { path: &self.path }),
+        };
+        if let Some(ref previous) = previous {
+            if previous.data == self.rendered.as_bytes() {
+                return Ok(None);
+            }
+        }
+
+        debug!("Writing {:?}", &self.path);
+        write_atomically(
+            &self.path,
+            self.rendered.as_bytes(),
+            previous.as_ref().map(|p| &p.attributes),
+        )?;
+
+        Ok(Some(ConfigFileBackup {
+            name: self.name.clone(),
+            path: self.path.clone(),
+            previous,
+        }))
+    }
+}
+
+/// The state of configuration files before they were written, so they can be restored, for
+/// example if services fail to restart with the new files.
+#[derive(Debug, Default)]
+pub struct ConfigFileBackups(Vec<ConfigFileBackup>);
+
+impl ConfigFileBackups {
+    /// Returns true if no files were written.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Returns the names of the files that were written, meaning their content changed.
+    pub fn changed_files(&self) -> HashSet<String> {
+        self.0.iter().map(|backup| backup.name.clone()).collect()
+    }
+
+    /// Puts back the previous contents of each written file.  Files that didn't exist before are
+    /// left in place, since there's nothing to go back to, and removing them could break services
+    /// that need them.  Tries every file, even if some fail, and returns the first error.
+    pub fn restore(self) -> Result<()> {
+        let mut result = Ok(());
+        for backup in self.0.into_iter().rev() {
+            debug!("Restoring {:?}", &backup.path);
+            if let Err(e) = backup.restore() {
+                error!("{}", e);
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+        result
+    }
+}
+
+/// The state of a single configuration file before it was written.
+#[derive(Debug)]
+struct ConfigFileBackup {
+    name: String,
+    path: PathBuf,
+    /// None if the file didn't exist.
+    previous: Option<PreviousFile>,
+}
+
+#[derive(Debug)]
+struct PreviousFile {
+    data: Vec<u8>,
+    attributes: FileAttributes,
+}
+
+impl PreviousFile {
+    fn read(mut file: File) -> io::Result<Self> {
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+        let attributes = FileAttributes::read(&file)?;
+        Ok(Self { data, attributes })
+    }
+}
+
+/// The extended attribute holding a file's SELinux label.
+const SELINUX_XATTR: &[u8] = b"security.selinux\0";
+
+/// The attributes of an existing file that a file replacing it should keep, so services that need
+/// a particular owner or label can still read it.
+#[derive(Debug)]
+struct FileAttributes {
+    permissions: fs::Permissions,
+    uid: u32,
+    gid: u32,
+    /// None if the file has no label, for example on a filesystem without SELinux support.
+    label: Option<Vec<u8>>,
+}
+
+impl FileAttributes {
+    fn read(file: &File) -> io::Result<Self> {
+        let metadata = file.metadata()?;
+        Ok(Self {
+            permissions: metadata.permissions(),
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            label: get_label(file)?,
         })
     }
+
+    fn apply(&self, file: &File) -> io::Result<()> {
+        // Change the owner before the mode, since changing the owner clears setuid and setgid.
+        fchown(
+            file.as_raw_fd(),
+            Some(Uid::from_raw(self.uid)),
+            Some(Gid::from_raw(self.gid)),
+        )?;
+        file.set_permissions(self.permissions.clone())?;
+        if let Some(label) = &self.label {
+            set_label(file, label)?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the SELinux label of the file, or None if it has none.
+fn get_label(file: &File) -> io::Result<Option<Vec<u8>>> {
+    let name = SELINUX_XATTR.as_ptr() as *const libc::c_char;
+    loop {
+        // Ask for the size first, then read into a buffer of that size; if the label changes in
+        // between and no longer fits, try again.
+        let size = unsafe { libc::fgetxattr(file.as_raw_fd(), name, std::ptr::null_mut(), 0) };
+        if size < 0 {
+            let e = io::Error::last_os_error();
+            return match e.raw_os_error() {
+                Some(libc::ENODATA) | Some(libc::ENOTSUP) => Ok(None),
+                _ => Err(e),
+            };
+        }
+        let mut label = vec![0; size as usize];
+        let size = unsafe {
+            libc::fgetxattr(
+                file.as_raw_fd(),
+                name,
+                label.as_mut_ptr() as *mut libc::c_void,
+                label.len(),
+            )
+        };
+        if size >= 0 {
+            label.truncate(size as usize);
+            return Ok(Some(label));
+        }
+        let e = io::Error::last_os_error();
+        if e.raw_os_error() != Some(libc::ERANGE) {
+            return Err(e);
+        }
+    }
+}
+
+/// Sets the SELinux label of the file.
+fn set_label(file: &File, label: &[u8]) -> io::Result<()> {
+    let result = unsafe {
+        libc::fsetxattr(
+            file.as_raw_fd(),
+            SELINUX_XATTR.as_ptr() as *const libc::c_char,
+            label.as_ptr() as *const libc::c_void,
+            label.len(),
+            0,
+        )
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+impl ConfigFileBackup {
+    fn restore(self) -> Result<()> {
+        match self.previous {
+            Some(previous) => {
+                write_atomically(&self.path, &previous.data, Some(&previous.attributes))
+            }
+            None => {
+                warn!(
+                    "{:?} didn't exist before, leaving the new version in place",
+                    &self.path
+                );
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Writes data to a temporary file next to `path` and renames it into place, so readers of `path`
+/// never see a partially written file.  If `attributes` are given, they're applied to the new
+/// file before the rename, so replacing a file keeps its mode, owner, and SELinux label.
+fn write_atomically(path: &Path, data: &[u8], attributes: Option<&FileAttributes>) -> Result<()> {
+    let file_name = path.file_name().context(error::ConfigPath { path })?;
+    let mut temp_name = OsString::from(".");
+    temp_name.push(file_name);
+    temp_name.push(".tmp");
+    let temp_path = path.with_file_name(temp_name);
+
+    let write = |temp_path: &Path| -> io::Result<()> {
+        let mut file = File::create(temp_path)?;
+        file.write_all(data)?;
+        if let Some(attributes) = attributes {
+            attributes.apply(&file)?;
+        }
+        file.sync_all()?;
+        fs::rename(temp_path, path)
+    };
+    write(&temp_path)
+        .map_err(|e| {
+            let _ = fs::remove_file(&temp_path);
+            e
+        })
+        .context(error::TemplateWrite {
+            path,
+            pathtype: "file",
+        })
 }
 
 #[cfg(test)]
@@ -121,17 +389,23 @@ mod test {
     use crate::service::Services;
     use maplit::{hashmap, hashset};
     use std::convert::TryInto;
+    use std::os::unix::fs::PermissionsExt;
+    use tempfile::TempDir;
 
     #[test]
     fn test_get_config_file_names() {
         let input_map = hashmap!(
             "foo".to_string() => model::Service {
                 configuration_files: vec!["file1".try_into().unwrap()],
-                restart_commands: vec!["echo hi".to_string()]
+                restart_commands: vec!["echo hi".to_string()],
+                restart_after: vec![],
+                restart_only_on_file_change: false,
             },
             "bar".to_string() => model::Service {
                 configuration_files: vec!["file1".try_into().unwrap(), "file2".try_into().unwrap()],
-                restart_commands: vec!["echo hi".to_string()]
+                restart_commands: vec!["echo hi".to_string()],
+                restart_after: vec![],
+                restart_only_on_file_change: false,
             },
         );
         let services = Services::from_model_services(input_map, None);
@@ -140,4 +414,137 @@ mod test {
 
         assert_eq!(get_config_file_names(&services), expected_output)
     }
+
+    #[test]
+    fn test_template_affected() {
+        let mut registry = handlebars::Handlebars::new();
+        registry
+            .register_partial("updates-toml", "seed = {{settings.updates.seed}}")
+            .unwrap();
+        registry
+            .register_template_string(
+                "updog-toml",
+                "url = {{settings.updates.metadata-base-url}}\n{{> updates-toml}}",
+            )
+            .unwrap();
+        registry
+            .register_template_string(
+                "kubelet-config",
+                "{{#each settings.kubernetes.node-labels}}{{@key}}={{this}}{{/each}}",
+            )
+            .unwrap();
+
+        let mut references = TemplateReferences::new();
+        let mut affected = |name, setting: &str| {
+            template_affected(
+                &registry,
+                &mut references,
+                name,
+                &hashset! {setting.to_string()},
+            )
+        };
+        // Settings used through a partial count, and other sub-keys of the same parent don't.
+        assert!(affected("updog-toml", "settings.updates.seed"));
+        assert!(affected("updog-toml", "settings.updates.metadata-base-url"));
+        assert!(!affected("updog-toml", "settings.updates.targets-base-url"));
+        // A reference covers the keys under it.
+        assert!(affected(
+            "kubelet-config",
+            "settings.kubernetes.node-labels.\"example.com/zone\""
+        ));
+        assert!(!affected("kubelet-config", "settings.kubernetes.max-pods"));
+        // Templates we don't know are assumed to be affected.
+        assert!(affected("missing", "settings.motd"));
+    }
+
+    fn rendered(path: &Path, rendered: &str) -> RenderedConfigFile {
+        let name = path.file_name().unwrap().to_str().unwrap().to_string();
+        RenderedConfigFile::new(name, path.to_str().unwrap(), rendered.to_string())
+    }
+
+    #[test]
+    fn test_write_and_restore() {
+        let dir = TempDir::new().unwrap();
+        let existing = dir.path().join("existing.conf");
+        let new = dir.path().join("sub/new.conf");
+        fs::write(&existing, "old").unwrap();
+        fs::set_permissions(&existing, fs::Permissions::from_mode(0o600)).unwrap();
+
+        let backups = write_config_files(vec![
+            rendered(&existing, "replaced"),
+            rendered(&new, "created"),
+        ])
+        .unwrap();
+        assert_eq!(fs::read_to_string(&existing).unwrap(), "replaced");
+        assert_eq!(fs::read_to_string(&new).unwrap(), "created");
+        // The mode of a replaced file is kept, and no temporary files are left behind.
+        let mode = fs::metadata(&existing).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+        assert_eq!(fs::read_dir(dir.path().join("sub")).unwrap().count(), 1);
+
+        backups.restore().unwrap();
+        assert_eq!(fs::read_to_string(&existing).unwrap(), "old");
+        let mode = fs::metadata(&existing).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        // There's no previous version of a new file, so it's left alone.
+        assert_eq!(fs::read_to_string(&new).unwrap(), "created");
+    }
+
+    #[test]
+    fn test_owner_kept() {
+        // Only root can give files away.
+        if !Uid::effective().is_root() {
+            return;
+        }
+        let dir = TempDir::new().unwrap();
+        let existing = dir.path().join("existing.conf");
+        fs::write(&existing, "old").unwrap();
+        let (uid, gid) = (Uid::from_raw(1234), Gid::from_raw(5678));
+        nix::unistd::chown(&existing, Some(uid), Some(gid)).unwrap();
+
+        let backups = write_config_files(vec![rendered(&existing, "replaced")]).unwrap();
+        let metadata = fs::metadata(&existing).unwrap();
+        assert_eq!((metadata.uid(), metadata.gid()), (1234, 5678));
+
+        backups.restore().unwrap();
+        let metadata = fs::metadata(&existing).unwrap();
+        assert_eq!((metadata.uid(), metadata.gid()), (1234, 5678));
+    }
+
+    #[test]
+    fn test_failed_write_restores() {
+        let dir = TempDir::new().unwrap();
+        let existing = dir.path().join("existing.conf");
+        fs::write(&existing, "old").unwrap();
+        // A directory can't be replaced by a file, so the second write fails.
+        let blocked = dir.path().join("blocked");
+        fs::create_dir(&blocked).unwrap();
+
+        let result = write_config_files(vec![
+            rendered(&existing, "replaced"),
+            rendered(&blocked, "oops"),
+        ]);
+        assert!(result.is_err());
+        assert_eq!(fs::read_to_string(&existing).unwrap(), "old");
+    }
+
+    #[test]
+    fn test_unchanged_not_written() {
+        let dir = TempDir::new().unwrap();
+        let same = dir.path().join("same.conf");
+        let changed = dir.path().join("changed.conf");
+        fs::write(&same, "same").unwrap();
+        fs::write(&changed, "old").unwrap();
+        let modified = fs::metadata(&same).unwrap().modified().unwrap();
+
+        let backups =
+            write_config_files(vec![rendered(&same, "same"), rendered(&changed, "new")]).unwrap();
+        assert_eq!(
+            backups.changed_files(),
+            hashset! {"changed.conf".to_string()}
+        );
+        assert_eq!(fs::metadata(&same).unwrap().modified().unwrap(), modified);
+        assert_eq!(fs::read_to_string(&changed).unwrap(), "new");
+    }
 }
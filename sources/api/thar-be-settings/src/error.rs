@@ -1,7 +1,10 @@
+use crate::service::RestartResult;
 use http::StatusCode;
+use itertools::join;
 use snafu::Snafu;
 use std::io;
 use std::path::PathBuf;
+use std::time::Duration;
 
 /// Potential errors during configuration application
 #[derive(Debug, Snafu)]
@@ -13,6 +16,12 @@ pub enum Error {
         source: io::Error,
     },
 
+    #[snafu(display("Failed to listen on socket {}: {}", path.display(), source))]
+    ListenBind { path: PathBuf, source: io::Error },
+
+    #[snafu(display("Failed to read notification: {}", source))]
+    ListenRead { source: io::Error },
+
     #[snafu(display("{} - input '{}' - {}", reason, input, source))]
     InvalidInput {
         reason: &'static str,
@@ -27,12 +36,41 @@ pub enum Error {
         source: io::Error,
     },
 
+    #[snafu(display("Failed to back up configuration file {}: {}", path.display(), source))]
+    ConfigBackup { path: PathBuf, source: io::Error },
+
+    #[snafu(display("Configuration file path {} has no file name", path.display()))]
+    ConfigPath { path: PathBuf },
+
     #[snafu(display("Failed to run restart command - '{}': {}", command, source))]
     CommandExecutionFailure { command: String, source: io::Error },
 
     #[snafu(display("Restart command failed - '{}': {}", command, stderr))]
     FailedRestartCommand { command: String, stderr: String },
 
+    #[snafu(display(
+        "Restart command timed out after {}s - '{}'",
+        timeout.as_secs(),
+        command
+    ))]
+    RestartTimeout {
+        command: String,
+        timeout: Duration,
+        source: tokio::time::error::Elapsed,
+    },
+
+    #[snafu(display("Failed to restart services: {}", join(failures, "; ")))]
+    RestartFailures { failures: Vec<RestartResult> },
+
+    #[snafu(display("Services restart after each other in a cycle: {}", services))]
+    RestartCycle { services: String },
+
+    #[snafu(display("Restart task for service '{}' failed: {}", service, source))]
+    RestartTask {
+        service: String,
+        source: tokio::task::JoinError,
+    },
+
     #[snafu(display("Restart command is invalid (empty, space prefix, etc.) - {}", command))]
     InvalidRestartCommand { command: String },
 
@@ -69,6 +107,22 @@ pub enum Error {
         source: serde_json::Error,
     },
 
+    #[snafu(display("Failed to get settings: {}", source))]
+    GetSettings { source: schnauzer::Error },
+
+    #[snafu(display("Failed to record settings before applying changes: {}", source))]
+    Snapshot {
+        source: datastore::serialization::Error,
+    },
+
+    #[snafu(display("Failed to build previous settings to roll back to: {}", source))]
+    RollbackSettings {
+        source: datastore::deserialization::Error,
+    },
+
+    #[snafu(display("Failed to serialize settings: {}", source))]
+    SerializeSettings { source: serde_json::Error },
+
     #[snafu(display("Error GETing JSON from '{}': {}", uri, source))]
     GetJson {
         uri: String,
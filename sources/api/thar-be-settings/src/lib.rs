@@ -8,10 +8,34 @@ In the normal ("specific keys") mode, it's intended to be called by the Bottlero
 It's told the keys that changed, and then queries metadata APIs to determine which services and configuration files are affected by changes to those keys.
 Detailed data is then fetched for the relevant services and configuration files.
 Configuration file data from the API includes paths to template files for each configuration file, along with the final path to write.
-It then renders the templates and rewrites the affected configuration files.
+It then renders the templates and rewrites the affected configuration files, skipping any whose rendered content matches the file already on disk.
 Service data from the API includes any commands needed to restart services affected by configuration file changes, which are run here.
+Services that depend on each other can list the services they must restart after in `restart-after`, for example kubelet after containerd; services that don't depend on each other are restarted in parallel.
+Each restart command is killed and considered failed if it runs longer than the restart timeout, and a service isn't restarted if a service it restarts after failed; the result for each service is logged.
+
+Configuration files are written atomically: each is written to a temporary file in the same directory and renamed into place, so services never read a partially written file.
+A replaced file keeps its mode, owner, group, and SELinux label.
+The previous contents of each file are kept, and if a restart command fails, the previous files are restored, the services are restarted again with them, and the original failure is reported.
+Files that didn't exist before are left in place.
+When run for all services, as at boot, there's no earlier state to go back to, so files aren't restored; restart failures are reported.
+Settings are already committed when thar-be-settings runs, so by default they aren't rolled back; the restart failure is reported so the settings can be corrected.
+In the listen mode, `--rollback-settings` also sets the changed settings back to their previous values, through the API, after restoring the files; thar-be-settings keeps a snapshot of the settings each batch of changes applied, so it knows the previous values.
+Settings that had no previous value can't be removed through the API, so they're left in place.
+
+In the specific keys mode, a service marked `restart-only-on-file-change` is only restarted if at least one of its configuration files changed.
+Other services are always restarted, since their restart commands may read settings directly, like `host-containers`.
+The services that were restarted are logged.
 
 In the standalone ("all keys") mode, it queries the API for all services and configuration files, then renders and rewrites all configuration files and restarts all services.
+
+In the listen mode, thar-be-settings keeps running and listens on `/run/thar-be-settings.sock` for notifications from the API server, each a line of JSON like `{"keys": ["settings.motd"]}`, or `{"keys": null}` for all keys.
+Notifications that arrive close together are merged, so a burst of commits leads to one pass of rendering and restarts, and batches are applied in the order received.
+The template registry is kept between batches rather than rebuilt each time.
+If nothing is listening on the socket, the API server starts thar-be-settings for each change instead.
+
+Templates can include shared partials, which schnauzer registers from `/usr/share/templates/partials` along with its helpers.
+A configuration file is only rewritten when a changed key's `affected-services` lists a service that uses the file, so those lists must account for the settings used by a template's partials, too; `schnauzer-lint` checks this at build time.
+Of those files, only the ones whose templates or partials refer to a changed key are rendered, so a change to one sub-key doesn't rewrite files that only use its siblings; a template that refers to a key, like `{{#each settings.kubernetes.node-labels}}`, uses every key under it.
 */
 
 #![deny(rust_2018_idioms)]
@@ -25,6 +49,8 @@ use std::io::{self, Read};
 
 pub mod config;
 pub mod error;
+pub mod listen;
+pub mod rollback;
 pub mod service;
 
 pub use error::Error;
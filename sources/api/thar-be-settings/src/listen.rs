@@ -0,0 +1,176 @@
+//! The listen module receives change notifications over a Unix socket, so a long-running
+//! thar-be-settings can apply changes as they're committed rather than being started for each
+//! one.
+//!
+//! Each notification is a line of JSON, like `{"keys": ["settings.motd"]}`, giving the settings
+//! that changed, or `{"keys": null}` to apply changes for all settings.  Notifications that
+//! arrive close together are merged, so a burst of commits leads to one render pass.
+
+use crate::{error, Result};
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tokio::time::{timeout_at, Instant};
+
+/// A request to apply changes to the system.
+#[derive(Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct ChangeNotification {
+    /// The settings that changed, or None to apply changes for all settings.
+    pub keys: Option<HashSet<String>>,
+}
+
+impl ChangeNotification {
+    /// Adds the changes from `other` to these, so applying the result covers both.
+    pub fn merge(&mut self, other: ChangeNotification) {
+        match (&mut self.keys, other.keys) {
+            (Some(keys), Some(other_keys)) => keys.extend(other_keys),
+            // Applying all settings covers anything else.
+            _ => self.keys = None,
+        }
+    }
+}
+
+/// Binds the socket at the given path, replacing any stale socket left by a previous listener.
+pub fn bind<P>(socket_path: P) -> Result<UnixListener>
+where
+    P: AsRef<Path>,
+{
+    let socket_path = socket_path.as_ref();
+    match fs::remove_file(socket_path) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e).context(error::ListenBind { path: socket_path }),
+    }
+    UnixListener::bind(socket_path).context(error::ListenBind { path: socket_path })
+}
+
+/// Accepts connections on the listener forever, sending each notification received to `sender`
+/// in the order received.  Problems with individual connections are logged and don't stop the
+/// listener.
+pub async fn receive(listener: UnixListener, sender: UnboundedSender<ChangeNotification>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let sender = sender.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = receive_stream(stream, sender).await {
+                        error!("{}", e);
+                    }
+                });
+            }
+            Err(e) => error!("Failed to accept connection: {}", e),
+        }
+    }
+}
+
+/// Reads notifications, one per line, from a single connection.
+async fn receive_stream(
+    stream: UnixStream,
+    sender: UnboundedSender<ChangeNotification>,
+) -> Result<()> {
+    let mut lines = BufReader::new(stream).lines();
+    while let Some(line) = lines.next_line().await.context(error::ListenRead)? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        trace!("Received notification: {}", line);
+        let notification = serde_json::from_str(&line).context(error::InvalidInput {
+            reason: "Notification must be JSON like {\"keys\": [...]}",
+            input: line.as_str(),
+        })?;
+        // The receiver only goes away when we're shutting down.
+        let _ = sender.send(notification);
+    }
+    Ok(())
+}
+
+/// Waits for the next notification, then merges it with any others that arrive within `window`,
+/// so bursts of commits are applied together.  Returns None once all senders are gone.
+pub async fn next_batch(
+    receiver: &mut UnboundedReceiver<ChangeNotification>,
+    window: Duration,
+) -> Option<ChangeNotification> {
+    let mut batch = receiver.recv().await?;
+    let deadline = Instant::now() + window;
+    while let Ok(Some(notification)) = timeout_at(deadline, receiver.recv()).await {
+        batch.merge(notification);
+    }
+    Some(batch)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use maplit::hashset;
+    use tempfile::TempDir;
+    use tokio::io::AsyncWriteExt;
+    use tokio::sync::mpsc;
+
+    fn keys(keys: &[&str]) -> ChangeNotification {
+        ChangeNotification {
+            keys: Some(keys.iter().map(|k| k.to_string()).collect()),
+        }
+    }
+
+    #[test]
+    fn merge() {
+        let mut notification = keys(&["settings.motd"]);
+        notification.merge(keys(&["settings.ntp", "settings.motd"]));
+        assert_eq!(
+            notification.keys,
+            Some(hashset! {"settings.motd".to_string(), "settings.ntp".to_string()})
+        );
+
+        notification.merge(ChangeNotification { keys: None });
+        assert_eq!(notification.keys, None);
+        notification.merge(keys(&["settings.motd"]));
+        assert_eq!(notification.keys, None);
+    }
+
+    #[tokio::test]
+    async fn batches() {
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        sender.send(keys(&["settings.motd"])).unwrap();
+        sender.send(keys(&["settings.ntp"])).unwrap();
+
+        let batch = next_batch(&mut receiver, Duration::from_millis(50)).await;
+        assert_eq!(batch, Some(keys(&["settings.motd", "settings.ntp"])));
+
+        drop(sender);
+        assert_eq!(
+            next_batch(&mut receiver, Duration::from_millis(50)).await,
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn socket() {
+        let dir = TempDir::new().unwrap();
+        let socket_path = dir.path().join("thar-be-settings.sock");
+        // A stale socket file is replaced.
+        fs::write(&socket_path, "").unwrap();
+        let listener = bind(&socket_path).unwrap();
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        tokio::spawn(receive(listener, sender));
+
+        let mut stream = UnixStream::connect(&socket_path).await.unwrap();
+        stream
+            .write_all(b"{\"keys\": [\"settings.motd\"]}\n\n{\"keys\": null}\n")
+            .await
+            .unwrap();
+        drop(stream);
+
+        assert_eq!(receiver.recv().await, Some(keys(&["settings.motd"])));
+        assert_eq!(
+            receiver.recv().await,
+            Some(ChangeNotification { keys: None })
+        );
+    }
+}
@@ -2,16 +2,23 @@
 extern crate log;
 
 use constants;
+use handlebars::Handlebars;
 use nix::unistd::{fork, ForkResult};
+use schnauzer::references::TemplateReferences;
 use simplelog::{Config as LogConfig, LevelFilter, SimpleLogger};
 use snafu::ResultExt;
 use std::collections::HashSet;
 use std::env;
 use std::process;
 use std::str::FromStr;
+use std::time::Duration;
 use tokio::runtime::Runtime;
+use tokio::sync::mpsc;
 
-use thar_be_settings::{config, get_changed_settings, service};
+use thar_be_settings::config::{self, ConfigFileBackups};
+use thar_be_settings::rollback::{self, Snapshot};
+use thar_be_settings::service::{self, Services, DEFAULT_RESTART_TIMEOUT};
+use thar_be_settings::{get_changed_settings, listen};
 
 mod error {
     use snafu::Snafu;
@@ -23,6 +30,18 @@ mod error {
         #[snafu(display("Logger setup error: {}", source))]
         Logger { source: log::SetLoggerError },
 
+        #[snafu(display(
+            "Restored previous config files after services failed to restart: {}",
+            source
+        ))]
+        RestartRolledBack { source: thar_be_settings::Error },
+
+        #[snafu(display(
+            "Restored previous config files and settings after services failed to restart: {}",
+            source
+        ))]
+        SettingsRolledBack { source: thar_be_settings::Error },
+
         #[snafu(display("Failure to read template '{}' from '{}': {}", name, path.display(), source))]
         TemplateRegister {
             name: String,
@@ -33,19 +52,27 @@ mod error {
 }
 
 /// RunMode represents how thar-be-settings was requested to be run, either handling all
-/// configuration files and services, or handling configuration files and services based on
-/// specific keys given by the user.
+/// configuration files and services, handling configuration files and services based on
+/// specific keys given by the user, or listening for changes to handle as they're committed.
 #[derive(Debug)]
 enum RunMode {
     All,
     SpecificKeys,
+    Listen,
 }
 
+/// How long to wait for more change notifications after receiving one, so a burst
+/// of commits is applied together.
+const COALESCE_WINDOW: Duration = Duration::from_millis(500);
+
 /// Store the args we receive on the command line
 struct Args {
     daemon: bool,
     log_level: LevelFilter,
+    listen_socket: String,
     mode: RunMode,
+    restart_timeout: Duration,
+    rollback_settings: bool,
     socket_path: String,
 }
 
@@ -54,21 +81,37 @@ fn usage() -> ! {
     let program_name = env::args().next().unwrap_or_else(|| "program".to_string());
     eprintln!(
         r"Usage: {}
-            [ --all ]
+            [ --all | --listen [ --listen-socket PATH ] [ --rollback-settings ] ]
             [ --daemon ]
+            [ --restart-timeout SECONDS ]
             [ --socket-path PATH ]
             [ --log-level trace|debug|info|warn|error ]
 
     If --all is given, all configuration files will be written and all
-    services will have their restart-commands run.  Otherwise, settings keys
-    will be read from stdin; only files related to those keys will be written,
-    and only services related to those keys will be restarted.
+    services will have their restart-commands run.  If neither --all nor
+    --listen is given, settings keys will be read from stdin; only files
+    related to those keys will be written, and only services related to those
+    keys will be restarted.
+
+    If --listen is given, thar-be-settings keeps running and listens on a
+    socket for notifications of changed settings, as sent by the API server
+    after a commit.  Notifications that arrive close together are applied
+    together.  The listen socket defaults to {}
+
+    If --rollback-settings is given with --listen, and services fail to restart
+    after a change, the changed settings are set back to their previous values
+    along with the configuration files.
 
     If --daemon is given, thar-be-settings will fork and do its work in a new
     process; this is useful to prevent blocking an API call.
 
+    Each restart command is killed and considered failed if it runs longer
+    than the restart timeout, which defaults to {} seconds.
+
     Socket path defaults to {}",
         program_name,
+        constants::THAR_BE_SETTINGS_SOCKET,
+        DEFAULT_RESTART_TIMEOUT.as_secs(),
         constants::API_SOCKET,
     );
     process::exit(2);
@@ -83,8 +126,11 @@ fn usage_msg<S: AsRef<str>>(msg: S) -> ! {
 /// Parse the args to the program and return an Args struct
 fn parse_args(args: env::Args) -> Args {
     let mut daemon = false;
+    let mut listen_socket = None;
     let mut log_level = None;
     let mut mode = RunMode::SpecificKeys;
+    let mut restart_timeout = None;
+    let mut rollback_settings = false;
     let mut socket_path = None;
 
     let mut iter = args.skip(1);
@@ -94,6 +140,15 @@ fn parse_args(args: env::Args) -> Args {
 
             "--daemon" => daemon = true,
 
+            "--listen" => mode = RunMode::Listen,
+
+            "--listen-socket" => {
+                listen_socket = Some(
+                    iter.next()
+                        .unwrap_or_else(|| usage_msg("Did not give argument to --listen-socket")),
+                )
+            }
+
             "--log-level" => {
                 let log_level_str = iter
                     .next()
@@ -103,6 +158,17 @@ fn parse_args(args: env::Args) -> Args {
                 }));
             }
 
+            "--restart-timeout" => {
+                let timeout_str = iter
+                    .next()
+                    .unwrap_or_else(|| usage_msg("Did not give argument to --restart-timeout"));
+                restart_timeout = Some(Duration::from_secs(timeout_str.parse().unwrap_or_else(
+                    |_| usage_msg(format!("Invalid restart timeout '{}'", timeout_str)),
+                )));
+            }
+
+            "--rollback-settings" => rollback_settings = true,
+
             "--socket-path" => {
                 socket_path = Some(
                     iter.next()
@@ -114,29 +180,52 @@ fn parse_args(args: env::Args) -> Args {
         }
     }
 
+    // Only a listener knows the settings it applied before each change.
+    if rollback_settings && !matches!(mode, RunMode::Listen) {
+        usage_msg("--rollback-settings requires --listen");
+    }
+
     Args {
         daemon,
+        listen_socket: listen_socket
+            .unwrap_or_else(|| constants::THAR_BE_SETTINGS_SOCKET.to_string()),
         mode,
         log_level: log_level.unwrap_or_else(|| LevelFilter::Info),
+        restart_timeout: restart_timeout.unwrap_or(DEFAULT_RESTART_TIMEOUT),
+        rollback_settings,
         socket_path: socket_path.unwrap_or_else(|| constants::API_SOCKET.to_string()),
     }
 }
 
 /// Render and write config files to disk.  If `files_limit` is Some, only
-/// write those files, otherwise write all known files.
+/// write those files, otherwise write all known files.  If `changed_settings`
+/// is Some, files whose templates don't refer to any of them, directly or
+/// through partials, are skipped.  Templates are added to
+/// `template_registry` as needed, so a registry can be reused along with the
+/// `template_references` found in its templates.  Returns backups
+/// of the files' previous contents, so they can be restored if services fail to
+/// restart.
 async fn write_config_files(
     args: &Args,
+    template_registry: &mut Handlebars<'static>,
+    template_references: &mut TemplateReferences,
     files_limit: Option<HashSet<String>>,
-) -> Result<(), Box<dyn std::error::Error>> {
+    changed_settings: Option<&HashSet<String>>,
+) -> Result<ConfigFileBackups, Box<dyn std::error::Error>> {
     // Create a vec of ConfigFile structs from the list of changed services
     info!("Requesting configuration file data for affected services");
-    let config_files = config::get_affected_config_files(&args.socket_path, files_limit).await?;
+    // Rendering is strict when applying specific changes; when applying all
+    // changes, we write what we can.
+    let files_limit_given = files_limit.is_some();
+    let mut config_files =
+        config::get_affected_config_files(&args.socket_path, files_limit).await?;
     trace!("Found config files: {:?}", config_files);
 
-    // Build the template registry from config file metadata
-    debug!("Building template registry");
-    let mut template_registry = schnauzer::build_template_registry()?;
+    // Register any templates we haven't seen yet, from config file metadata
     for (name, metadata) in &config_files {
+        if template_registry.has_template(name) {
+            continue;
+        }
         debug!(
             "Registering {} at path '{}'",
             &name, &metadata.template_path
@@ -149,69 +238,264 @@ async fn write_config_files(
             })?;
     }
 
+    // A service's files may not all use the settings that changed, like when only a sub-key
+    // used by one of them changed; those files would render the same, so skip them.
+    if let Some(changed_settings) = changed_settings {
+        config_files.retain(|name, _| {
+            let affected = config::template_affected(
+                template_registry,
+                template_references,
+                name,
+                changed_settings,
+            );
+            if !affected {
+                debug!("{} doesn't use the changed settings, not rendering", name);
+            }
+            affected
+        });
+    }
+
     // Get all settings values for config file templates
     debug!("Requesting settings values");
     let settings = schnauzer::get_settings(&args.socket_path).await?;
 
     // Ensure all files render properly
     info!("Rendering config files...");
-    let strict = match &args.mode {
-        RunMode::SpecificKeys => true,
-        RunMode::All => false,
-    };
-    let rendered = config::render_config_files(&template_registry, config_files, settings, strict)?;
+    let strict = files_limit_given;
+    let rendered = config::render_config_files(template_registry, config_files, settings, strict)?;
 
     // If all the config renders properly, write it to disk
     info!("Writing config files to disk...");
-    config::write_config_files(rendered)?;
+    let backups = config::write_config_files(rendered)?;
 
-    Ok(())
+    Ok(backups)
 }
 
-async fn run(args: Args) -> Result<(), Box<dyn std::error::Error>> {
-    // SimpleLogger will send errors to stderr and anything less to stdout.
-    SimpleLogger::init(args.log_level, LogConfig::default()).context(error::Logger)?;
+/// Restart the given services.  If a restart fails, restore the previous config
+/// files from `backups`, restart the services again so they pick the old files
+/// back up, and return the original failure.  If `rollback` gives the changed
+/// settings and a snapshot of the settings from before the change, the changed
+/// settings are also set back to their previous values.
+async fn restart_services(
+    args: &Args,
+    services: &Services,
+    backups: ConfigFileBackups,
+    rollback: Option<(&HashSet<String>, &Snapshot)>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let restart_err = match service::restart_services(services, args.restart_timeout).await {
+        Ok(()) => return Ok(()),
+        Err(e) => e,
+    };
+    if backups.is_empty() {
+        return Err(restart_err.into());
+    }
 
-    info!("thar-be-settings started");
+    error!(
+        "Failed to restart services, restoring previous config files: {}",
+        restart_err
+    );
+    backups.restore()?;
 
-    match args.mode {
-        RunMode::SpecificKeys => {
-            // Get the settings that changed via stdin
-            info!("Parsing stdin for updated settings");
-            let changed_settings = get_changed_settings()?;
+    info!("Restarting services with previous config files...");
+    if let Err(e) = service::restart_services(services, args.restart_timeout).await {
+        error!(
+            "Failed to restart services with previous config files: {}",
+            e
+        );
+    }
 
+    if let Some((changed_settings, previous)) = rollback {
+        match rollback::roll_back(&args.socket_path, changed_settings, previous).await {
+            Ok(()) => {
+                return Err(restart_err)
+                    .context(error::SettingsRolledBack)
+                    .map_err(Into::into)
+            }
+            Err(e) => error!("Failed to roll back settings: {}", e),
+        }
+    }
+
+    Err(restart_err)
+        .context(error::RestartRolledBack)
+        .map_err(Into::into)
+}
+
+/// Apply changes to the system: write affected config files and restart affected
+/// services.  If `changed_settings` is Some, only handle what those settings
+/// affect, otherwise handle all config files and services.  If `previous` is
+/// Some, it's a snapshot of the settings before the change, and the changed
+/// settings are rolled back to it if services fail to restart.
+async fn apply_changes(
+    args: &Args,
+    template_registry: &mut Handlebars<'static>,
+    template_references: &mut TemplateReferences,
+    changed_settings: Option<HashSet<String>>,
+    previous: Option<&Snapshot>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match changed_settings {
+        Some(changed_settings) => {
             // Create a HashSet of affected services
             info!(
                 "Requesting affected services for settings: {:?}",
                 &changed_settings
             );
-            let services =
-                service::get_affected_services(&args.socket_path, Some(changed_settings)).await?;
+            let mut services =
+                service::get_affected_services(&args.socket_path, Some(changed_settings.clone()))
+                    .await?;
             trace!("Found services: {:?}", services);
             if services.0.is_empty() {
-                info!("No services are affected, exiting...");
-                process::exit(0)
+                info!("No services are affected");
+                return Ok(());
             }
 
             // Create a HashSet of configuration file names
             let config_file_names = config::get_config_file_names(&services);
 
-            if !config_file_names.is_empty() {
-                write_config_files(&args, Some(config_file_names)).await?;
+            let backups = if !config_file_names.is_empty() {
+                write_config_files(
+                    args,
+                    template_registry,
+                    template_references,
+                    Some(config_file_names),
+                    Some(&changed_settings),
+                )
+                .await?
+            } else {
+                ConfigFileBackups::default()
+            };
+
+            // Services whose configuration files all rendered the same as before don't need a
+            // restart; the settings change didn't affect them.
+            let unchanged = services.remove_unchanged(&backups.changed_files());
+            if !unchanged.is_empty() {
+                info!(
+                    "Configuration files unchanged, not restarting: {:?}",
+                    unchanged
+                );
             }
 
             // Now go bounce the affected services
             info!("Restarting affected services...");
-            service::restart_services(services)?;
+            let rollback = previous.map(|previous| (&changed_settings, previous));
+            restart_services(args, &services, backups, rollback).await?;
         }
-        RunMode::All => {
-            write_config_files(&args, None).await?;
+        None => {
+            // When handling everything, like at boot, there's no earlier working state to roll
+            // back to; restoring would undo every service's configuration because of one failure.
+            // Failures are reported, and the files are left as rendered.
+            write_config_files(args, template_registry, template_references, None, None).await?;
 
             info!("Restarting all services...");
             let services = service::get_affected_services(&args.socket_path, None).await?;
             trace!("Found services: {:?}", services);
-            service::restart_services(services)?;
+            service::restart_services(&services, args.restart_timeout).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Listen for change notifications, applying each batch of changes in the order
+/// received.  The template registry, and the settings its templates refer to,
+/// are kept between batches.  Failures to apply
+/// a batch are logged rather than stopping the listener.
+///
+/// If settings are to be rolled back when services fail to restart, we keep the
+/// settings each batch applied, since they're what the next batch goes back to.
+async fn listen(
+    args: &Args,
+    mut template_registry: Handlebars<'static>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let listener = listen::bind(&args.listen_socket)?;
+    let mut template_references = TemplateReferences::new();
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    tokio::spawn(listen::receive(listener, sender));
+    info!("Listening for changes on {}", args.listen_socket);
+
+    let mut previous = take_snapshot(args).await;
+    while let Some(notification) = listen::next_batch(&mut receiver, COALESCE_WINDOW).await {
+        let current = take_snapshot(args).await;
+        info!("Applying changes for settings: {:?}", notification.keys);
+        match apply_changes(
+            args,
+            &mut template_registry,
+            &mut template_references,
+            notification.keys,
+            previous.as_ref(),
+        )
+        .await
+        {
+            Ok(()) => previous = current,
+            Err(e) => {
+                error!("Failed to apply changes: {}", e);
+                // Rolled back settings are as they were before this batch.
+                if !matches!(
+                    e.downcast_ref::<error::Error>(),
+                    Some(error::Error::SettingsRolledBack { .. })
+                ) {
+                    previous = current;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns a snapshot of the current settings if we're to roll back settings, or None if not,
+/// or if the settings couldn't be fetched.
+async fn take_snapshot(args: &Args) -> Option<Snapshot> {
+    if !args.rollback_settings {
+        return None;
+    }
+    match rollback::snapshot(&args.socket_path).await {
+        Ok(snapshot) => Some(snapshot),
+        Err(e) => {
+            warn!(
+                "Unable to get settings, so they can't be rolled back: {}",
+                e
+            );
+            None
+        }
+    }
+}
+
+async fn run(args: Args) -> Result<(), Box<dyn std::error::Error>> {
+    // SimpleLogger will send errors to stderr and anything less to stdout.
+    SimpleLogger::init(args.log_level, LogConfig::default()).context(error::Logger)?;
+
+    info!("thar-be-settings started");
+
+    // Build the template registry, with shared partials; templates for config
+    // files are added as they're needed
+    debug!("Building template registry");
+    let mut template_registry = schnauzer::build_template_registry()?;
+
+    match args.mode {
+        RunMode::SpecificKeys => {
+            // Get the settings that changed via stdin
+            info!("Parsing stdin for updated settings");
+            let changed_settings = get_changed_settings()?;
+            apply_changes(
+                &args,
+                &mut template_registry,
+                &mut TemplateReferences::new(),
+                Some(changed_settings),
+                None,
+            )
+            .await?;
+        }
+        RunMode::All => {
+            apply_changes(
+                &args,
+                &mut template_registry,
+                &mut TemplateReferences::new(),
+                None,
+                None,
+            )
+            .await?
         }
+        RunMode::Listen => listen(&args, template_registry).await?,
     }
 
     Ok(())
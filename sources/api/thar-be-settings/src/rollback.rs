@@ -0,0 +1,146 @@
+//! The rollback module lets thar-be-settings put changed settings back to their previous values
+//! when the services they affect fail to restart.  Settings are already committed when we're told
+//! about them, so we keep a snapshot of the settings we last applied to know what to go back to.
+
+use crate::{error, Result};
+use datastore::deserialization::from_map;
+use datastore::serialization::to_pairs_with_prefix;
+use datastore::Key;
+use snafu::{ensure, ResultExt};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+/// Transaction used to commit settings we put back.
+const ROLLBACK_TRANSACTION: &str = "thar-be-settings-rollback";
+
+/// The value of each setting, in serialized datastore form, keyed by data key.
+pub type Snapshot = HashMap<Key, String>;
+
+/// Fetches the current settings from the API.
+pub async fn snapshot<P>(socket_path: P) -> Result<Snapshot>
+where
+    P: AsRef<Path>,
+{
+    let model = schnauzer::get_settings(socket_path)
+        .await
+        .context(error::GetSettings)?;
+    match model.settings {
+        Some(settings) => to_pairs_with_prefix("settings", &settings).context(error::Snapshot),
+        None => Ok(Snapshot::new()),
+    }
+}
+
+/// Returns the previous values of the given changed settings, and of any settings under them.
+/// Changed settings that had no previous value can't be removed through the API, so they're
+/// logged and left alone.
+#[allow(clippy::implicit_hasher)]
+pub fn previous_values<'a>(
+    changed_settings: &HashSet<String>,
+    previous: &'a Snapshot,
+) -> HashMap<&'a Key, &'a String> {
+    let mut values = HashMap::new();
+    for setting in changed_settings {
+        let prefix = format!("{}.", setting);
+        let before: Vec<_> = previous
+            .iter()
+            .filter(|(key, _)| key.name() == setting || key.name().starts_with(&prefix))
+            .collect();
+        if before.is_empty() {
+            warn!(
+                "'{}' had no previous value, so it can't be rolled back",
+                setting
+            );
+        }
+        values.extend(before);
+    }
+    values
+}
+
+/// Sets the given changed settings back to their values in `previous`, through the API, and
+/// commits them.  The changes aren't applied; the caller has already restored the configuration
+/// files and services that go with the previous values.
+#[allow(clippy::implicit_hasher)]
+pub async fn roll_back<P>(
+    socket_path: P,
+    changed_settings: &HashSet<String>,
+    previous: &Snapshot,
+) -> Result<()>
+where
+    P: AsRef<Path>,
+{
+    let values = previous_values(changed_settings, previous);
+    if values.is_empty() {
+        return Ok(());
+    }
+    info!(
+        "Rolling back settings: {:?}",
+        values.keys().map(|key| key.name()).collect::<Vec<_>>()
+    );
+    let settings: model::Settings = from_map(&values).context(error::RollbackSettings)?;
+    let body = serde_json::to_string(&settings).context(error::SerializeSettings)?;
+
+    let uri = format!(
+        "{}?tx={}",
+        constants::API_SETTINGS_URI,
+        ROLLBACK_TRANSACTION
+    );
+    api_request(&socket_path, &uri, "PATCH", Some(body)).await?;
+    let uri = format!("/tx/commit?tx={}", ROLLBACK_TRANSACTION);
+    api_request(&socket_path, &uri, "POST", None).await
+}
+
+async fn api_request<P>(socket_path: P, uri: &str, method: &str, body: Option<String>) -> Result<()>
+where
+    P: AsRef<Path>,
+{
+    let (code, response_body) = apiclient::raw_request(socket_path.as_ref(), uri, method, body)
+        .await
+        .context(error::APIRequest { method, uri })?;
+    ensure!(
+        code.is_success(),
+        error::APIResponse {
+            method,
+            uri,
+            code,
+            response_body,
+        }
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use datastore::KeyType;
+    use maplit::{hashmap, hashset};
+
+    fn key(name: &str) -> Key {
+        Key::new(KeyType::Data, name).unwrap()
+    }
+
+    #[test]
+    fn previous_values_of_changed_settings() {
+        let previous = hashmap!(
+            key("settings.motd") => "\"hi\"".to_string(),
+            key("settings.kubernetes.node-labels.a") => "\"1\"".to_string(),
+            key("settings.kubernetes.node-labels.b") => "\"2\"".to_string(),
+            key("settings.kubernetes.max-pods") => "29".to_string(),
+        );
+        let changed = hashset!(
+            "settings.motd".to_string(),
+            "settings.kubernetes.node-labels".to_string(),
+            "settings.ntp.time-servers".to_string(),
+        );
+        let values = previous_values(&changed, &previous);
+        let mut names: Vec<&str> = values.keys().map(|key| key.name().as_str()).collect();
+        names.sort_unstable();
+        assert_eq!(
+            names,
+            vec![
+                "settings.kubernetes.node-labels.a",
+                "settings.kubernetes.node-labels.b",
+                "settings.motd",
+            ]
+        );
+    }
+}
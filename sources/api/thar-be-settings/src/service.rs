@@ -1,9 +1,11 @@
-use crate::{error, Result};
+use crate::{error, Error, Result};
 use itertools::join;
 use snafu::{ensure, OptionExt, ResultExt};
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
 use std::path::Path;
-use std::process::Command;
+use std::time::{Duration, Instant};
+use tokio::process::Command;
 
 // TODO: thar-be-settings isn't used as a library; declare its modules in main rather than lib so
 // we don't have to expose helper types like this just so we can call related functions in main.
@@ -42,6 +44,29 @@ impl Services {
         }
         Self(output)
     }
+
+    /// Removes services marked `restart-only-on-file-change` for which none of their
+    /// configuration files changed, since restarting them wouldn't change anything.  Other
+    /// services are kept, because their restart commands may act on settings directly.  Returns
+    /// the names of the removed services.
+    #[allow(clippy::implicit_hasher)]
+    pub fn remove_unchanged(&mut self, changed_files: &HashSet<String>) -> Vec<String> {
+        let unchanged: Vec<String> = self
+            .0
+            .iter()
+            .filter(|(_, service)| {
+                let files = &service.model.configuration_files;
+                service.model.restart_only_on_file_change
+                    && !files.is_empty()
+                    && !files.iter().any(|f| changed_files.contains(f.as_ref()))
+            })
+            .map(|(name, _)| name.clone())
+            .collect();
+        for name in &unchanged {
+            self.0.remove(name);
+        }
+        unchanged
+    }
 }
 
 /// Returns a `Services` reflecting the set of services affected by the given changed settings in
@@ -133,70 +158,442 @@ where
     Ok(service_map)
 }
 
-/// Call the `restart()` method on each Service in a Services object
-pub fn restart_services(services: Services) -> Result<()> {
-    for (name, service) in services.0 {
-        debug!("Checking for restart-commands for {}", name);
-        service.restart()?;
+/// How long a single restart command may run before it's killed and considered failed, unless
+/// another timeout is given.
+pub const DEFAULT_RESTART_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// The result of restarting one service.
+#[derive(Debug)]
+pub struct RestartResult {
+    pub service: String,
+    pub outcome: RestartOutcome,
+    /// How long the service's restart commands ran.
+    pub elapsed: Duration,
+}
+
+#[derive(Debug)]
+pub enum RestartOutcome {
+    Restarted,
+    Failed(Error),
+    /// Not attempted, because a service it restarts after didn't restart.
+    Skipped,
+}
+
+impl fmt::Display for RestartResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.outcome {
+            RestartOutcome::Restarted => write!(
+                f,
+                "{}: restarted in {:.1}s",
+                self.service,
+                self.elapsed.as_secs_f32()
+            ),
+            RestartOutcome::Failed(e) => write!(
+                f,
+                "{}: failed after {:.1}s: {}",
+                self.service,
+                self.elapsed.as_secs_f32(),
+                e
+            ),
+            RestartOutcome::Skipped => write!(
+                f,
+                "{}: skipped because a service it restarts after didn't restart",
+                self.service
+            ),
+        }
     }
-    Ok(())
 }
 
-/// This trait is primarily meant to extend the Service model.  It uses the metadata
-/// inside the Service struct to restart the service.
-trait ServiceRestart {
-    /// Restart the service
-    fn restart(&self) -> Result<()>;
+/// Runs the restart commands of each service in a Services object.  Services restart after those
+/// listed in their `restart-after`, if those are also being restarted; services that don't depend
+/// on each other restart in parallel.  Each restart command is killed if it runs longer than
+/// `timeout`.  If any service fails to restart, returns an error listing the result of each
+/// service that didn't restart.
+pub async fn restart_services(services: &Services, timeout: Duration) -> Result<()> {
+    let results = restart_in_order(services, timeout).await?;
+    for result in &results {
+        match result.outcome {
+            RestartOutcome::Restarted => info!("{}", result),
+            _ => error!("{}", result),
+        }
+    }
+
+    let restarted: Vec<_> = results
+        .iter()
+        .filter(|r| matches!(r.outcome, RestartOutcome::Restarted))
+        .map(|r| r.service.as_str())
+        .collect();
+    info!("Restarted services: [{}]", join(restarted, ", "));
+
+    let failures: Vec<_> = results
+        .into_iter()
+        .filter(|r| !matches!(r.outcome, RestartOutcome::Restarted))
+        .collect();
+    ensure!(failures.is_empty(), error::RestartFailures { failures });
+    Ok(())
 }
 
-impl ServiceRestart for Service {
-    fn restart(&self) -> Result<()> {
-        let restart_commands = &self.model.restart_commands;
-        info!("restart commands {:?}", restart_commands);
-        for restart_command in restart_commands {
-            // Split on space, assume the first item is the command
-            // and the rest are args.
-            debug!("Restart command: {:?}", &restart_command);
-            let mut command_strings = restart_command.split(' ');
-            let command = command_strings
-                .next()
-                .context(error::InvalidRestartCommand {
-                    command: restart_command.as_str(),
-                })?;
-            trace!("Command: {}", &command);
-            trace!("Args: {:?}", &command_strings);
-
-            // Go execute the restart command
-            let mut process_command = Command::new(command);
-            process_command.args(command_strings);
-            if let Some(ref changed_settings) = self.changed_settings {
-                if !changed_settings.is_empty() {
-                    process_command.env("CHANGED_SETTINGS", join(changed_settings, " "));
-                }
+/// Restarts each wave of services from `restart_waves` in turn, the services in a wave in
+/// parallel, and returns the result for every service.
+async fn restart_in_order(services: &Services, timeout: Duration) -> Result<Vec<RestartResult>> {
+    let mut results = Vec::new();
+    let mut restarted = HashSet::new();
+    for wave in restart_waves(services)? {
+        let mut tasks = Vec::new();
+        for name in wave {
+            let service = &services.0[name];
+            let blocked = service
+                .model
+                .restart_after
+                .iter()
+                .map(|dep| dep.as_ref())
+                .any(|dep| services.0.contains_key(dep) && !restarted.contains(dep));
+            if blocked {
+                results.push(RestartResult {
+                    service: name.to_string(),
+                    outcome: RestartOutcome::Skipped,
+                    elapsed: Duration::default(),
+                });
+                continue;
             }
-            let result = process_command
-                .output()
-                .context(error::CommandExecutionFailure {
-                    command: restart_command.as_str(),
-                })?;
-
-            // If the restart command exited nonzero, call it a failure
-            ensure!(
-                result.status.success(),
-                error::FailedRestartCommand {
-                    command: restart_command.as_str(),
-                    stderr: String::from_utf8_lossy(&result.stderr),
+
+            debug!("Checking for restart-commands for {}", name);
+            let commands = service.model.restart_commands.clone();
+            let changed_settings = service.changed_settings.clone();
+            let task = tokio::spawn(async move {
+                let start = Instant::now();
+                let result = restart_service(&commands, changed_settings.as_ref(), timeout).await;
+                (result, start.elapsed())
+            });
+            tasks.push((name, task));
+        }
+
+        for (name, task) in tasks {
+            let (result, elapsed) = task.await.context(error::RestartTask { service: name })?;
+            let outcome = match result {
+                Ok(()) => {
+                    restarted.insert(name);
+                    RestartOutcome::Restarted
                 }
-            );
-            trace!(
-                "Command stdout: {}",
-                String::from_utf8_lossy(&result.stdout)
-            );
-            trace!(
-                "Command stderr: {}",
-                String::from_utf8_lossy(&result.stderr)
+                Err(e) => RestartOutcome::Failed(e),
+            };
+            results.push(RestartResult {
+                service: name.to_string(),
+                outcome,
+                elapsed,
+            });
+        }
+    }
+    Ok(results)
+}
+
+/// Groups services into waves that can be restarted in parallel; each service is in a later wave
+/// than the services in its `restart-after` list.  Services that aren't being restarted are
+/// ignored in `restart-after`.  Returns an error if services restart after each other in a cycle.
+fn restart_waves(services: &Services) -> Result<Vec<Vec<&str>>> {
+    // Sorted, so restart order and logs are stable.
+    let mut remaining: BTreeMap<&str, HashSet<&str>> = services
+        .0
+        .iter()
+        .map(|(name, service)| {
+            let deps = service
+                .model
+                .restart_after
+                .iter()
+                .map(|dep| dep.as_ref())
+                .filter(|dep| services.0.contains_key(*dep))
+                .collect();
+            (name.as_str(), deps)
+        })
+        .collect();
+
+    let mut waves = Vec::new();
+    while !remaining.is_empty() {
+        let wave: Vec<&str> = remaining
+            .iter()
+            .filter(|(_, deps)| deps.is_empty())
+            .map(|(name, _)| *name)
+            .collect();
+        ensure!(
+            !wave.is_empty(),
+            error::RestartCycle {
+                services: join(remaining.keys(), ", "),
+            }
+        );
+        for name in &wave {
+            remaining.remove(name);
+        }
+        for deps in remaining.values_mut() {
+            for name in &wave {
+                deps.remove(name);
+            }
+        }
+        waves.push(wave);
+    }
+    Ok(waves)
+}
+
+/// Runs a service's restart commands in order, stopping at the first failure.
+async fn restart_service(
+    restart_commands: &[String],
+    changed_settings: Option<&HashSet<String>>,
+    timeout: Duration,
+) -> Result<()> {
+    info!("restart commands {:?}", restart_commands);
+    for restart_command in restart_commands {
+        // Split on space, assume the first item is the command
+        // and the rest are args.
+        debug!("Restart command: {:?}", &restart_command);
+        let mut command_strings = restart_command.split(' ');
+        let command = command_strings
+            .next()
+            .context(error::InvalidRestartCommand {
+                command: restart_command.as_str(),
+            })?;
+        trace!("Command: {}", &command);
+        trace!("Args: {:?}", &command_strings);
+
+        // Go execute the restart command, killing it if it takes too long
+        let mut process_command = Command::new(command);
+        process_command.args(command_strings).kill_on_drop(true);
+        if let Some(changed_settings) = changed_settings {
+            if !changed_settings.is_empty() {
+                process_command.env("CHANGED_SETTINGS", join(changed_settings, " "));
+            }
+        }
+        let result = tokio::time::timeout(timeout, process_command.output())
+            .await
+            .context(error::RestartTimeout {
+                command: restart_command.as_str(),
+                timeout,
+            })?
+            .context(error::CommandExecutionFailure {
+                command: restart_command.as_str(),
+            })?;
+
+        // If the restart command exited nonzero, call it a failure
+        ensure!(
+            result.status.success(),
+            error::FailedRestartCommand {
+                command: restart_command.as_str(),
+                stderr: String::from_utf8_lossy(&result.stderr),
+            }
+        );
+        trace!(
+            "Command stdout: {}",
+            String::from_utf8_lossy(&result.stdout)
+        );
+        trace!(
+            "Command stderr: {}",
+            String::from_utf8_lossy(&result.stderr)
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use maplit::{hashmap, hashset};
+    use std::convert::TryInto;
+
+    fn services(input: Vec<(&str, Vec<&str>, Vec<&str>)>) -> Services {
+        let mut model_services = HashMap::new();
+        for (name, restart_commands, restart_after) in input {
+            model_services.insert(
+                name.to_string(),
+                model::Service {
+                    configuration_files: vec![],
+                    restart_commands: restart_commands.into_iter().map(String::from).collect(),
+                    restart_after: restart_after
+                        .into_iter()
+                        .map(|s| s.try_into().unwrap())
+                        .collect(),
+                    restart_only_on_file_change: false,
+                },
             );
         }
-        Ok(())
+        Services::from_model_services(model_services, None)
+    }
+
+    fn outcomes(results: &[RestartResult]) -> HashMap<&str, &'static str> {
+        results
+            .iter()
+            .map(|r| {
+                let outcome = match r.outcome {
+                    RestartOutcome::Restarted => "restarted",
+                    RestartOutcome::Failed(_) => "failed",
+                    RestartOutcome::Skipped => "skipped",
+                };
+                (r.service.as_str(), outcome)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn waves() {
+        let services = services(vec![
+            ("kubernetes", vec![], vec!["containerd"]),
+            ("static-pods", vec![], vec!["kubernetes"]),
+            ("containerd", vec![], vec![]),
+            ("chronyd", vec![], vec![]),
+            // Services that aren't being restarted are ignored.
+            ("ecs", vec![], vec!["docker"]),
+        ]);
+        assert_eq!(
+            restart_waves(&services).unwrap(),
+            vec![
+                vec!["chronyd", "containerd", "ecs"],
+                vec!["kubernetes"],
+                vec!["static-pods"],
+            ]
+        );
+    }
+
+    #[test]
+    fn cycle() {
+        let services = services(vec![
+            ("a", vec![], vec!["b"]),
+            ("b", vec![], vec!["a"]),
+            ("c", vec![], vec![]),
+        ]);
+        assert!(matches!(
+            restart_waves(&services),
+            Err(Error::RestartCycle { services }) if services == "a, b"
+        ));
+    }
+
+    #[tokio::test]
+    async fn dependents_of_failures_skipped() {
+        let services = services(vec![
+            ("containerd", vec!["/bin/false"], vec![]),
+            ("kubernetes", vec!["/bin/true"], vec!["containerd"]),
+            ("static-pods", vec!["/bin/true"], vec!["kubernetes"]),
+            ("chronyd", vec!["/bin/true"], vec![]),
+        ]);
+        let results = restart_in_order(&services, DEFAULT_RESTART_TIMEOUT)
+            .await
+            .unwrap();
+        assert_eq!(
+            outcomes(&results),
+            hashmap!(
+                "containerd" => "failed",
+                "kubernetes" => "skipped",
+                "static-pods" => "skipped",
+                "chronyd" => "restarted",
+            )
+        );
+
+        match restart_services(&services, DEFAULT_RESTART_TIMEOUT).await {
+            Err(Error::RestartFailures { failures }) => assert_eq!(failures.len(), 3),
+            other => panic!("expected restart failures, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn timeout() {
+        let services = services(vec![
+            ("hung", vec!["/bin/sleep 10"], vec![]),
+            ("quick", vec!["/bin/true"], vec![]),
+        ]);
+        let start = Instant::now();
+        let results = restart_in_order(&services, Duration::from_millis(200))
+            .await
+            .unwrap();
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        let hung = results.iter().find(|r| r.service == "hung").unwrap();
+        assert!(matches!(
+            hung.outcome,
+            RestartOutcome::Failed(Error::RestartTimeout { .. })
+        ));
+        assert_eq!(outcomes(&results)["quick"], "restarted");
+    }
+
+    #[tokio::test]
+    async fn parallel() {
+        // Each restart marks that it started, then waits for the others to start, so they can
+        // only all succeed if they run at the same time.
+        let dir = tempfile::TempDir::new().unwrap();
+        let barrier = dir.path().join("barrier.sh");
+        std::fs::write(
+            &barrier,
+            format!(
+                r#"touch "{dir}/$1"
+for i in $(seq 100); do
+    [ -e "{dir}/a" ] && [ -e "{dir}/b" ] && [ -e "{dir}/c" ] && exit 0
+    sleep 0.1
+done
+exit 1
+"#,
+                dir = dir.path().display()
+            ),
+        )
+        .unwrap();
+        let command = |name| format!("/bin/sh {} {}", barrier.display(), name);
+        let (a, b, c) = (command("a"), command("b"), command("c"));
+        let services = services(vec![
+            ("a", vec![&a], vec![]),
+            ("b", vec![&b], vec![]),
+            ("c", vec![&c], vec![]),
+        ]);
+        restart_services(&services, DEFAULT_RESTART_TIMEOUT)
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn remove_unchanged() {
+        let service = |files: &[&str], restart_only_on_file_change| model::Service {
+            configuration_files: files.iter().map(|f| (*f).try_into().unwrap()).collect(),
+            restart_commands: vec![],
+            restart_after: vec![],
+            restart_only_on_file_change,
+        };
+        let mut services = Services::from_model_services(
+            hashmap!(
+                "kubernetes".to_string() => service(&["kubelet-config", "proxy-env"], true),
+                "ntp".to_string() => service(&["chrony-conf"], true),
+                "sysctl".to_string() => service(&[], true),
+                // host-containers reads its settings from the API when restarted, so a change to
+                // them needs a restart even though its configuration file doesn't change.
+                "host-containers".to_string() => service(&["host-ctr-toml"], false),
+            ),
+            None,
+        );
+
+        let removed = services.remove_unchanged(&hashset! {"proxy-env".to_string()});
+        assert_eq!(removed, vec!["ntp".to_string()]);
+        let mut remaining: Vec<_> = services.0.keys().map(String::as_str).collect();
+        remaining.sort_unstable();
+        assert_eq!(remaining, vec!["host-containers", "kubernetes", "sysctl"]);
+    }
+
+    #[tokio::test]
+    async fn host_containers_change_restarts() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let marker = dir.path().join("restarted");
+        let mut services = Services::from_model_services(
+            hashmap!(
+                "host-containers".to_string() => model::Service {
+                    configuration_files: vec!["host-ctr-toml".try_into().unwrap()],
+                    restart_commands: vec![format!("/bin/touch {}", marker.display())],
+                    restart_after: vec![],
+                    restart_only_on_file_change: false,
+                },
+            ),
+            Some(hashmap!(
+                "host-containers".to_string() => hashset! {
+                    "settings.host-containers.admin.enabled".to_string()
+                },
+            )),
+        );
+
+        // Enabling the admin container doesn't change host-ctr-toml.
+        assert!(services.remove_unchanged(&HashSet::new()).is_empty());
+        restart_services(&services, DEFAULT_RESTART_TIMEOUT)
+            .await
+            .unwrap();
+        assert!(marker.exists());
     }
 }
@@ -0,0 +1,319 @@
+/*!
+`typed-apiclient` provides a typed, async client for the Bottlerocket API.
+
+Where the `apiclient` library hands back raw response bodies, `ApiClient` builds the request URIs
+for you, deserializes responses into `model` types, and reports every failure through a single
+`Error` type.  This saves API consumers from having to know the API's URIs and query parameters,
+and from each defining their own errors for the same handful of calls.
+
+```no_run
+# async fn example() -> typed_apiclient::Result<()> {
+use typed_apiclient::ApiClient;
+
+let client = ApiClient::new("/run/api.sock");
+let settings = client.get_settings(Some("host-containers")).await?;
+let services = client.get_services(&["hostname"]).await?;
+# Ok(())
+# }
+```
+
+Settings changes are made in a named transaction, so that unrelated pending changes aren't
+committed by accident.  `patch_settings` adds changes to a transaction, and `commit_and_apply`
+commits it and asks the system to apply the changed settings.
+*/
+
+#![deny(rust_2018_idioms)]
+
+#[cfg(test)]
+mod test;
+
+use bottlerocket_release::BottlerocketRelease;
+use http::StatusCode;
+use log::trace;
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use serde::de::DeserializeOwned;
+use snafu::{ensure, ResultExt};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use thar_be_updates::status::UpdateStatus;
+
+// https://url.spec.whatwg.org/#query-percent-encode-set, plus the separators we use between and
+// inside query parameters, so that user-provided values can't add parameters of their own.
+const ENCODE_QUERY_CHARS: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'<')
+    .add(b'>')
+    .add(b'&')
+    .add(b'=')
+    .add(b'+');
+
+/// A client for the Bottlerocket API, listening on the given Unix-domain socket.
+#[derive(Debug, Clone)]
+pub struct ApiClient {
+    socket_path: PathBuf,
+}
+
+impl ApiClient {
+    pub fn new<P>(socket_path: P) -> Self
+    where
+        P: AsRef<Path>,
+    {
+        Self {
+            socket_path: socket_path.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Returns the full API model, including settings, services, configuration files, and OS
+    /// information.
+    pub async fn get_model(&self) -> Result<model::Model> {
+        self.get_json("/", &[]).await
+    }
+
+    /// Returns the live settings.  If `prefix` is given, only settings whose names start with it
+    /// are returned; the prefix should not include "settings.", for example "host-containers".
+    pub async fn get_settings(&self, prefix: Option<&str>) -> Result<model::Settings> {
+        match prefix {
+            Some(prefix) => self.get_json("/settings", &[("prefix", prefix)]).await,
+            None => self.get_json("/settings", &[]).await,
+        }
+    }
+
+    /// Returns the live settings with the given full names, for example "settings.motd".
+    pub async fn get_settings_keys<S>(&self, keys: &[S]) -> Result<model::Settings>
+    where
+        S: AsRef<str>,
+    {
+        let keys = comma_join(keys);
+        self.get_json("/settings", &[("keys", &keys)]).await
+    }
+
+    /// Adds the given settings changes to the pending transaction `tx`.  Only the settings that
+    /// are populated (i.e. Option::Some) are changed.
+    pub async fn patch_settings(&self, settings: &model::Settings, tx: &str) -> Result<()> {
+        let body = serde_json::to_string(settings).context(error::Serialize)?;
+        self.request("PATCH", "/settings", &[("tx", tx)], Some(body))
+            .await
+            .map(|_| ())
+    }
+
+    /// Returns the settings that are pending in transaction `tx`.
+    pub async fn get_transaction(&self, tx: &str) -> Result<model::Settings> {
+        self.get_json("/tx", &[("tx", tx)]).await
+    }
+
+    /// Returns the names of all transactions that have pending settings.
+    pub async fn list_transactions(&self) -> Result<HashSet<String>> {
+        self.get_json("/tx/list", &[]).await
+    }
+
+    /// Deletes transaction `tx`, returning the names of the settings that were pending in it.
+    pub async fn delete_transaction(&self, tx: &str) -> Result<HashSet<String>> {
+        let body = self.request("DELETE", "/tx", &[("tx", tx)], None).await?;
+        parse_json("DELETE", "/tx", &body)
+    }
+
+    /// Commits transaction `tx` to the live settings, returning the names of the changed settings.
+    /// Nothing is applied to the system; see `commit_and_apply`.
+    pub async fn commit(&self, tx: &str) -> Result<HashSet<String>> {
+        let body = self
+            .request("POST", "/tx/commit", &[("tx", tx)], None)
+            .await?;
+        parse_json("POST", "/tx/commit", &body)
+    }
+
+    /// Commits transaction `tx` to the live settings and starts applying the changed settings to
+    /// the system, returning the names of the changed settings.
+    pub async fn commit_and_apply(&self, tx: &str) -> Result<HashSet<String>> {
+        let uri = "/tx/commit_and_apply";
+        let body = self.request("POST", uri, &[("tx", tx)], None).await?;
+        parse_json("POST", uri, &body)
+    }
+
+    /// Returns all services, or if `names` is non-empty, the services with those names.
+    pub async fn get_services<S>(&self, names: &[S]) -> Result<model::Services>
+    where
+        S: AsRef<str>,
+    {
+        if names.is_empty() {
+            self.get_json("/services", &[]).await
+        } else {
+            let names = comma_join(names);
+            self.get_json("/services", &[("names", &names)]).await
+        }
+    }
+
+    /// Returns all configuration files, or if `names` is non-empty, the configuration files with
+    /// those names.
+    pub async fn get_configuration_files<S>(&self, names: &[S]) -> Result<model::ConfigurationFiles>
+    where
+        S: AsRef<str>,
+    {
+        if names.is_empty() {
+            self.get_json("/configuration-files", &[]).await
+        } else {
+            let names = comma_join(names);
+            self.get_json("/configuration-files", &[("names", &names)])
+                .await
+        }
+    }
+
+    /// Returns the affected-services metadata for the given settings, keyed by setting name.
+    pub async fn get_affected_services<S>(&self, keys: &[S]) -> Result<HashMap<String, Vec<String>>>
+    where
+        S: AsRef<str>,
+    {
+        let keys = comma_join(keys);
+        self.get_json("/metadata/affected-services", &[("keys", &keys)])
+            .await
+    }
+
+    /// Returns the setting-generator metadata of all settings that have it, keyed by setting name.
+    /// Each is a command string, or an object with the command and how to run it.
+    pub async fn get_setting_generators(&self) -> Result<HashMap<String, serde_json::Value>> {
+        self.get_json("/metadata/setting-generators", &[]).await
+    }
+
+    /// Returns the template metadata for the given settings, keyed by setting name.
+    pub async fn get_templates<S>(&self, keys: &[S]) -> Result<HashMap<String, String>>
+    where
+        S: AsRef<str>,
+    {
+        let keys = comma_join(keys);
+        self.get_json("/metadata/templates", &[("keys", &keys)])
+            .await
+    }
+
+    /// Returns information about the running OS.
+    pub async fn get_os(&self) -> Result<BottlerocketRelease> {
+        self.get_json("/os", &[]).await
+    }
+
+    /// Returns the status of the update API.
+    pub async fn update_status(&self) -> Result<UpdateStatus> {
+        self.get_json("/updates/status", &[]).await
+    }
+
+    /// Makes a GET request and deserializes the JSON response.
+    async fn get_json<T>(&self, path: &str, query: &[(&str, &str)]) -> Result<T>
+    where
+        T: DeserializeOwned,
+    {
+        let body = self.request("GET", path, query, None).await?;
+        parse_json("GET", path, &body)
+    }
+
+    /// Makes a request to the API, returning the response body if the response status was
+    /// successful.
+    async fn request(
+        &self,
+        method: &'static str,
+        path: &str,
+        query: &[(&str, &str)],
+        data: Option<String>,
+    ) -> Result<String> {
+        let uri = build_uri(path, query);
+        trace!("{}ing {}", method, uri);
+        let (code, body) = apiclient::raw_request_unchecked(&self.socket_path, &uri, method, data)
+            .await
+            .context(error::Request { method, uri: &uri })?;
+        ensure!(
+            code.is_success(),
+            error::ResponseStatus {
+                method,
+                uri,
+                code,
+                body
+            }
+        );
+        trace!("Response: {}", body);
+        Ok(body)
+    }
+}
+
+impl Error {
+    /// Returns the HTTP status code of the API response, if the API responded with an error.
+    /// This lets callers handle specific cases, like a 404 for an uninitialized update status.
+    pub fn status_code(&self) -> Option<StatusCode> {
+        match self {
+            Error::ResponseStatus { code, .. } => Some(*code),
+            _ => None,
+        }
+    }
+}
+
+/// Builds a URI from a path and query parameters, escaping the parameter values.
+fn build_uri(path: &str, query: &[(&str, &str)]) -> String {
+    let query = query
+        .iter()
+        .map(|(name, value)| {
+            format!(
+                "{}={}",
+                name,
+                utf8_percent_encode(value, ENCODE_QUERY_CHARS)
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("&");
+    if query.is_empty() {
+        path.to_string()
+    } else {
+        format!("{}?{}", path, query)
+    }
+}
+
+/// Joins a list of names into the comma-separated form the API expects in query parameters.
+fn comma_join<S>(items: &[S]) -> String
+where
+    S: AsRef<str>,
+{
+    items
+        .iter()
+        .map(|s| s.as_ref())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn parse_json<T>(method: &'static str, path: &str, body: &str) -> Result<T>
+where
+    T: DeserializeOwned,
+{
+    serde_json::from_str(body).context(error::ResponseJson { method, uri: path })
+}
+
+mod error {
+    use http::StatusCode;
+    use snafu::Snafu;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility = "pub(super)")]
+    pub enum Error {
+        #[snafu(display("Failed to {} '{}': {}", method, uri, source))]
+        Request {
+            method: &'static str,
+            uri: String,
+            source: apiclient::Error,
+        },
+
+        #[snafu(display("Status {} when {}ing '{}': {}", code.as_str(), method, uri, body))]
+        ResponseStatus {
+            method: &'static str,
+            uri: String,
+            code: StatusCode,
+            body: String,
+        },
+
+        #[snafu(display("Error deserializing response to {} '{}': {}", method, uri, source))]
+        ResponseJson {
+            method: &'static str,
+            uri: String,
+            source: serde_json::Error,
+        },
+
+        #[snafu(display("Unable to serialize settings: {}", source))]
+        Serialize { source: serde_json::Error },
+    }
+}
+pub use error::Error;
+pub type Result<T> = std::result::Result<T, error::Error>;
@@ -0,0 +1,212 @@
+//! Provides end-to-end tests of `ApiClient` against an in-process `apiserver` listening on a
+//! socket in a temporary directory.  This module is conditionally compiled for cfg(test) only.
+use crate::ApiClient;
+use datastore::{Committed, DataStore, FilesystemDataStore, Key, KeyType};
+use http::StatusCode;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+/// Holds the lifetime of a `TempDir` containing the datastore and API socket used by a test
+/// server.  The server thread is left running and goes away with the test process.
+struct TestServer {
+    _tmp: TempDir,
+    socket_path: PathBuf,
+}
+
+impl TestServer {
+    /// Populates a datastore with a few settings, services, and metadata, then starts an API
+    /// server for it and waits until it's listening.
+    async fn start() -> Self {
+        let tmp = TempDir::new().unwrap();
+        let datastore_path = tmp.path().join("datastore");
+        let socket_path = tmp.path().join("api.sock");
+        populate_datastore(&datastore_path);
+
+        let server_socket = socket_path.clone();
+        thread::spawn(move || {
+            actix_web::rt::System::new()
+                .block_on(apiserver::serve(&server_socket, &datastore_path, 1, None))
+                .unwrap();
+        });
+
+        // actix removes the socket file if a connection arrives before its worker is ready, so
+        // clients connect through a link to the socket, which it doesn't know about.  The socket
+        // appears when the server binds it, and queues connections until the worker is ready.
+        let client_socket = tmp.path().join("client.sock");
+        let deadline = Instant::now() + Duration::from_secs(30);
+        while fs::hard_link(&socket_path, &client_socket).is_err() {
+            assert!(
+                Instant::now() < deadline,
+                "API server didn't create its socket"
+            );
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        Self {
+            _tmp: tmp,
+            socket_path: client_socket,
+        }
+    }
+
+    fn client(&self) -> ApiClient {
+        ApiClient::new(&self.socket_path)
+    }
+}
+
+fn populate_datastore(path: &Path) {
+    let mut ds = FilesystemDataStore::new(path);
+    let live = Committed::Live;
+    let data = [
+        ("settings.motd", "\"hello\""),
+        ("settings.ntp.time-servers", "[\"time.example.com\"]"),
+        ("services.motd.configuration-files", "[\"motd\"]"),
+        ("services.motd.restart-commands", "[]"),
+        ("services.chronyd.configuration-files", "[\"chrony-conf\"]"),
+        (
+            "services.chronyd.restart-commands",
+            "[\"/bin/systemctl try-restart chronyd\"]",
+        ),
+        ("configuration-files.motd.path", "\"/etc/motd\""),
+        (
+            "configuration-files.motd.template-path",
+            "\"/usr/share/templates/motd\"",
+        ),
+    ];
+    for (key, value) in data.iter() {
+        let key = Key::new(KeyType::Data, key).unwrap();
+        ds.set_key(&key, value, &live).unwrap();
+    }
+
+    let motd = Key::new(KeyType::Data, "settings.motd").unwrap();
+    let affected = Key::new(KeyType::Meta, "affected-services").unwrap();
+    ds.set_metadata(&affected, &motd, "[\"motd\"]").unwrap();
+    let template = Key::new(KeyType::Meta, "template").unwrap();
+    ds.set_metadata(&template, &motd, "\"{{ os.variant_id }}\"")
+        .unwrap();
+}
+
+#[tokio::test]
+async fn get_settings() {
+    let server = TestServer::start().await;
+    let client = server.client();
+
+    let settings = client.get_settings(None).await.unwrap();
+    assert_eq!(settings.motd.unwrap(), "hello");
+    assert!(settings.ntp.is_some());
+
+    let settings = client.get_settings(Some("ntp")).await.unwrap();
+    assert!(settings.motd.is_none());
+    let time_servers = settings.ntp.unwrap().time_servers.unwrap();
+    assert_eq!(time_servers.len(), 1);
+
+    let settings = client.get_settings_keys(&["settings.motd"]).await.unwrap();
+    assert_eq!(settings.motd.unwrap(), "hello");
+    assert!(settings.ntp.is_none());
+}
+
+#[tokio::test]
+async fn patch_and_commit_settings() {
+    let server = TestServer::start().await;
+    let client = server.client();
+    let tx = "typed-apiclient-test";
+
+    let settings = model::Settings {
+        motd: Some("goodbye".to_string()),
+        ..Default::default()
+    };
+    client.patch_settings(&settings, tx).await.unwrap();
+
+    let pending = client.get_transaction(tx).await.unwrap();
+    assert_eq!(pending.motd.unwrap(), "goodbye");
+    assert!(client.list_transactions().await.unwrap().contains(tx));
+    // Pending changes don't affect live settings.
+    let live = client.get_settings(None).await.unwrap();
+    assert_eq!(live.motd.unwrap(), "hello");
+
+    let changed = client.commit(tx).await.unwrap();
+    assert_eq!(changed.len(), 1);
+    assert!(changed.contains("settings.motd"));
+    let live = client.get_settings(None).await.unwrap();
+    assert_eq!(live.motd.unwrap(), "goodbye");
+
+    // Nothing left to commit.
+    let err = client.commit(tx).await.unwrap_err();
+    assert_eq!(err.status_code(), Some(StatusCode::UNPROCESSABLE_ENTITY));
+}
+
+#[tokio::test]
+async fn delete_transaction() {
+    let server = TestServer::start().await;
+    let client = server.client();
+    let tx = "typed-apiclient-delete";
+
+    let settings = model::Settings {
+        motd: Some("never committed".to_string()),
+        ..Default::default()
+    };
+    client.patch_settings(&settings, tx).await.unwrap();
+
+    let deleted = client.delete_transaction(tx).await.unwrap();
+    assert!(deleted.contains("settings.motd"));
+    assert!(!client.list_transactions().await.unwrap().contains(tx));
+}
+
+#[tokio::test]
+async fn get_services_and_configuration_files() {
+    let server = TestServer::start().await;
+    let client = server.client();
+
+    let services = client.get_services(&[] as &[&str]).await.unwrap();
+    assert_eq!(services.len(), 2);
+
+    let services = client.get_services(&["chronyd"]).await.unwrap();
+    assert_eq!(services.len(), 1);
+    let chronyd = &services["chronyd"];
+    assert_eq!(
+        chronyd.restart_commands,
+        vec!["/bin/systemctl try-restart chronyd".to_string()]
+    );
+
+    let files = client.get_configuration_files(&["motd"]).await.unwrap();
+    assert_eq!(files["motd"].path.as_ref(), "/etc/motd");
+}
+
+#[tokio::test]
+async fn get_metadata() {
+    let server = TestServer::start().await;
+    let client = server.client();
+
+    let affected = client
+        .get_affected_services(&["settings.motd"])
+        .await
+        .unwrap();
+    assert_eq!(affected["settings.motd"], vec!["motd".to_string()]);
+
+    let templates = client.get_templates(&["settings.motd"]).await.unwrap();
+    assert_eq!(templates["settings.motd"], "{{ os.variant_id }}");
+}
+
+#[tokio::test]
+async fn error_status() {
+    let server = TestServer::start().await;
+    let client = server.client();
+
+    // The API rejects an empty prefix.
+    let err = client.get_settings(Some("")).await.unwrap_err();
+    assert_eq!(err.status_code(), Some(StatusCode::BAD_REQUEST));
+}
+
+#[test]
+fn build_uri_escapes_values() {
+    assert_eq!(crate::build_uri("/settings", &[]), "/settings");
+    assert_eq!(
+        crate::build_uri("/settings", &[("tx", "a b&c=d")]),
+        "/settings?tx=a%20b%26c%3Dd"
+    );
+    assert_eq!(
+        crate::build_uri("/services", &[("names", "a,b"), ("x", "y")]),
+        "/services?names=a,b&x=y"
+    );
+}
@@ -6,10 +6,30 @@
 pub const API_SOCKET: &str = "/run/api.sock";
 pub const API_SETTINGS_URI: &str = "/settings";
 pub const API_SETTINGS_GENERATORS_URI: &str = "/metadata/setting-generators";
+pub const API_SETTING_ORIGINS_URI: &str = "/metadata/setting-origins";
+
+// Socket where a long-running thar-be-settings listens for changes to apply
+pub const THAR_BE_SETTINGS_SOCKET: &str = "/run/thar-be-settings.sock";
 
 // Shared transaction used by boot time services
 pub const LAUNCH_TRANSACTION: &str = "bottlerocket-launch";
 
+// Transaction used by storewolf for default settings; it's committed before the launch
+// transaction, and the API marks settings committed from it as defaults
+pub const DEFAULTS_TRANSACTION: &str = "bottlerocket-defaults";
+
+// Transaction used by sundog for generated settings; the API marks settings committed from it
+// as generated
+pub const GENERATED_TRANSACTION: &str = "bottlerocket-generated";
+
+// Where the migrator keeps its reports of recent runs, in the data store directory
+pub const MIGRATION_REPORT: &str = "/var/lib/bottlerocket/datastore/migration-report.json";
+
 // Shared binaries' locations
 pub const SYSTEMCTL_BIN: &str = "/bin/systemctl";
 pub const HOST_CTR_BIN: &str = "/bin/host-ctr";
+pub const SUNDOG_BIN: &str = "/usr/bin/sundog";
+
+// Held by sundog while it generates settings at boot, and by the API while a refresh it started
+// is running, so only one runs at a time
+pub const SUNDOG_LOCKFILE: &str = "/run/lock/sundog.lock";
@@ -195,6 +195,8 @@ struct UpdatesSettings {
     // Version to update to when updating via the API.
     version_lock: FriendlyVersion,
     ignore_waves: bool,
+    // Maximum download rate for updates, in KiB per second.
+    max_download_rate: u32,
 }
 
 #[model]
@@ -257,6 +259,14 @@ pub type Services = HashMap<String, Service>;
 struct Service {
     configuration_files: Vec<SingleLineString>,
     restart_commands: Vec<String>,
+    // Services that must finish restarting before this one, when both are being restarted.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    restart_after: Vec<SingleLineString>,
+    // Whether the service only needs a restart when one of its configuration files changes.  If
+    // not, its restart commands may read settings directly, so it's restarted whenever settings
+    // that affect it change.
+    #[serde(default)]
+    restart_only_on_file_change: bool,
 }
 
 pub type ConfigurationFiles = HashMap<String, ConfigurationFile>;
@@ -96,6 +96,32 @@ pub struct Update {
     #[serde(deserialize_with = "de::deserialize_bound")]
     pub waves: BTreeMap<u32, DateTime<Utc>>,
     pub images: Images,
+    /// Binary diffs that reconstruct this update's images from those of earlier versions.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub deltas: Vec<Delta>,
+}
+
+/// Binary diffs that let a host running `from_version` build an update's images from its active
+/// partitions instead of downloading the full images.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Delta {
+    pub from_version: Version,
+    pub boot: DeltaImage,
+    pub root: DeltaImage,
+    pub hash: DeltaImage,
+}
+
+/// A binary diff from one version of an image to another.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeltaImage {
+    /// The name of the target holding the LZ4-compressed diff.
+    pub target: String,
+    /// The size of the image the diff applies to, which is how much of the active partition to
+    /// read, since partitions are usually larger than their images.
+    pub source_size: u64,
+    /// The hex-encoded SHA-256 digest of the image the diff produces, which matches the
+    /// decompressed full image target.
+    pub sha256: String,
 }
 
 #[derive(Debug, Default, Serialize, Deserialize)]
@@ -171,6 +197,7 @@ impl Manifest {
             max_version: max_version.clone(),
             images,
             waves: BTreeMap::new(),
+            deltas: Vec::new(),
         };
         self.update_max_version(
             &update.max_version,
@@ -237,6 +264,26 @@ impl Manifest {
             .collect()
     }
 
+    /// Adds a delta to the updates matching variant, arch, and version, replacing any existing
+    /// delta from the same version; returns the number of matching updates.
+    pub fn add_delta(
+        &mut self,
+        variant: String,
+        arch: String,
+        image_version: Version,
+        delta: &Delta,
+    ) -> usize {
+        let matching = self.get_matching_updates(variant, arch, image_version);
+        let num_matching = matching.len();
+        for update in matching {
+            update
+                .deltas
+                .retain(|existing| existing.from_version != delta.from_version);
+            update.deltas.push(delta.clone());
+        }
+        num_matching
+    }
+
     /// Adds a vec of waves to update, returns number of matching updates for wave
     // Wave format in `manifest.json` is slightly different from the wave structs
     // provided to this function. For example, if two `UpdateWave` structs are
@@ -391,6 +438,14 @@ impl Update {
         // There are no waves, so we consider the update available
         true
     }
+
+    /// Returns the delta that builds this update's images from those of the given version, if any.
+    #[must_use]
+    pub fn delta_from(&self, version: &Version) -> Option<&Delta> {
+        self.deltas
+            .iter()
+            .find(|delta| delta.from_version == *version)
+    }
 }
 
 pub fn find_migrations(from: &Version, to: &Version, manifest: &Manifest) -> Result<Vec<String>> {
@@ -475,6 +530,7 @@ mod tests {
                 root: String::from("root"),
                 hash: String::from("hash"),
             },
+            deltas: Vec::new(),
         }
     }
 
@@ -640,6 +696,7 @@ mod tests {
                 root: String::from("root"),
                 hash: String::from("hash"),
             },
+            deltas: Vec::new(),
         };
         let seed = 1024;
         // Construct a DateTime object for 1/1/2000 00:00:00
@@ -691,4 +748,50 @@ mod tests {
         assert!(i.next().unwrap() == "migration_1.1.0_b");
         assert!(i.next().unwrap() == "migration_1.1.0_a");
     }
+
+    #[test]
+    fn test_add_delta() {
+        let mut manifest = Manifest::default();
+        manifest.updates.push(test_update());
+        // Manifests without deltas are written as before.
+        let json = serde_json::to_string(&manifest).unwrap();
+        assert!(!json.contains("deltas"));
+
+        let delta_image = |target: &str| DeltaImage {
+            target: target.to_string(),
+            source_size: 1024,
+            sha256: String::from("0123"),
+        };
+        let from_version = Version::parse("1.1.0").unwrap();
+        let delta = |root: &str| Delta {
+            from_version: from_version.clone(),
+            boot: delta_image("boot-delta"),
+            root: delta_image(root),
+            hash: delta_image("hash-delta"),
+        };
+        let version = Version::parse("1.1.1").unwrap();
+        let add = |manifest: &mut Manifest, delta: &Delta| {
+            manifest.add_delta(
+                "bottlerocket".to_string(),
+                "test".to_string(),
+                version.clone(),
+                delta,
+            )
+        };
+        assert_eq!(add(&mut manifest, &delta("root-delta")), 1);
+        // Adding a delta from the same version again replaces it.
+        assert_eq!(add(&mut manifest, &delta("new-root-delta")), 1);
+
+        let json = serde_json::to_string(&manifest).unwrap();
+        let manifest: Manifest = serde_json::from_str(&json).unwrap();
+        let update = &manifest.updates[0];
+        assert_eq!(update.deltas.len(), 1);
+        assert_eq!(
+            update.delta_from(&from_version).unwrap().root.target,
+            "new-root-delta"
+        );
+        assert!(update
+            .delta_from(&Version::parse("1.0.0").unwrap())
+            .is_none());
+    }
 }
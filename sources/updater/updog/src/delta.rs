@@ -0,0 +1,230 @@
+//! The delta module applies bsdiff patches as streams, so an image can be built from the active
+//! partition and written to the inactive partition without holding either image in memory.
+
+use sha2::{Digest, Sha256};
+use std::convert::TryFrom;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+/// How much of the mixed data, which is added to the old image, we handle at a time.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Applies a bsdiff patch to the first `old_len` bytes of `old`, writing the new image to `new`
+/// as it's built.  Returns the length of the new image.
+///
+/// A patch is a series of entries, each a control block followed by data: "mix" bytes that are
+/// added to bytes of the old image, then "copy" bytes that are new.  The control block also says
+/// how far to move in the old image before the next entry.
+pub(crate) fn patch<O, P, W>(
+    old: &mut O,
+    old_len: u64,
+    patch: &mut P,
+    new: &mut W,
+) -> io::Result<u64>
+where
+    O: Read + Seek,
+    P: Read,
+    W: Write,
+{
+    let mut old_pos: u64 = 0;
+    let mut new_len: u64 = 0;
+    let mut mix = vec![0; CHUNK_SIZE];
+    let mut old_data = vec![0; CHUNK_SIZE];
+    while let Some(control) = read_control(patch)? {
+        let mix_end = old_pos
+            .checked_add(control.mix_len)
+            .filter(|end| *end <= old_len)
+            .ok_or_else(|| invalid("patch reads past the end of the old image"))?;
+
+        old.seek(SeekFrom::Start(old_pos))?;
+        let mut remaining = control.mix_len;
+        while remaining > 0 {
+            let len = usize::try_from(remaining).map_or(CHUNK_SIZE, |r| r.min(CHUNK_SIZE));
+            patch.read_exact(&mut mix[..len])?;
+            old.read_exact(&mut old_data[..len])?;
+            for (n, o) in mix[..len].iter_mut().zip(&old_data[..len]) {
+                *n = n.wrapping_add(*o);
+            }
+            new.write_all(&mix[..len])?;
+            remaining -= len as u64;
+        }
+
+        let copied = io::copy(&mut patch.take(control.copy_len), new)?;
+        if copied != control.copy_len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        new_len += control.mix_len + control.copy_len;
+        old_pos = if control.seek_back {
+            mix_end.checked_sub(control.seek_len)
+        } else {
+            mix_end.checked_add(control.seek_len)
+        }
+        .ok_or_else(|| invalid("patch seeks outside the old image"))?;
+    }
+    Ok(new_len)
+}
+
+/// The control block at the start of each patch entry.
+struct Control {
+    mix_len: u64,
+    copy_len: u64,
+    /// How far to move in the old image after the mix bytes; bsdiff stores this as a
+    /// sign-magnitude number.
+    seek_len: u64,
+    seek_back: bool,
+}
+
+/// Reads the next control block, or returns None if the patch is done.
+fn read_control<P: Read>(patch: &mut P) -> io::Result<Option<Control>> {
+    let mut buf = [0; 24];
+    let mut filled = 0;
+    while filled < buf.len() {
+        match patch.read(&mut buf[filled..]) {
+            Ok(0) if filled == 0 => return Ok(None),
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    let field = |i: usize| {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(&buf[i * 8..(i + 1) * 8]);
+        u64::from_le_bytes(bytes)
+    };
+    let sign = 1 << 63;
+    Ok(Some(Control {
+        mix_len: field(0),
+        copy_len: field(1),
+        seek_len: field(2) & !sign,
+        seek_back: field(2) & sign != 0,
+    }))
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Passes writes through to another writer, keeping a SHA-256 digest of everything written, so an
+/// image can be verified as it's written rather than read back afterward.
+pub(crate) struct HashingWriter<W> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W: Write> HashingWriter<W> {
+    pub(crate) fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    /// Flushes the inner writer and returns the hex-encoded digest of everything written.
+    pub(crate) fn finish(mut self) -> io::Result<String> {
+        self.inner.flush()?;
+        Ok(format!("{:x}", self.hasher.finalize()))
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// Builds a new image from an old one with changes throughout, so the patch has mixed,
+    /// copied, and moved data.
+    fn images() -> (Vec<u8>, Vec<u8>) {
+        // The old image can't repeat, or bsdiff could match any part of the new image anywhere.
+        let mut state: u32 = 1;
+        let old: Vec<u8> = (0..300 * 1024)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                state.to_be_bytes()[0]
+            })
+            .collect();
+        let mut new = Vec::new();
+        new.extend_from_slice(&old[200 * 1024..]);
+        new.extend_from_slice(b"something new in the middle");
+        new.extend_from_slice(&old[..150 * 1024]);
+        for i in (0..new.len()).step_by(1000) {
+            new[i] = new[i].wrapping_add(1);
+        }
+        (old, new)
+    }
+
+    #[test]
+    fn matches_bsdiff() {
+        let (old, new) = images();
+        let mut patch_data = Vec::new();
+        bsdiff::diff(&old, &new, &mut patch_data).unwrap();
+
+        let mut expected = Vec::new();
+        bsdiff::patch(&old, &mut patch_data.as_slice(), &mut expected).unwrap();
+        assert_eq!(expected, new);
+
+        // Like a partition, the old data can be longer than the image.
+        let mut partition = old.clone();
+        partition.extend_from_slice(&[0xff; 4096]);
+        let mut built = HashingWriter::new(Vec::new());
+        let len = patch(
+            &mut Cursor::new(partition),
+            old.len() as u64,
+            &mut patch_data.as_slice(),
+            &mut built,
+        )
+        .unwrap();
+        assert_eq!(len, new.len() as u64);
+        assert_eq!(built.inner, new);
+        assert_eq!(
+            built.finish().unwrap(),
+            format!("{:x}", Sha256::digest(&new))
+        );
+    }
+
+    #[test]
+    fn rejects_reads_past_old_image() {
+        let (old, new) = images();
+        let mut patch_data = Vec::new();
+        bsdiff::diff(&old, &new, &mut patch_data).unwrap();
+
+        // If the old image is shorter than the patch expects, it's an error, not a short read of
+        // whatever follows it.
+        let result = patch(
+            &mut Cursor::new(old.clone()),
+            old.len() as u64 / 2,
+            &mut patch_data.as_slice(),
+            &mut io::sink(),
+        );
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn rejects_truncated_patch() {
+        let (old, new) = images();
+        let mut patch_data = Vec::new();
+        bsdiff::diff(&old, &new, &mut patch_data).unwrap();
+        patch_data.truncate(patch_data.len() - 10);
+
+        let result = patch(
+            &mut Cursor::new(old.clone()),
+            old.len() as u64,
+            &mut patch_data.as_slice(),
+            &mut io::sink(),
+        );
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -53,6 +53,26 @@ pub(crate) enum Error {
         path: PathBuf,
     },
 
+    #[snafu(display("Failed to apply delta {}: {}", target, source))]
+    DeltaApply {
+        target: String,
+        source: std::io::Error,
+        backtrace: Backtrace,
+    },
+
+    #[snafu(display(
+        "Image built from delta {} has SHA-256 {}, expected {}",
+        target,
+        actual,
+        expected
+    ))]
+    DeltaVerify {
+        target: String,
+        expected: String,
+        actual: String,
+        backtrace: Backtrace,
+    },
+
     #[snafu(display("Logger setup error: {}", source))]
     Logger { source: log::SetLoggerError },
 
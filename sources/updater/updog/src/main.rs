@@ -1,14 +1,16 @@
 #![deny(rust_2018_idioms)]
 #![warn(clippy::pedantic)]
 
+mod delta;
 mod error;
 mod transport;
 
+use crate::delta::HashingWriter;
 use crate::error::Result;
 use crate::transport::{HttpQueryTransport, QueryParams};
 use bottlerocket_release::BottlerocketRelease;
 use chrono::Utc;
-use log::debug;
+use log::{debug, warn};
 use model::modeled_types::FriendlyVersion;
 use semver::Version;
 use serde::{Deserialize, Serialize};
@@ -16,16 +18,16 @@ use signal_hook::consts::SIGTERM;
 use signal_hook::iterator::Signals;
 use signpost::State;
 use simplelog::{Config as LogConfig, LevelFilter, SimpleLogger};
-use snafu::{ErrorCompat, OptionExt, ResultExt};
+use snafu::{ensure, ErrorCompat, OptionExt, ResultExt};
 use std::convert::{TryFrom, TryInto};
 use std::fs::{self, File, OpenOptions};
-use std::io;
+use std::io::{self, BufReader, BufWriter, Read};
 use std::path::Path;
 use std::process;
 use std::str::FromStr;
 use std::thread;
 use tough::{Repository, RepositoryLoader};
-use update_metadata::{find_migrations, Manifest, Update};
+use update_metadata::{find_migrations, DeltaImage, Manifest, Update};
 use url::Url;
 
 #[cfg(target_arch = "x86_64")]
@@ -42,6 +44,11 @@ const MIGRATION_PATH: &str = "/var/lib/bottlerocket-migrations";
 /// This is where we store the TUF metadata used by migrator after reboot.
 const METADATA_PATH: &str = "/var/cache/bottlerocket-metadata";
 
+/// This is where we keep partial downloads of targets, so they can be resumed.  Each takes up to
+/// its target's compressed size until it's been read to the end, and any left over are removed
+/// once the update is written.
+const DOWNLOAD_PATH: &str = "/var/cache/bottlerocket-downloads";
+
 #[derive(Debug, Deserialize, PartialEq)]
 #[serde(rename_all = "kebab-case")]
 enum Command {
@@ -63,6 +70,8 @@ struct Config {
     ignore_waves: bool,
     https_proxy: Option<String>,
     no_proxy: Option<Vec<String>>,
+    /// The maximum download rate, in KiB per second.
+    max_download_rate: Option<u32>,
     // TODO API sourced configuration, eg.
     // blacklist: Option<Vec<Version>>,
     // mode: Option<{Automatic, Managed, Disabled}>
@@ -267,7 +276,7 @@ fn retrieve_migrations(
     Ok(())
 }
 
-fn update_image(update: &Update, repository: &Repository) -> Result<()> {
+fn update_image(update: &Update, repository: &Repository, current_version: &Version) -> Result<()> {
     let mut gpt_state = State::load().context(error::PartitionTableRead)?;
     gpt_state.clear_inactive();
     // Write out the clearing of the inactive partition immediately, because we're about to
@@ -275,18 +284,147 @@ fn update_image(update: &Update, repository: &Repository) -> Result<()> {
     // know we're done with all components.
     gpt_state.write().context(error::PartitionTableWrite)?;
 
+    let active = gpt_state.active_set();
     let inactive = gpt_state.inactive_set();
 
+    // If the update has deltas from the version we're running, we can build its images from our
+    // own partitions rather than downloading them whole.
+    let delta = update.delta_from(current_version);
+    if delta.is_some() {
+        debug!("Update has deltas from {}", current_version);
+    }
+
+    // Partial downloads of anything else are from attempts at other updates, and can't be used.
+    let mut targets = vec![
+        update.images.root.as_str(),
+        update.images.boot.as_str(),
+        update.images.hash.as_str(),
+    ];
+    if let Some(delta) = delta {
+        targets.extend(&[
+            delta.root.target.as_str(),
+            delta.boot.target.as_str(),
+            delta.hash.target.as_str(),
+        ]);
+    }
+    if let Err(e) = transport::remove_stale_downloads(Path::new(DOWNLOAD_PATH), &targets) {
+        warn!("Failed to remove stale partial downloads: {}", e);
+    }
+
     // TODO Do we want to recover the inactive side on an error?
-    write_target_to_disk(repository, &update.images.root, &inactive.root)?;
-    write_target_to_disk(repository, &update.images.boot, &inactive.boot)?;
-    write_target_to_disk(repository, &update.images.hash, &inactive.hash)?;
+    write_image(
+        repository,
+        &update.images.root,
+        delta.map(|d| &d.root),
+        &active.root,
+        &inactive.root,
+    )?;
+    write_image(
+        repository,
+        &update.images.boot,
+        delta.map(|d| &d.boot),
+        &active.boot,
+        &inactive.boot,
+    )?;
+    write_image(
+        repository,
+        &update.images.hash,
+        delta.map(|d| &d.hash),
+        &active.hash,
+        &inactive.hash,
+    )?;
+
+    // The images are written, so partial downloads, like of a full image we tried before a
+    // delta worked, won't be needed.
+    if let Err(e) = transport::remove_stale_downloads(Path::new(DOWNLOAD_PATH), &[]) {
+        warn!("Failed to remove partial downloads: {}", e);
+    }
 
     gpt_state.mark_inactive_valid();
     gpt_state.write().context(error::PartitionTableWrite)?;
     Ok(())
 }
 
+/// Writes an image to the given inactive partition.  If there's a delta for it, we try to build
+/// the image from the matching active partition first, and fall back to the full image target if
+/// that fails for any reason.
+fn write_image(
+    repository: &Repository,
+    target: &str,
+    delta: Option<&DeltaImage>,
+    active_path: &Path,
+    inactive_path: &Path,
+) -> Result<()> {
+    if let Some(delta) = delta {
+        match write_delta_to_disk(repository, delta, active_path, inactive_path) {
+            Ok(()) => return Ok(()),
+            Err(e) => warn!(
+                "Failed to build {} from delta, downloading full image: {}",
+                target, e
+            ),
+        }
+    }
+    write_target_to_disk(repository, target, inactive_path)
+}
+
+/// Builds an image from the active partition and a delta, and writes it to the inactive partition,
+/// verifying it against the expected hash.
+fn write_delta_to_disk(
+    repository: &Repository,
+    delta: &DeltaImage,
+    active_path: &Path,
+    inactive_path: &Path,
+) -> Result<()> {
+    let target = &delta.target;
+    let reader = repository
+        .read_target(target)
+        .context(error::Metadata)?
+        .context(error::TargetNotFound { target })?;
+    let reader = lz4::Decoder::new(reader).context(error::Lz4Decode { target })?;
+    apply_delta(delta, active_path, reader, inactive_path)
+}
+
+/// Applies a decompressed delta to the image at the start of the active partition, writing the
+/// resulting image to the inactive partition as it's built, and checks that it matches the
+/// expected hash.
+///
+/// Neither image is held in memory, so this works for images of any size.  If the hash doesn't
+/// match, the inactive partition is left with a bad image; that's safe because the partition set
+/// isn't marked valid until every image is written, and the full image is written over it.
+fn apply_delta<R: Read>(
+    delta: &DeltaImage,
+    active_path: &Path,
+    mut patch: R,
+    inactive_path: &Path,
+) -> Result<()> {
+    let mut source = BufReader::new(
+        File::open(active_path).context(error::OpenPartition { path: active_path })?,
+    );
+    let inactive = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .open(inactive_path)
+        .context(error::OpenPartition {
+            path: inactive_path,
+        })?;
+    let mut image = HashingWriter::new(BufWriter::new(inactive));
+
+    let context = error::DeltaApply {
+        target: &delta.target,
+    };
+    delta::patch(&mut source, delta.source_size, &mut patch, &mut image).context(context)?;
+    let actual = image.finish().context(context)?;
+    ensure!(
+        actual == delta.sha256,
+        error::DeltaVerify {
+            target: &delta.target,
+            expected: &delta.sha256,
+            actual,
+        }
+    );
+    Ok(())
+}
+
 fn update_flags() -> Result<()> {
     let mut gpt_state = State::load().context(error::PartitionTableRead)?;
     gpt_state
@@ -497,7 +635,12 @@ fn main_inner() -> Result<()> {
     set_https_proxy_environment_variables(&config.https_proxy, &config.no_proxy)?;
     let current_release = BottlerocketRelease::new().context(error::ReleaseVersion)?;
     let variant = arguments.variant.unwrap_or(current_release.variant_id);
-    let transport = HttpQueryTransport::new();
+    let targets_base_url = Url::parse(&config.targets_base_url).context(error::UrlParse {
+        url: &config.targets_base_url,
+    })?;
+    let transport = HttpQueryTransport::new()
+        .resume_downloads(DOWNLOAD_PATH, targets_base_url)
+        .max_download_rate(config.max_download_rate.map(|kib| u64::from(kib) * 1024));
     // get a shared pointer to the transport's query_params so we can add metrics information to
     // the transport's HTTP calls.
     let mut query_params = transport.query_params();
@@ -549,7 +692,7 @@ fn main_inner() -> Result<()> {
                     u,
                     &current_release.version_id,
                 )?;
-                update_image(u, &repository)?;
+                update_image(u, &repository, &current_release.version_id)?;
                 if command == Command::Update {
                     update_flags()?;
                     if arguments.reboot {
@@ -614,6 +757,7 @@ fn main() -> ! {
 mod tests {
     use super::*;
     use chrono::Duration as TestDuration;
+    use sha2::{Digest, Sha256};
     use std::collections::BTreeMap;
     use update_metadata::Images;
 
@@ -667,6 +811,7 @@ mod tests {
             ignore_waves: false,
             https_proxy: None,
             no_proxy: None,
+            max_download_rate: None,
         };
         let version = Version::parse("1.18.0").unwrap();
         let variant = String::from("bottlerocket-aws-eks");
@@ -700,6 +845,7 @@ mod tests {
             ignore_waves: false,
             https_proxy: None,
             no_proxy: None,
+            max_download_rate: None,
         };
 
         let version = Version::parse("0.1.3").unwrap();
@@ -738,6 +884,7 @@ mod tests {
             ignore_waves: false,
             https_proxy: None,
             no_proxy: None,
+            max_download_rate: None,
         };
 
         let version = Version::parse("1.10.0").unwrap();
@@ -780,6 +927,7 @@ mod tests {
             ignore_waves: false,
             https_proxy: None,
             no_proxy: None,
+            max_download_rate: None,
         };
 
         let version = Version::parse("1.10.0").unwrap();
@@ -850,6 +998,7 @@ mod tests {
                 root: String::from("boot"),
                 hash: String::from("boot"),
             },
+            deltas: Vec::new(),
         };
 
         let current_version = Version::parse("1.0.0").unwrap();
@@ -863,6 +1012,7 @@ mod tests {
             ignore_waves: false,
             https_proxy: None,
             no_proxy: None,
+            max_download_rate: None,
         };
 
         // Two waves; the 1st wave that starts immediately, and the final wave which starts in one hour
@@ -902,4 +1052,36 @@ mod tests {
             "Later wave incorrectly sees update"
         );
     }
+
+    #[test]
+    fn delta_builds_verified_image() {
+        let old_image: Vec<u8> = (0..64 * 1024).map(|i| (i % 251) as u8).collect();
+        let mut new_image = old_image.clone();
+        new_image[1000..1100].copy_from_slice(&[7; 100]);
+        new_image.extend_from_slice(b"new data at the end");
+        let mut patch = Vec::new();
+        bsdiff::diff(&old_image, &new_image, &mut patch).unwrap();
+
+        // The partition is larger than the image written to it.
+        let partition = tempfile::NamedTempFile::new().unwrap();
+        let mut contents = old_image.clone();
+        contents.extend_from_slice(&[0xff; 4096]);
+        fs::write(partition.path(), contents).unwrap();
+
+        let mut delta = DeltaImage {
+            target: String::from("root-delta"),
+            source_size: old_image.len() as u64,
+            sha256: format!("{:x}", Sha256::digest(&new_image)),
+        };
+        let inactive = tempfile::NamedTempFile::new().unwrap();
+        apply_delta(&delta, partition.path(), patch.as_slice(), inactive.path()).unwrap();
+        assert_eq!(fs::read(inactive.path()).unwrap(), new_image);
+
+        // An image that doesn't match the expected hash is rejected.
+        delta.sha256 = format!("{:x}", Sha256::digest(&old_image));
+        assert!(matches!(
+            apply_delta(&delta, partition.path(), patch.as_slice(), inactive.path()),
+            Err(error::Error::DeltaVerify { .. })
+        ));
+    }
 }
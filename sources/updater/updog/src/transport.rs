@@ -1,19 +1,45 @@
+use std::cmp;
+use std::convert::TryFrom;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use log::error;
-use tough::{HttpTransport, Transport, TransportError};
+use log::{debug, error, warn};
+use reqwest::blocking::{Client, Response};
+use reqwest::header::{CONTENT_RANGE, RANGE};
+use reqwest::StatusCode;
+use tough::{HttpTransport, Transport, TransportError, TransportErrorKind};
 use url::Url;
 
+/// The number of times we'll try to resume a download that fails partway through, in one fetch.
+const DOWNLOAD_TRIES: u32 = 4;
+
+/// How long to wait before trying to resume a failed download.
+const DOWNLOAD_BACKOFF: Duration = Duration::from_secs(1);
+
 /// A shared pointer to a list of query params that the transport will add to HTTP calls.
 #[derive(Debug, Clone, Default)]
 pub(crate) struct QueryParams(Arc<RwLock<Vec<(String, String)>>>);
 
-/// A `tough` `Transport` that allows us to add query parameters to HTTP calls.
+/// A `tough` `Transport` that allows us to add query parameters to HTTP calls.  It can also keep
+/// partial downloads of targets so they can be resumed, and limit download bandwidth.
 #[derive(Debug, Clone)]
 #[allow(clippy::module_name_repetitions)]
 pub(crate) struct HttpQueryTransport {
     pub inner: HttpTransport,
     parameters: QueryParams,
+    downloads: Option<Downloads>,
+    max_rate: Option<u64>,
+}
+
+/// Where we keep partial downloads, and which URLs they're for.
+#[derive(Debug, Clone)]
+struct Downloads {
+    dir: PathBuf,
+    targets_base_url: Url,
 }
 
 impl QueryParams {
@@ -58,9 +84,29 @@ impl HttpQueryTransport {
         Self {
             inner: HttpTransport::default(),
             parameters: QueryParams::default(),
+            downloads: None,
+            max_rate: None,
         }
     }
 
+    /// Keep partial downloads of targets under `targets_base_url` in `dir`, so that a download
+    /// that's interrupted, even by updog exiting, picks up where it left off the next time the
+    /// target is fetched.  What's been downloaded is replayed from `dir` for verification, so a
+    /// target takes up to its full size there until it's been read to the end, when it's removed.
+    pub fn resume_downloads<P: Into<PathBuf>>(mut self, dir: P, targets_base_url: Url) -> Self {
+        self.downloads = Some(Downloads {
+            dir: dir.into(),
+            targets_base_url,
+        });
+        self
+    }
+
+    /// Limit each download to the given number of bytes per second.
+    pub fn max_download_rate(mut self, bytes_per_second: Option<u64>) -> Self {
+        self.max_rate = bytes_per_second.filter(|rate| *rate > 0);
+        self
+    }
+
     /// Obtain a shared pointer to the query params for this transport.
     pub fn query_params(&self) -> QueryParams {
         QueryParams(Arc::clone(&self.parameters.0))
@@ -72,6 +118,453 @@ impl Transport for HttpQueryTransport {
         &self,
         url: Url,
     ) -> std::result::Result<Box<dyn std::io::Read + Send>, TransportError> {
-        self.inner.fetch(self.parameters.add_params_to_url(url))
+        let url = self.parameters.add_params_to_url(url);
+        if let Some(downloads) = &self.downloads {
+            if url
+                .as_str()
+                .starts_with(downloads.targets_base_url.as_str())
+            {
+                return downloads.fetch(&url, self.max_rate);
+            }
+        }
+
+        let reader = self.inner.fetch(url)?;
+        Ok(match self.max_rate {
+            Some(rate) => Box::new(RateLimited::new(reader, rate)),
+            None => reader,
+        })
+    }
+}
+
+impl Downloads {
+    /// Fetches the given URL, starting with what's already been downloaded, if anything.
+    fn fetch(
+        &self,
+        url: &Url,
+        max_rate: Option<u64>,
+    ) -> std::result::Result<Box<dyn Read + Send>, TransportError> {
+        let other =
+            |e: io::Error| TransportError::new_with_cause(TransportErrorKind::Other, url, e);
+        let name = url
+            .path_segments()
+            .and_then(Iterator::last)
+            .filter(|name| !name.is_empty())
+            .ok_or_else(|| TransportError::new(TransportErrorKind::Other, url))?;
+        fs::create_dir_all(&self.dir).map_err(other)?;
+        let path = self.dir.join(format!("{}.partial", name));
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(other)?;
+        let offset = file.metadata().map_err(other)?.len();
+        let client = Client::builder()
+            .timeout(Duration::from_secs(30))
+            .connect_timeout(Duration::from_secs(10))
+            .build()
+            .map_err(|e| TransportError::new_with_cause(TransportErrorKind::Other, url, e))?;
+        let mut download = Download {
+            client,
+            url: url.clone(),
+            path: path.clone(),
+            file,
+            offset,
+            response: None,
+            tries_left: DOWNLOAD_TRIES - 1,
+        };
+
+        let response = download.request()?;
+        let prefix = match response.status() {
+            StatusCode::PARTIAL_CONTENT if offset > 0 => {
+                debug!("Resuming download of {} at byte {}", url, offset);
+                download.response = Some(response);
+                Some(File::open(&path).map_err(other)?.take(offset))
+            }
+            // We're starting fresh, or the server ignored the range and sent the whole file.
+            StatusCode::OK => {
+                download.restart().map_err(other)?;
+                download.response = Some(response);
+                None
+            }
+            // We asked for bytes past the end.  If we already have the whole file, for example
+            // because we were stopped before passing it all on, it's verified like any download.
+            // Otherwise what we have can't be resumed, for example because the file changed;
+            // start over, making a new request on the first read.
+            StatusCode::RANGE_NOT_SATISFIABLE if offset > 0 => {
+                match complete_length(&response) {
+                    Some(length) if length == offset => {
+                        debug!("Already downloaded all of {}", url);
+                        let file = File::open(&path).map_err(other)?;
+                        return Ok(Box::new(CompleteDownload { file, path }));
+                    }
+                    _ => download.restart().map_err(other)?,
+                }
+                None
+            }
+            StatusCode::NOT_FOUND => {
+                return Err(TransportError::new(TransportErrorKind::FileNotFound, url))
+            }
+            status => return Err(status_error(url, status)),
+        };
+
+        let download: Box<dyn Read + Send> = match max_rate {
+            Some(rate) => Box::new(RateLimited::new(download, rate)),
+            None => Box::new(download),
+        };
+        Ok(match prefix {
+            Some(prefix) => Box::new(prefix.chain(download)),
+            None => download,
+        })
+    }
+}
+
+/// The network side of a resumable download, which saves what it reads to the partial download
+/// file, and resumes from the end of that file if the connection fails.
+struct Download {
+    client: Client,
+    url: Url,
+    path: PathBuf,
+    /// The partial download file, opened for appending.
+    file: File,
+    /// The number of bytes downloaded so far.
+    offset: u64,
+    response: Option<Response>,
+    tries_left: u32,
+}
+
+impl Download {
+    /// Requests the rest of the file, from the current offset.
+    fn request(&self) -> std::result::Result<Response, TransportError> {
+        let mut request = self.client.get(self.url.clone());
+        if self.offset > 0 {
+            request = request.header(RANGE, format!("bytes={}-", self.offset));
+        }
+        request
+            .send()
+            .map_err(|e| TransportError::new_with_cause(TransportErrorKind::Other, &self.url, e))
+    }
+
+    /// Discards what's been downloaded so far.
+    fn restart(&mut self) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.offset = 0;
+        Ok(())
+    }
+
+    /// Makes a new request for the rest of the file after a failure.
+    fn resume(&mut self) -> io::Result<Response> {
+        let response = self.request().map_err(to_io_error)?;
+        match response.status() {
+            StatusCode::PARTIAL_CONTENT => Ok(response),
+            StatusCode::OK if self.offset == 0 => Ok(response),
+            status => Err(to_io_error(status_error(&self.url, status))),
+        }
+    }
+}
+
+impl Read for Download {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.response.is_none() {
+                self.response = Some(self.resume()?);
+            }
+            let result = match &mut self.response {
+                Some(response) => response.read(buf),
+                None => unreachable!(),
+            };
+            match result {
+                Ok(0) => {
+                    // Everything has been downloaded and passed on for verification; we don't
+                    // need the partial download anymore.
+                    remove_download(&self.path);
+                    return Ok(0);
+                }
+                Ok(n) => {
+                    self.file.write_all(&buf[..n])?;
+                    self.offset += n as u64;
+                    return Ok(n);
+                }
+                Err(e) if self.tries_left > 0 => {
+                    warn!(
+                        "Download of {} failed at byte {}, resuming: {}",
+                        self.url, self.offset, e
+                    );
+                    self.tries_left -= 1;
+                    self.response = None;
+                    thread::sleep(DOWNLOAD_BACKOFF);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// A download that was already complete, read from its file.
+struct CompleteDownload {
+    file: File,
+    path: PathBuf,
+}
+
+impl Read for CompleteDownload {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.file.read(buf)?;
+        if n == 0 && !buf.is_empty() {
+            // If it doesn't verify, we start over next time rather than trying it again.
+            remove_download(&self.path);
+        }
+        Ok(n)
+    }
+}
+
+/// Gets the length of the whole file from the Content-Range header of a 416 response, which looks
+/// like "bytes */12345".
+fn complete_length(response: &Response) -> Option<u64> {
+    response
+        .headers()
+        .get(CONTENT_RANGE)?
+        .to_str()
+        .ok()?
+        .strip_prefix("bytes */")?
+        .parse()
+        .ok()
+}
+
+/// Removes a partial download that's been passed on in full.
+fn remove_download(path: &Path) {
+    match fs::remove_file(path) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => warn!(
+            "Failed to remove partial download '{}': {}",
+            path.display(),
+            e
+        ),
+    }
+}
+
+fn status_error(url: &Url, status: StatusCode) -> TransportError {
+    TransportError::new_with_cause(
+        TransportErrorKind::Other,
+        url,
+        format!("unexpected HTTP status {}", status),
+    )
+}
+
+fn to_io_error(e: TransportError) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e)
+}
+
+/// Removes partial downloads in the given directory, except those of the given targets, so that
+/// downloads of updates we're no longer interested in don't take up space forever.  With no
+/// targets, every partial download is removed.
+pub(crate) fn remove_stale_downloads(dir: &Path, targets: &[&str]) -> io::Result<()> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    for entry in entries {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        // Targets may be stored with a hash prefix, so we check the end of the name.
+        let wanted = targets
+            .iter()
+            .any(|target| name.ends_with(&format!("{}.partial", target)));
+        if !wanted {
+            debug!("Removing stale partial download '{}'", name);
+            fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+/// A reader that limits the rate at which bytes are read from the inner reader.
+struct RateLimited<R> {
+    inner: R,
+    bytes_per_second: u64,
+    start: Option<Instant>,
+    bytes: u64,
+}
+
+impl<R> RateLimited<R> {
+    fn new(inner: R, bytes_per_second: u64) -> Self {
+        Self {
+            inner,
+            bytes_per_second,
+            start: None,
+            bytes: 0,
+        }
+    }
+}
+
+impl<R: Read> Read for RateLimited<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let start = *self.start.get_or_insert_with(Instant::now);
+        // Read at most a tenth of a second's worth at a time, so the rate stays smooth.
+        let max_len = usize::try_from(self.bytes_per_second / 10)
+            .unwrap_or(usize::MAX)
+            .max(1);
+        let len = cmp::min(buf.len(), max_len);
+        let n = self.inner.read(&mut buf[..len])?;
+
+        self.bytes += n as u64;
+        let expected = Duration::from_millis(self.bytes * 1000 / self.bytes_per_second);
+        if let Some(wait) = expected.checked_sub(start.elapsed()) {
+            thread::sleep(wait);
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use std::sync::Mutex;
+    use tempfile::TempDir;
+
+    /// A local HTTP server for a single file that supports range requests.  Returns its URL and
+    /// the start of the range of each request it's served.
+    fn serve(data: Vec<u8>, interrupt_after: Option<usize>) -> (Url, Arc<Mutex<Vec<u64>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!(
+            "http://{}/targets/image.lz4",
+            listener.local_addr().unwrap()
+        ))
+        .unwrap();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let served = Arc::clone(&requests);
+        thread::spawn(move || {
+            let mut interrupt_after = interrupt_after;
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut start = 0;
+                for line in BufReader::new(&stream).lines() {
+                    let line = line.unwrap();
+                    if line.is_empty() {
+                        break;
+                    }
+                    if let Some(range) = line.to_lowercase().strip_prefix("range: bytes=") {
+                        start = range.trim_end_matches('-').parse().unwrap();
+                    }
+                }
+                served.lock().unwrap().push(start as u64);
+
+                let header = if start >= data.len() {
+                    format!(
+                        "HTTP/1.1 416 Range Not Satisfiable\r\nContent-Range: bytes */{}\r\n",
+                        data.len()
+                    )
+                } else if start > 0 {
+                    format!(
+                        "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {}-{}/{}\r\n",
+                        start,
+                        data.len() - 1,
+                        data.len()
+                    )
+                } else {
+                    "HTTP/1.1 200 OK\r\n".to_string()
+                };
+                let body = &data[cmp::min(start, data.len())..];
+                write!(
+                    stream,
+                    "{}Content-Length: {}\r\nConnection: close\r\n\r\n",
+                    header,
+                    body.len()
+                )
+                .unwrap();
+                // Send only part of the body the first time, if asked, as if the connection was
+                // lost.
+                let len = interrupt_after.take().unwrap_or(body.len());
+                let _ = stream.write_all(&body[..len]);
+            }
+        });
+        (url, requests)
+    }
+
+    fn data() -> Vec<u8> {
+        (0..64 * 1024).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn transport(dir: &Path, url: &Url) -> HttpQueryTransport {
+        HttpQueryTransport::new().resume_downloads(dir, url.join("/targets/").unwrap())
+    }
+
+    #[test]
+    fn resumes_interrupted_download() {
+        let (url, requests) = serve(data(), Some(10000));
+        let dir = TempDir::new().unwrap();
+
+        let mut downloaded = Vec::new();
+        transport(dir.path(), &url)
+            .fetch(url.clone())
+            .unwrap()
+            .read_to_end(&mut downloaded)
+            .unwrap();
+        assert_eq!(downloaded, data());
+        assert_eq!(*requests.lock().unwrap(), vec![0, 10000]);
+        // The partial download is removed once it's complete.
+        assert!(!dir.path().join("image.lz4.partial").exists());
+    }
+
+    #[test]
+    fn resumes_earlier_partial_download() {
+        let (url, requests) = serve(data(), None);
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join("image.lz4.partial"), &data()[..20000]).unwrap();
+
+        let mut downloaded = Vec::new();
+        transport(dir.path(), &url)
+            .fetch(url.clone())
+            .unwrap()
+            .read_to_end(&mut downloaded)
+            .unwrap();
+        assert_eq!(downloaded, data());
+        assert_eq!(*requests.lock().unwrap(), vec![20000]);
+        assert!(!dir.path().join("image.lz4.partial").exists());
+    }
+
+    #[test]
+    fn verifies_complete_partial_download() {
+        let (url, requests) = serve(data(), None);
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join("image.lz4.partial"), data()).unwrap();
+
+        let mut downloaded = Vec::new();
+        transport(dir.path(), &url)
+            .fetch(url.clone())
+            .unwrap()
+            .read_to_end(&mut downloaded)
+            .unwrap();
+        // The server says there's nothing more, and what we have is passed on, not fetched again.
+        assert_eq!(downloaded, data());
+        assert_eq!(*requests.lock().unwrap(), vec![data().len() as u64]);
+        assert!(!dir.path().join("image.lz4.partial").exists());
+    }
+
+    #[test]
+    fn limits_download_rate() {
+        let data = data();
+        let start = Instant::now();
+        let mut read = Vec::new();
+        RateLimited::new(&data[..3000], 10000)
+            .read_to_end(&mut read)
+            .unwrap();
+        assert_eq!(read, &data[..3000]);
+        assert!(start.elapsed() >= Duration::from_millis(250));
+    }
+
+    #[test]
+    fn removes_stale_downloads() {
+        let dir = TempDir::new().unwrap();
+        for name in &["abc123.root-1.1.lz4.partial", "root-1.0.lz4.partial"] {
+            fs::write(dir.path().join(name), "").unwrap();
+        }
+        remove_stale_downloads(dir.path(), &["root-1.1.lz4"]).unwrap();
+        assert!(dir.path().join("abc123.root-1.1.lz4.partial").exists());
+        assert!(!dir.path().join("root-1.0.lz4.partial").exists());
     }
 }
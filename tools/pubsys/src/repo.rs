@@ -1,6 +1,7 @@
 //! The repo module owns the 'repo' subcommand and controls the process of building a repository.
 
 pub(crate) mod check_expirations;
+mod delta;
 pub(crate) mod refresh_repo;
 pub(crate) mod validate_repo;
 
@@ -22,13 +23,13 @@ use std::num::NonZeroU64;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use structopt::StructOpt;
-use tempfile::NamedTempFile;
+use tempfile::{NamedTempFile, TempDir};
 use tough::{
     editor::signed::PathExists,
     editor::RepositoryEditor,
     key_source::{KeySource, LocalKeySource},
     schema::Target,
-    RepositoryLoader, TransportErrorKind,
+    Repository, RepositoryLoader, TransportErrorKind,
 };
 use tough_kms::{KmsKeySource, KmsSigningAlgorithm};
 use tough_ssm::SsmKeySource;
@@ -67,6 +68,9 @@ pub(crate) struct RepoArgs {
     #[structopt(long, parse(from_os_str))]
     /// Path to the image containing the verity hashes
     hash_image: PathBuf,
+    #[structopt(long = "delta-from", parse(try_from_str=friendly_version))]
+    /// Optional earlier versions to build delta images from, using their images in the repo
+    delta_from: Vec<Version>,
 
     // Optionally add other files to the repo
     #[structopt(long = "link-target", parse(from_os_str))]
@@ -328,12 +332,12 @@ fn repo_urls<'a>(
 
 /// Builds an editor and manifest; will start from an existing repo if one is specified in the
 /// configuration.  Returns Err if we fail to read from the repo.  Returns Ok(None) if we detect
-/// that the repo does not exist.
+/// that the repo does not exist.  The existing repo is returned too, so its targets can be read.
 fn load_editor_and_manifest<'a, P>(
     root_role_path: P,
     metadata_url: &'a Url,
     targets_url: &'a Url,
-) -> Result<Option<(RepositoryEditor, Manifest)>>
+) -> Result<Option<(RepositoryEditor, Manifest, Repository)>>
 where
     P: AsRef<Path>,
 {
@@ -364,10 +368,10 @@ where
                 path: "manifest.json",
             })?;
 
-            let editor =
-                RepositoryEditor::from_repo(root_role_path, repo).context(error::EditorFromRepo)?;
+            let editor = RepositoryEditor::from_repo(root_role_path, repo.clone())
+                .context(error::EditorFromRepo)?;
 
-            Ok(Some((editor, manifest)))
+            Ok(Some((editor, manifest, repo)))
         }
         // If we fail to load, but we only failed because the repo doesn't exist yet, then start
         // fresh by signalling that there is no known repo.  Otherwise, fail hard.
@@ -476,11 +480,12 @@ pub(crate) fn run(args: &Args, repo_args: &RepoArgs) -> Result<()> {
 
     // Build a repo editor and manifest, from an existing repo if available, otherwise fresh
     let maybe_urls = repo_urls(&repo_config, &repo_args.variant, &repo_args.arch)?;
-    let (mut editor, mut manifest) = if let Some((metadata_url, targets_url)) = maybe_urls.as_ref()
+    let (mut editor, mut manifest, repo) = if let Some((metadata_url, targets_url)) =
+        maybe_urls.as_ref()
     {
         info!("Found metadata and target URLs, loading existing repository");
         match load_editor_and_manifest(&repo_args.root_role_path, &metadata_url, &targets_url)? {
-            Some((editor, manifest)) => (editor, manifest),
+            Some((editor, manifest, repo)) => (editor, manifest, Some(repo)),
             None => {
                 warn!(
                     "Did not find repo at '{}', starting a new one",
@@ -489,6 +494,7 @@ pub(crate) fn run(args: &Args, repo_args: &RepoArgs) -> Result<()> {
                 (
                     RepositoryEditor::new(&repo_args.root_role_path).context(error::NewEditor)?,
                     Manifest::default(),
+                    None,
                 )
             }
         }
@@ -497,11 +503,16 @@ pub(crate) fn run(args: &Args, repo_args: &RepoArgs) -> Result<()> {
         (
             RepositoryEditor::new(&repo_args.root_role_path).context(error::NewEditor)?,
             Manifest::default(),
+            None,
         )
     };
 
     // Add update information to manifest
     update_manifest(&repo_args, &mut manifest)?;
+    // Build any requested deltas from earlier updates, adding them to the manifest
+    let delta_dir = TempDir::new().context(error::TempFile)?;
+    let delta_targets =
+        delta::add_deltas(repo_args, repo.as_ref(), &mut manifest, delta_dir.path())?;
     // Write manifest to tempfile so it can be copied in as target later
     let manifest_path = NamedTempFile::new()
         .context(error::TempFile)?
@@ -511,13 +522,17 @@ pub(crate) fn run(args: &Args, repo_args: &RepoArgs) -> Result<()> {
     })?;
 
     // Add manifest and targets to editor
-    let copy_targets = &repo_args.copy_targets;
+    let copy_targets: Vec<&PathBuf> = repo_args
+        .copy_targets
+        .iter()
+        .chain(&delta_targets)
+        .collect();
     let link_targets = repo_args.link_targets.iter().chain(vec![
         &repo_args.boot_image,
         &repo_args.root_image,
         &repo_args.hash_image,
     ]);
-    let all_targets = copy_targets.iter().chain(link_targets.clone());
+    let all_targets = copy_targets.iter().copied().chain(link_targets.clone());
 
     update_editor(&repo_args, &mut editor, all_targets, &manifest_path)?;
 
@@ -609,6 +624,7 @@ pub(crate) fn run(args: &Args, repo_args: &RepoArgs) -> Result<()> {
 
 mod error {
     use chrono::{DateTime, Utc};
+    use semver::Version;
     use snafu::Snafu;
     use std::io;
     use std::path::PathBuf;
@@ -644,6 +660,18 @@ mod error {
         #[snafu(display("Error reading config: {}", source))]
         Config { source: pubsys_config::Error },
 
+        #[snafu(display("Failed to build delta '{}': {}", path.display(), source))]
+        DeltaBuild { path: PathBuf, source: io::Error },
+
+        #[snafu(display("Deltas requested, but there's no existing repo to build them from"))]
+        DeltaNoRepo,
+
+        #[snafu(display("Can't build delta from version {}, which isn't in the repo", version))]
+        DeltaSourceMissing { version: Version },
+
+        #[snafu(display("Failed to write delta '{}': {}", path.display(), source))]
+        DeltaWrite { path: PathBuf, source: io::Error },
+
         #[snafu(display("Failed to create directory '{}': {}", path.display(), source))]
         CreateDir { path: PathBuf, source: io::Error },
 
@@ -669,6 +697,9 @@ mod error {
             source: tough::error::Error,
         },
 
+        #[snafu(display("Failed to decompress image '{}': {}", path.display(), source))]
+        Lz4Decode { path: PathBuf, source: io::Error },
+
         #[snafu(display("Failed to write Manifest to '{}': {}", path.display(), source))]
         ManifestWrite {
             path: PathBuf,
@@ -754,6 +785,9 @@ mod error {
             source: update_metadata::error::Error,
         },
 
+        #[snafu(display("Target '{}' not found in repo", target))]
+        TargetNotFound { target: String },
+
         #[snafu(display("Failed to create temporary file: {}", source))]
         TempFile { source: io::Error },
 
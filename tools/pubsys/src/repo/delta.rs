@@ -0,0 +1,304 @@
+//! The delta module builds binary diffs between the images of an earlier update and the update
+//! being added, so that hosts running the earlier version can build the new images from their
+//! active partitions instead of downloading them in full.
+//!
+//! bsdiff needs about 17 bytes of memory for each byte of the old image it searches, so a delta
+//! is built a block of the new image at a time, searching only the part of the old image around
+//! the same offset.  Each delta takes about 1.2 GiB of memory to build, whatever the image size.
+//! The blocks' patches are joined into one bsdiff patch, so hosts apply it like any other.
+
+use super::{error, RepoArgs, Result};
+use log::info;
+use semver::Version;
+use sha2::{Digest, Sha256};
+use snafu::{OptionExt, ResultExt};
+use std::convert::{TryFrom, TryInto};
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use tough::Repository;
+use update_metadata::{Delta, DeltaImage, Manifest};
+
+/// How much of the new image each part of a delta builds.
+const BLOCK_SIZE: usize = 32 * 1024 * 1024;
+
+/// How far before and after each block's offset we search the old image, so data that moved a
+/// little between versions is still found.
+const OLD_MARGIN: usize = 16 * 1024 * 1024;
+
+/// Marks a negative number in a bsdiff patch, which stores numbers as sign and magnitude.
+const SIGN: u64 = 1 << 63;
+
+/// Builds deltas to the update being added from each version given with `--delta-from`, whose
+/// images are read from the existing repo.  The deltas are added to the manifest and written to
+/// `delta_dir`; returns their paths, so they can be added to the repo as targets.
+pub(super) fn add_deltas(
+    repo_args: &RepoArgs,
+    repo: Option<&Repository>,
+    manifest: &mut Manifest,
+    delta_dir: &Path,
+) -> Result<Vec<PathBuf>> {
+    if repo_args.delta_from.is_empty() {
+        return Ok(Vec::new());
+    }
+    let repo = repo.context(error::DeltaNoRepo)?;
+
+    let mut delta_paths = Vec::new();
+    for from_version in &repo_args.delta_from {
+        let from_images = &manifest
+            .updates
+            .iter()
+            .find(|update| {
+                update.variant == repo_args.variant
+                    && update.arch == repo_args.arch
+                    && update.version == *from_version
+            })
+            .context(error::DeltaSourceMissing {
+                version: from_version.clone(),
+            })?
+            .images;
+
+        info!(
+            "Building deltas from version {} to {}",
+            from_version, repo_args.version
+        );
+        let mut build = |from_target: &str, to_path: &Path| -> Result<DeltaImage> {
+            let (delta, path) = build_delta(repo, from_target, to_path, from_version, delta_dir)?;
+            delta_paths.push(path);
+            Ok(delta)
+        };
+        let delta = Delta {
+            from_version: from_version.clone(),
+            boot: build(&from_images.boot, &repo_args.boot_image)?,
+            root: build(&from_images.root, &repo_args.root_image)?,
+            hash: build(&from_images.hash, &repo_args.hash_image)?,
+        };
+
+        manifest.add_delta(
+            repo_args.variant.clone(),
+            repo_args.arch.clone(),
+            repo_args.version.clone(),
+            &delta,
+        );
+    }
+
+    Ok(delta_paths)
+}
+
+/// Builds a delta from the image in the given repo target to the image at the given path, both
+/// LZ4-compressed, and writes it to `delta_dir`.  Returns the delta's description for the
+/// manifest, and its path.
+fn build_delta(
+    repo: &Repository,
+    from_target: &str,
+    to_path: &Path,
+    from_version: &Version,
+    delta_dir: &Path,
+) -> Result<(DeltaImage, PathBuf)> {
+    let reader = repo
+        .read_target(from_target)
+        .context(error::ReadTarget {
+            target: from_target,
+        })?
+        .context(error::TargetNotFound {
+            target: from_target,
+        })?;
+    // The old image is decompressed to a file, so the parts around each block can be read.
+    let mut from_image = tempfile::tempfile().context(error::TempFile)?;
+    let source_size = io::copy(
+        &mut lz4::Decoder::new(reader).context(error::Lz4Decode { path: from_target })?,
+        &mut from_image,
+    )
+    .context(error::Lz4Decode { path: from_target })?;
+
+    let to_file = File::open(to_path).context(error::File { path: to_path })?;
+    let mut to_image = lz4::Decoder::new(to_file).context(error::Lz4Decode { path: to_path })?;
+
+    // The delta is named after the image it builds, so it's clear which update it belongs to.
+    let to_name = to_path
+        .file_name()
+        .context(error::InvalidImagePath { path: to_path })?
+        .to_str()
+        .context(error::NonUtf8Path { path: to_path })?;
+    let delta_name = format!(
+        "{}.delta-from-{}.lz4",
+        to_name.trim_end_matches(".lz4"),
+        from_version
+    );
+    let delta_path = delta_dir.join(&delta_name);
+
+    let mut encoder = lz4::EncoderBuilder::new()
+        .build(File::create(&delta_path).context(error::DeltaWrite { path: &delta_path })?)
+        .context(error::DeltaWrite { path: &delta_path })?;
+    let (patch_len, sha256) = write_delta(
+        &mut from_image,
+        source_size,
+        &mut to_image,
+        &mut encoder,
+        BLOCK_SIZE,
+        OLD_MARGIN,
+    )
+    .context(error::DeltaBuild { path: &delta_path })?;
+    let (_, result) = encoder.finish();
+    result.context(error::DeltaWrite { path: &delta_path })?;
+
+    info!(
+        "Built {} ({} bytes, {} uncompressed)",
+        delta_name,
+        delta_path
+            .metadata()
+            .context(error::File { path: &delta_path })?
+            .len(),
+        patch_len
+    );
+    let delta = DeltaImage {
+        target: delta_name,
+        source_size,
+        sha256,
+    };
+    Ok((delta, delta_path))
+}
+
+/// Writes a bsdiff patch that builds `new` from the first `old_len` bytes of `old`, one block of
+/// `new` at a time, each diffed against the part of `old` from `margin` bytes before the block's
+/// offset to `margin` bytes after its end.  Returns the length of the patch, and the hex-encoded
+/// SHA-256 digest of `new`.
+fn write_delta<O, N, W>(
+    old: &mut O,
+    old_len: u64,
+    new: &mut N,
+    patch: &mut W,
+    block_size: usize,
+    margin: usize,
+) -> io::Result<(u64, String)>
+where
+    O: Read + Seek,
+    N: Read,
+    W: Write,
+{
+    let mut hasher = Sha256::new();
+    let mut new_block = vec![0; block_size];
+    let mut old_window = Vec::new();
+    let mut block_patch = Vec::new();
+    let mut patch_len = 0;
+    let mut block_start: u64 = 0;
+    // Where the patch so far leaves the position in the old image.
+    let mut old_pos: u64 = 0;
+    loop {
+        let len = read_block(new, &mut new_block)?;
+        if len == 0 {
+            break;
+        }
+        let new_block = &new_block[..len];
+        hasher.update(new_block);
+
+        let window_start = block_start.saturating_sub(margin as u64).min(old_len);
+        let window_end = (block_start + (len + margin) as u64).min(old_len);
+        old_window.resize(to_usize(window_end - window_start)?, 0);
+        old.seek(SeekFrom::Start(window_start))?;
+        old.read_exact(&mut old_window)?;
+
+        // Each block's patch works from the start of its window in the old image, so we move
+        // there first.
+        block_patch.clear();
+        if window_start != old_pos {
+            write_control(&mut block_patch, 0, 0, signed(window_start, old_pos)?);
+        }
+        let block_end = if old_window.is_empty() {
+            // There's nothing to diff against, so the block is all new data.
+            write_control(&mut block_patch, 0, len as u64, 0);
+            block_patch.extend_from_slice(new_block);
+            window_start
+        } else {
+            let diff_start = block_patch.len();
+            bsdiff::diff(&old_window, new_block, &mut block_patch)?;
+            offset(window_start, end_position(&block_patch[diff_start..])?)?
+        };
+        patch.write_all(&block_patch)?;
+        patch_len += block_patch.len() as u64;
+        old_pos = block_end;
+        block_start += len as u64;
+    }
+    Ok((patch_len, format!("{:x}", hasher.finalize())))
+}
+
+/// Fills `buf` from `reader`, unless the reader ends first.  Returns the number of bytes read.
+fn read_block<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// Appends a bsdiff control block, which says how many bytes to add to the old image, how many
+/// new bytes to copy, and how far to move in the old image afterward.
+fn write_control(patch: &mut Vec<u8>, mix_len: u64, copy_len: u64, seek: i64) {
+    let seek = if seek < 0 {
+        seek.unsigned_abs() | SIGN
+    } else {
+        seek.unsigned_abs()
+    };
+    for field in &[mix_len, copy_len, seek] {
+        patch.extend_from_slice(&field.to_le_bytes());
+    }
+}
+
+/// Finds where a bsdiff patch leaves the position in the old image, relative to where it starts.
+fn end_position(patch: &[u8]) -> io::Result<i64> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid bsdiff patch");
+    let mut position: i64 = 0;
+    let mut rest = patch;
+    while !rest.is_empty() {
+        let field = |i: usize| -> io::Result<u64> {
+            let bytes = rest.get(i * 8..(i + 1) * 8).ok_or_else(invalid)?;
+            Ok(u64::from_le_bytes(bytes.try_into().map_err(|_| invalid())?))
+        };
+        let (mix_len, copy_len, seek) = (field(0)?, field(1)?, field(2)?);
+        let seek = i64::try_from(seek & !SIGN).map_err(|_| invalid())?;
+        let seek = if field(2)? & SIGN != 0 { -seek } else { seek };
+        position = i64::try_from(mix_len)
+            .ok()
+            .and_then(|mix_len| position.checked_add(mix_len)?.checked_add(seek))
+            .ok_or_else(invalid)?;
+        let data_len = mix_len
+            .checked_add(copy_len)
+            .and_then(|len| usize::try_from(len).ok())
+            .ok_or_else(invalid)?;
+        rest = rest.get(24 + data_len..).ok_or_else(invalid)?;
+    }
+    Ok(position)
+}
+
+/// Returns `to - from`, for moving between positions in the old image.
+fn signed(to: u64, from: u64) -> io::Result<i64> {
+    let overflow = || io::Error::new(io::ErrorKind::InvalidData, "image too large");
+    let to = i64::try_from(to).map_err(|_| overflow())?;
+    let from = i64::try_from(from).map_err(|_| overflow())?;
+    Ok(to - from)
+}
+
+/// Returns `start + relative`, for a position in the old image.
+fn offset(start: u64, relative: i64) -> io::Result<u64> {
+    let result = if relative < 0 {
+        start.checked_sub(relative.unsigned_abs())
+    } else {
+        start.checked_add(relative.unsigned_abs())
+    };
+    result.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "bsdiff patch moves outside the old image",
+        )
+    })
+}
+
+fn to_usize(n: u64) -> io::Result<usize> {
+    usize::try_from(n)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "block too large for memory"))
+}